@@ -0,0 +1,309 @@
+//! Random well-formed ASTs for property tests, and shrinking of the ones a
+//! property fails for down to a small counterexample.
+//!
+//! The trees only use what the grammar can express: valid identifiers, calls
+//! to builtins with their number of arguments (any number for `sum` and
+//! `product`) and to user functions with at least one, and spans left at
+//! their default.
+
+use crate::parser::{CmpOp, Expr, Pattern, Span};
+
+/// Names that are valid identifiers and not keywords.
+const NAMES: &[&str] = &["x", "y", "n", "acc", "total", "a_1", "f", "g"];
+
+/// Deepest tree generated.
+const MAX_DEPTH: u32 = 5;
+
+/// A small deterministic generator (xorshift64*), so that a failure is found
+/// again on every run.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Zero is the one state xorshift never leaves
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number in `0..bound`.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn name(&mut self) -> String {
+        NAMES[self.below(NAMES.len() as u64) as usize].to_string()
+    }
+
+    fn number(&mut self) -> i64 {
+        self.below(41) as i64 - 20
+    }
+}
+
+/// A random expression at most `depth` levels deep.
+pub fn expr(rng: &mut Rng, depth: u32) -> Expr {
+    let span = Span::default();
+    let sub = |rng: &mut Rng| Box::new(expr(rng, depth - 1));
+    if depth == 0 {
+        return match rng.below(5) {
+            0 | 1 => Expr::Number(rng.number()),
+            2 | 3 => Expr::Ident(rng.name().into(), span),
+            _ => Expr::EnvInt("MLIA_VALUE".to_string(), span),
+        };
+    }
+    match rng.below(10) {
+        0 => expr(rng, 0),
+        1 => {
+            const BINARY: &[&str] = &[
+                "+", "-", "*", "/", "%", "emod", "fdiv", "<", ">", "=", "!=", "&", "|",
+            ];
+            let op = BINARY[rng.below(BINARY.len() as u64) as usize];
+            Expr::Call(op.into(), vec![*sub(rng), *sub(rng)], span)
+        }
+        2 => match rng.below(5) {
+            0 => Expr::Break(None, span),
+            1 => Expr::Break(Some(sub(rng)), span),
+            4 => {
+                const PIECES: &[&str] = &["", "x = ", " and ", "{", "}", "%d ", "\"\\\t\n"];
+                let args: Vec<Expr> = (0..rng.below(3))
+                    .map(|_| Expr::Ident(rng.name().into(), span))
+                    .collect();
+                let pieces = (0..=args.len())
+                    .map(|_| PIECES[rng.below(PIECES.len() as u64) as usize].to_string())
+                    .collect();
+                Expr::PrintFormat(pieces, args, span)
+            }
+            _ => {
+                const UNARY: &[&str] = &["print", "!", "ord", "chr", "print_char"];
+                let name = UNARY[rng.below(UNARY.len() as u64) as usize];
+                Expr::Call(name.into(), vec![*sub(rng)], span)
+            }
+        },
+        3 => match rng.below(3) {
+            0 => {
+                let name = if rng.below(2) == 0 { "sum" } else { "product" };
+                let args = (0..rng.below(4)).map(|_| *sub(rng)).collect();
+                Expr::Call(name.into(), args, span)
+            }
+            _ => {
+                let args = (0..=rng.below(3)).map(|_| *sub(rng)).collect();
+                Expr::Call(rng.name().into(), args, span)
+            }
+        },
+        4 => Expr::Block((0..2 + rng.below(2)).map(|_| *sub(rng)).collect()),
+        5 => Expr::Assign(rng.name().into(), sub(rng), span),
+        6 => Expr::Decl(rng.name().into(), vec![], sub(rng), sub(rng), span),
+        7 => {
+            let params = (0..=rng.below(2))
+                .map(|_| {
+                    let default = (rng.below(3) == 0).then(|| rng.number());
+                    (rng.name().into(), default)
+                })
+                .collect();
+            Expr::Decl(rng.name().into(), params, sub(rng), sub(rng), span)
+        }
+        8 => match rng.below(3) {
+            0 => Expr::While(sub(rng), sub(rng), span),
+            1 => Expr::WhileYield(sub(rng), sub(rng), span),
+            _ => Expr::Loop(sub(rng), span),
+        },
+        _ => {
+            let mut arms: Vec<(Pattern, Expr)> = (0..=rng.below(2))
+                .map(|_| (pattern(rng), *sub(rng)))
+                .collect();
+            if rng.below(2) == 0 {
+                arms.push((Pattern::Wildcard, *sub(rng)));
+            }
+            Expr::Match(sub(rng), arms, span)
+        }
+    }
+}
+
+/// A random pattern: mostly a literal, sometimes a comparison, a name (alone
+/// or with `@`), an option or a result.
+fn pattern(rng: &mut Rng) -> Pattern {
+    match rng.below(10) {
+        0 => Pattern::Bind(rng.name().into(), Span::default()),
+        1 => Pattern::None,
+        2 => Pattern::Some(Box::new(pattern(rng))),
+        3 => Pattern::Ok(Box::new(pattern(rng))),
+        4 => Pattern::Err(Box::new(pattern(rng))),
+        5 => Pattern::At(rng.name().into(), Box::new(pattern(rng)), Span::default()),
+        6 => {
+            let ops = [CmpOp::Less, CmpOp::LessEq, CmpOp::Greater, CmpOp::GreaterEq];
+            Pattern::Cmp(ops[rng.below(4) as usize], rng.number())
+        }
+        _ => Pattern::Literal(rng.number()),
+    }
+}
+
+/// Smaller trees like `expr`: its subexpressions, and `expr` with one of
+/// them shrunk, simplest first.
+pub fn shrink(expr: &Expr) -> Vec<Expr> {
+    let span = Span::default();
+    let mut smaller = Vec::new();
+    if !matches!(expr, Expr::Number(0)) {
+        smaller.push(Expr::Number(0));
+    }
+    match expr {
+        Expr::Number(_) | Expr::Ident(..) | Expr::EnvInt(..) | Expr::PrintFormat(..) => {}
+        // Never generated
+        Expr::Str(..) | Expr::Array(..) => {}
+        Expr::Call(name, args, _) => {
+            smaller.extend(args.iter().cloned());
+            for (i, arg) in args.iter().enumerate() {
+                for arg in shrink(arg) {
+                    let mut args = args.clone();
+                    args[i] = arg;
+                    smaller.push(Expr::Call(name.clone(), args, span));
+                }
+            }
+        }
+        Expr::Block(statements) => {
+            smaller.extend(statements.iter().cloned());
+            // Without one of the statements, if two are left
+            if statements.len() > 2 {
+                for i in 0..statements.len() {
+                    let mut statements = statements.clone();
+                    statements.remove(i);
+                    smaller.push(Expr::Block(statements));
+                }
+            }
+            for (i, statement) in statements.iter().enumerate() {
+                for statement in shrink(statement) {
+                    let mut statements = statements.clone();
+                    statements[i] = statement;
+                    smaller.push(Expr::Block(statements));
+                }
+            }
+        }
+        Expr::NamedArg(name, value, _) => smaller.extend(
+            shrink(value)
+                .into_iter()
+                .map(|value| Expr::NamedArg(name.clone(), Box::new(value), span)),
+        ),
+        Expr::Assign(name, value, _) => {
+            smaller.push((**value).clone());
+            smaller.extend(
+                shrink(value)
+                    .into_iter()
+                    .map(|value| Expr::Assign(name.clone(), Box::new(value), span)),
+            );
+        }
+        Expr::Decl(name, params, value, body, _) => {
+            smaller.extend([(**value).clone(), (**body).clone()]);
+            let decl = |value: &Expr, body: &Expr| {
+                Expr::Decl(
+                    name.clone(),
+                    params.clone(),
+                    Box::new(value.clone()),
+                    Box::new(body.clone()),
+                    span,
+                )
+            };
+            smaller.extend(shrink(value).iter().map(|value| decl(value, body)));
+            smaller.extend(shrink(body).iter().map(|body| decl(value, body)));
+        }
+        Expr::While(cond, body, _) => {
+            smaller.extend([(**cond).clone(), (**body).clone()]);
+            smaller.extend(
+                shrink(cond)
+                    .into_iter()
+                    .map(|cond| Expr::While(Box::new(cond), body.clone(), span)),
+            );
+            smaller.extend(
+                shrink(body)
+                    .into_iter()
+                    .map(|body| Expr::While(cond.clone(), Box::new(body), span)),
+            );
+        }
+        Expr::WhileYield(cond, body, _) => {
+            smaller.extend([(**cond).clone(), (**body).clone()]);
+            smaller.push(Expr::While(cond.clone(), body.clone(), span));
+            smaller.extend(
+                shrink(cond)
+                    .into_iter()
+                    .map(|cond| Expr::WhileYield(Box::new(cond), body.clone(), span)),
+            );
+            smaller.extend(
+                shrink(body)
+                    .into_iter()
+                    .map(|body| Expr::WhileYield(cond.clone(), Box::new(body), span)),
+            );
+        }
+        // Not generated: only valid at the start of a program
+        Expr::Global(..) => {}
+        Expr::Loop(body, _) => {
+            smaller.push((**body).clone());
+            smaller.extend(
+                shrink(body)
+                    .into_iter()
+                    .map(|body| Expr::Loop(Box::new(body), span)),
+            );
+        }
+        Expr::Break(None, _) => {}
+        Expr::Break(Some(value), _) => {
+            smaller.extend([Expr::Break(None, span), (**value).clone()]);
+            smaller.extend(
+                shrink(value)
+                    .into_iter()
+                    .map(|value| Expr::Break(Some(Box::new(value)), span)),
+            );
+        }
+        Expr::Match(scrutinee, arms, _) => {
+            smaller.push((**scrutinee).clone());
+            smaller.extend(arms.iter().map(|(_, arm)| arm.clone()));
+            if arms.len() > 1 {
+                for i in 0..arms.len() {
+                    let mut fewer = arms.clone();
+                    fewer.remove(i);
+                    smaller.push(Expr::Match(scrutinee.clone(), fewer, span));
+                }
+            }
+            smaller.extend(
+                shrink(scrutinee)
+                    .into_iter()
+                    .map(|scrutinee| Expr::Match(Box::new(scrutinee), arms.clone(), span)),
+            );
+            for (i, (pattern, arm)) in arms.iter().enumerate() {
+                for arm in shrink(arm) {
+                    let mut arms = arms.clone();
+                    arms[i] = (pattern.clone(), arm);
+                    smaller.push(Expr::Match(scrutinee.clone(), arms, span));
+                }
+            }
+        }
+    }
+    smaller
+}
+
+/// Checks `property` on `cases` random expressions. When it fails, the
+/// expression is shrunk as far as it keeps failing, and the test panics with
+/// the message `property` gave for the smallest one.
+pub fn check_exprs(cases: u64, property: impl Fn(&Expr) -> Result<(), String>) {
+    for seed in 0..cases {
+        let mut rng = Rng::new(seed);
+        let generated = expr(&mut rng, MAX_DEPTH);
+        let Err(mut message) = property(&generated) else {
+            continue;
+        };
+
+        let mut smallest = generated;
+        while let Some((smaller, smaller_message)) = shrink(&smallest)
+            .into_iter()
+            .find_map(|candidate| property(&candidate).err().map(|m| (candidate, m)))
+        {
+            smallest = smaller;
+            message = smaller_message;
+        }
+        panic!(
+            "property failed for seed {} (shrunk to {:?}):\n{}",
+            seed, smallest, message
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! Running a program many times and timing each run, for `mlia bench`.
+//!
+//! [`measure`] runs whatever it is given and reads the time from a clock it
+//! is also given, so that the statistics and the check that every run
+//! returns the same result can be tested without timing anything for real;
+//! `LoadedProgram::bench` calls it with the program compiled once, and
+//! [`wall_clock`].
+
+use crate::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How a program is run by `LoadedProgram::bench`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Runner {
+    /// Compiled once with the JIT, and called again for each run.
+    #[default]
+    Jit,
+    /// Built as an executable, and started again for each run. The result is
+    /// its exit status: the program's result truncated to 8 bits.
+    Executable,
+}
+
+impl Runner {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "jit" => Some(Runner::Jit),
+            "exe" => Some(Runner::Executable),
+            _ => None,
+        }
+    }
+}
+
+/// How many times to run a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Runs that are timed; there has to be at least one.
+    pub iterations: usize,
+    /// Runs before those, which aren't timed (their result is still checked).
+    pub warmup: usize,
+    pub runner: Runner,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            iterations: 10,
+            warmup: 0,
+            runner: Runner::default(),
+        }
+    }
+}
+
+/// The fastest, middle, average and slowest of some durations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub min: Duration,
+    /// The average of the two middle ones for an even number of durations.
+    pub median: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+}
+
+impl Stats {
+    /// The statistics of `samples`, or `None` if there are none.
+    pub fn of(samples: &[Duration]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let (&min, &max) = (sorted.first()?, sorted.last()?);
+        let middle = sorted.len() / 2;
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2
+        } else {
+            sorted[middle]
+        };
+        let total: Duration = sorted.iter().sum();
+        Some(Stats {
+            min,
+            median,
+            mean: total / sorted.len() as u32,
+            max,
+        })
+    }
+}
+
+/// What `measure` found: the result every run returned, and how long each
+/// timed run took.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub result: i64,
+    pub warmup: usize,
+    /// In the order the runs happened, without the warmup runs.
+    pub samples: Vec<Duration>,
+    pub stats: Stats,
+}
+
+impl BenchReport {
+    /// The report as a JSON object on one line, with every duration in
+    /// nanoseconds.
+    pub fn to_json(&self) -> String {
+        let samples: Vec<String> = self
+            .samples
+            .iter()
+            .map(|sample| sample.as_nanos().to_string())
+            .collect();
+        format!(
+            "{{\"result\":{},\"iterations\":{},\"warmup\":{},\"min_ns\":{},\"median_ns\":{},\
+             \"mean_ns\":{},\"max_ns\":{},\"samples_ns\":[{}]}}",
+            self.result,
+            self.samples.len(),
+            self.warmup,
+            self.stats.min.as_nanos(),
+            self.stats.median.as_nanos(),
+            self.stats.mean.as_nanos(),
+            self.stats.max.as_nanos(),
+            samples.join(",")
+        )
+    }
+}
+
+/// A table with the result, the number of runs and the statistics.
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let row = |f: &mut fmt::Formatter<'_>, name: &str, duration: Duration| {
+            writeln!(
+                f,
+                "{:<10} {:>10.3} ms",
+                name,
+                duration.as_secs_f64() * 1000.0
+            )
+        };
+        writeln!(f, "{:<10} {:>13}", "result", self.result)?;
+        writeln!(
+            f,
+            "{:<10} {:>13}",
+            "runs",
+            format!("{} (+{} warmup)", self.samples.len(), self.warmup)
+        )?;
+        row(f, "min", self.stats.min)?;
+        row(f, "median", self.stats.median)?;
+        row(f, "mean", self.stats.mean)?;
+        row(f, "max", self.stats.max)
+    }
+}
+
+/// A clock for `measure` that reads the time elapsed since it was made.
+pub fn wall_clock() -> impl FnMut() -> Duration {
+    let start = Instant::now();
+    move || start.elapsed()
+}
+
+/// Calls `run` `settings.warmup` times and then `settings.iterations` times,
+/// reading `now` before and after each of the latter, and fails with
+/// `Error::UnstableResult` as soon as a run returns something else than the
+/// first one did.
+pub fn measure(
+    settings: &Settings,
+    mut now: impl FnMut() -> Duration,
+    mut run: impl FnMut() -> Result<i64, Error>,
+) -> Result<BenchReport, Error> {
+    if settings.iterations == 0 {
+        return Err(Error::InvalidOptions(
+            "a benchmark needs at least one iteration".to_string(),
+        ));
+    }
+
+    let mut first = None;
+    let mut samples = Vec::with_capacity(settings.iterations);
+    for number in 1..=settings.warmup + settings.iterations {
+        let start = now();
+        let result = run()?;
+        let elapsed = now().saturating_sub(start);
+        match first {
+            None => first = Some(result),
+            Some(first) if first != result => {
+                return Err(Error::UnstableResult {
+                    run: number,
+                    first,
+                    result,
+                });
+            }
+            Some(_) => {}
+        }
+        if number > settings.warmup {
+            samples.push(elapsed);
+        }
+    }
+
+    Ok(BenchReport {
+        result: first.expect("there was at least one run"),
+        warmup: settings.warmup,
+        stats: Stats::of(&samples).expect("there was at least one timed run"),
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    /// Runs that return the results in `runs`, each one moving `clock` on by
+    /// the milliseconds it says it took.
+    fn fake_runs<'a>(
+        clock: &'a Cell<Duration>,
+        runs: &'a [(u64, i64)],
+    ) -> impl FnMut() -> Result<i64, Error> + 'a {
+        let mut runs = runs.iter();
+        move || {
+            let &(millis, result) = runs.next().expect("no more runs");
+            clock.set(clock.get() + ms(millis));
+            Ok(result)
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        assert_eq!(Stats::of(&[]), None);
+        assert_eq!(
+            Stats::of(&[ms(4), ms(1), ms(3)]),
+            Some(Stats {
+                min: ms(1),
+                median: ms(3),
+                mean: Duration::from_nanos(2_666_666),
+                max: ms(4),
+            })
+        );
+        assert_eq!(
+            Stats::of(&[ms(4), ms(1), ms(2), ms(9)]).unwrap().median,
+            ms(3)
+        );
+    }
+
+    #[test]
+    fn test_measure_leaves_the_warmup_runs_out() {
+        let clock = Cell::new(Duration::ZERO);
+        let settings = Settings {
+            iterations: 3,
+            warmup: 2,
+            runner: Runner::Jit,
+        };
+        let runs = [(50, 7), (40, 7), (3, 7), (2, 7), (4, 7)];
+        let report = measure(&settings, || clock.get(), fake_runs(&clock, &runs)).unwrap();
+        assert_eq!(report.result, 7);
+        assert_eq!(report.warmup, 2);
+        assert_eq!(report.samples, [ms(3), ms(2), ms(4)]);
+        assert_eq!(
+            report.stats,
+            Stats {
+                min: ms(2),
+                median: ms(3),
+                mean: ms(3),
+                max: ms(4),
+            }
+        );
+
+        assert_eq!(
+            report.to_string(),
+            "result                 7\n\
+             runs       3 (+2 warmup)\n\
+             min             2.000 ms\n\
+             median          3.000 ms\n\
+             mean            3.000 ms\n\
+             max             4.000 ms\n"
+        );
+        assert_eq!(
+            report.to_json(),
+            "{\"result\":7,\"iterations\":3,\"warmup\":2,\"min_ns\":2000000,\
+             \"median_ns\":3000000,\"mean_ns\":3000000,\"max_ns\":4000000,\
+             \"samples_ns\":[3000000,2000000,4000000]}"
+        );
+    }
+
+    #[test]
+    fn test_measure_stops_at_a_different_result() {
+        let clock = Cell::new(Duration::ZERO);
+        let settings = Settings {
+            iterations: 3,
+            warmup: 1,
+            runner: Runner::Jit,
+        };
+        let runs = [(1, 7), (1, 7), (1, 8), (1, 7)];
+        let error = measure(&settings, || clock.get(), fake_runs(&clock, &runs)).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                Error::UnstableResult {
+                    run: 3,
+                    first: 7,
+                    result: 8
+                }
+            ),
+            "{:?}",
+            error
+        );
+        assert_eq!(
+            error.to_string(),
+            "run 3 returned 8, but the first run returned 7"
+        );
+    }
+
+    #[test]
+    fn test_measure_needs_an_iteration() {
+        let settings = Settings {
+            iterations: 0,
+            ..Settings::default()
+        };
+        let error = measure(&settings, wall_clock(), || Ok(0)).unwrap_err();
+        assert!(matches!(error, Error::InvalidOptions(_)), "{:?}", error);
+    }
+}
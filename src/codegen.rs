@@ -1,84 +1,1285 @@
-use crate::parser::{Expr, Pattern};
+use crate::exhaustiveness::{self, NonExhaustive};
+use crate::optimizer::assigns_to;
+use crate::parser::{CmpOp, Expr, Name, Pattern, Span, arity, builtin_arity, is_reduction};
+use crate::timings::{self, Timings};
+use inkwell::IntPredicate;
 use inkwell::OptimizationLevel;
-use inkwell::builder::Builder;
+use inkwell::attributes::AttributeLoc;
+use inkwell::basic_block::BasicBlock;
+use inkwell::builder::{Builder, BuilderError};
 use inkwell::context::Context;
+use inkwell::debug_info::{
+    AsDIScope, DICompileUnit, DIFlags, DIFlagsConstants, DISubprogram, DWARFEmissionKind,
+    DWARFSourceLanguage, DebugInfoBuilder,
+};
 use inkwell::execution_engine::{ExecutionEngine, JitFunction};
-use inkwell::module::Module;
+use inkwell::module::{FlagBehavior, Linkage, Module};
+use inkwell::passes::PassBuilderOptions;
 use inkwell::targets::{
-    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine,
+    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
 };
-use inkwell::values::{FunctionValue, IntValue, PointerValue};
-use std::collections::{HashMap, HashSet};
+use inkwell::types::{BasicType, FunctionType};
+use inkwell::values::{BasicMetadataValueEnum, FunctionValue, GlobalValue, IntValue, PointerValue};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
 use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+unsafe extern "C" {
+    /// C stdio's `fflush`; with a null stream it flushes every output stream.
+    fn fflush(stream: *mut std::ffi::c_void) -> i32;
+    /// C's `getenv` and `atoll`, which `env_int` calls; JIT-ed programs get
+    /// the ones of this process.
+    fn getenv(name: *const std::ffi::c_char) -> *mut std::ffi::c_char;
+    fn atoll(text: *const std::ffi::c_char) -> i64;
+    /// What `read_line` calls.
+    fn malloc(size: usize) -> *mut std::ffi::c_void;
+    fn getchar() -> i32;
+    /// What `strlen` and `print_str` call.
+    fn strlen(text: *const std::ffi::c_char) -> usize;
+    fn puts(text: *const std::ffi::c_char) -> i32;
+    /// What the file builtins call.
+    fn fopen(path: *const std::ffi::c_char, mode: *const std::ffi::c_char)
+    -> *mut std::ffi::c_void;
+    fn fprintf(stream: *mut std::ffi::c_void, format: *const std::ffi::c_char, ...) -> i32;
+    fn fscanf(stream: *mut std::ffi::c_void, format: *const std::ffi::c_char, ...) -> i32;
+    fn fclose(stream: *mut std::ffi::c_void) -> i32;
+    /// What `push` calls to grow a vector.
+    fn realloc(block: *mut std::ffi::c_void, size: usize) -> *mut std::ffi::c_void;
+    /// What the builtin `free` calls.
+    fn free(block: *mut std::ffi::c_void);
+    /// What a failed runtime check calls (see `build_runtime_check`).
+    fn dprintf(fd: i32, format: *const std::ffi::c_char, ...) -> i32;
+    fn exit(status: i32) -> !;
+}
+
+thread_local! {
+    /// What JIT-ed programs printed while their output is being captured.
+    static CAPTURED_OUTPUT: RefCell<String> = const { RefCell::new(String::new()) };
+
+    /// What `read_line` reads in JIT-ed programs whose output is captured
+    /// (see `execute_captured`), instead of standard input.
+    static CAPTURED_INPUT: RefCell<VecDeque<u8>> = const { RefCell::new(VecDeque::new()) };
+
+    /// Fuel left to a program run by `execute_with_limit`. Programs compiled
+    /// for it have their `mlia_fuel` global mapped here.
+    static FUEL: Cell<i64> = const { Cell::new(0) };
+
+    /// Set by a JIT-ed program whose runtime check failed, which then
+    /// returns from every function up to `main`. Programs that can fail have
+    /// their `mlia_failed` global mapped here.
+    static FAILED: Cell<i64> = const { Cell::new(0) };
+
+    /// What the runtime check that failed said (see `runtime_error`).
+    static RUNTIME_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    /// How many `CodeGen`s the thread has created, for the test that
+    /// checking a program never gets to LLVM. Each test has a thread of its
+    /// own, so the others don't add to it.
+    #[cfg(test)]
+    static CREATED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Stands in for printf in the print runtime function when the output is
+/// captured (see `execute_program_captured`). The program runs on the calling
+/// thread, so a thread-local buffer keeps concurrent runs apart.
+extern "C" fn capture_print_i64(value: i64) {
+    CAPTURED_OUTPUT.with(|output| output.borrow_mut().push_str(&format!("{}\n", value)));
+}
+
+/// Stands in for printf in formatted prints (`print "x is {x}"`) when the
+/// output is captured. `format` is the one printf would get, with `%lld` for
+/// each value in `args`.
+extern "C" fn capture_print_format(format: *const std::ffi::c_char, args: *const i64) {
+    let text = format_values(format, args);
+    CAPTURED_OUTPUT.with(|output| output.borrow_mut().push_str(&text));
+}
+
+/// What a failed runtime check calls in JIT-ed programs instead of writing
+/// its message and exiting, which would end the whole process: it keeps the
+/// message for the caller of the program (see `take_runtime_error`) and sets
+/// `FAILED` so that the program returns. The arguments are as for
+/// `capture_print_format`.
+extern "C" fn runtime_error(format: *const std::ffi::c_char, args: *const i64) {
+    let message = format_values(format, args);
+    RUNTIME_ERROR.with(|error| *error.borrow_mut() = Some(message));
+    FAILED.with(|failed| failed.set(1));
+}
+
+/// `format` filled with `args` as printf would, for a format whose only
+/// conversions are `%lld` and `%%`.
+fn format_values(format: *const std::ffi::c_char, args: *const i64) -> String {
+    // SAFETY: the generated code passes a NUL-terminated string and an array
+    // with one value for each `%lld` in it
+    let format = unsafe { std::ffi::CStr::from_ptr(format) }.to_string_lossy();
+    let mut text = String::new();
+    let mut next_arg = 0;
+    let mut rest = &format[..];
+    while let Some(percent) = rest.find('%') {
+        text.push_str(&rest[..percent]);
+        rest = &rest[percent..];
+        if let Some(after) = rest.strip_prefix("%%") {
+            text.push('%');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("%lld") {
+            text.push_str(&unsafe { *args.add(next_arg) }.to_string());
+            next_arg += 1;
+            rest = after;
+        } else {
+            text.push('%');
+            rest = &rest[1..];
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+/// Clears what a previous run of a JIT-ed program left in `FAILED` and
+/// `RUNTIME_ERROR`, before running one.
+fn clear_runtime_error() {
+    FAILED.with(|failed| failed.set(0));
+    RUNTIME_ERROR.with(|error| error.borrow_mut().take());
+}
+
+/// Fails with the `RuntimeError` of the JIT-ed program that just ran, if one
+/// of its runtime checks failed.
+fn take_runtime_error() -> Result<(), CodegenError> {
+    FAILED.with(|failed| failed.set(0));
+    match RUNTIME_ERROR.with(|error| error.borrow_mut().take()) {
+        Some(message) => Err(CodegenError::RuntimeError(message)),
+        None => Ok(()),
+    }
+}
+
+/// Stands in for putchar in `print_char` when the output is captured.
+extern "C" fn capture_print_char(code: i64) {
+    let c = char::from_u32(code as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+    CAPTURED_OUTPUT.with(|output| output.borrow_mut().push(c));
+}
+
+/// Stands in for puts in `print_str` when the output is captured.
+extern "C" fn capture_print_str(text: *const std::ffi::c_char) {
+    // SAFETY: strings are NUL-terminated buffers made by read_line
+    let text = unsafe { std::ffi::CStr::from_ptr(text) }.to_string_lossy();
+    CAPTURED_OUTPUT.with(|output| output.borrow_mut().push_str(&format!("{}\n", text)));
+}
+
+/// Stands in for getchar in `read_line` when the output is captured: the
+/// next byte of `CAPTURED_INPUT`, or EOF (-1) once it is all read.
+extern "C" fn capture_getchar() -> i32 {
+    CAPTURED_INPUT.with(|input| input.borrow_mut().pop_front().map_or(-1, i32::from))
+}
+
+/// The external functions a sandboxed JIT lets programs call (see
+/// `CodeGen::set_sandboxed`): the C functions the runtime uses, and the ones
+/// that stand in for them when the output is captured or a runtime check
+/// fails. Not `exit`: under the JIT it would end the caller's process.
+const JIT_EXTERNALS: [&str; 14] = [
+    "printf",
+    "putchar",
+    "puts",
+    "getchar",
+    "strlen",
+    "malloc",
+    "realloc",
+    "free",
+    "atoll",
+    "mlia_capture_i64",
+    "mlia_capture_format",
+    "mlia_capture_char",
+    "mlia_capture_str",
+    "mlia_runtime_error",
+];
+
+/// The external functions that reach files and the environment (the file
+/// builtins and `env_int`), which a sandboxed JIT only lets programs call
+/// with `CodeGen::set_sandbox_io`.
+const JIT_IO_EXTERNALS: [&str; 5] = ["fopen", "fprintf", "fscanf", "fclose", "getenv"];
+
+/// How many bytes `read_line` allocates for each line, the terminating NUL
+/// included. Longer lines are split.
+const LINE_BUFFER_SIZE: u64 = 4096;
+
+/// How deeply expressions can be nested in one another before compiling them
+/// fails with `CodegenError::TooDeep`. Chains of sequences and declarations
+/// don't count (see `compile_chain`), so only programs that nest constructs
+/// get anywhere near it.
+const MAX_DEPTH: usize = 256;
+
+/// How many characters of an expression an error shows (see
+/// `CodegenError::found_in`).
+const ERROR_EXPR_WIDTH: usize = 60;
+
+/// Compiler driver used to link executables. On macOS `gcc` is, at best, an
+/// alias for clang; `cc` is what the Xcode command-line tools always provide.
+const LINKER: &str = if cfg!(target_os = "macos") {
+    "cc"
+} else {
+    "gcc"
+};
+
+/// Prefix of the symbols user-defined functions are emitted as (see `mangle`).
+const USER_FUNCTION_PREFIX: &str = "_mlia_";
+
+/// The symbol of the user-defined function `name`: `fact` is `_mlia_fact`.
+/// Without the prefix a function called `printf` or `main` would clash with
+/// the C symbols the runtime calls and the linker expects. Calls are still
+/// resolved, and errors still reported, by `name` (see `user_functions`).
+fn mangle(name: &str) -> String {
+    format!("{}{}", USER_FUNCTION_PREFIX, name)
+}
+
+/// What `compile_to_executable` produces code for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileTarget {
+    /// The machine the compiler runs on; linked with the system C compiler.
+    Native,
+    /// `wasm32-unknown-unknown` (experimental). `print` calls the host function
+    /// imported as `env.print_i64` and `main` is exported.
+    Wasm32,
+}
+
+impl CompileTarget {
+    /// Parses a target triple given on the command line.
+    pub fn from_triple(triple: &str) -> Option<Self> {
+        match triple {
+            "native" => Some(CompileTarget::Native),
+            "wasm32-unknown-unknown" | "wasm32" => Some(CompileTarget::Wasm32),
+            _ => None,
+        }
+    }
+
+    /// The triple modules are generated for.
+    fn triple(self) -> TargetTriple {
+        match self {
+            CompileTarget::Native => TargetMachine::get_default_triple(),
+            CompileTarget::Wasm32 => TargetTriple::create("wasm32-unknown-unknown"),
+        }
+    }
+}
+
+/// The files `compile_to_executable` writes, as chosen with `--emit`. Each one
+/// is named after the output: `<output>.ll`, `<output>.s`, `<output>.o`, and
+/// the executable is the output itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Emit {
+    /// Textual LLVM IR (`ir`)
+    pub ir: bool,
+    /// Assembly for the target (`asm`)
+    pub asm: bool,
+    /// The object file that gets linked (`obj`)
+    pub obj: bool,
+    /// The linked executable (`exe`)
+    pub exe: bool,
+}
+
+impl Emit {
+    /// The names `parse` accepts.
+    pub const KINDS: [&str; 4] = ["ir", "asm", "obj", "exe"];
+
+    /// Parses a comma-separated list such as `ir,obj`.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut emit = Emit {
+            ir: false,
+            asm: false,
+            obj: false,
+            exe: false,
+        };
+        for kind in list.split(',') {
+            match kind.trim() {
+                "ir" => emit.ir = true,
+                "asm" => emit.asm = true,
+                "obj" => emit.obj = true,
+                "exe" => emit.exe = true,
+                other => {
+                    return Err(format!(
+                        "invalid --emit value '{}'; valid values are {}",
+                        other,
+                        Self::KINDS.join(", ")
+                    ));
+                }
+            }
+        }
+        Ok(emit)
+    }
+}
+
+impl Default for Emit {
+    /// Just the executable.
+    fn default() -> Self {
+        Emit {
+            ir: false,
+            asm: false,
+            obj: false,
+            exe: true,
+        }
+    }
+}
+
+/// The printf format for one i64 and a newline on `triple`. The msvcrt printf
+/// used on Windows (MSVC and MinGW) doesn't understand `%lld` everywhere, but
+/// it has always had `%I64d`.
+fn print_format(triple: &TargetTriple) -> &'static str {
+    let triple = triple.as_str().to_string_lossy();
+    if triple.contains("windows") || triple.contains("mingw") {
+        "%I64d\n"
+    } else {
+        "%lld\n"
+    }
+}
+
+/// The tags of the pairs on the heap that hold options and results (see
+/// `CodeGen::compile_tagged`).
+const TAG_NONE: u64 = 0;
+const TAG_SOME: u64 = 1;
+const TAG_OK: u64 = 2;
+const TAG_ERR: u64 = 3;
+
+/// The tag of the pairs `pattern` matches, if it matches options or results.
+fn pattern_tag(pattern: &Pattern) -> Option<u64> {
+    match pattern.unbound() {
+        Pattern::None => Some(TAG_NONE),
+        Pattern::Some(_) => Some(TAG_SOME),
+        Pattern::Ok(_) => Some(TAG_OK),
+        Pattern::Err(_) => Some(TAG_ERR),
+        Pattern::Literal(_)
+        | Pattern::Cmp(..)
+        | Pattern::Wildcard
+        | Pattern::Bind(..)
+        | Pattern::At(..) => None,
+    }
+}
+
+/// Whether `name` is one of the builtins over raw heap blocks (see `CodeGen::compile_heap_op`).
+fn is_heap_op(name: &str) -> bool {
+    matches!(name, "alloc" | "load8" | "store8" | "free")
+}
+
+/// Whether `name` is one of the builtins over arrays (see `CodeGen::compile_array_op`).
+fn is_array_op(name: &str) -> bool {
+    matches!(name, "get" | "set" | "len" | "slice")
+}
+
+/// Whether `name` is one of the builtins over vectors (see `CodeGen::compile_vector_op`).
+fn is_vector_op(name: &str) -> bool {
+    matches!(name, "vec_new" | "push" | "pop" | "vlen" | "vget" | "vset")
+}
+
+/// Whether `name` is one of the builtins over files (see `CodeGen::compile_file_op`).
+fn is_file_op(name: &str) -> bool {
+    matches!(name, "fopen" | "write_line" | "fread_int" | "fclose")
+}
+
+/// Whether a call to `name` with `arity` arguments is a comparison or a logical
+/// operator, which yield an i1 (see `CodeGen::compile_boolean_op`).
+fn is_boolean_op(name: &str, arity: usize) -> bool {
+    match name {
+        "<" | ">" | "=" | "!=" | "&" | "|" => arity == 2,
+        "!" => arity == 1,
+        _ => false,
+    }
+}
 
 /// Convenience type alias for the main function.
 /// Returns an i64 value representing the program's exit code.
 type MainFunc = unsafe extern "C" fn() -> i64;
 
+/// A compiled user function: the LLVM function, the variables it captures
+/// and its parameters with their default values.
+type UserFunction<'ctx> = (FunctionValue<'ctx>, Vec<Name>, Vec<(Name, Option<i64>)>);
+
+/// A program compiled by the JIT that can be run any number of times without
+/// compiling it again (see `CodeGen::compile_to_callable`).
+///
+/// Its machine code lives in the `CodeGen`'s execution engine, which in turn
+/// lives in the LLVM `Context`, so the program borrows the `CodeGen` (`'a`) and
+/// can't outlive it or the `Context`. For the same reason the `CodeGen` can't
+/// compile another program while this one is around: that would free the code.
+pub struct CompiledProgram<'a> {
+    main: JitFunction<'a, MainFunc>,
+}
+
+impl CompiledProgram<'_> {
+    /// Runs the program and returns its result, or the `RuntimeError` of the
+    /// runtime check that stopped it. Each run starts from scratch: variables
+    /// are initialized again, but what the program did to the world (printing,
+    /// writing files, allocating memory it didn't free) stays done.
+    pub fn run(&self) -> Result<i64, CodegenError> {
+        clear_runtime_error();
+        // The main function was verified and takes no arguments, so calling it
+        // can't break Rust's invariants; the generated code only calls the
+        // runtime functions mapped by `compile_program`, and a failed runtime
+        // check returns to here instead of exiting.
+        let result = unsafe { self.main.call() };
+        // The JIT-ed printf calls share this process' stdio buffers; flush them
+        // so the program's output comes out before anything printed afterwards.
+        unsafe {
+            fflush(std::ptr::null_mut());
+        }
+        take_runtime_error()?;
+        Ok(result)
+    }
+}
+
+/// How a program run by `CodeGen::run` went: its result, how long compiling
+/// and running it took, and how big its IR is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionReport {
+    pub result: i64,
+    /// Generating the IR, optimizing it if asked to and compiling it with the JIT.
+    pub compile_time: Duration,
+    pub run_time: Duration,
+    /// Length of the module's IR as printed, in bytes.
+    pub ir_size_bytes: usize,
+}
+
+/// A uniquely named temporary directory for intermediate build files.
+/// The directory and everything in it is removed when the value is dropped.
+struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    fn new() -> std::io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let path = env::temp_dir().join(format!(
+            "mlia-{}-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            nanos
+        ));
+        // create_dir (not create_dir_all) fails if the path already exists,
+        // so two invocations can never share a directory.
+        fs::create_dir(&path)?;
+        Ok(ScratchDir { path })
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Errors produced while compiling an MLIA program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodegenError {
+    /// A variable is read where no declaration of it is in scope. This is an
+    /// error: reading it no longer warns and evaluates to 0. `expr` is the
+    /// expression around it, on one line (see `Expr::display_truncated`).
+    UndefinedVariable {
+        name: String,
+        span: Option<Span>,
+        expr: Option<String>,
+    },
+    /// A call to a function that was never declared; `expr` is the call.
+    UnknownFunction {
+        name: String,
+        arity: usize,
+        expr: Option<String>,
+    },
+    /// A call to a user function with the wrong number of arguments.
+    WrongArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// An assignment to a variable that was never declared.
+    AssignToUndeclared { name: String, span: Option<Span> },
+    /// A match expression whose arms leave out some values.
+    NonExhaustiveMatch {
+        error: NonExhaustive,
+        span: Option<Span>,
+    },
+    /// A `break` outside of any `loop` in its function.
+    BreakOutsideLoop { span: Option<Span> },
+    /// `ref` of something that isn't a variable.
+    RefOfNonVariable { span: Option<Span> },
+    /// A constant index outside of an array whose length is known.
+    IndexOutOfBounds {
+        index: i64,
+        len: u64,
+        span: Option<Span>,
+    },
+    /// A `slice` of constant bounds that doesn't fit in an array whose length
+    /// is known.
+    SliceOutOfBounds {
+        start: i64,
+        count: i64,
+        len: u64,
+        span: Option<Span>,
+    },
+    /// A builtin that the target has no way to implement (`env_int` on wasm32).
+    UnsupportedBuiltin {
+        name: &'static str,
+        span: Option<Span>,
+    },
+    /// A program run by `execute_with_limit` used up its execution budget.
+    ExecutionBudgetExceeded { limit: u64 },
+    /// A runtime check of a program run by the JIT failed: a division by
+    /// zero, an index out of bounds, a file that isn't open... An executable
+    /// writes the same message to stderr and exits with status 1.
+    RuntimeError(String),
+    /// The module declares an external function that a sandboxed JIT doesn't
+    /// let programs call (see `CodeGen::set_sandboxed`).
+    DisallowedExternal(String),
+    /// Expressions nested more than `limit` levels deep (sequences don't
+    /// count), which would overflow the compiler's stack.
+    TooDeep { limit: usize, span: Option<Span> },
+    /// LLVM rejected the generated code for a function. `messages` is the
+    /// verifier's report, `ir_dump` where the offending module was written.
+    VerificationFailed {
+        function: String,
+        messages: String,
+        ir_dump: Option<PathBuf>,
+    },
+    /// An LLVM builder or execution engine operation failed.
+    Llvm(String),
+    /// Producing the object file or linking the executable failed.
+    Emit(String),
+    /// A broken invariant inside the code generator itself.
+    Internal(&'static str),
+}
+
+/// Formats ", in `expr`" for the expression an error was found in.
+fn expr_suffix(expr: &Option<String>) -> String {
+    match expr {
+        Some(expr) => format!(", in `{}`", expr),
+        None => String::new(),
+    }
+}
+
+/// Formats " at line L, column C" for a known source position.
+fn location_suffix(span: Option<Span>) -> String {
+    match span {
+        Some(span) => format!(" at line {}, column {}", span.line, span.column),
+        None => String::new(),
+    }
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodegenError::UndefinedVariable { name, span, expr } => write!(
+                f,
+                "undefined variable '{}'{}{}",
+                name,
+                location_suffix(*span),
+                expr_suffix(expr)
+            ),
+            CodegenError::UnknownFunction { name, arity, expr } => write!(
+                f,
+                "unknown function '{}' called with {} argument(s){}",
+                name,
+                arity,
+                expr_suffix(expr)
+            ),
+            CodegenError::WrongArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function '{}' expects {} argument(s) but was called with {}",
+                name, expected, found
+            ),
+            CodegenError::AssignToUndeclared { name, span } => write!(
+                f,
+                "cannot assign to undeclared variable '{}'{}",
+                name,
+                location_suffix(*span)
+            ),
+            CodegenError::NonExhaustiveMatch { error, span } => {
+                write!(f, "{}{}", error, location_suffix(*span))
+            }
+            CodegenError::BreakOutsideLoop { span } => {
+                write!(f, "'break' outside of a loop{}", location_suffix(*span))
+            }
+            CodegenError::RefOfNonVariable { span } => {
+                write!(f, "'ref' takes a variable{}", location_suffix(*span))
+            }
+            CodegenError::IndexOutOfBounds { index, len, span } => write!(
+                f,
+                "index {} out of bounds (len {}){}",
+                index,
+                len,
+                location_suffix(*span)
+            ),
+            CodegenError::SliceOutOfBounds {
+                start,
+                count,
+                len,
+                span,
+            } => write!(
+                f,
+                "slice of {} elements from {} out of bounds (len {}){}",
+                count,
+                start,
+                len,
+                location_suffix(*span)
+            ),
+            CodegenError::UnsupportedBuiltin { name, span } => write!(
+                f,
+                "'{}' is not available on wasm32{}",
+                name,
+                location_suffix(*span)
+            ),
+            CodegenError::ExecutionBudgetExceeded { limit } => write!(
+                f,
+                "execution budget exceeded: the program ran for more than {} loop \
+                 iterations and function calls",
+                limit
+            ),
+            CodegenError::RuntimeError(message) => write!(f, "{}", message),
+            CodegenError::DisallowedExternal(name) => write!(
+                f,
+                "the program declares the external function '{}', which the sandbox \
+                 doesn't allow",
+                name
+            ),
+            CodegenError::TooDeep { limit, span } => write!(
+                f,
+                "expression nested more than {} levels deep{}; split it into functions \
+                 or declarations of its own",
+                limit,
+                location_suffix(*span)
+            ),
+            CodegenError::VerificationFailed {
+                function,
+                messages,
+                ir_dump,
+            } => {
+                write!(f, "verification of function '{}' failed", function)?;
+                if !messages.is_empty() {
+                    write!(f, ":\n{}", messages.trim_end())?;
+                }
+                if let Some(path) = ir_dump {
+                    write!(f, "\nmodule IR written to {}", path.display())?;
+                }
+                Ok(())
+            }
+            CodegenError::Llvm(message) => write!(f, "LLVM error: {}", message),
+            CodegenError::Emit(message) => write!(f, "{}", message),
+            CodegenError::Internal(message) => write!(f, "internal compiler error: {}", message),
+        }
+    }
+}
+
+impl Error for CodegenError {}
+
+impl CodegenError {
+    /// Where in the source the error is, for the errors that know it.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CodegenError::UndefinedVariable { span, .. }
+            | CodegenError::AssignToUndeclared { span, .. }
+            | CodegenError::NonExhaustiveMatch { span, .. }
+            | CodegenError::BreakOutsideLoop { span }
+            | CodegenError::RefOfNonVariable { span }
+            | CodegenError::IndexOutOfBounds { span, .. }
+            | CodegenError::SliceOutOfBounds { span, .. }
+            | CodegenError::UnsupportedBuiltin { span, .. }
+            | CodegenError::TooDeep { span, .. } => *span,
+            CodegenError::UnknownFunction { .. }
+            | CodegenError::WrongArgumentCount { .. }
+            | CodegenError::ExecutionBudgetExceeded { .. }
+            | CodegenError::RuntimeError(_)
+            | CodegenError::DisallowedExternal(_)
+            | CodegenError::VerificationFailed { .. }
+            | CodegenError::Llvm(_)
+            | CodegenError::Emit(_)
+            | CodegenError::Internal(_) => None,
+        }
+    }
+
+    /// Fills in the expression an undefined name was found in, on its way out
+    /// of `expr`: the innermost one around an undefined variable (the
+    /// variable alone says nothing more than its name), the call itself for
+    /// an unknown function.
+    fn found_in(self, expr: &Expr) -> Self {
+        let shown = || Some(expr.display_truncated(ERROR_EXPR_WIDTH));
+        match self {
+            CodegenError::UndefinedVariable {
+                name,
+                span,
+                expr: None,
+            } if !matches!(expr, Expr::Ident(..)) => CodegenError::UndefinedVariable {
+                name,
+                span,
+                expr: shown(),
+            },
+            CodegenError::UnknownFunction {
+                name,
+                arity,
+                expr: None,
+            } => CodegenError::UnknownFunction {
+                name,
+                arity,
+                expr: shown(),
+            },
+            error => error,
+        }
+    }
+}
+
+impl From<BuilderError> for CodegenError {
+    fn from(error: BuilderError) -> Self {
+        CodegenError::Llvm(error.to_string())
+    }
+}
+
+/// DWARF debug info being emitted for the module (only when compiling with `-g`).
+struct DebugInfo<'ctx> {
+    builder: DebugInfoBuilder<'ctx>,
+    compile_unit: DICompileUnit<'ctx>,
+
+    /// Subprogram of the function currently being compiled
+    scope: Option<DISubprogram<'ctx>>,
+}
+
 /// LLVM code generator for the MLIA language.
 ///
 /// This struct manages the LLVM context, module, builder, and execution engine
-/// to compile MLIA AST expressions into executable LLVM IR.
+/// to compile MLIA AST expressions into executable LLVM IR. The execution
+/// engine is only created once a program is run with the JIT.
 pub struct CodeGen<'ctx> {
     context: &'ctx Context,
     module: Module<'ctx>,
     builder: Builder<'ctx>,
-    execution_engine: ExecutionEngine<'ctx>,
+    /// JIT engine for the current module, created by `compile_program` the
+    /// first time the module is run (compiling to an executable never needs
+    /// one)
+    execution_engine: Option<ExecutionEngine<'ctx>>,
+
+    /// Symbol table for variables in the current scope.
+    /// Like `user_functions`, it is cleared between programs (see `reset`).
+    variables: HashMap<Name, PointerValue<'ctx>>,
 
-    /// Symbol table for variables in the current scope
-    variables: HashMap<String, PointerValue<'ctx>>,
+    /// Lengths of the arrays that variables in scope are known to hold: they
+    /// were declared with an array of known length (see `static_length`) and
+    /// are never assigned.
+    array_lengths: HashMap<Name, u64>,
+
+    /// Globals in scope, as LLVM globals. Unlike `variables`, they are seen by
+    /// every function body, which reads and writes them in place instead of
+    /// capturing them. A variable with the same name shadows the global.
+    globals: HashMap<Name, PointerValue<'ctx>>,
 
     /// Current function being compiled
     current_function: Option<FunctionValue<'ctx>>,
 
-    /// Print function for output operations
+    /// Exit block and break value slot of each `loop` around the expression
+    /// being compiled, innermost last. Only the loops of the current function.
+    loops: Vec<(BasicBlock<'ctx>, PointerValue<'ctx>)>,
+
+    /// Runtime print function (`mlia_print_i64`) for output operations
     print_function: Option<FunctionValue<'ctx>>,
 
     /// User-defined functions with their captured variables
-    /// Maps function name to (LLVM function, list of captured variable names)
-    user_functions: HashMap<String, (FunctionValue<'ctx>, Vec<String>)>,
+    /// Maps function name, as written in the source rather than its mangled
+    /// symbol, to (LLVM function, list of captured variable names, parameters)
+    user_functions: HashMap<Name, UserFunction<'ctx>>,
+
+    /// Files `compile_to_executable` writes next to the output. An object file
+    /// that is only needed for linking is built in a temporary directory that
+    /// gets removed.
+    emit: Emit,
+
+    /// Target `compile_to_executable` generates code for
+    target: CompileTarget,
+
+    /// Make `print` append to `CAPTURED_OUTPUT` instead of calling printf, and
+    /// `read_line` read `CAPTURED_INPUT`
+    capture_output: bool,
+
+    /// Spend fuel on every loop iteration and function call (`execute_with_limit`)
+    fuel_limited: bool,
+
+    /// The code is being generated for the JIT (`compile_program`), where a
+    /// failed runtime check returns to the caller instead of exiting
+    jit: bool,
+
+    /// Only let the JIT run modules whose external functions are all in
+    /// `JIT_EXTERNALS`
+    sandboxed: bool,
+
+    /// Let sandboxed modules call `JIT_IO_EXTERNALS` too
+    sandbox_io: bool,
+
+    /// Check at runtime that array indices are in bounds
+    bounds_checks: bool,
+
+    /// Count the blocks `alloc` hands out and `free` returns, and report the
+    /// ones left when `main` returns
+    debug_heap: bool,
+
+    /// Run the LLVM optimization passes on `main`'s module before it runs or
+    /// is emitted
+    optimize: bool,
+
+    /// Run LLVM's verifier on every function compiled
+    verify: bool,
+
+    /// Debug info builder state, if debug info was requested
+    debug_info: Option<DebugInfo<'ctx>>,
+
+    /// Source file debug info is emitted for, kept to set it up again on `reset`
+    debug_source: Option<String>,
+
+    /// Source file the module is named after (see `set_source_name`)
+    source_name: Option<String>,
+
+    /// Source position of the innermost expression being compiled
+    current_span: Option<Span>,
+
+    /// How many expressions the one being compiled is nested in (see
+    /// `MAX_DEPTH`)
+    depth: usize,
+
+    /// How long each phase took, when asked for (`enable_timings`)
+    timings: Option<Timings>,
+
+    /// The output of the last `compile_to_executable`, if it is an object
+    /// file left unlinked for want of a linker (see `unlinked_output`)
+    unlinked_output: Option<PathBuf>,
+
+    /// Test hook: leave main without its return so that verification fails
+    #[cfg(test)]
+    break_main: bool,
 }
 
+/// The name of modules whose source file isn't known (see
+/// `CodeGen::set_source_name`).
+const MODULE_NAME: &str = "mlia_module";
+
 impl<'ctx> CodeGen<'ctx> {
     /// Creates a new CodeGen instance with the given context.
-    pub fn new(context: &'ctx Context) -> Result<Self, Box<dyn Error>> {
-        let module = context.create_module("mlia_module");
-        let execution_engine = module.create_jit_execution_engine(OptimizationLevel::None)?;
+    pub fn new(context: &'ctx Context) -> Result<Self, CodegenError> {
+        #[cfg(test)]
+        CREATED.with(|created| created.set(created.get() + 1));
+        let module = context.create_module(MODULE_NAME);
         let builder = context.create_builder();
 
         let mut codegen = CodeGen {
             context,
             module,
             builder,
-            execution_engine,
+            execution_engine: None,
             variables: HashMap::new(),
+            array_lengths: HashMap::new(),
             user_functions: HashMap::new(), // Inicializar tabla de funciones
             current_function: None,
+            loops: Vec::new(),
+            globals: HashMap::new(),
             print_function: None,
+            emit: Emit::default(),
+            target: CompileTarget::Native,
+            capture_output: false,
+            fuel_limited: false,
+            jit: false,
+            sandboxed: false,
+            sandbox_io: false,
+            bounds_checks: true,
+            debug_heap: false,
+            optimize: false,
+            verify: true,
+            debug_info: None,
+            debug_source: None,
+            source_name: None,
+            current_span: None,
+            depth: 0,
+            timings: None,
+            unlinked_output: None,
+            #[cfg(test)]
+            break_main: false,
         };
 
+        codegen.set_module_target()?;
+        codegen.identify_module()?;
         // Declare external print function
         codegen.declare_print_function();
         Ok(codegen)
     }
 
-    /// Declares the external print function for outputting integers.
-    /// This links to the C library printf function.
+    /// Discards everything compiled so far and starts over with an empty module
+    /// and no execution engine, so that another program can be compiled.
+    ///
+    /// Variables and user functions don't carry over to the next program;
+    /// settings (debug info, keeping intermediates) do. `compile_program` and
+    /// `compile_to_executable` call this themselves when a program was
+    /// already compiled.
+    pub fn reset(&mut self) -> Result<(), CodegenError> {
+        // The debug info builder finalizes into the old module when dropped
+        self.debug_info = None;
+
+        self.module = self.context.create_module(MODULE_NAME);
+        self.execution_engine = None;
+        self.set_module_target()?;
+        self.identify_module()?;
+
+        self.variables.clear();
+        self.array_lengths.clear();
+        self.globals.clear();
+        self.user_functions.clear();
+        self.current_function = None;
+        self.loops.clear();
+        self.current_span = None;
+        self.depth = 0;
+        self.declare_print_function();
+        if self.fuel_limited {
+            let fuel = self
+                .module
+                .add_global(self.context.i64_type(), None, "mlia_fuel");
+            fuel.set_linkage(Linkage::External);
+        }
+        if let Some(source_path) = self.debug_source.clone() {
+            self.enable_debug_info(&source_path);
+        }
+        Ok(())
+    }
+
+    /// Gives the module the triple and data layout of `self.target`, which the
+    /// sizes and alignments in the generated code depend on.
+    fn set_module_target(&self) -> Result<(), CodegenError> {
+        let (target_triple, target_machine) = self.create_target_machine()?;
+        self.module.set_triple(&target_triple);
+        self.module
+            .set_data_layout(&target_machine.get_target_data().get_data_layout());
+        Ok(())
+    }
+
+    /// Names the module and its source file after `name`, the path of the
+    /// source file (or `<stdin>`, `<cmdline>`), instead of `mlia_module`, and
+    /// adds the path to the `llvm.ident` that records the compiler. This starts
+    /// a new module, like `set_target`.
+    pub fn set_source_name(&mut self, name: &str) -> Result<(), CodegenError> {
+        self.source_name = Some(name.to_string());
+        self.reset()
+    }
+
+    /// Names the module after `source_name` and records in `llvm.ident` which
+    /// compiler made it and from what; object files keep it (in `.comment` on
+    /// Linux), so `strings` shows where they come from.
+    fn identify_module(&self) -> Result<(), CodegenError> {
+        let name = self.source_name.as_deref().unwrap_or(MODULE_NAME);
+        self.module.set_name(name);
+        self.module.set_source_file_name(name);
+
+        let producer = match &self.source_name {
+            Some(source) => format!("mlia {} ({})", env!("CARGO_PKG_VERSION"), source),
+            None => format!("mlia {}", env!("CARGO_PKG_VERSION")),
+        };
+        let ident = self
+            .context
+            .metadata_node(&[self.context.metadata_string(&producer).into()]);
+        self.module
+            .add_global_metadata("llvm.ident", &ident)
+            .map_err(|e| CodegenError::Llvm(format!("failed to add llvm.ident: {}", e)))
+    }
+
+    /// Selects the target of `compile_to_executable`. This starts a new module,
+    /// since the runtime functions differ between targets.
+    pub fn set_target(&mut self, target: CompileTarget) -> Result<(), CodegenError> {
+        self.target = target;
+        self.reset()
+    }
+
+    /// Optimizes the module once the program is compiled: promotes variables to
+    /// registers and hoists loop-invariant code out of loops.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Checks at runtime that array indices are in bounds, stopping the program
+    /// when one isn't (the default). Constant indices into arrays of known
+    /// length are checked when compiling either way.
+    pub fn set_bounds_checks(&mut self, bounds_checks: bool) {
+        self.bounds_checks = bounds_checks;
+    }
+
+    /// Refuses to run programs with the JIT when their module declares an
+    /// external function that isn't one of the runtime's (`JIT_EXTERNALS`),
+    /// failing with `DisallowedExternal` instead. The JIT looks external
+    /// functions up in this process, so a module built some other way than
+    /// from source code (or by a future bug) could call anything in libc.
+    /// Off by default. Files and the environment stay out of reach unless
+    /// `set_sandbox_io` lets them in.
+    pub fn set_sandboxed(&mut self, sandboxed: bool) {
+        self.sandboxed = sandboxed;
+    }
+
+    /// Lets sandboxed programs open, read and write files and read
+    /// environment variables (the file builtins and `env_int`), which the
+    /// sandbox refuses otherwise. Off by default; without the sandbox it
+    /// changes nothing.
+    pub fn set_sandbox_io(&mut self, allowed: bool) {
+        self.sandbox_io = allowed;
+    }
+
+    /// Counts the blocks of `alloc` that are live, and when `main` returns
+    /// reports on standard error how many were never freed.
+    pub fn set_debug_heap(&mut self, debug_heap: bool) {
+        self.debug_heap = debug_heap;
+    }
+
+    /// Runs LLVM's verifier on every function, failing with
+    /// `VerificationFailed` and the verifier's report when one is broken (the
+    /// default). Without it, broken IR goes on to the optimizer and emission,
+    /// which helps to see what a new part of the code generator produces.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Records how long code generation, optimization, emission and linking
+    /// take, for `take_timings`.
+    pub fn enable_timings(&mut self) {
+        self.timings = Some(Timings::default());
+    }
+
+    /// The phases timed since `enable_timings`.
+    pub fn take_timings(&mut self) -> Option<Timings> {
+        self.timings.take()
+    }
+
+    /// The output of the last `compile_to_executable`, when it is the object
+    /// file rather than an executable: a wasm32 module is left unlinked if
+    /// `wasm-ld` isn't found.
+    pub fn unlinked_output(&self) -> Option<&Path> {
+        self.unlinked_output.as_deref()
+    }
+
+    /// Runs `phase`, timing it as `name` when timings are on.
+    fn timed<T>(&mut self, name: &'static str, phase: impl FnOnce(&mut Self) -> T) -> T {
+        if self.timings.is_none() {
+            return phase(self);
+        }
+        let start = Instant::now();
+        let value = phase(self);
+        if let Some(timings) = &mut self.timings {
+            timings.record(name, start.elapsed());
+        }
+        value
+    }
+
+    /// Selects the files `compile_to_executable` writes.
+    pub fn set_emit(&mut self, emit: Emit) {
+        self.emit = emit;
+    }
+
+    /// Emits DWARF debug info for `source_path`: a compile unit for the file, a
+    /// subprogram for every function and a line location for each expression.
+    pub fn enable_debug_info(&mut self, source_path: &str) {
+        let path = fs::canonicalize(source_path).unwrap_or_else(|_| PathBuf::from(source_path));
+        let filename = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or(source_path);
+        let directory = path.parent().and_then(|p| p.to_str()).unwrap_or(".");
+
+        // Without this flag LLVM drops the debug info as being of an unknown version
+        let version = self.context.i32_type().const_int(3, false);
+        self.module
+            .add_basic_value_flag("Debug Info Version", FlagBehavior::Warning, version);
+
+        let (builder, compile_unit) = self.module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            filename,
+            directory,
+            "mlia",
+            false,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+        self.debug_info = Some(DebugInfo {
+            builder,
+            compile_unit,
+            scope: None,
+        });
+        self.debug_source = Some(source_path.to_string());
+    }
+
+    /// Creates a debug-info subprogram for `function` and makes it the current scope.
+    fn debug_enter_function(&mut self, function: FunctionValue<'ctx>, name: &str) {
+        let line = self.current_span.map_or(0, |span| span.line as u32);
+        let Some(debug) = &mut self.debug_info else {
+            return;
+        };
+
+        let file = debug.compile_unit.get_file();
+        let i64_type = debug
+            .builder
+            .create_basic_type("i64", 64, 0x05 /* DW_ATE_signed */, DIFlags::PUBLIC)
+            .unwrap();
+        let fn_type = debug.builder.create_subroutine_type(
+            file,
+            Some(i64_type.as_type()),
+            &[],
+            DIFlags::PUBLIC,
+        );
+        // Debuggers show `name`, and find the function by its mangled symbol
+        let symbol = function
+            .get_name()
+            .to_str()
+            .ok()
+            .filter(|symbol| *symbol != name);
+        let subprogram = debug.builder.create_function(
+            debug.compile_unit.as_debug_info_scope(),
+            name,
+            symbol,
+            file,
+            line,
+            fn_type,
+            false,
+            true,
+            line,
+            DIFlags::PUBLIC,
+            false,
+        );
+        function.set_subprogram(subprogram);
+        debug.scope = Some(subprogram);
+
+        self.set_debug_location(self.current_span);
+    }
+
+    /// Points the debug location of the next instructions at `span`.
+    fn set_debug_location(&self, span: Option<Span>) {
+        let Some(debug) = &self.debug_info else {
+            return;
+        };
+        let Some(scope) = debug.scope else {
+            return;
+        };
+
+        let (line, column) = span.map_or((0, 0), |span| (span.line as u32, span.column as u32));
+        let location = debug.builder.create_debug_location(
+            self.context,
+            line,
+            column,
+            scope.as_debug_info_scope(),
+            None,
+        );
+        self.builder.set_current_debug_location(location);
+    }
+
+    /// Resolves the debug info emitted so far; must run before a function is verified
+    /// or the module is emitted.
+    fn finalize_debug_info(&self) {
+        if let Some(debug) = &self.debug_info {
+            debug.builder.finalize();
+        }
+    }
+
+    /// Defines the runtime print function `void mlia_print_i64(i64)`, which
+    /// every `print` calls. It's the one place that knows how integers are
+    /// written out: natively it calls the C library printf function, on wasm32
+    /// the `env.print_i64` function provided by the host.
     fn declare_print_function(&mut self) {
         let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
         let i8_ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
 
-        // Declare printf function: i32 printf(i8* format, ...)
-        let printf_type = i32_type.fn_type(&[i8_ptr_type.into()], true);
-        let printf_function = self.module.add_function("printf", printf_type, None);
+        // Define void mlia_print_i64(i64 value)
+        let print_type = self.context.void_type().fn_type(&[i64_type.into()], false);
+        let print_function =
+            self.module
+                .add_function("mlia_print_i64", print_type, Some(Linkage::Internal));
+        let builder = self.context.create_builder();
+        builder.position_at_end(self.context.append_basic_block(print_function, "entry"));
+        let value = print_function.get_nth_param(0).unwrap();
+
+        match self.target {
+            CompileTarget::Native if self.capture_output => {
+                // Resolved to capture_print_i64 by compile_program
+                let capture = self
+                    .module
+                    .add_function("mlia_capture_i64", print_type, None);
+                builder.build_call(capture, &[value.into()], "").unwrap();
+                // And to capture_print_format and capture_print_char, for
+                // compile_print_format and compile_print_char
+                let format_type = self
+                    .context
+                    .void_type()
+                    .fn_type(&[i8_ptr_type.into(), i8_ptr_type.into()], false);
+                self.module
+                    .add_function("mlia_capture_format", format_type, None);
+                self.module
+                    .add_function("mlia_capture_char", print_type, None);
+                let str_type = self
+                    .context
+                    .void_type()
+                    .fn_type(&[i8_ptr_type.into()], false);
+                self.module.add_function("mlia_capture_str", str_type, None);
+            }
+            CompileTarget::Native => {
+                // Declare printf function: i32 printf(i8* format, ...)
+                let printf_type = i32_type.fn_type(&[i8_ptr_type.into()], true);
+                let printf_function = self.module.add_function("printf", printf_type, None);
+
+                // printf("%lld\n", value), or its equivalent on this target
+                let format = print_format(&self.module.get_triple());
+                let format_str = builder.build_global_string_ptr(format, "fmt_str").unwrap();
+                builder
+                    .build_call(
+                        printf_function,
+                        &[format_str.as_pointer_value().into(), value.into()],
+                        "printf_call",
+                    )
+                    .unwrap();
+            }
+            CompileTarget::Wasm32 => {
+                // Import void print_i64(i64) from the host's "env" module
+                let host_print = self.module.add_function("print_i64", print_type, None);
+                for (key, val) in [
+                    ("wasm-import-module", "env"),
+                    ("wasm-import-name", "print_i64"),
+                ] {
+                    host_print.add_attribute(
+                        AttributeLoc::Function,
+                        self.context.create_string_attribute(key, val),
+                    );
+                }
+                builder.build_call(host_print, &[value.into()], "").unwrap();
+            }
+        }
+        builder.build_return(None).unwrap();
 
-        self.print_function = Some(printf_function);
+        self.print_function = Some(print_function);
     }
 
     /// Creates a stack allocation for a variable in the entry block of the current function.
     fn create_entry_block_alloca(&self, name: &str) -> PointerValue<'ctx> {
+        self.create_entry_block_alloca_of(self.context.i64_type(), name)
+    }
+
+    /// Creates a stack allocation of `ty` in the entry block of the current
+    /// function, so that it isn't allocated again on every loop iteration.
+    fn create_entry_block_alloca_of(
+        &self,
+        ty: impl BasicType<'ctx>,
+        name: &str,
+    ) -> PointerValue<'ctx> {
         let builder = self.context.create_builder();
         let entry = self
             .current_function
@@ -91,7 +1292,7 @@ impl<'ctx> CodeGen<'ctx> {
             None => builder.position_at_end(entry),
         }
 
-        builder.build_alloca(self.context.i64_type(), name).unwrap()
+        builder.build_alloca(ty, name).unwrap()
     }
 
     /// Builds a load instruction for the given pointer.
@@ -103,1238 +1304,5102 @@ impl<'ctx> CodeGen<'ctx> {
     }
 
     /// Compiles an expression into an LLVM IntValue.
-    fn compile_expr(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, &'static str> {
+    fn compile_expr(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, CodegenError> {
+        self.nested(expr, |this| {
+            this.at_position_of(expr, |this| this.compile_expr_kind(expr))
+        })
+        .map_err(|error| error.found_in(expr))
+    }
+
+    /// Runs `compile` one level deeper into the tree, or fails with `TooDeep`
+    /// past `MAX_DEPTH`: compiling recurses once per level, and running out of
+    /// stack would abort the whole process instead of reporting an error.
+    fn nested<T>(
+        &mut self,
+        expr: &Expr,
+        compile: impl FnOnce(&mut Self) -> Result<T, CodegenError>,
+    ) -> Result<T, CodegenError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(CodegenError::TooDeep {
+                limit: MAX_DEPTH,
+                span: expr.span().or(self.current_span),
+            });
+        }
+        self.depth += 1;
+        let result = compile(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Runs `compile` with the position set to the one of `expr`, if it has one.
+    fn at_position_of<T>(
+        &mut self,
+        expr: &Expr,
+        compile: impl FnOnce(&mut Self) -> Result<T, CodegenError>,
+    ) -> Result<T, CodegenError> {
+        let Some(span) = expr.span() else {
+            return compile(self);
+        };
+
+        // Instructions of this node (and errors in it) point at its position
+        let outer_span = self.current_span.replace(span);
+        self.set_debug_location(Some(span));
+        let result = compile(self);
+        self.current_span = outer_span;
+        self.set_debug_location(outer_span);
+        result
+    }
+
+    /// Compiles an expression of which only whether it is zero matters (a loop
+    /// condition, an operand of `&`, `|` or `!`) into an i1. Comparisons and
+    /// logical operators hand over their i1 as it is, rather than extending it
+    /// to i64 only to compare it with 0 again.
+    fn compile_condition(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, CodegenError> {
+        if let Expr::Call(func_name, args, _) = expr
+            && is_boolean_op(func_name, args.len())
+        {
+            return self.nested(expr, |this| {
+                this.at_position_of(expr, |this| this.compile_boolean_op(func_name, args))
+            });
+        }
+        let value = self.compile_expr(expr)?;
+        Ok(self.builder.build_int_compare(
+            inkwell::IntPredicate::NE,
+            value,
+            self.context.i64_type().const_zero(),
+            "cond",
+        )?)
+    }
+
+    /// Compiles the expression itself, once `compile_expr` has set its position.
+    fn compile_expr_kind(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, CodegenError> {
         match expr {
             Expr::Number(n) => Ok(self.context.i64_type().const_int(*n as u64, true)),
 
-            Expr::Ident(name) => match self.variables.get(name) {
-                Some(var) => Ok(self.build_load(*var, name)),
-                None => {
-                    eprintln!("Warning: undefined variable '{}' used. Value will be 0.", name);
-                    Ok(self.context.i64_type().const_zero())
-                },
+            Expr::Ident(name, span) => match self.lookup_variable(name) {
+                Some(var) => Ok(self.build_load(var, name)),
+                None => Err(CodegenError::UndefinedVariable {
+                    name: name.to_string(),
+                    span: Some(*span),
+                    expr: None,
+                }),
             },
 
-            Expr::Call(func_name, args) => {
+            Expr::Call(func_name, args, _) => {
                 if func_name == "print" && args.len() == 1 {
                     self.compile_print_call(&args[0])
+                } else if func_name == "ord" && args.len() == 1 {
+                    // Characters already are their codepoints
+                    self.compile_expr(&args[0])
+                } else if func_name == "chr" && args.len() == 1 {
+                    let code = self.compile_expr(&args[0])?;
+                    let ascii = self.context.i64_type().const_int(128, false);
+                    self.build_euclidean_rem(code, ascii)
+                } else if func_name == "print_char" && args.len() == 1 {
+                    self.compile_print_char(&args[0], expr.span())
+                } else if func_name == "read_line" && args.is_empty() {
+                    self.compile_read_line(expr.span())
+                } else if (func_name == "strlen" || func_name == "print_str") && args.len() == 1 {
+                    self.compile_string_op(func_name, &args[0], expr.span())
+                } else if func_name == "ref" && args.len() == 1 {
+                    self.compile_ref(&args[0], expr.span())
+                } else if (func_name == "deref" || func_name == "store")
+                    && builtin_arity(func_name) == Some(args.len())
+                {
+                    let i64_type = self.context.i64_type();
+                    let address = self.compile_expr(&args[0])?;
+                    let pointer = self.heap_slot(address, i64_type.const_zero())?;
+                    if func_name == "deref" {
+                        Ok(self
+                            .builder
+                            .build_load(i64_type, pointer, "deref")?
+                            .into_int_value())
+                    } else {
+                        let value = self.compile_expr(&args[1])?;
+                        self.builder.build_store(pointer, value)?;
+                        Ok(value)
+                    }
+                } else if matches!(func_name.as_str(), "some" | "none" | "ok" | "err")
+                    && builtin_arity(func_name) == Some(args.len())
+                {
+                    self.compile_tagged(func_name, args, expr.span())
+                } else if func_name == "try" && args.len() == 1 {
+                    self.compile_try(&args[0])
+                } else if is_heap_op(func_name) && builtin_arity(func_name) == Some(args.len()) {
+                    self.compile_heap_op(func_name, args, expr.span())
+                } else if is_array_op(func_name) && builtin_arity(func_name) == Some(args.len()) {
+                    self.compile_array_op(func_name, args, expr.span())
+                } else if is_vector_op(func_name) && builtin_arity(func_name) == Some(args.len()) {
+                    self.compile_vector_op(func_name, args, expr.span())
+                } else if is_file_op(func_name) && builtin_arity(func_name) == Some(args.len()) {
+                    self.compile_file_op(func_name, args, expr.span())
                 } else if (func_name == "+"
                     || func_name == "-"
                     || func_name == "*"
                     || func_name == "/"
-                    || func_name == "%")
+                    || func_name == "%"
+                    || func_name == "emod"
+                    || func_name == "fdiv")
                     && args.len() == 2
                 {
                     self.compile_binop(func_name, &args[0], &args[1])
-                } else if (func_name == "<"
-                    || func_name == ">"
-                    || func_name == "="
-                    || func_name == "!=")
-                    && args.len() == 2
-                {
-                    self.compile_cmp(func_name, &args[0], &args[1])
-                } else if (func_name == "&" || func_name == "|") && args.len() == 2 {
-                    self.compile_logical(func_name, &args[0], &args[1])
-                } else if func_name == "!" && args.len() == 1 {
-                    self.compile_not(&args[0])
+                } else if is_reduction(func_name) {
+                    self.compile_reduction(func_name, args)
+                } else if is_boolean_op(func_name, args.len()) {
+                    // Used as a number: true -> 1, false -> 0
+                    let result = self.compile_boolean_op(func_name, args)?;
+                    Ok(self.builder.build_int_z_extend(
+                        result,
+                        self.context.i64_type(),
+                        "bool_to_i64",
+                    )?)
+                } else if let Some(expected) = builtin_arity(func_name) {
+                    Err(CodegenError::WrongArgumentCount {
+                        name: func_name.to_string(),
+                        expected,
+                        found: args.len(),
+                    })
                 } else {
                     // User-defined function
                     self.compile_user_function_call(func_name, args)
                 }
             }
 
-            Expr::Seq(first, second) => {
-                // Compile first expression (result is discarded)
-                self.compile_expr(first)?;
-                // Compile and return second expression
-                self.compile_expr(second)
-            }
+            Expr::Block(..) => self.compile_chain(expr),
 
-            Expr::Assign(var_name, value) => {
+            Expr::Assign(var_name, value, span) => {
                 let val = self.compile_expr(value)?;
 
-                match self.variables.get(var_name) {
+                match self.lookup_variable(var_name) {
                     Some(var) => {
-                        self.builder.build_store(*var, val).unwrap();
+                        self.builder.build_store(var, val).unwrap();
                         Ok(val)
                     }
-                    None => Err("Cannot assign to undefined variable"),
+                    None => Err(CodegenError::AssignToUndeclared {
+                        name: var_name.to_string(),
+                        span: Some(*span),
+                    }),
                 }
             }
 
-            Expr::Decl(var_name, params, value, body) => {
-                if params.is_empty() {
-                    let val = self.compile_expr(value)?;
+            Expr::Decl(_, params, _, _, _) if params.is_empty() => self.compile_chain(expr),
+            Expr::Decl(func_name, params, func_body, continuation, _) => {
+                self.compile_function_decl(func_name, params, func_body, continuation)
+            }
 
-                    // Create stack allocation for the variable
-                    let alloca = self.create_entry_block_alloca(var_name);
+            // Implement While loop codegen (T034-T037)
+            Expr::While(condition, body, _) => self.compile_while(condition, body, false),
+            Expr::WhileYield(condition, body, _) => self.compile_while(condition, body, true),
 
-                    // Store the initial value
-                    self.builder.build_store(alloca, val).unwrap();
+            Expr::Loop(body, _) => self.compile_loop(body),
 
-                    // Save old variable binding if it exists
-                    let old_binding = self.variables.insert(var_name.clone(), alloca);
+            Expr::Global(name, value, body, _) => self.compile_global(name, value, body),
 
-                    // Compile the body with the new variable in scope
-                    let result = self.compile_expr(body);
+            Expr::Break(value, span) => self.compile_break(value.as_deref(), *span),
 
-                    // Restore old binding or remove the variable
-                    match old_binding {
-                        Some(old_var) => {
-                            self.variables.insert(var_name.clone(), old_var);
-                        }
-                        None => {
-                            self.variables.remove(var_name);
-                        }
-                    }
-                    result
+            // Match expressions - pattern matching with exhaustiveness check
+            Expr::Match(scrutinee, arms, _) => self.compile_match(scrutinee, arms),
 
-                } else {
-                    // User-defined Function Declaration
-                    self.compile_function_decl(var_name, params, value, body)
-                }    
+            Expr::EnvInt(name, span) => self.compile_env_int(name, *span),
+
+            Expr::Array(elements, span) => self.compile_array(elements, *span),
+
+            Expr::Str(text, _) => {
+                let text = self.builder.build_global_string_ptr(text, "str")?;
+                let i64_type = self.context.i64_type();
+                Ok(self
+                    .builder
+                    .build_ptr_to_int(text.as_pointer_value(), i64_type, "str_addr")?)
             }
 
-            // Implement While loop codegen (T034-T037)
-            Expr::While(condition, body) => self.compile_while(condition, body),
+            Expr::PrintFormat(pieces, args, span) => self.compile_print_format(pieces, args, *span),
 
-            // Match expressions - pattern matching with exhaustiveness check
-            Expr::Match(scrutinee, arms) => self.compile_match(scrutinee, arms),
+            Expr::NamedArg(..) => Err(CodegenError::Internal(
+                "named arguments are lowered before code generation",
+            )),
         }
     }
 
-    /// Compiles a print function call.
-    fn compile_print_call(&mut self, arg: &Expr) -> Result<IntValue<'ctx>, &'static str> {
-        let arg_val = self.compile_expr(arg)?;
+    /// Compiles a block or a variable declaration, and the blocks and
+    /// declarations that end it: the last statement of each block and the
+    /// body of each `decl` (the results of the other statements are
+    /// discarded). A long program is one long chain of these, so it is
+    /// followed in a loop rather than recursively, with the variables it
+    /// declares unbound at the end.
+    fn compile_chain(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, CodegenError> {
+        // What each declaration shadowed: the variable and the known array
+        // length of its name
+        let mut shadowed = Vec::new();
+        let outer_span = self.current_span;
+        let mut rest = expr;
+        let result = loop {
+            match rest {
+                Expr::Block(statements) => {
+                    let Some((last, statements)) = statements.split_last() else {
+                        break Ok(self.context.i64_type().const_zero());
+                    };
+                    let compiled = statements
+                        .iter()
+                        .try_for_each(|statement| self.compile_expr(statement).map(drop));
+                    if let Err(error) = compiled {
+                        break Err(error);
+                    }
+                    rest = last;
+                }
+                Expr::Decl(var_name, params, value, body, span) if params.is_empty() => {
+                    // The declaration and its body point at its position, as
+                    // with `at_position_of`
+                    self.current_span = Some(*span);
+                    self.set_debug_location(Some(*span));
+                    let val = match self.compile_expr(value) {
+                        Ok(val) => val,
+                        Err(error) => break Err(error),
+                    };
+
+                    // Create stack allocation for the variable, with its initial value
+                    let alloca = self.create_entry_block_alloca(var_name);
+                    if let Err(error) = self.builder.build_store(alloca, val) {
+                        break Err(error.into());
+                    }
+
+                    let old_binding = self.variables.insert(var_name.clone(), alloca);
+                    let old_length = match self.static_length(value) {
+                        Some(len) if !assigns_to(body, var_name) => {
+                            self.array_lengths.insert(var_name.clone(), len)
+                        }
+                        _ => self.array_lengths.remove(var_name),
+                    };
+                    shadowed.push((var_name, old_binding, old_length));
+                    rest = &**body;
+                }
+                last => break self.compile_expr(last),
+            }
+        };
+
+        // Restore old bindings or remove the variables, innermost first
+        for (var_name, old_binding, old_length) in shadowed.into_iter().rev() {
+            match old_length {
+                Some(len) => self.array_lengths.insert(var_name.clone(), len),
+                None => self.array_lengths.remove(var_name),
+            };
+            match old_binding {
+                Some(old_var) => self.variables.insert(var_name.clone(), old_var),
+                None => self.variables.remove(var_name),
+            };
+        }
+        self.current_span = outer_span;
+        self.set_debug_location(outer_span);
+        result
+    }
+
+    /// Where the variable `name` in scope lives: a local (or a parameter, or a
+    /// captured variable), or else a global.
+    fn lookup_variable(&self, name: &str) -> Option<PointerValue<'ctx>> {
+        self.variables
+            .get(name)
+            .or_else(|| self.globals.get(name))
+            .copied()
+    }
+
+    /// Compiles `global name <- value` and the rest of the program, where the
+    /// global is in scope. A literal value is the global's initializer; any
+    /// other value is computed and stored here, at the start of `main`.
+    fn compile_global(
+        &mut self,
+        name: &Name,
+        value: &Expr,
+        body: &Expr,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let i64_type = self.context.i64_type();
+        let global = self.module.add_global(i64_type, None, name);
+        global.set_linkage(Linkage::Internal);
+        match value {
+            Expr::Number(n) => global.set_initializer(&i64_type.const_int(*n as u64, true)),
+            _ => {
+                global.set_initializer(&i64_type.const_zero());
+                let val = self.compile_expr(value)?;
+                self.builder.build_store(global.as_pointer_value(), val)?;
+            }
+        }
+
+        // In the body, the name is the global's, even if a variable had it
+        let old_variable = self.variables.remove(name);
+        let old_global = self.globals.insert(name.clone(), global.as_pointer_value());
+        let result = self.compile_expr(body);
+        match old_global {
+            Some(old) => self.globals.insert(name.clone(), old),
+            None => self.globals.remove(name),
+        };
+        if let Some(old) = old_variable {
+            self.variables.insert(name.clone(), old);
+        }
+        result
+    }
 
-        // Create format string for printf: "%lld\n"
-        let format_str = self
+    /// Compiles `env_int "NAME"`: the value of the environment variable NAME
+    /// read as an integer, or 0 when it isn't set.
+    fn compile_env_int(&mut self, name: &str, span: Span) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 {
+            return Err(CodegenError::UnsupportedBuiltin {
+                name: "env_int",
+                span: Some(span),
+            });
+        }
+        let env_int = self.env_int_function();
+        let name = self.builder.build_global_string_ptr(name, "env_name")?;
+        let value = self
             .builder
-            .build_global_string_ptr("%lld\n", "fmt_str")
+            .build_call(env_int, &[name.as_pointer_value().into()], "env_int")?
+            .try_as_basic_value()
+            .left()
+            .ok_or(CodegenError::Internal("env_int returns no value"))?;
+        Ok(value.into_int_value())
+    }
+
+    /// The runtime function `i64 mlia_env_int(i8* name)`, defined the first
+    /// time a program uses `env_int`. It returns `atoll(getenv(name))`, and 0
+    /// when the variable isn't set (atoll already gives 0 for text that isn't
+    /// a number).
+    fn env_int_function(&mut self) -> FunctionValue<'ctx> {
+        if let Some(function) = self.module.get_function("mlia_env_int") {
+            return function;
+        }
+        let i64_type = self.context.i64_type();
+        let i8_ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+
+        let getenv_type = i8_ptr_type.fn_type(&[i8_ptr_type.into()], false);
+        let getenv_function = self.module.add_function("getenv", getenv_type, None);
+        let atoll_type = i64_type.fn_type(&[i8_ptr_type.into()], false);
+        let atoll_function = self.module.add_function("atoll", atoll_type, None);
+
+        let env_int_type = i64_type.fn_type(&[i8_ptr_type.into()], false);
+        let function =
+            self.module
+                .add_function("mlia_env_int", env_int_type, Some(Linkage::Internal));
+        let builder = self.context.create_builder();
+        let entry = self.context.append_basic_block(function, "entry");
+        let set = self.context.append_basic_block(function, "set");
+        let unset = self.context.append_basic_block(function, "unset");
+
+        builder.position_at_end(entry);
+        let name = function.get_nth_param(0).unwrap();
+        let text = builder
+            .build_call(getenv_function, &[name.into()], "text")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value();
+        let is_unset = builder.build_is_null(text, "is_unset").unwrap();
+        builder
+            .build_conditional_branch(is_unset, unset, set)
             .unwrap();
 
-        // Call printf function
-        let printf_fn = self.print_function.ok_or("Print function not available")?;
-        self.builder
-            .build_call(
-                printf_fn,
-                &[format_str.as_pointer_value().into(), arg_val.into()],
-                "printf_call",
-            )
+        builder.position_at_end(set);
+        let value = builder
+            .build_call(atoll_function, &[text.into()], "value")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
             .unwrap();
+        builder.build_return(Some(&value)).unwrap();
+
+        builder.position_at_end(unset);
+        builder.build_return(Some(&i64_type.const_zero())).unwrap();
+        function
+    }
+
+    /// Compiles a print function call.
+    fn compile_print_call(&mut self, arg: &Expr) -> Result<IntValue<'ctx>, CodegenError> {
+        let arg_val = self.compile_expr(arg)?;
+
+        // Call the runtime print function
+        let print_fn = self
+            .print_function
+            .ok_or(CodegenError::Internal("print function not declared"))?;
+        self.builder.build_call(print_fn, &[arg_val.into()], "")?;
 
         // Return the original value
         Ok(arg_val)
     }
 
-    /// Compiles a binary operation.
-    fn compile_binop(
-        &mut self,
-        op: &str,
-        lhs: &Expr,
-        rhs: &Expr,
-    ) -> Result<IntValue<'ctx>, &'static str> {
-        let lhs_val = self.compile_expr(lhs)?;
-        let rhs_val = self.compile_expr(rhs)?;
+    /// The function `name` of the C library, declared with `ty` the first time.
+    fn libc_function(&self, name: &str, ty: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        self.module
+            .get_function(name)
+            .unwrap_or_else(|| self.module.add_function(name, ty, None))
+    }
 
-        let op_result = match op {
-            "+" => Some(self.builder.build_int_add(lhs_val, rhs_val, "add")),
-            "-" => Some(self.builder.build_int_sub(lhs_val, rhs_val, "sub")),
-            "*" => Some(self.builder.build_int_mul(lhs_val, rhs_val, "mul")),
-            "/" => Some(self.builder.build_int_signed_div(lhs_val, rhs_val, "div")),
-            "%" => Some(self.builder.build_int_signed_rem(lhs_val, rhs_val, "rem")),
-            _ => None,
-        };
+    /// Compiles `read_line`: the next line of standard input, without its
+    /// newline, in a new buffer of `LINE_BUFFER_SIZE` bytes that is never
+    /// freed. Strings are the address of their first byte. At the end of the
+    /// input the line is empty.
+    fn compile_read_line(&mut self, span: Option<Span>) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 {
+            return Err(CodegenError::UnsupportedBuiltin {
+                name: "read_line",
+                span,
+            });
+        }
+        let read_line = self.read_line_function();
+        let line = self
+            .builder
+            .build_call(read_line, &[], "line")?
+            .try_as_basic_value()
+            .left()
+            .ok_or(CodegenError::Internal("read_line returns no value"))?;
+        Ok(line.into_int_value())
+    }
 
-        match op_result {
-            Some(result) => match result {
-                Ok(val) => Ok(val),
-                Err(_) => Err("Failed to build binary operation"),
-            },
-            None => Err("Invalid operand type"),
+    /// The runtime function `i64 mlia_read_line()`, defined the first time a
+    /// program uses `read_line`. It mallocs the buffer and fills it with
+    /// getchar until a newline, the end of the input or a full buffer.
+    fn read_line_function(&mut self) -> FunctionValue<'ctx> {
+        if let Some(function) = self.module.get_function("mlia_read_line") {
+            return function;
         }
+        let i8_type = self.context.i8_type();
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let malloc = self.libc_function("malloc", ptr_type.fn_type(&[i64_type.into()], false));
+        let getchar = self.libc_function("getchar", i32_type.fn_type(&[], false));
+
+        let function = self.module.add_function(
+            "mlia_read_line",
+            i64_type.fn_type(&[], false),
+            Some(Linkage::Internal),
+        );
+        let builder = self.context.create_builder();
+        let entry = self.context.append_basic_block(function, "entry");
+        let read = self.context.append_basic_block(function, "read");
+        let store = self.context.append_basic_block(function, "store");
+        let end = self.context.append_basic_block(function, "end");
+
+        builder.position_at_end(entry);
+        let size = i64_type.const_int(LINE_BUFFER_SIZE, false);
+        let buffer = builder
+            .build_call(malloc, &[size.into()], "buffer")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value();
+        builder.build_unconditional_branch(read).unwrap();
+
+        // len = number of bytes stored so far
+        builder.position_at_end(read);
+        let len = builder.build_phi(i64_type, "len").unwrap();
+        let c = builder
+            .build_call(getchar, &[], "c")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        let eof = builder
+            .build_int_compare(IntPredicate::EQ, c, i32_type.const_all_ones(), "eof")
+            .unwrap();
+        let newline = builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                c,
+                i32_type.const_int(10, false),
+                "newline",
+            )
+            .unwrap();
+        let last = i64_type.const_int(LINE_BUFFER_SIZE - 1, false);
+        let len_value = len.as_basic_value().into_int_value();
+        let full = builder
+            .build_int_compare(IntPredicate::EQ, len_value, last, "full")
+            .unwrap();
+        let stop = builder.build_or(eof, newline, "stop").unwrap();
+        let stop = builder.build_or(stop, full, "stop").unwrap();
+        builder.build_conditional_branch(stop, end, store).unwrap();
+
+        builder.position_at_end(store);
+        // SAFETY: len is below LINE_BUFFER_SIZE - 1
+        let slot = unsafe { builder.build_gep(i8_type, buffer, &[len_value], "slot") }.unwrap();
+        let byte = builder.build_int_truncate(c, i8_type, "byte").unwrap();
+        builder.build_store(slot, byte).unwrap();
+        let next = builder
+            .build_int_add(len_value, i64_type.const_int(1, false), "next")
+            .unwrap();
+        builder.build_unconditional_branch(read).unwrap();
+        len.add_incoming(&[(&i64_type.const_zero(), entry), (&next, store)]);
+
+        builder.position_at_end(end);
+        // SAFETY: len is at most LINE_BUFFER_SIZE - 1
+        let slot = unsafe { builder.build_gep(i8_type, buffer, &[len_value], "slot") }.unwrap();
+        builder.build_store(slot, i8_type.const_zero()).unwrap();
+        let address = builder
+            .build_ptr_to_int(buffer, i64_type, "address")
+            .unwrap();
+        builder.build_return(Some(&address)).unwrap();
+        function
     }
 
-    /// Compiles comparison operations into LLVM IR.
-    /// Returns 1 for true, 0 for false as i64 values.
-    fn compile_cmp(
+    /// Compiles `strlen s` (the length of the string `s`, in bytes) or
+    /// `print_str s` (writes it and a newline; evaluates to `s`).
+    fn compile_string_op(
         &mut self,
         op: &str,
-        lhs: &Expr,
-        rhs: &Expr,
-    ) -> Result<IntValue<'ctx>, &'static str> {
-        use inkwell::IntPredicate;
-
-        let lhs_val = self.compile_expr(lhs)?;
-        let rhs_val = self.compile_expr(rhs)?;
-
-        let predicate = match op {
-            "<" => IntPredicate::SLT, // Signed Less Than
-            ">" => IntPredicate::SGT, // Signed Greater Than
-            "=" => IntPredicate::EQ,  // Equal
-            "!=" => IntPredicate::NE, // Not Equal
-            _ => return Err("Invalid comparison operator"),
+        arg: &Expr,
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 {
+            return Err(CodegenError::UnsupportedBuiltin {
+                name: if op == "strlen" {
+                    "strlen"
+                } else {
+                    "print_str"
+                },
+                span,
+            });
+        }
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let string = self.compile_expr(arg)?;
+        let pointer = self.builder.build_int_to_ptr(string, ptr_type, "string")?;
+        if op == "strlen" {
+            let strlen = self.libc_function("strlen", i64_type.fn_type(&[ptr_type.into()], false));
+            let len = self
+                .builder
+                .build_call(strlen, &[pointer.into()], "len")?
+                .try_as_basic_value()
+                .left()
+                .ok_or(CodegenError::Internal("strlen returns no value"))?;
+            return Ok(len.into_int_value());
+        }
+        let print = match self.module.get_function("mlia_capture_str") {
+            Some(capture) => capture,
+            None => {
+                let i32_type = self.context.i32_type();
+                self.libc_function("puts", i32_type.fn_type(&[ptr_type.into()], false))
+            }
         };
-
-        let cmp_result = self
-            .builder
-            .build_int_compare(predicate, lhs_val, rhs_val, "cmp")
-            .map_err(|_| "Failed to build comparison")?;
-
-        // Convert i1 (bool) to i64: true -> 1, false -> 0
-        self.builder
-            .build_int_z_extend(cmp_result, self.context.i64_type(), "cmp_ext")
-            .map_err(|_| "Failed to extend comparison result")
+        self.builder.build_call(print, &[pointer.into()], "")?;
+        Ok(string)
     }
 
-    fn compile_not(
+    /// Compiles `ref x`: the address of the variable `x` (where a local or
+    /// global lives), for `deref` to read and `store` to write through. Nothing
+    /// checks that the variable is still in scope when that happens.
+    fn compile_ref(
         &mut self,
         arg: &Expr,
-    ) -> Result<IntValue<'ctx>, &'static str> {
-        let zero = Expr::Number(0);
-        self.compile_cmp("=", arg, &zero)
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let Expr::Ident(name, name_span) = arg else {
+            return Err(CodegenError::RefOfNonVariable { span });
+        };
+        let variable =
+            self.lookup_variable(name)
+                .ok_or_else(|| CodegenError::UndefinedVariable {
+                    name: name.to_string(),
+                    span: Some(*name_span),
+                    expr: None,
+                })?;
+        let i64_type = self.context.i64_type();
+        Ok(self.builder.build_ptr_to_int(variable, i64_type, "ref")?)
     }
 
-    /// Compiles logical operators AND/OR
-    /// Normalize args to {0,1}
-    fn compile_logical(
+    /// Compiles the builtins over raw blocks of i64 on the heap: `alloc n`
+    /// (the address of a new block of `n` of them), `load8 p i` (the one at
+    /// index `i`), `store8 p i v` (stores `v` there; evaluates to `v`) and
+    /// `free p` (evaluates to 0). Nothing is checked.
+    fn compile_heap_op(
         &mut self,
         op: &str,
-        lhs: &Expr,
-        rhs: &Expr,
-    ) -> Result<IntValue<'ctx>, &'static str> {
-        use inkwell::IntPredicate;
+        args: &[Expr],
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 && (op == "alloc" || op == "free") {
+            let name = if op == "alloc" { "alloc" } else { "free" };
+            return Err(CodegenError::UnsupportedBuiltin { name, span });
+        }
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let values = args
+            .iter()
+            .map(|arg| self.compile_expr(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        match op {
+            "alloc" => {
+                let malloc_type = ptr_type.fn_type(&[i64_type.into()], false);
+                let malloc = self.libc_function("malloc", malloc_type);
+                let eight = i64_type.const_int(8, false);
+                let size = self.builder.build_int_mul(values[0], eight, "size")?;
+                let block = self
+                    .builder
+                    .build_call(malloc, &[size.into()], "block")?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or(CodegenError::Internal("malloc returns no value"))?;
+                self.count_live_blocks(1)?;
+                let block = block.into_pointer_value();
+                Ok(self
+                    .builder
+                    .build_ptr_to_int(block, i64_type, "block_addr")?)
+            }
+            "free" => {
+                let free_type = self.context.void_type().fn_type(&[ptr_type.into()], false);
+                let free = self.libc_function("free", free_type);
+                let block = self
+                    .builder
+                    .build_int_to_ptr(values[0], ptr_type, "block")?;
+                self.builder.build_call(free, &[block.into()], "")?;
+                self.count_live_blocks(-1)?;
+                Ok(i64_type.const_zero())
+            }
+            "load8" => {
+                let slot = self.heap_slot(values[0], values[1])?;
+                Ok(self
+                    .builder
+                    .build_load(i64_type, slot, "loaded")?
+                    .into_int_value())
+            }
+            _ => {
+                let slot = self.heap_slot(values[0], values[1])?;
+                self.builder.build_store(slot, values[2])?;
+                Ok(values[2])
+            }
+        }
+    }
 
-        let lhs_val = self.compile_expr(lhs)?;
-        let rhs_val = self.compile_expr(rhs)?;
+    /// With `debug_heap`, the global that counts the blocks of `alloc` that
+    /// haven't been freed.
+    fn live_blocks(&self) -> Option<PointerValue<'ctx>> {
+        if !self.debug_heap {
+            return None;
+        }
+        let global = self
+            .module
+            .get_global("mlia_live_blocks")
+            .unwrap_or_else(|| {
+                let i64_type = self.context.i64_type();
+                let global = self.module.add_global(i64_type, None, "mlia_live_blocks");
+                global.set_linkage(Linkage::Internal);
+                global.set_initializer(&i64_type.const_zero());
+                global
+            });
+        Some(global.as_pointer_value())
+    }
 
-        // Normalize left!=0 -> 1
-        let left = self.builder.build_int_compare(
-            IntPredicate::NE,
-            lhs_val,
-            self.context.i64_type().const_int(0,false),
-            "left",
-        ).map_err(|_| "builder error")?;
+    /// With `debug_heap`, adds `change` to the count of live blocks: 1 for a
+    /// block handed out by `alloc`, -1 for one given back to `free`.
+    fn count_live_blocks(&mut self, change: i64) -> Result<(), CodegenError> {
+        let Some(live_blocks) = self.live_blocks() else {
+            return Ok(());
+        };
+        let i64_type = self.context.i64_type();
+        let live = self
+            .builder
+            .build_load(i64_type, live_blocks, "live")?
+            .into_int_value();
+        let change = i64_type.const_int(change as u64, true);
+        let live = self.builder.build_int_add(live, change, "live")?;
+        self.builder.build_store(live_blocks, live)?;
+        Ok(())
+    }
 
-        //Normalize right!=0 -> 1
-        let right = self.builder.build_int_compare(
-            IntPredicate::NE,
-            rhs_val,
-            self.context.i64_type().const_int(0,false),
-            "right",
-        ).map_err(|_| "builder error")?;
-
-        let result = match op{
-            "&" => {
-                // AND
-                self.builder
-                    .build_and(left, right, "and_tmp")
-                    .map_err(|_| "builder error")?
-            }
-            "|" => {
-                // OR
-                self.builder
-                    .build_or(left, right, "or_tmp")
-                    .map_err(|_| "builder error")?
-            }
-            _ => return Err("Invalid logical operator"),
+    /// With `debug_heap`, writes how many blocks of `alloc` were never freed to
+    /// standard error, if any.
+    fn report_leaks(&mut self) -> Result<(), CodegenError> {
+        let Some(live_blocks) = self.live_blocks() else {
+            return Ok(());
         };
+        let i64_type = self.context.i64_type();
+        let i32_type = self.context.i32_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let function = self
+            .builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .ok_or(CodegenError::Internal("leak report outside a function"))?;
+        let leaked = self.context.append_basic_block(function, "leaked");
+        let done = self.context.append_basic_block(function, "leaks_reported");
+        let live = self
+            .builder
+            .build_load(i64_type, live_blocks, "live")?
+            .into_int_value();
+        let any = self.builder.build_int_compare(
+            IntPredicate::NE,
+            live,
+            i64_type.const_zero(),
+            "any_leaked",
+        )?;
+        self.builder.build_conditional_branch(any, leaked, done)?;
+
+        self.builder.position_at_end(leaked);
+        let dprintf_type = i32_type.fn_type(&[i32_type.into(), ptr_type.into()], true);
+        let dprintf = self.libc_function("dprintf", dprintf_type);
+        let format = "leak: %lld block(s) from alloc never freed\n";
+        let format = self
+            .builder
+            .build_global_string_ptr(format, "leak_format")?;
+        let args = [
+            i32_type.const_int(2, false).into(),
+            format.as_pointer_value().into(),
+            live.into(),
+        ];
+        self.builder.build_call(dprintf, &args, "")?;
+        self.builder.build_unconditional_branch(done)?;
+
+        self.builder.position_at_end(done);
+        Ok(())
+    }
 
-        // Convert i1 (bool) to i64: true -> 1, false -> 0
-        let result_i64 = self.builder
-                        .build_int_z_extend(result, self.context.i64_type(), "bool_to_i64")
-                        .map_err(|_| "builder error")?;
+    /// Compiles an array literal. An array is the address of a block on the
+    /// heap holding its length and then its elements, one i64 each; the block
+    /// is never freed.
+    fn compile_array(
+        &mut self,
+        elements: &[Expr],
+        span: Span,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 {
+            return Err(CodegenError::UnsupportedBuiltin {
+                name: "[...]",
+                span: Some(span),
+            });
+        }
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let values = elements
+            .iter()
+            .map(|element| self.compile_expr(element))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let malloc = self.libc_function("malloc", ptr_type.fn_type(&[i64_type.into()], false));
+        let size = i64_type.const_int(8 * (values.len() as u64 + 1), false);
+        let block = self
+            .builder
+            .build_call(malloc, &[size.into()], "array")?
+            .try_as_basic_value()
+            .left()
+            .ok_or(CodegenError::Internal("malloc returns no value"))?;
+        let array =
+            self.builder
+                .build_ptr_to_int(block.into_pointer_value(), i64_type, "array_addr")?;
+        let len = i64_type.const_int(values.len() as u64, false);
+        let header = self.heap_slot(array, i64_type.const_zero())?;
+        self.builder.build_store(header, len)?;
+        for (i, value) in values.into_iter().enumerate() {
+            let slot = self.heap_slot(array, i64_type.const_int(i as u64 + 1, false))?;
+            self.builder.build_store(slot, value)?;
+        }
+        Ok(array)
+    }
 
-        Ok(result_i64)
+    /// Where the i64 at `offset` in the block of memory at address `block` is.
+    /// In an array, 0 is the length and the elements follow.
+    fn heap_slot(
+        &mut self,
+        block: IntValue<'ctx>,
+        offset: IntValue<'ctx>,
+    ) -> Result<PointerValue<'ctx>, CodegenError> {
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let block = self.builder.build_int_to_ptr(block, ptr_type, "block")?;
+        // SAFETY: offsets are checked against the length, or left to the program
+        Ok(unsafe { self.builder.build_gep(i64_type, block, &[offset], "slot") }?)
     }
 
-    /// Find free variables in an expression
-    /// Free variables are identifiers that are used but not defined in the current scope
-    fn find_free_variables(&self, expr: &Expr, bound: &HashSet<String>) -> HashSet<String> {
-        let mut free = HashSet::new();
-        match expr {
-            Expr::Number(_) => {},
-            Expr::Ident(name) => {
-                if !bound.contains(name) {
-                    free.insert(name.clone());
-                }
-            },
-            Expr::Call(func, args) => {
-                // Don't treat function name as free variable
-                for arg in args {
-                    free.extend(self.find_free_variables(arg, bound));
-                }
-            },
-            Expr::Seq(first, second) => {
-                free.extend(self.find_free_variables(first, bound));
-                free.extend(self.find_free_variables(second, bound));
-            },
-            Expr::Assign(var, value) => {
-                free.extend(self.find_free_variables(value, bound));
-                // Assignment doesn't bind, it just mutates
-                if !bound.contains(var) {
-                    free.insert(var.clone());
-                }
-            },
-            Expr::Decl(var, params, value, body) => {
-                // Variables in 'value' can only see outer scope
-                free.extend(self.find_free_variables(value, bound));
-                
-                // Variables in 'body' can see var and params
-                let mut inner_bound = bound.clone();
-                inner_bound.insert(var.clone());
-                for param in params {
-                    inner_bound.insert(param.clone());
-                }
-                free.extend(self.find_free_variables(body, &inner_bound));
-            },
-            Expr::While(cond, body) => {
-                free.extend(self.find_free_variables(cond, bound));
-                free.extend(self.find_free_variables(body, bound));
-            },
-            Expr::Match(scrutinee, arms) => {
-                free.extend(self.find_free_variables(scrutinee, bound));
-                for (_pattern, arm_expr) in arms {
-                    // Pattern matching doesn't bind variables in MLIA (only literals and wildcards)
-                    free.extend(self.find_free_variables(arm_expr, bound));
-                }
+    /// The length of an array known when compiling: a literal, a variable in
+    /// `array_lengths`, a slice of constant length, or a literal picked out of
+    /// a nested one by a constant index (`get [[1 2] [3 4 5]] 1`).
+    fn static_length(&self, array: &Expr) -> Option<u64> {
+        match array {
+            Expr::Array(elements, _) => Some(elements.len() as u64),
+            Expr::Ident(name, _) => self.array_lengths.get(name).copied(),
+            Expr::Call(name, args, _) if name == "slice" && args.len() == 3 => match args[2] {
+                Expr::Number(count) => u64::try_from(count).ok(),
+                _ => None,
             },
+            Expr::Call(name, args, _) if name == "get" && args.len() == 2 => {
+                let (Expr::Array(rows, _), Expr::Number(index)) = (&args[0], &args[1]) else {
+                    return None;
+                };
+                let row = rows.get(usize::try_from(*index).ok()?)?;
+                self.static_length(row)
+            }
+            _ => None,
         }
-        free
     }
 
-    /// Compile user-defined function declaration
-    fn compile_function_decl(
+    /// Compiles `get a i` (the element at `i`), `set a i v` (stores `v` there;
+    /// evaluates to `v`) and `len a`. Indices start at 0. With `bounds_checks`
+    /// an index out of bounds stops the program; a constant one into an array
+    /// of known length is an error either way.
+    fn compile_array_op(
         &mut self,
-        func_name: &str,
-        params: &[String],
-        body: &Expr,
-        continuation: &Expr,
-    ) -> Result<IntValue<'ctx>, &'static str> {
-        // Find free variables in the function body
-        let mut bound = HashSet::new();
-        for param in params {
-            bound.insert(param.clone());
-        }
-        let free_vars = self.find_free_variables(body, &bound);
-        
-        // Filter free variables to only those currently in scope
-        let captured_vars: Vec<String> = free_vars.iter()
-            .filter(|var| self.variables.contains_key(*var))
-            .cloned()
-            .collect();
-        
-        // Create function type with extra parameters for captured variables
-        // i64 (i64, i64, ..., captured1, captured2, ...)
+        op: &str,
+        args: &[Expr],
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
         let i64_type = self.context.i64_type();
-        let mut param_types: Vec<_> = params.iter()
-            .map(|_| i64_type.into())
-            .collect();
-        // Add types for captured variables
-        for _ in &captured_vars {
-            param_types.push(i64_type.into());
+        let array = self.compile_expr(&args[0])?;
+        if op == "slice" {
+            return self.compile_slice(array, args, span);
+        }
+        if op == "len" {
+            let header = self.heap_slot(array, i64_type.const_zero())?;
+            return Ok(self
+                .builder
+                .build_load(i64_type, header, "len")?
+                .into_int_value());
         }
-        let fn_type = i64_type.fn_type(&param_types, false);
-
-        // Create LLVM function
-        let function = self.module.add_function(func_name, fn_type, None);
-        
-        // Register function with its captured variables before compiling body
-        self.user_functions.insert(func_name.to_string(), (function, captured_vars.clone()));
-        
-        // Save current context
-        let parent_function = self.current_function;
-        let old_variables = self.variables.clone();
-        
-        // Set new context
-        self.current_function = Some(function);
-        self.variables.clear();
-        
-        // Create entry block
-        let entry_block = self.context.append_basic_block(function, "entry");
-        self.builder.position_at_end(entry_block);
 
-        // Create allocas for explicit parameters and store values
-        for (i, param_name) in params.iter().enumerate() {
-            let param_value = function.get_nth_param(i as u32)
-                .ok_or("Failed to get parameter")?
-                .into_int_value();
-            
-            let alloca = self.create_entry_block_alloca(param_name);
-            self.builder.build_store(alloca, param_value)
-                .map_err(|_| "Failed to store parameter")?;
-            
-            self.variables.insert(param_name.clone(), alloca);
+        let index = self.compile_expr(&args[1])?;
+        if let (Some(len), Some(constant)) = (
+            self.static_length(&args[0]),
+            index.get_sign_extended_constant(),
+        ) && (constant < 0 || constant as u64 >= len)
+        {
+            return Err(CodegenError::IndexOutOfBounds {
+                index: constant,
+                len,
+                span: args[1].span().or(span),
+            });
         }
-        
-        // Create allocas for captured variables (hidden parameters)
-        for (i, var_name) in captured_vars.iter().enumerate() {
-            let param_idx = (params.len() + i) as u32;
-            let param_value = function.get_nth_param(param_idx)
-                .ok_or("Failed to get captured variable parameter")?
+        if self.bounds_checks {
+            let header = self.heap_slot(array, i64_type.const_zero())?;
+            let len = self
+                .builder
+                .build_load(i64_type, header, "len")?
                 .into_int_value();
-            
-            let alloca = self.create_entry_block_alloca(var_name);
-            self.builder.build_store(alloca, param_value)
-                .map_err(|_| "Failed to store captured variable")?;
-            
-            self.variables.insert(var_name.clone(), alloca);
+            // Unsigned, so that negative indices are out of bounds too
+            let in_bounds =
+                self.builder
+                    .build_int_compare(IntPredicate::ULT, index, len, "in_bounds")?;
+            let message = "index %lld out of bounds (len %lld)";
+            self.build_runtime_check(in_bounds, message, &[index, len])?;
         }
-
-        // Compile function body
-        let result = self.compile_expr(body)?;
-        
-        // Return result
-        self.builder.build_return(Some(&result))
-            .map_err(|_| "Failed to build return")?;
-
-        // Verify function
-        if !function.verify(true) {
-            return Err("Function verification failed");
+        let offset = self
+            .builder
+            .build_int_add(index, i64_type.const_int(1, false), "offset")?;
+        let slot = self.heap_slot(array, offset)?;
+        if op == "get" {
+            return Ok(self
+                .builder
+                .build_load(i64_type, slot, "element")?
+                .into_int_value());
         }
+        let value = self.compile_expr(&args[2])?;
+        self.builder.build_store(slot, value)?;
+        Ok(value)
+    }
 
-        // Restore previous context
-        self.current_function = parent_function;
-        self.variables = old_variables;
-        
-        // Reposition builder in parent context
-        if let Some(parent_fn) = parent_function {
-            let last_block = parent_fn.get_last_basic_block()
-                .ok_or("No basic block in parent function")?;
-            self.builder.position_at_end(last_block);
+    /// Compiles `slice a start count`: a new array (on the heap, like literals)
+    /// with the `count` elements of `array` from `start` on. The range is
+    /// checked like an index of `get`.
+    fn compile_slice(
+        &mut self,
+        array: IntValue<'ctx>,
+        args: &[Expr],
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let start = self.compile_expr(&args[1])?;
+        let count = self.compile_expr(&args[2])?;
+        if let (Some(len), Some(start), Some(count)) = (
+            self.static_length(&args[0]),
+            start.get_sign_extended_constant(),
+            count.get_sign_extended_constant(),
+        ) && (start < 0 || count < 0 || (start as u64).saturating_add(count as u64) > len)
+        {
+            return Err(CodegenError::SliceOutOfBounds {
+                start,
+                count,
+                len,
+                span,
+            });
+        }
+        if self.bounds_checks {
+            let header = self.heap_slot(array, i64_type.const_zero())?;
+            let len = self
+                .builder
+                .build_load(i64_type, header, "len")?
+                .into_int_value();
+            // Unsigned, so that negative starts and counts are out of bounds too
+            let start_fits =
+                self.builder
+                    .build_int_compare(IntPredicate::ULE, start, len, "start_fits")?;
+            let room = self.builder.build_int_sub(len, start, "room")?;
+            let count_fits =
+                self.builder
+                    .build_int_compare(IntPredicate::ULE, count, room, "count_fits")?;
+            let fits = self.builder.build_and(start_fits, count_fits, "fits")?;
+            let message = "slice of %lld elements from %lld out of bounds (len %lld)";
+            self.build_runtime_check(fits, message, &[count, start, len])?;
         }
 
-        // Compile continuation
-        self.compile_expr(continuation)
+        let one = i64_type.const_int(1, false);
+        let eight = i64_type.const_int(8, false);
+        let malloc = self.libc_function("malloc", ptr_type.fn_type(&[i64_type.into()], false));
+        let slots = self.builder.build_int_add(count, one, "slots")?;
+        let size = self.builder.build_int_mul(slots, eight, "size")?;
+        let block = self
+            .builder
+            .build_call(malloc, &[size.into()], "slice")?
+            .try_as_basic_value()
+            .left()
+            .ok_or(CodegenError::Internal("malloc returns no value"))?;
+        let slice =
+            self.builder
+                .build_ptr_to_int(block.into_pointer_value(), i64_type, "slice_addr")?;
+        let header = self.heap_slot(slice, i64_type.const_zero())?;
+        self.builder.build_store(header, count)?;
+        let to = self.heap_slot(slice, one)?;
+        let offset = self.builder.build_int_add(start, one, "offset")?;
+        let from = self.heap_slot(array, offset)?;
+        let bytes = self.builder.build_int_mul(count, eight, "bytes")?;
+        self.builder
+            .build_memcpy(to, 8, from, 8, bytes)
+            .map_err(|e| CodegenError::Llvm(e.to_string()))?;
+        Ok(slice)
     }
 
-    /// Compile a user-defined function call
-    fn compile_user_function_call(
+    /// Compiles the builtins over vectors, arrays that grow. A vector is the
+    /// address of a header on the heap: its length, its capacity and the
+    /// address of its elements (see `vector_functions`). `vec_new` makes an
+    /// empty one, `push v x` appends `x` (evaluates to `x`), `pop v` removes
+    /// the last element and evaluates to it, and `vlen`, `vget` and `vset` are
+    /// `len`, `get` and `set`. Popping an empty vector stops the program;
+    /// indices are checked like those of arrays, with `bounds_checks`.
+    fn compile_vector_op(
         &mut self,
-        func_name: &str,
+        op: &str,
         args: &[Expr],
-    ) -> Result<IntValue<'ctx>, &'static str> {
-        // Look up function and captured variables
-        let (function, captured_vars) = self.user_functions.get(func_name)
-            .ok_or("Undefined function")?;
-        let function = *function; // Dereference to copy FunctionValue
-        let captured_vars = captured_vars.clone(); // Clone the vector
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 {
+            let name = match op {
+                "vec_new" => "vec_new",
+                "push" => "push",
+                "pop" => "pop",
+                "vlen" => "vlen",
+                "vget" => "vget",
+                _ => "vset",
+            };
+            return Err(CodegenError::UnsupportedBuiltin { name, span });
+        }
+        let i64_type = self.context.i64_type();
+        let (vec_new, push) = self.vector_functions();
+        let values = args
+            .iter()
+            .map(|arg| self.compile_expr(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let call = |codegen: &mut Self, function, args: &[BasicMetadataValueEnum<'ctx>]| {
+            let value = codegen
+                .builder
+                .build_call(function, args, op)?
+                .try_as_basic_value()
+                .left()
+                .ok_or(CodegenError::Internal("vector functions return a value"))?;
+            Ok::<_, CodegenError>(value.into_int_value())
+        };
+        match op {
+            "vec_new" => return call(self, vec_new, &[]),
+            "push" => return call(self, push, &[values[0].into(), values[1].into()]),
+            _ => {}
+        }
 
-        // Verify number of user-provided arguments (not including captured variables)
-        let expected_args = function.count_params() as usize - captured_vars.len();
-        if args.len() != expected_args {
-            return Err("Wrong number of arguments");
+        let vector = values[0];
+        let len_slot = self.heap_slot(vector, i64_type.const_zero())?;
+        let len = self
+            .builder
+            .build_load(i64_type, len_slot, "len")?
+            .into_int_value();
+        if op == "vlen" {
+            return Ok(len);
+        }
+        let data_slot = self.heap_slot(vector, i64_type.const_int(2, false))?;
+        let data = self
+            .builder
+            .build_load(i64_type, data_slot, "data")?
+            .into_int_value();
+        if op == "pop" {
+            let not_empty = self.builder.build_int_compare(
+                IntPredicate::NE,
+                len,
+                i64_type.const_zero(),
+                "not_empty",
+            )?;
+            self.build_runtime_check(not_empty, "pop from an empty vector", &[])?;
+            let last = self
+                .builder
+                .build_int_sub(len, i64_type.const_int(1, false), "last")?;
+            self.builder.build_store(len_slot, last)?;
+            let slot = self.heap_slot(data, last)?;
+            return Ok(self
+                .builder
+                .build_load(i64_type, slot, "popped")?
+                .into_int_value());
         }
 
-        // Compile user-provided arguments
-        let mut arg_values = Vec::new();
-        for arg in args {
-            let val = self.compile_expr(arg)?;
-            arg_values.push(val.into());
+        let index = values[1];
+        if self.bounds_checks {
+            let in_bounds =
+                self.builder
+                    .build_int_compare(IntPredicate::ULT, index, len, "in_bounds")?;
+            let message = "index %lld out of bounds (len %lld)";
+            self.build_runtime_check(in_bounds, message, &[index, len])?;
         }
-        
-        // Add captured variables as extra arguments
-        for var_name in &captured_vars {
-            let var_ptr = self.variables.get(var_name)
-                .ok_or("Captured variable not in scope")?;
-            let val = self.builder.build_load(self.context.i64_type(), *var_ptr, var_name)
-                .map_err(|_| "Failed to load captured variable")?
-                .into_int_value();
-            arg_values.push(val.into());
+        let slot = self.heap_slot(data, index)?;
+        if op == "vget" {
+            return Ok(self
+                .builder
+                .build_load(i64_type, slot, "element")?
+                .into_int_value());
         }
+        self.builder.build_store(slot, values[2])?;
+        Ok(values[2])
+    }
 
-        // Create function call
-        let call_site = self.builder
-            .build_call(function, &arg_values, "call")
-            .map_err(|_| "Failed to build call")?;
+    /// The runtime functions `i64 mlia_vec_new()` and `i64 mlia_vec_push(i64
+    /// vector, i64 value)`, defined the first time a program uses a vector.
+    /// Push doubles the capacity (from 4) with realloc when the vector is full.
+    fn vector_functions(&mut self) -> (FunctionValue<'ctx>, FunctionValue<'ctx>) {
+        if let (Some(vec_new), Some(push)) = (
+            self.module.get_function("mlia_vec_new"),
+            self.module.get_function("mlia_vec_push"),
+        ) {
+            return (vec_new, push);
+        }
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let malloc = self.libc_function("malloc", ptr_type.fn_type(&[i64_type.into()], false));
+        let realloc_type = ptr_type.fn_type(&[ptr_type.into(), i64_type.into()], false);
+        let realloc = self.libc_function("realloc", realloc_type);
+        let builder = self.context.create_builder();
+        // The header and element slots, as pointers
+        let slot = |block: IntValue<'ctx>, offset: IntValue<'ctx>| {
+            let block = builder.build_int_to_ptr(block, ptr_type, "block").unwrap();
+            // SAFETY: offsets are within the header, or below the capacity
+            unsafe { builder.build_gep(i64_type, block, &[offset], "slot") }.unwrap()
+        };
+        let field = |index: u64| i64_type.const_int(index, false);
 
-        // Get return value
-        let result = call_site.try_as_basic_value()
+        let vec_new = self.module.add_function(
+            "mlia_vec_new",
+            i64_type.fn_type(&[], false),
+            Some(Linkage::Internal),
+        );
+        builder.position_at_end(self.context.append_basic_block(vec_new, "entry"));
+        let header = builder
+            .build_call(malloc, &[field(24).into()], "header")
+            .unwrap()
+            .try_as_basic_value()
             .left()
-            .ok_or("Function call did not return a value")?
+            .unwrap()
+            .into_pointer_value();
+        let vector = builder
+            .build_ptr_to_int(header, i64_type, "vector")
+            .unwrap();
+        // Length, capacity and elements: none yet
+        for index in 0..3 {
+            builder
+                .build_store(slot(vector, field(index)), i64_type.const_zero())
+                .unwrap();
+        }
+        builder.build_return(Some(&vector)).unwrap();
+
+        let push = self.module.add_function(
+            "mlia_vec_push",
+            i64_type.fn_type(&[i64_type.into(), i64_type.into()], false),
+            Some(Linkage::Internal),
+        );
+        let entry = self.context.append_basic_block(push, "entry");
+        let grow = self.context.append_basic_block(push, "grow");
+        let append = self.context.append_basic_block(push, "append");
+        builder.position_at_end(entry);
+        let vector = push.get_nth_param(0).unwrap().into_int_value();
+        let value = push.get_nth_param(1).unwrap().into_int_value();
+        let len = builder
+            .build_load(i64_type, slot(vector, field(0)), "len")
+            .unwrap();
+        let len = len.into_int_value();
+        let capacity = builder
+            .build_load(i64_type, slot(vector, field(1)), "capacity")
+            .unwrap();
+        let capacity = capacity.into_int_value();
+        let full = builder
+            .build_int_compare(IntPredicate::EQ, len, capacity, "full")
+            .unwrap();
+        builder
+            .build_conditional_branch(full, grow, append)
+            .unwrap();
+
+        builder.position_at_end(grow);
+        let is_empty = builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                capacity,
+                i64_type.const_zero(),
+                "is_empty",
+            )
+            .unwrap();
+        let doubled = builder
+            .build_int_mul(capacity, field(2), "doubled")
+            .unwrap();
+        let new_capacity = builder
+            .build_select(is_empty, field(4), doubled, "new_capacity")
+            .unwrap()
             .into_int_value();
+        let data = builder
+            .build_load(i64_type, slot(vector, field(2)), "data")
+            .unwrap();
+        let data = builder
+            .build_int_to_ptr(data.into_int_value(), ptr_type, "data_ptr")
+            .unwrap();
+        let size = builder
+            .build_int_mul(new_capacity, field(8), "size")
+            .unwrap();
+        let grown = builder
+            .build_call(realloc, &[data.into(), size.into()], "grown")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value();
+        let grown = builder
+            .build_ptr_to_int(grown, i64_type, "grown_addr")
+            .unwrap();
+        builder.build_store(slot(vector, field(2)), grown).unwrap();
+        builder
+            .build_store(slot(vector, field(1)), new_capacity)
+            .unwrap();
+        builder.build_unconditional_branch(append).unwrap();
 
-        Ok(result)
+        builder.position_at_end(append);
+        let data = builder
+            .build_load(i64_type, slot(vector, field(2)), "data")
+            .unwrap();
+        builder
+            .build_store(slot(data.into_int_value(), len), value)
+            .unwrap();
+        let next = builder.build_int_add(len, field(1), "next").unwrap();
+        builder.build_store(slot(vector, field(0)), next).unwrap();
+        builder.build_return(Some(&value)).unwrap();
+        (vec_new, push)
     }
 
-    /// Compiles while loops using the standard three-block pattern.
-    /// Returns 0 when the loop exits (final condition value).
-    fn compile_while(
+    /// Compiles the file builtins over C's `FILE*`, which programs hold as an
+    /// integer handle: `fopen "path" "mode"` (0 when the file can't be opened),
+    /// `write_line f n` (writes `n` and a newline; evaluates to `n`),
+    /// `fread_int f` (the next number in the file, 0 when there is none) and
+    /// `fclose f` (evaluates to 0). Using a 0 handle stops the program.
+    fn compile_file_op(
         &mut self,
-        condition: &Expr,
-        body: &Expr,
-    ) -> Result<IntValue<'ctx>, &'static str> {
-        let function = self
-            .current_function
-            .ok_or("No current function for while loop")?;
+        op: &str,
+        args: &[Expr],
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 {
+            let name = match op {
+                "fopen" => "fopen",
+                "write_line" => "write_line",
+                "fread_int" => "fread_int",
+                _ => "fclose",
+            };
+            return Err(CodegenError::UnsupportedBuiltin { name, span });
+        }
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let values = args
+            .iter()
+            .map(|arg| self.compile_expr(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if op == "fopen" {
+            let fopen_type = ptr_type.fn_type(&[ptr_type.into(), ptr_type.into()], false);
+            let fopen = self.libc_function("fopen", fopen_type);
+            let path = self.builder.build_int_to_ptr(values[0], ptr_type, "path")?;
+            let mode = self.builder.build_int_to_ptr(values[1], ptr_type, "mode")?;
+            let file = self
+                .builder
+                .build_call(fopen, &[path.into(), mode.into()], "file")?
+                .try_as_basic_value()
+                .left()
+                .ok_or(CodegenError::Internal("fopen returns no value"))?;
+            let file = file.into_pointer_value();
+            return Ok(self.builder.build_ptr_to_int(file, i64_type, "handle")?);
+        }
 
-        // Create basic blocks
-        let loop_header = self.context.append_basic_block(function, "loop_header");
-        let loop_body = self.context.append_basic_block(function, "loop_body");
-        let loop_exit = self.context.append_basic_block(function, "loop_exit");
+        let handle = values[0];
+        let is_open = self.builder.build_int_compare(
+            IntPredicate::NE,
+            handle,
+            i64_type.const_zero(),
+            "is_open",
+        )?;
+        let message = format!("{} needs an open file, got handle %lld", op);
+        self.build_runtime_check(is_open, &message, &[handle])?;
+        let file = self.builder.build_int_to_ptr(handle, ptr_type, "file")?;
+        match op {
+            "write_line" => {
+                let fprintf_type = i32_type.fn_type(&[ptr_type.into(), ptr_type.into()], true);
+                let fprintf = self.libc_function("fprintf", fprintf_type);
+                let format = self
+                    .builder
+                    .build_global_string_ptr("%lld\n", "write_format")?;
+                let args = [
+                    file.into(),
+                    format.as_pointer_value().into(),
+                    values[1].into(),
+                ];
+                self.builder.build_call(fprintf, &args, "")?;
+                Ok(values[1])
+            }
+            "fread_int" => {
+                let fscanf_type = i32_type.fn_type(&[ptr_type.into(), ptr_type.into()], true);
+                let fscanf = self.libc_function("fscanf", fscanf_type);
+                let format = self
+                    .builder
+                    .build_global_string_ptr("%lld", "read_format")?;
+                // fscanf leaves the slot alone when there is no number to read
+                let slot = self.create_entry_block_alloca_of(i64_type, "read_value");
+                self.builder.build_store(slot, i64_type.const_zero())?;
+                let target = self
+                    .builder
+                    .build_pointer_cast(slot, ptr_type, "read_slot")?;
+                let args = [file.into(), format.as_pointer_value().into(), target.into()];
+                self.builder.build_call(fscanf, &args, "")?;
+                Ok(self
+                    .builder
+                    .build_load(i64_type, slot, "read")?
+                    .into_int_value())
+            }
+            _ => {
+                let fclose_type = i32_type.fn_type(&[ptr_type.into()], false);
+                let fclose = self.libc_function("fclose", fclose_type);
+                self.builder.build_call(fclose, &[file.into()], "")?;
+                Ok(i64_type.const_zero())
+            }
+        }
+    }
 
-        // Branch to header
+    /// Continues when `ok` holds; otherwise writes "error: " and `format`,
+    /// filled with `values` as printf would, to standard error and exits the
+    /// program with status 1. The shared way for the runtime to fail. Under
+    /// the JIT the program runs inside the caller's process, so the message
+    /// goes to `runtime_error` instead and the function returns, as do its
+    /// callers in turn (see `return_if_failed`).
+    fn build_runtime_check(
+        &mut self,
+        ok: IntValue<'ctx>,
+        format: &str,
+        values: &[IntValue<'ctx>],
+    ) -> Result<(), CodegenError> {
+        let function = self
+            .builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .ok_or(CodegenError::Internal("runtime check outside a function"))?;
+        let failed = self.context.append_basic_block(function, "check_failed");
+        let passed = self.context.append_basic_block(function, "check_passed");
+        self.builder.build_conditional_branch(ok, passed, failed)?;
+
+        self.builder.position_at_end(failed);
+        if self.jit {
+            self.build_runtime_error(format, values)?;
+            self.builder.position_at_end(passed);
+            return Ok(());
+        }
+        let i32_type = self.context.i32_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let dprintf_type = i32_type.fn_type(&[i32_type.into(), ptr_type.into()], true);
+        let dprintf = self.libc_function("dprintf", dprintf_type);
+        let exit_type = self.context.void_type().fn_type(&[i32_type.into()], false);
+        let exit = self.libc_function("exit", exit_type);
+        let format = format!("error: {}\n", format);
+        let format = self
+            .builder
+            .build_global_string_ptr(&format, "error_format")?;
+        let mut args: Vec<BasicMetadataValueEnum> = vec![
+            i32_type.const_int(2, false).into(),
+            format.as_pointer_value().into(),
+        ];
+        args.extend(
+            values
+                .iter()
+                .map(|&value| BasicMetadataValueEnum::from(value)),
+        );
+        self.builder.build_call(dprintf, &args, "")?;
         self.builder
-            .build_unconditional_branch(loop_header)
-            .map_err(|_| "Failed to build branch to loop header")?;
+            .build_call(exit, &[i32_type.const_int(1, false).into()], "")?;
+        self.builder.build_unreachable()?;
 
-        // Header: evaluate condition
-        self.builder.position_at_end(loop_header);
-        let cond_val = self.compile_expr(condition)?;
+        self.builder.position_at_end(passed);
+        Ok(())
+    }
 
-        // Convert condition to boolean (non-zero = true, zero = false)
-        let cond_bool = self
+    /// The failure of a runtime check under the JIT: passes `format` and
+    /// `values` to `runtime_error` and returns 0.
+    fn build_runtime_error(
+        &mut self,
+        format: &str,
+        values: &[IntValue<'ctx>],
+    ) -> Result<(), CodegenError> {
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let error_type = self
+            .context
+            .void_type()
+            .fn_type(&[ptr_type.into(), ptr_type.into()], false);
+        let runtime_error = self.libc_function("mlia_runtime_error", error_type);
+        self.failed_flag();
+        let format = self
             .builder
-            .build_int_compare(
-                inkwell::IntPredicate::NE,
-                cond_val,
-                self.context.i64_type().const_zero(),
-                "loop_cond",
-            )
-            .map_err(|_| "Failed to build loop condition")?;
+            .build_global_string_ptr(format, "error_format")?;
+        let array_type = i64_type.array_type(values.len() as u32);
+        let array = self.create_entry_block_alloca_of(array_type, "error_args");
+        for (i, value) in values.iter().enumerate() {
+            let indices = [i64_type.const_zero(), i64_type.const_int(i as u64, false)];
+            // SAFETY: the index is within the array
+            let slot = unsafe { self.builder.build_gep(array_type, array, &indices, "arg")? };
+            self.builder.build_store(slot, *value)?;
+        }
+        let args = [format.as_pointer_value().into(), array.into()];
+        self.builder.build_call(runtime_error, &args, "")?;
+        self.builder.build_return(Some(&i64_type.const_zero()))?;
+        Ok(())
+    }
 
-        self.builder
-            .build_conditional_branch(cond_bool, loop_body, loop_exit)
-            .map_err(|_| "Failed to build conditional branch")?;
+    /// The `mlia_failed` global that JIT-ed programs read after each call to
+    /// know whether a runtime check failed in it (see `FAILED`).
+    fn failed_flag(&self) -> GlobalValue<'ctx> {
+        self.module.get_global("mlia_failed").unwrap_or_else(|| {
+            let failed = self
+                .module
+                .add_global(self.context.i64_type(), None, "mlia_failed");
+            failed.set_linkage(Linkage::External);
+            failed
+        })
+    }
 
-        // Body: execute loop body
-        self.builder.position_at_end(loop_body);
-        self.compile_expr(body)?;
+    /// Compiles `print_char c`: writes the character with codepoint `c` (its
+    /// low byte, natively: `putchar`) with no newline, and evaluates to `c`.
+    fn compile_print_char(
+        &mut self,
+        arg: &Expr,
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 {
+            return Err(CodegenError::UnsupportedBuiltin {
+                name: "print_char",
+                span,
+            });
+        }
+        let code = self.compile_expr(arg)?;
+        if let Some(capture) = self.module.get_function("mlia_capture_char") {
+            self.builder.build_call(capture, &[code.into()], "")?;
+            return Ok(code);
+        }
+        let i32_type = self.context.i32_type();
+        let putchar = self.libc_function("putchar", i32_type.fn_type(&[i32_type.into()], false));
+        let byte = self.builder.build_int_truncate(code, i32_type, "char")?;
         self.builder
-            .build_unconditional_branch(loop_header)
-            .map_err(|_| "Failed to build branch back to header")?;
-
-        // Exit: continue after loop
-        self.builder.position_at_end(loop_exit);
-
-        // Return 0 (final condition value when loop exits)
-        Ok(self.context.i64_type().const_zero())
+            .build_call(putchar, &[byte.into()], "putchar_call")?;
+        Ok(code)
     }
 
-    /// Compiles match expressions with pattern matching.
-    /// Requires wildcard pattern for exhaustiveness or returns error.
-    /// Returns the value of the matched arm's result expression.
-    fn compile_match(
+    /// Compiles `print "text {x}"`: one printf call with the text as the format
+    /// and the values as its arguments. Evaluates to 0.
+    fn compile_print_format(
         &mut self,
-        scrutinee: &Expr,
-        arms: &[(Pattern, Expr)],
-    ) -> Result<IntValue<'ctx>, &'static str> {
-        // Check for wildcard pattern (exhaustiveness requirement)
-        let has_wildcard = arms.iter().any(|(pat, _)| matches!(pat, Pattern::Wildcard));
-        if !has_wildcard {
-            return Err("Match expression must have wildcard pattern for exhaustiveness");
+        pieces: &[String],
+        args: &[Expr],
+        span: Span,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        if self.target == CompileTarget::Wasm32 {
+            return Err(CodegenError::UnsupportedBuiltin {
+                name: "print \"...\"",
+                span: Some(span),
+            });
         }
-
-        let function = self
-            .current_function
-            .ok_or("No current function for match expression")?;
-
-        // Evaluate scrutinee
-        let scrutinee_val = self.compile_expr(scrutinee)?;
-
-        // Create merge block where all arms converge
-        let merge_block = self.context.append_basic_block(function, "match_merge");
-
-        // Allocate result variable in entry block
-        let saved_insert_point = self.builder.get_insert_block();
-        let entry_block = function
-            .get_first_basic_block()
-            .ok_or("Function has no entry block")?;
-        self.builder.position_at_end(entry_block);
-        let result_ptr = self.create_entry_block_alloca("match_result");
-        if let Some(block) = saved_insert_point {
-            self.builder.position_at_end(block);
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let values = args
+            .iter()
+            .map(|arg| self.compile_expr(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The captured output is formatted by capture_print_format, which
+        // always takes %lld
+        let capture = self.module.get_function("mlia_capture_format");
+        let integer = match capture {
+            Some(_) => "%lld",
+            None => print_format(&self.module.get_triple()).trim_end_matches('\n'),
+        };
+        let escaped: Vec<String> = pieces
+            .iter()
+            .map(|piece| piece.replace('%', "%%"))
+            .collect();
+        let format = format!("{}\n", escaped.join(integer));
+        let format = self.builder.build_global_string_ptr(&format, "print_fmt")?;
+
+        if let Some(capture) = capture {
+            let array_type = i64_type.array_type(values.len() as u32);
+            let array = self.create_entry_block_alloca_of(array_type, "print_args");
+            for (i, value) in values.iter().enumerate() {
+                let indices = [i64_type.const_zero(), i64_type.const_int(i as u64, false)];
+                // SAFETY: the index is within the array
+                let slot = unsafe { self.builder.build_gep(array_type, array, &indices, "arg")? };
+                self.builder.build_store(slot, *value)?;
+            }
+            let array = self.builder.build_pointer_cast(array, ptr_type, "args")?;
+            self.builder.build_call(
+                capture,
+                &[format.as_pointer_value().into(), array.into()],
+                "",
+            )?;
+        } else {
+            let printf_type = self.context.i32_type().fn_type(&[ptr_type.into()], true);
+            let printf = self.libc_function("printf", printf_type);
+            let mut printf_args: Vec<BasicMetadataValueEnum> =
+                vec![format.as_pointer_value().into()];
+            printf_args.extend(
+                values
+                    .iter()
+                    .map(|value| BasicMetadataValueEnum::from(*value)),
+            );
+            self.builder
+                .build_call(printf, &printf_args, "printf_call")?;
         }
+        Ok(i64_type.const_zero())
+    }
 
-        // Build comparison chain for each arm
-        let mut next_check_block = self.context.append_basic_block(function, "match_check_0");
-        self.builder
-            .build_unconditional_branch(next_check_block)
-            .map_err(|_| "Failed to build branch to first match check")?;
-
-        for (idx, (pattern, result_expr)) in arms.iter().enumerate() {
-            self.builder.position_at_end(next_check_block);
+    /// Compiles `sum` or `product`: the arguments, left to right, chained with
+    /// adds or multiplies. Without arguments it's the identity, 0 or 1.
+    fn compile_reduction(
+        &mut self,
+        op: &str,
+        args: &[Expr],
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let Some((first, rest)) = args.split_first() else {
+            let identity = if op == "sum" { 0 } else { 1 };
+            return Ok(self.context.i64_type().const_int(identity, false));
+        };
+        let mut result = self.compile_expr(first)?;
+        for arg in rest {
+            let value = self.compile_expr(arg)?;
+            result = if op == "sum" {
+                self.builder.build_int_add(result, value, "sum")?
+            } else {
+                self.builder.build_int_mul(result, value, "product")?
+            };
+        }
+        Ok(result)
+    }
 
-            match pattern {
-                Pattern::Literal(lit_val) => {
-                    // Create blocks for this arm
-                    let arm_block = self
-                        .context
-                        .append_basic_block(function, &format!("match_arm_{}", idx));
-                    let next_idx = idx + 1;
-                    next_check_block = if next_idx < arms.len() {
-                        self.context
-                            .append_basic_block(function, &format!("match_check_{}", next_idx))
-                    } else {
-                        merge_block // Last check goes to merge if no match
-                    };
+    /// Compiles a binary operation.
+    fn compile_binop(
+        &mut self,
+        op: &str,
+        lhs: &Expr,
+        rhs: &Expr,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let lhs_val = self.compile_expr(lhs)?;
+        let rhs_val = self.compile_expr(rhs)?;
 
-                    // Compare scrutinee with pattern literal
-                    let lit_const = self.context.i64_type().const_int(*lit_val as u64, true);
-                    let matches = self
-                        .builder
-                        .build_int_compare(
-                            inkwell::IntPredicate::EQ,
-                            scrutinee_val,
-                            lit_const,
-                            &format!("match_cmp_{}", idx),
-                        )
-                        .map_err(|_| "Failed to build match comparison")?;
-
-                    self.builder
-                        .build_conditional_branch(matches, arm_block, next_check_block)
-                        .map_err(|_| "Failed to build conditional branch for match arm")?;
-
-                    // Compile arm result expression
-                    self.builder.position_at_end(arm_block);
-                    let arm_val = self.compile_expr(result_expr)?;
-                    self.builder
-                        .build_store(result_ptr, arm_val)
-                        .map_err(|_| "Failed to store match arm result")?;
-                    self.builder
-                        .build_unconditional_branch(merge_block)
-                        .map_err(|_| "Failed to build branch to merge block")?;
-                }
+        let op_result = match op {
+            "+" => Some(self.builder.build_int_add(lhs_val, rhs_val, "add")),
+            "-" => Some(self.builder.build_int_sub(lhs_val, rhs_val, "sub")),
+            "*" => Some(self.builder.build_int_mul(lhs_val, rhs_val, "mul")),
+            "/" | "%" | "emod" | "fdiv" => return self.build_division(op, lhs_val, rhs_val),
+            _ => None,
+        };
 
-                Pattern::Wildcard => {
-                    // Wildcard always matches - compile result and branch to merge
-                    let arm_val = self.compile_expr(result_expr)?;
-                    self.builder
-                        .build_store(result_ptr, arm_val)
-                        .map_err(|_| "Failed to store wildcard result")?;
-                    self.builder
-                        .build_unconditional_branch(merge_block)
-                        .map_err(|_| "Failed to build branch from wildcard to merge")?;
-                }
-            }
+        match op_result {
+            Some(result) => Ok(result?),
+            None => Err(CodegenError::Internal("invalid arithmetic operator")),
         }
-
-        // Position at merge block and load result
-        self.builder.position_at_end(merge_block);
-        Ok(self.build_load(result_ptr, "match_result"))
     }
 
-    /// Compiles the entire program and returns a JIT-compiled function.
-    pub fn compile_program(
-        &'_ mut self,
-        expr: &Expr,
-    ) -> Result<JitFunction<'_, MainFunc>, Box<dyn Error>> {
-        // Create main function
+    /// Compiles `/`, `%`, `emod` or `fdiv` once a runtime check has made sure
+    /// that `rhs` isn't zero: dividing by zero stops the program with an
+    /// error, natively and with the JIT alike, instead of trapping or being
+    /// undefined.
+    ///
+    /// `sdiv` and `srem` are undefined for `i64::MIN` by -1 too, whose quotient
+    /// doesn't fit, so -1 divides by 1 and the quotient is negated: it wraps
+    /// to `i64::MIN`, as `- 0 x` does, and the remainder is 0.
+    fn build_division(
+        &mut self,
+        op: &str,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
         let i64_type = self.context.i64_type();
-        let fn_type = i64_type.fn_type(&[], false);
-        let main_function = self.module.add_function("main", fn_type, None);
+        let zero = i64_type.const_zero();
+        let nonzero = self
+            .builder
+            .build_int_compare(IntPredicate::NE, rhs, zero, "nonzero")?;
+        self.build_runtime_check(nonzero, "division by zero", &[])?;
+
+        let minus_one = i64_type.const_all_ones();
+        let by_minus_one =
+            self.builder
+                .build_int_compare(IntPredicate::EQ, rhs, minus_one, "by_minus_one")?;
+        let divisor = self
+            .builder
+            .build_select(by_minus_one, i64_type.const_int(1, false), rhs, "divisor")?
+            .into_int_value();
+        let negated = self.builder.build_int_neg(lhs, "negated")?;
+        let quotient = |quotient: IntValue<'ctx>| -> Result<IntValue<'ctx>, CodegenError> {
+            Ok(self
+                .builder
+                .build_select(by_minus_one, negated, quotient, "quotient")?
+                .into_int_value())
+        };
+        match op {
+            "/" => quotient(self.builder.build_int_signed_div(lhs, divisor, "div")?),
+            "%" => Ok(self.builder.build_int_signed_rem(lhs, divisor, "rem")?),
+            "emod" => self.build_euclidean_rem(lhs, divisor),
+            "fdiv" => quotient(self.build_floor_div(lhs, divisor)?),
+            _ => Err(CodegenError::Internal("invalid division operator")),
+        }
+    }
 
-        // Create entry basic block
-        let entry_block = self.context.append_basic_block(main_function, "entry");
-        self.builder.position_at_end(entry_block);
+    /// The Euclidean remainder of `lhs` by `rhs`, which is never negative:
+    /// `srem` takes the sign of `lhs`, so a negative one gets `|rhs|` added.
+    fn build_euclidean_rem(
+        &self,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let zero = self.context.i64_type().const_zero();
+        let rem = self.builder.build_int_signed_rem(lhs, rhs, "rem")?;
+        let negative_rhs = self.builder.build_int_compare(
+            inkwell::IntPredicate::SLT,
+            rhs,
+            zero,
+            "negative_rhs",
+        )?;
+        let minus_rhs = self.builder.build_int_neg(rhs, "minus_rhs")?;
+        let abs_rhs = self
+            .builder
+            .build_select(negative_rhs, minus_rhs, rhs, "abs_rhs")?
+            .into_int_value();
+        let negative_rem = self.builder.build_int_compare(
+            inkwell::IntPredicate::SLT,
+            rem,
+            zero,
+            "negative_rem",
+        )?;
+        let adjusted = self.builder.build_int_add(rem, abs_rhs, "adjusted")?;
+        Ok(self
+            .builder
+            .build_select(negative_rem, adjusted, rem, "emod")?
+            .into_int_value())
+    }
 
-        // Set current function
-        self.current_function = Some(main_function);
+    /// `lhs` divided by `rhs`, rounded down: `sdiv` rounds toward zero, which
+    /// is one too many when the division isn't exact and the operands have
+    /// different signs.
+    fn build_floor_div(
+        &self,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let zero = self.context.i64_type().const_zero();
+        let quotient = self.builder.build_int_signed_div(lhs, rhs, "div")?;
+        let rem = self.builder.build_int_signed_rem(lhs, rhs, "rem")?;
+        let inexact =
+            self.builder
+                .build_int_compare(inkwell::IntPredicate::NE, rem, zero, "inexact")?;
+        // The remainder has the sign of lhs: it differs from the one of rhs
+        // when their xor is negative
+        let signs = self.builder.build_xor(rem, rhs, "signs")?;
+        let signs_differ = self.builder.build_int_compare(
+            inkwell::IntPredicate::SLT,
+            signs,
+            zero,
+            "signs_differ",
+        )?;
+        let round_down = self
+            .builder
+            .build_and(inexact, signs_differ, "round_down")?;
+        let adjustment =
+            self.builder
+                .build_int_z_extend(round_down, self.context.i64_type(), "adjustment")?;
+        Ok(self.builder.build_int_sub(quotient, adjustment, "fdiv")?)
+    }
 
-        // Compile the expression
-        let result = self.compile_expr(expr)?;
+    /// Compiles a comparison, `&`, `|` or `!` (see `is_boolean_op`) into an i1.
+    /// The operands of logical operators are conditions: anything non-zero is
+    /// true. Both operands are always evaluated.
+    fn compile_boolean_op(
+        &mut self,
+        op: &str,
+        args: &[Expr],
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        use inkwell::IntPredicate;
 
-        // Return the result
-        self.builder.build_return(Some(&result)).unwrap();
+        let predicate = match op {
+            "<" => IntPredicate::SLT, // Signed Less Than
+            ">" => IntPredicate::SGT, // Signed Greater Than
+            "=" => IntPredicate::EQ,  // Equal
+            "!=" => IntPredicate::NE, // Not Equal
+            "!" => {
+                let arg = self.compile_condition(&args[0])?;
+                return Ok(self.builder.build_not(arg, "not")?);
+            }
+            "&" | "|" => {
+                let left = self.compile_condition(&args[0])?;
+                let right = self.compile_condition(&args[1])?;
+                return Ok(if op == "&" {
+                    self.builder.build_and(left, right, "and_tmp")?
+                } else {
+                    self.builder.build_or(left, right, "or_tmp")?
+                });
+            }
+            _ => return Err(CodegenError::Internal("invalid boolean operator")),
+        };
 
-        // Verify the function
-        if main_function.verify(true) {
-            // Get the compiled function
-            unsafe {
-                self.execution_engine
-                    .get_function("main")
-                    .map_err(|e| format!("Failed to get main function: {}", e).into())
+        let lhs_val = self.compile_expr(&args[0])?;
+        let rhs_val = self.compile_expr(&args[1])?;
+        Ok(self
+            .builder
+            .build_int_compare(predicate, lhs_val, rhs_val, "cmp")?)
+    }
+
+    /// Find free variables in an expression
+    /// Free variables are identifiers that are used but not defined in the current scope
+    fn find_free_variables(&self, expr: &Expr, bound: &HashSet<Name>) -> HashSet<Name> {
+        let mut free = HashSet::new();
+        match expr {
+            Expr::Number(_) | Expr::EnvInt(..) | Expr::Str(..) => {}
+            Expr::Ident(name, _) => {
+                if !bound.contains(name) {
+                    free.insert(name.clone());
+                }
             }
-        } else {
-            Err("Function verification failed".into())
-        }
+            Expr::NamedArg(_, value, _) => {
+                free.extend(self.find_free_variables(value, bound));
+            }
+            Expr::PrintFormat(_, args, _) | Expr::Array(args, _) => {
+                for arg in args {
+                    free.extend(self.find_free_variables(arg, bound));
+                }
+            }
+            Expr::Call(_func, args, _) => {
+                // Don't treat function name as free variable
+                for arg in args {
+                    free.extend(self.find_free_variables(arg, bound));
+                }
+            }
+            Expr::Block(statements) => {
+                for statement in statements {
+                    free.extend(self.find_free_variables(statement, bound));
+                }
+            }
+            Expr::Assign(var, value, _) => {
+                free.extend(self.find_free_variables(value, bound));
+                // Assignment doesn't bind, it just mutates
+                if !bound.contains(var) {
+                    free.insert(var.clone());
+                }
+            }
+            Expr::Decl(var, params, value, body, _) => {
+                // Variables in 'value' can only see outer scope
+                free.extend(self.find_free_variables(value, bound));
+
+                // Variables in 'body' can see var and params
+                let mut inner_bound = bound.clone();
+                inner_bound.insert(var.clone());
+                for (param, _) in params {
+                    inner_bound.insert(param.clone());
+                }
+                free.extend(self.find_free_variables(body, &inner_bound));
+            }
+            Expr::While(cond, body, _) | Expr::WhileYield(cond, body, _) => {
+                free.extend(self.find_free_variables(cond, bound));
+                free.extend(self.find_free_variables(body, bound));
+            }
+            Expr::Loop(body, _) => {
+                free.extend(self.find_free_variables(body, bound));
+            }
+            Expr::Global(var, value, body, _) => {
+                free.extend(self.find_free_variables(value, bound));
+                let mut inner_bound = bound.clone();
+                inner_bound.insert(var.clone());
+                free.extend(self.find_free_variables(body, &inner_bound));
+            }
+            Expr::Break(value, _) => {
+                if let Some(value) = value {
+                    free.extend(self.find_free_variables(value, bound));
+                }
+            }
+            Expr::Match(scrutinee, arms, _) => {
+                free.extend(self.find_free_variables(scrutinee, bound));
+                for (pattern, arm_expr) in arms {
+                    let mut arm_bound = bound.clone();
+                    let names = pattern.bindings().into_iter().map(|(name, _)| name.clone());
+                    arm_bound.extend(names);
+                    free.extend(self.find_free_variables(arm_expr, &arm_bound));
+                }
+            }
+        }
+        free
+    }
+
+    /// Compile user-defined function declaration
+    fn compile_function_decl(
+        &mut self,
+        func_name: &Name,
+        params: &[(Name, Option<i64>)],
+        body: &Expr,
+        continuation: &Expr,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        // Find free variables in the function body
+        let mut bound = HashSet::new();
+        for (param, _) in params {
+            bound.insert(param.clone());
+        }
+        let free_vars = self.find_free_variables(body, &bound);
+
+        // Filter free variables to only those currently in scope
+        let captured_vars: Vec<Name> = free_vars
+            .iter()
+            .filter(|var| self.variables.contains_key(*var))
+            .cloned()
+            .collect();
+
+        // Create function type with extra parameters for captured variables
+        // i64 (i64, i64, ..., captured1, captured2, ...)
+        let i64_type = self.context.i64_type();
+        let mut param_types: Vec<_> = params.iter().map(|_| i64_type.into()).collect();
+        // Add types for captured variables
+        for _ in &captured_vars {
+            param_types.push(i64_type.into());
+        }
+        let fn_type = i64_type.fn_type(&param_types, false);
+
+        // Create LLVM function
+        let function = self.module.add_function(&mangle(func_name), fn_type, None);
+
+        // Register function with its captured variables before compiling body
+        self.user_functions.insert(
+            func_name.clone(),
+            (function, captured_vars.clone(), params.to_vec()),
+        );
+
+        // Save current context
+        let parent_function = self.current_function;
+        let parent_block = self.builder.get_insert_block();
+        let old_variables = self.variables.clone();
+        // Parameters shadow the arrays of the same name
+        let old_array_lengths = self.array_lengths.clone();
+        for (param, _) in params {
+            self.array_lengths.remove(param);
+        }
+        // A break in the body can't leave the loops around the declaration
+        let parent_loops = std::mem::take(&mut self.loops);
+        let parent_debug_scope = self.debug_info.as_ref().and_then(|debug| debug.scope);
+
+        // Set new context
+        self.current_function = Some(function);
+        self.variables.clear();
+        self.debug_enter_function(function, func_name);
+
+        // Create entry block
+        let entry_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry_block);
+
+        // Create allocas for explicit parameters and store values
+        for (i, (param_name, _)) in params.iter().enumerate() {
+            let param_value = function
+                .get_nth_param(i as u32)
+                .ok_or(CodegenError::Internal("failed to get parameter"))?
+                .into_int_value();
+
+            let alloca = self.create_entry_block_alloca(param_name);
+            self.builder.build_store(alloca, param_value)?;
+
+            self.variables.insert(param_name.clone(), alloca);
+        }
+
+        // Create allocas for captured variables (hidden parameters)
+        for (i, var_name) in captured_vars.iter().enumerate() {
+            let param_idx = (params.len() + i) as u32;
+            let param_value = function
+                .get_nth_param(param_idx)
+                .ok_or(CodegenError::Internal(
+                    "failed to get captured variable parameter",
+                ))?
+                .into_int_value();
+
+            let alloca = self.create_entry_block_alloca(var_name);
+            self.builder.build_store(alloca, param_value)?;
+
+            self.variables.insert(var_name.clone(), alloca);
+        }
+        self.consume_fuel()?;
+
+        // Compile function body
+        let result = self.compile_expr(body)?;
+
+        // Return result
+        self.builder.build_return(Some(&result))?;
+
+        // Verify function (its debug info has to be resolved first)
+        self.finalize_debug_info();
+        if self.verify && !function.verify(false) {
+            return Err(self.verification_failed(func_name));
+        }
+
+        // Restore previous context
+        self.current_function = parent_function;
+        self.variables = old_variables;
+        self.array_lengths = old_array_lengths;
+        self.loops = parent_loops;
+        if let Some(debug) = &mut self.debug_info {
+            debug.scope = parent_debug_scope;
+        }
+        self.set_debug_location(self.current_span);
+
+        // Reposition builder where the parent was (not at its last block: inside
+        // a loop or a match arm that is a block still waiting for a terminator)
+        if let Some(block) = parent_block {
+            self.builder.position_at_end(block);
+        }
+
+        // Compile continuation
+        self.compile_expr(continuation)
+    }
+
+    /// Compile a user-defined function call
+    fn compile_user_function_call(
+        &mut self,
+        func_name: &str,
+        args: &[Expr],
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        // Look up function and captured variables
+        let (function, captured_vars, params) =
+            self.user_functions
+                .get(func_name)
+                .ok_or_else(|| CodegenError::UnknownFunction {
+                    name: func_name.to_string(),
+                    arity: args.len(),
+                    expr: None,
+                })?;
+        let function = *function; // Dereference to copy FunctionValue
+        let captured_vars = captured_vars.clone(); // Clone the vector
+
+        // Verify number of user-provided arguments (not including captured
+        // variables): missing trailing arguments take their default value
+        let (required, total) = arity(params);
+        let defaults: Vec<Option<i64>> = params
+            .iter()
+            .skip(args.len())
+            .map(|(_, default)| *default)
+            .collect();
+        if args.len() < required || args.len() > total {
+            return Err(CodegenError::WrongArgumentCount {
+                name: func_name.to_string(),
+                expected: if args.len() < required {
+                    required
+                } else {
+                    total
+                },
+                found: args.len(),
+            });
+        }
+
+        // Compile user-provided arguments
+        let mut arg_values = Vec::new();
+        for arg in args {
+            let val = self.compile_expr(arg)?;
+            arg_values.push(val.into());
+        }
+        for default in defaults {
+            let default = default.ok_or(CodegenError::Internal("missing default value"))?;
+            arg_values.push(
+                self.context
+                    .i64_type()
+                    .const_int(default as u64, true)
+                    .into(),
+            );
+        }
+
+        // Add captured variables as extra arguments
+        for var_name in &captured_vars {
+            let var_ptr = self
+                .variables
+                .get(var_name)
+                .ok_or(CodegenError::Internal("captured variable not in scope"))?;
+            let val = self
+                .builder
+                .build_load(self.context.i64_type(), *var_ptr, var_name)?
+                .into_int_value();
+            arg_values.push(val.into());
+        }
+
+        // Create function call
+        let call_site = self.builder.build_call(function, &arg_values, "call")?;
+
+        // Get return value
+        let result = call_site
+            .try_as_basic_value()
+            .left()
+            .ok_or(CodegenError::Internal(
+                "function call did not return a value",
+            ))?
+            .into_int_value();
+
+        // If the budget ran out or a runtime check failed inside the call,
+        // keep unwinding
+        if let Some(fuel) = self.module.get_global("mlia_fuel") {
+            let left = self
+                .builder
+                .build_load(self.context.i64_type(), fuel.as_pointer_value(), "fuel")?
+                .into_int_value();
+            self.return_if_out_of_fuel(left)?;
+        }
+        if self.jit {
+            self.return_if_failed()?;
+        }
+
+        Ok(result)
+    }
+
+    /// With an execution budget (`execute_with_limit`), spends one unit of fuel.
+    /// Without one this emits nothing.
+    fn consume_fuel(&mut self) -> Result<(), CodegenError> {
+        let Some(fuel) = self.module.get_global("mlia_fuel") else {
+            return Ok(());
+        };
+        let i64_type = self.context.i64_type();
+        let fuel = fuel.as_pointer_value();
+        let left = self
+            .builder
+            .build_load(i64_type, fuel, "fuel")?
+            .into_int_value();
+        let left = self
+            .builder
+            .build_int_sub(left, i64_type.const_int(1, false), "fuel_left")?;
+        self.builder.build_store(fuel, left)?;
+        self.return_if_out_of_fuel(left)
+    }
+
+    /// Returns 0 from the current function when `fuel` is negative, that is once
+    /// the budget has run out, so that every caller returns in turn up to `main`.
+    fn return_if_out_of_fuel(&mut self, fuel: IntValue<'ctx>) -> Result<(), CodegenError> {
+        let zero = self.context.i64_type().const_zero();
+        let exhausted = self.builder.build_int_compare(
+            inkwell::IntPredicate::SLT,
+            fuel,
+            zero,
+            "out_of_fuel",
+        )?;
+        self.return_zero_if(exhausted, "out_of_fuel", "fuel_left")
+    }
+
+    /// Returns 0 from the current function when a runtime check failed in the
+    /// function just called, so that every caller returns in turn up to
+    /// `main`, as when the fuel runs out.
+    fn return_if_failed(&mut self) -> Result<(), CodegenError> {
+        let i64_type = self.context.i64_type();
+        let flag = self.failed_flag().as_pointer_value();
+        let failed = self
+            .builder
+            .build_load(i64_type, flag, "failed")?
+            .into_int_value();
+        let failed = self.builder.build_int_compare(
+            IntPredicate::NE,
+            failed,
+            i64_type.const_zero(),
+            "failed",
+        )?;
+        self.return_zero_if(failed, "call_failed", "call_passed")
+    }
+
+    /// Returns 0 from the current function when `condition` holds, going on
+    /// in a new block named `otherwise` when it doesn't.
+    fn return_zero_if(
+        &mut self,
+        condition: IntValue<'ctx>,
+        then: &str,
+        otherwise: &str,
+    ) -> Result<(), CodegenError> {
+        let function = self
+            .current_function
+            .ok_or(CodegenError::Internal("no current function to return from"))?;
+        let returns = self.context.append_basic_block(function, then);
+        let goes_on = self.context.append_basic_block(function, otherwise);
+        self.builder
+            .build_conditional_branch(condition, returns, goes_on)?;
+        self.builder.position_at_end(returns);
+        self.builder
+            .build_return(Some(&self.context.i64_type().const_zero()))?;
+        self.builder.position_at_end(goes_on);
+        Ok(())
+    }
+
+    /// `name` with the line of the loop or match being compiled, so that its
+    /// blocks can be told apart in the IR: `loop_header` for a loop on line 12
+    /// is `loop_header_l12`. Code built without positions keeps `name`.
+    fn block_name(&self, name: &str) -> String {
+        match self.current_span {
+            Some(span) if span.line > 0 => format!("{}_l{}", name, span.line),
+            _ => name.to_string(),
+        }
+    }
+
+    /// Compiles while loops using the standard three-block pattern.
+    /// Returns 0 when the loop exits (final condition value), or with `yields`
+    /// (`while ... yield done`) the value of the body on the last iteration,
+    /// which is 0 too if the body never ran.
+    fn compile_while(
+        &mut self,
+        condition: &Expr,
+        body: &Expr,
+        yields: bool,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let function = self
+            .current_function
+            .ok_or(CodegenError::Internal("no current function for while loop"))?;
+
+        // Create basic blocks
+        let block = |name| {
+            self.context
+                .append_basic_block(function, &self.block_name(name))
+        };
+        let loop_header = block("loop_header");
+        let loop_body = block("loop_body");
+        let loop_exit = block("loop_exit");
+
+        // The last body value, carried across the back-edge to the exit
+        let last_ptr = if yields {
+            let last_ptr = self.create_entry_block_alloca("loop_last");
+            self.builder
+                .build_store(last_ptr, self.context.i64_type().const_zero())?;
+            Some(last_ptr)
+        } else {
+            None
+        };
+
+        // Branch to header
+        self.builder.build_unconditional_branch(loop_header)?;
+
+        // Header: evaluate condition
+        self.builder.position_at_end(loop_header);
+        // Non-zero = true, zero = false
+        let cond_bool = self.compile_condition(condition)?;
+
+        self.builder
+            .build_conditional_branch(cond_bool, loop_body, loop_exit)?;
+
+        // Body: execute loop body
+        self.builder.position_at_end(loop_body);
+        let body_val = self.compile_expr(body)?;
+        if let Some(last_ptr) = last_ptr {
+            self.builder.build_store(last_ptr, body_val)?;
+        }
+        self.consume_fuel()?;
+        self.builder.build_unconditional_branch(loop_header)?;
+
+        // Exit: continue after loop
+        self.builder.position_at_end(loop_exit);
+
+        match last_ptr {
+            Some(last_ptr) => Ok(self.build_load(last_ptr, "loop_result")),
+            // Return 0 (final condition value when loop exits)
+            None => Ok(self.context.i64_type().const_zero()),
+        }
+    }
+
+    /// Compiles `loop body end`: the body runs again and again, until a
+    /// `break` jumps to the exit with the value of the loop.
+    fn compile_loop(&mut self, body: &Expr) -> Result<IntValue<'ctx>, CodegenError> {
+        let function = self
+            .current_function
+            .ok_or(CodegenError::Internal("no current function for loop"))?;
+
+        let block = |name| {
+            self.context
+                .append_basic_block(function, &self.block_name(name))
+        };
+        let loop_body = block("loop_body");
+        let loop_exit = block("loop_exit");
+        let break_ptr = self.create_entry_block_alloca("loop_break");
+
+        self.builder.build_unconditional_branch(loop_body)?;
+
+        // Body: runs and branches back to itself
+        self.builder.position_at_end(loop_body);
+        self.loops.push((loop_exit, break_ptr));
+        let body_result = self.compile_expr(body);
+        self.loops.pop();
+        body_result?;
+        self.consume_fuel()?;
+        self.builder.build_unconditional_branch(loop_body)?;
+
+        // Exit: only reached through a break
+        self.builder.position_at_end(loop_exit);
+        Ok(self.build_load(break_ptr, "loop_result"))
+    }
+
+    /// Compiles `break [value]`: stores the value (0 without one) for the
+    /// innermost loop and jumps to its exit. Whatever follows the break in the
+    /// same block is never run; it goes into a block of its own.
+    fn compile_break(
+        &mut self,
+        value: Option<&Expr>,
+        span: Span,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let function = self
+            .current_function
+            .ok_or(CodegenError::Internal("no current function for break"))?;
+        let Some(&(loop_exit, break_ptr)) = self.loops.last() else {
+            return Err(CodegenError::BreakOutsideLoop { span: Some(span) });
+        };
+
+        let value = match value {
+            Some(value) => self.compile_expr(value)?,
+            None => self.context.i64_type().const_zero(),
+        };
+        self.builder.build_store(break_ptr, value)?;
+        self.builder.build_unconditional_branch(loop_exit)?;
+
+        let after_break = self.context.append_basic_block(function, "after_break");
+        self.builder.position_at_end(after_break);
+        Ok(self.context.i64_type().const_zero())
+    }
+
+    /// Compiles match expressions with pattern matching.
+    /// Requires the patterns to be exhaustive (see `exhaustiveness`) or returns error.
+    /// Returns the value of the matched arm's result expression.
+    fn compile_match(
+        &mut self,
+        scrutinee: &Expr,
+        arms: &[(Pattern, Expr)],
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let patterns: Vec<&Pattern> = arms.iter().map(|(pattern, _)| pattern).collect();
+        if let Err(error) = exhaustiveness::check(scrutinee, &patterns) {
+            return Err(CodegenError::NonExhaustiveMatch {
+                error,
+                span: self.current_span,
+            });
+        }
+
+        let function = self.current_function.ok_or(CodegenError::Internal(
+            "no current function for match expression",
+        ))?;
+
+        // Evaluate scrutinee
+        let scrutinee_val = self.compile_expr(scrutinee)?;
+
+        // Create merge block where all arms converge. Blocks are named after
+        // the line of the match: the second arm of one on line 20 is
+        // `match_arm_l20_1`
+        let merge_block = self
+            .context
+            .append_basic_block(function, &self.block_name("match_merge"));
+        let check_name = self.block_name("match_check");
+        let arm_name = self.block_name("match_arm");
+
+        // Allocate result variable in entry block
+        let saved_insert_point = self.builder.get_insert_block();
+        let entry_block = function
+            .get_first_basic_block()
+            .ok_or(CodegenError::Internal("function has no entry block"))?;
+        self.builder.position_at_end(entry_block);
+        let result_ptr = self.create_entry_block_alloca("match_result");
+        if let Some(block) = saved_insert_point {
+            self.builder.position_at_end(block);
+        }
+
+        // Build a chain of checks, one per arm
+        let mut next_check_block = self
+            .context
+            .append_basic_block(function, &format!("{}_0", check_name));
+        self.builder.build_unconditional_branch(next_check_block)?;
+
+        for (idx, (pattern, result_expr)) in arms.iter().enumerate() {
+            self.builder.position_at_end(next_check_block);
+
+            let mut bindings = Vec::new();
+            if !pattern.is_irrefutable() {
+                // Create blocks for this arm
+                let arm_block = self
+                    .context
+                    .append_basic_block(function, &format!("{}_{}", arm_name, idx));
+                let next_idx = idx + 1;
+                next_check_block = if next_idx < arms.len() {
+                    self.context
+                        .append_basic_block(function, &format!("{}_{}", check_name, next_idx))
+                } else {
+                    merge_block // Last check goes to merge if no match
+                };
+                let checks = (arm_block, next_check_block);
+                self.build_pattern_test(pattern, scrutinee_val, checks, idx, &mut bindings)?;
+                self.builder.position_at_end(arm_block);
+            } else {
+                // Every name an irrefutable pattern binds is for the whole value
+                let names = pattern.bindings().into_iter().map(|(name, _)| name);
+                bindings.extend(names.map(|name| (name, scrutinee_val)));
+            }
+
+            // Compile arm result expression, with the names the pattern binds in scope
+            let mut saved = Vec::new();
+            for (name, value) in bindings {
+                let alloca = self.create_entry_block_alloca(name);
+                self.builder.build_store(alloca, value)?;
+                let old_binding = self.variables.insert(name.clone(), alloca);
+                let old_length = self.array_lengths.remove(name);
+                saved.push((name, old_binding, old_length));
+            }
+            let arm_val = self.compile_expr(result_expr);
+            for (name, old_binding, old_length) in saved.into_iter().rev() {
+                match old_binding {
+                    Some(old_var) => self.variables.insert(name.clone(), old_var),
+                    None => self.variables.remove(name),
+                };
+                if let Some(len) = old_length {
+                    self.array_lengths.insert(name.clone(), len);
+                }
+            }
+            self.builder.build_store(result_ptr, arm_val?)?;
+            self.builder.build_unconditional_branch(merge_block)?;
+
+            // The arm matches anything: the ones after it are never reached
+            if pattern.is_irrefutable() {
+                break;
+            }
+        }
+
+        // Position at merge block and load result
+        self.builder.position_at_end(merge_block);
+        Ok(self.build_load(result_ptr, "match_result"))
+    }
+
+    /// Branches to the first block of `(matched, otherwise)` if `value`
+    /// matches `pattern`, and to the second one if not. Adds the values of the
+    /// names the pattern binds to `bindings`. The payload of an option or a
+    /// result is only looked at once its tag matches.
+    fn build_pattern_test<'p>(
+        &mut self,
+        pattern: &'p Pattern,
+        value: IntValue<'ctx>,
+        (matched, otherwise): (BasicBlock<'ctx>, BasicBlock<'ctx>),
+        idx: usize,
+        bindings: &mut Vec<(&'p Name, IntValue<'ctx>)>,
+    ) -> Result<(), CodegenError> {
+        let i64_type = self.context.i64_type();
+        let (predicate, checked, expected) = match pattern {
+            Pattern::At(name, inner, _) => {
+                bindings.push((name, value));
+                return self.build_pattern_test(inner, value, (matched, otherwise), idx, bindings);
+            }
+            Pattern::Wildcard | Pattern::Bind(..) => {
+                if let Pattern::Bind(name, _) = pattern {
+                    bindings.push((name, value));
+                }
+                self.builder.build_unconditional_branch(matched)?;
+                return Ok(());
+            }
+            Pattern::Literal(lit_val) => (
+                IntPredicate::EQ,
+                value,
+                i64_type.const_int(*lit_val as u64, true),
+            ),
+            Pattern::Cmp(op, bound) => {
+                let predicate = match op {
+                    CmpOp::Less => IntPredicate::SLT,
+                    CmpOp::LessEq => IntPredicate::SLE,
+                    CmpOp::Greater => IntPredicate::SGT,
+                    CmpOp::GreaterEq => IntPredicate::SGE,
+                };
+                (predicate, value, i64_type.const_int(*bound as u64, true))
+            }
+            _ => {
+                let expected =
+                    pattern_tag(pattern).ok_or(CodegenError::Internal("pattern without a tag"))?;
+                let tag_slot = self.heap_slot(value, i64_type.const_zero())?;
+                let tag = self
+                    .builder
+                    .build_load(i64_type, tag_slot, "tag")?
+                    .into_int_value();
+                (IntPredicate::EQ, tag, i64_type.const_int(expected, false))
+            }
+        };
+        let matches = self.builder.build_int_compare(
+            predicate,
+            checked,
+            expected,
+            &format!("match_cmp_{}", idx),
+        )?;
+        let Some(inner) = pattern.payload() else {
+            self.builder
+                .build_conditional_branch(matches, matched, otherwise)?;
+            return Ok(());
+        };
+        let payload_block = self.context.prepend_basic_block(matched, "match_payload");
+        self.builder
+            .build_conditional_branch(matches, payload_block, otherwise)?;
+        self.builder.position_at_end(payload_block);
+        let payload_slot = self.heap_slot(value, i64_type.const_int(1, false))?;
+        let payload = self
+            .builder
+            .build_load(i64_type, payload_slot, "payload")?
+            .into_int_value();
+        self.build_pattern_test(inner, payload, (matched, otherwise), idx, bindings)
+    }
+
+    /// Compiles `some value`, `ok value` and `err error` (a new pair on the
+    /// heap: the tag and the payload) and `none` (a constant pair with its
+    /// tag, shared by all of them).
+    fn compile_tagged(
+        &mut self,
+        op: &str,
+        args: &[Expr],
+        span: Option<Span>,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        let i64_type = self.context.i64_type();
+        let pair_type = i64_type.array_type(2);
+        if op == "none" {
+            let none = self.module.get_global("mlia_none").unwrap_or_else(|| {
+                let global = self.module.add_global(pair_type, None, "mlia_none");
+                global.set_linkage(Linkage::Internal);
+                global.set_constant(true);
+                global.set_initializer(&pair_type.const_zero());
+                global
+            });
+            let none = none.as_pointer_value();
+            return Ok(self.builder.build_ptr_to_int(none, i64_type, "none")?);
+        }
+        let (name, tag) = match op {
+            "some" => ("some", TAG_SOME),
+            "ok" => ("ok", TAG_OK),
+            _ => ("err", TAG_ERR),
+        };
+        if self.target == CompileTarget::Wasm32 {
+            return Err(CodegenError::UnsupportedBuiltin { name, span });
+        }
+        let payload = self.compile_expr(&args[0])?;
+        let ptr_type = self.context.ptr_type(inkwell::AddressSpace::default());
+        let malloc_type = ptr_type.fn_type(&[i64_type.into()], false);
+        let malloc = self.libc_function("malloc", malloc_type);
+        let size = i64_type.const_int(16, false);
+        let pair = self
+            .builder
+            .build_call(malloc, &[size.into()], "pair")?
+            .try_as_basic_value()
+            .left()
+            .ok_or(CodegenError::Internal("malloc returns no value"))?;
+        let pair = self
+            .builder
+            .build_ptr_to_int(pair.into_pointer_value(), i64_type, name)?;
+        let tag_slot = self.heap_slot(pair, i64_type.const_zero())?;
+        self.builder
+            .build_store(tag_slot, i64_type.const_int(tag, false))?;
+        let payload_slot = self.heap_slot(pair, i64_type.const_int(1, false))?;
+        self.builder.build_store(payload_slot, payload)?;
+        Ok(pair)
+    }
+
+    /// Compiles `try result`: the value of an `ok`. An `err` is returned from
+    /// the current function at once, like the fuel check does; in `main` the
+    /// program ends with the error in it as its result.
+    fn compile_try(&mut self, result: &Expr) -> Result<IntValue<'ctx>, CodegenError> {
+        let function = self
+            .current_function
+            .ok_or(CodegenError::Internal("no current function for try"))?;
+        let i64_type = self.context.i64_type();
+        let result = self.compile_expr(result)?;
+        let tag_slot = self.heap_slot(result, i64_type.const_zero())?;
+        let tag = self
+            .builder
+            .build_load(i64_type, tag_slot, "tag")?
+            .into_int_value();
+        let is_err = self.builder.build_int_compare(
+            IntPredicate::EQ,
+            tag,
+            i64_type.const_int(TAG_ERR, false),
+            "is_err",
+        )?;
+        let failed = self.context.append_basic_block(function, "try_err");
+        let unwrapped = self.context.append_basic_block(function, "try_ok");
+        self.builder
+            .build_conditional_branch(is_err, failed, unwrapped)?;
+
+        self.builder.position_at_end(failed);
+        if Some(function) == self.module.get_function("main") {
+            let error_slot = self.heap_slot(result, i64_type.const_int(1, false))?;
+            let error = self.builder.build_load(i64_type, error_slot, "error")?;
+            self.report_leaks()?;
+            self.builder.build_return(Some(&error))?;
+        } else {
+            self.builder.build_return(Some(&result))?;
+        }
+
+        self.builder.position_at_end(unwrapped);
+        let value_slot = self.heap_slot(result, i64_type.const_int(1, false))?;
+        Ok(self
+            .builder
+            .build_load(i64_type, value_slot, "value")?
+            .into_int_value())
+    }
+
+    /// Adds the program's `main` function to the module (see `build_main`).
+    /// If verification fails, the module IR is written to `failed_ir_path` so
+    /// that it can be inspected or attached to a bug report.
+    fn emit_main(
+        &mut self,
+        expr: &Expr,
+        failed_ir_path: &Path,
+    ) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let main_function = self.timed(timings::CODEGEN, |this| this.build_main(expr));
+        let main_function = main_function.map_err(|error| match error {
+            CodegenError::VerificationFailed {
+                function, messages, ..
+            } => {
+                let ir_dump = self
+                    .module
+                    .print_to_file(failed_ir_path)
+                    .ok()
+                    .map(|()| failed_ir_path.to_path_buf());
+                CodegenError::VerificationFailed {
+                    function,
+                    messages,
+                    ir_dump,
+                }
+            }
+            other => other,
+        })?;
+        if self.optimize {
+            self.timed(timings::LLVM_OPTIMIZATION, |this| {
+                this.run_optimization_passes()
+            })?;
+        }
+        Ok(main_function)
+    }
+
+    /// Runs the optimization pipeline over the module. mem2reg goes first:
+    /// until the allocas are promoted, every variable read is a load that LICM
+    /// can't hoist. Loops are then put in canonical form (a preheader to hoist
+    /// into), invariant code is moved out and induction variables simplified.
+    fn run_optimization_passes(&self) -> Result<(), CodegenError> {
+        let (_, target_machine) = self.create_target_machine()?;
+        self.module
+            .run_passes(
+                "function(mem2reg,instcombine,loop-simplify,lcssa,loop-mssa(licm),loop(indvars))",
+                &target_machine,
+                PassBuilderOptions::create(),
+            )
+            .map_err(|e| CodegenError::Llvm(format!("optimization failed: {}", e)))
+    }
+
+    /// Compiles `expr` as the body of `main`, returns its result and verifies it.
+    /// Both the JIT and the executable paths go through here, so they always see
+    /// the same module.
+    fn build_main(&mut self, expr: &Expr) -> Result<FunctionValue<'ctx>, CodegenError> {
+        // Start from a fresh module if a program was compiled before
+        if self.module.get_function("main").is_some() {
+            self.reset()?;
+        }
+
+        // Create main function
+        let i64_type = self.context.i64_type();
+        let fn_type = i64_type.fn_type(&[], false);
+        let main_function = self.module.add_function("main", fn_type, None);
+        if self.target == CompileTarget::Wasm32 {
+            // Make main callable by the host
+            main_function.add_attribute(
+                AttributeLoc::Function,
+                self.context
+                    .create_string_attribute("wasm-export-name", "main"),
+            );
+        }
+
+        // Create entry basic block
+        let entry_block = self.context.append_basic_block(main_function, "entry");
+        self.builder.position_at_end(entry_block);
+
+        // Set current function
+        self.current_function = Some(main_function);
+        self.debug_enter_function(main_function, "main");
+
+        // Compile the expression
+        let result = self.compile_expr(expr)?;
+        if self.debug_heap {
+            self.report_leaks()?;
+        }
+
+        // Return the result
+        #[cfg(test)]
+        let skip_return = self.break_main;
+        #[cfg(not(test))]
+        let skip_return = false;
+        if !skip_return {
+            self.builder.build_return(Some(&result))?;
+        }
+        self.finalize_debug_info();
+
+        // Verify the function
+        if self.verify && !main_function.verify(false) {
+            return Err(self.verification_failed("main"));
+        }
+        Ok(main_function)
+    }
+
+    /// Builds the error for a function that failed verification, with the
+    /// verifier's messages for the whole module.
+    fn verification_failed(&self, function: &str) -> CodegenError {
+        let messages = match self.module.verify() {
+            Ok(()) => String::new(),
+            Err(messages) => messages.to_string(),
+        };
+        CodegenError::VerificationFailed {
+            function: function.to_string(),
+            messages,
+            ir_dump: None,
+        }
+    }
+
+    /// The JIT engine for the current module, created the first time it's
+    /// needed.
+    fn jit_engine(&mut self) -> Result<ExecutionEngine<'ctx>, CodegenError> {
+        if let Some(execution_engine) = &self.execution_engine {
+            return Ok(execution_engine.clone());
+        }
+        let execution_engine = self
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|e| CodegenError::Llvm(e.to_string()))?;
+        self.execution_engine = Some(execution_engine.clone());
+        Ok(execution_engine)
+    }
+
+    /// Fails with `DisallowedExternal` for the first function the module
+    /// declares without defining it that is neither in `JIT_EXTERNALS` (or
+    /// `JIT_IO_EXTERNALS`, if `sandbox_io`) nor an LLVM intrinsic, which never
+    /// leave LLVM.
+    fn check_externals(&self) -> Result<(), CodegenError> {
+        for function in self.module.get_functions() {
+            let name = function.get_name().to_string_lossy();
+            let allowed = JIT_EXTERNALS.contains(&name.as_ref())
+                || (self.sandbox_io && JIT_IO_EXTERNALS.contains(&name.as_ref()));
+            if function.count_basic_blocks() == 0 && !name.starts_with("llvm.") && !allowed {
+                return Err(CodegenError::DisallowedExternal(name.into_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a JIT engine was created for the current module.
+    #[cfg(test)]
+    fn has_execution_engine(&self) -> bool {
+        self.execution_engine.is_some()
+    }
+
+    /// Compiles the entire program and returns a JIT-compiled function.
+    pub fn compile_program(
+        &'_ mut self,
+        expr: &Expr,
+    ) -> Result<JitFunction<'_, MainFunc>, CodegenError> {
+        let failed_ir_path = env::temp_dir().join(format!("mlia-{}-failed.ll", process::id()));
+        self.jit = true;
+        let emitted = self.emit_main(expr, &failed_ir_path);
+        self.jit = false;
+        emitted?;
+        if self.sandboxed {
+            self.check_externals()?;
+        }
+        let execution_engine = self.jit_engine()?;
+        if let Some(capture) = self.module.get_function("mlia_capture_i64") {
+            execution_engine
+                .add_global_mapping(&capture, capture_print_i64 as extern "C" fn(i64) as usize);
+        }
+        if let Some(capture) = self.module.get_function("mlia_capture_format") {
+            execution_engine.add_global_mapping(
+                &capture,
+                capture_print_format as extern "C" fn(_, _) as usize,
+            );
+        }
+        if let Some(capture) = self.module.get_function("mlia_capture_char") {
+            execution_engine
+                .add_global_mapping(&capture, capture_print_char as extern "C" fn(i64) as usize);
+        }
+        if let Some(capture) = self.module.get_function("mlia_capture_str") {
+            execution_engine
+                .add_global_mapping(&capture, capture_print_str as extern "C" fn(_) as usize);
+        }
+        // Programs whose output is captured read their input from CAPTURED_INPUT
+        let read_char = if self.module.get_function("mlia_capture_i64").is_some() {
+            capture_getchar as extern "C" fn() -> i32 as usize
+        } else {
+            getchar as unsafe extern "C" fn() -> _ as usize
+        };
+        let libc: [(&str, usize); 12] = [
+            ("malloc", malloc as unsafe extern "C" fn(_) -> _ as usize),
+            ("getchar", read_char),
+            ("strlen", strlen as unsafe extern "C" fn(_) -> _ as usize),
+            ("puts", puts as unsafe extern "C" fn(_) -> _ as usize),
+            ("fopen", fopen as unsafe extern "C" fn(_, _) -> _ as usize),
+            (
+                "fprintf",
+                fprintf as unsafe extern "C" fn(_, _, ...) -> _ as usize,
+            ),
+            (
+                "fscanf",
+                fscanf as unsafe extern "C" fn(_, _, ...) -> _ as usize,
+            ),
+            ("fclose", fclose as unsafe extern "C" fn(_) -> _ as usize),
+            (
+                "realloc",
+                realloc as unsafe extern "C" fn(_, _) -> _ as usize,
+            ),
+            ("free", free as unsafe extern "C" fn(_) as usize),
+            (
+                "dprintf",
+                dprintf as unsafe extern "C" fn(_, _, ...) -> _ as usize,
+            ),
+            ("exit", exit as unsafe extern "C" fn(_) -> ! as usize),
+        ];
+        for (name, address) in libc {
+            if let Some(function) = self.module.get_function(name) {
+                execution_engine.add_global_mapping(&function, address);
+            }
+        }
+        if let Some(getenv_function) = self.module.get_function("getenv") {
+            execution_engine.add_global_mapping(
+                &getenv_function,
+                getenv as unsafe extern "C" fn(_) -> _ as usize,
+            );
+        }
+        if let Some(atoll_function) = self.module.get_function("atoll") {
+            execution_engine.add_global_mapping(
+                &atoll_function,
+                atoll as unsafe extern "C" fn(_) -> _ as usize,
+            );
+        }
+        if let Some(fuel) = self.module.get_global("mlia_fuel") {
+            let fuel_address = FUEL.with(|fuel| fuel.as_ptr() as usize);
+            execution_engine.add_global_mapping(&fuel, fuel_address);
+        }
+        if let Some(failed) = self.module.get_global("mlia_failed") {
+            let failed_address = FAILED.with(|failed| failed.as_ptr() as usize);
+            execution_engine.add_global_mapping(&failed, failed_address);
+        }
+        if let Some(error) = self.module.get_function("mlia_runtime_error") {
+            execution_engine
+                .add_global_mapping(&error, runtime_error as extern "C" fn(_, _) as usize);
+        }
+
+        // Get the compiled function
+        unsafe {
+            execution_engine
+                .get_function("main")
+                .map_err(|e| CodegenError::Llvm(format!("failed to get main function: {}", e)))
+        }
+    }
+
+    /// Creates the target machine for `self.target`, along with its triple,
+    /// initializing the LLVM target first.
+    fn create_target_machine(&self) -> Result<(TargetTriple, TargetMachine), CodegenError> {
+        // Initialize LLVM targets
+        match self.target {
+            CompileTarget::Native => Target::initialize_native(&InitializationConfig::default())
+                .map_err(CodegenError::Emit)?,
+            CompileTarget::Wasm32 => {
+                Target::initialize_webassembly(&InitializationConfig::default())
+            }
+        }
+
+        let target_triple = self.target.triple();
+        let target = Target::from_triple(&target_triple).map_err(|e| {
+            CodegenError::Emit(format!("failed to create target from triple: {}", e))
+        })?;
+
+        // Create target machine. Native objects are emitted as position-independent
+        // code because most Linux distributions make gcc produce PIE executables by
+        // default, and non-PIC relocations (R_X86_64_32) can't be linked into them.
+        let reloc_mode = match self.target {
+            CompileTarget::Native => RelocMode::PIC,
+            CompileTarget::Wasm32 => RelocMode::Default,
+        };
+        let target_machine = target
+            .create_target_machine(
+                &target_triple,
+                "generic",
+                "",
+                OptimizationLevel::None,
+                reloc_mode,
+                CodeModel::Default,
+            )
+            .ok_or_else(|| CodegenError::Emit("failed to create target machine".to_string()))?;
+        Ok((target_triple, target_machine))
+    }
+
+    /// Compiles the program and writes the files selected with `set_emit`: by
+    /// default, an object file that is linked into an executable. Returns the
+    /// files written besides the executable; an output left unlinked (see
+    /// `unlinked_output`) is one of them.
+    pub fn compile_to_executable(
+        &mut self,
+        expr: &Expr,
+        output_path: &str,
+    ) -> Result<Vec<PathBuf>, CodegenError> {
+        self.unlinked_output = None;
+        let failed_ir_path = PathBuf::from(format!("{}_failed.ll", output_path));
+        self.emit_main(expr, &failed_ir_path)?;
+
+        let (_, target_machine) = self.create_target_machine()?;
+
+        let mut written = Vec::new();
+        if self.emit.ir {
+            let ir_path = PathBuf::from(format!("{}.ll", output_path));
+            self.timed(timings::EMISSION, |this| {
+                this.module.print_to_file(&ir_path)
+            })
+            .map_err(|e| CodegenError::Emit(format!("failed to write IR: {}", e)))?;
+            written.push(ir_path);
+        }
+        if self.emit.asm {
+            let asm_path = PathBuf::from(format!("{}.s", output_path));
+            self.timed(timings::EMISSION, |this| {
+                target_machine.write_to_file(&this.module, FileType::Assembly, &asm_path)
+            })
+            .map_err(|e| CodegenError::Emit(format!("failed to write assembly: {}", e)))?;
+            written.push(asm_path);
+        }
+        if !self.emit.obj && !self.emit.exe {
+            return Ok(written);
+        }
+
+        // Generate the object file in a per-invocation scratch directory so that
+        // concurrent builds can't clobber each other's intermediates and no user
+        // file next to the output gets overwritten. When it was asked for it
+        // goes next to the output instead, so it survives a failed link.
+        let scratch;
+        let obj_path = if self.emit.obj {
+            PathBuf::from(format!("{}.o", output_path))
+        } else {
+            scratch = ScratchDir::new().map_err(|e| {
+                CodegenError::Emit(format!("failed to create temporary directory: {}", e))
+            })?;
+            let obj_name = Path::new(output_path)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("out");
+            scratch.path().join(format!("{}.o", obj_name))
+        };
+        self.timed(timings::EMISSION, |this| {
+            target_machine.write_to_file(&this.module, FileType::Object, &obj_path)
+        })
+        .map_err(|e| CodegenError::Emit(format!("failed to write object file: {}", e)))?;
+        if self.emit.obj {
+            written.push(obj_path.clone());
+        }
+        if !self.emit.exe {
+            return Ok(written);
+        }
+
+        // Link the object file to create an executable
+        let link_start = self.timings.is_some().then(Instant::now);
+        let link_result = match self.target {
+            CompileTarget::Native => std::process::Command::new(LINKER)
+                .arg(&obj_path)
+                .args(["-o", output_path])
+                .output(),
+            CompileTarget::Wasm32 => {
+                let linked = std::process::Command::new("wasm-ld")
+                    .args(["--no-entry", "--export=main", "--allow-undefined"])
+                    .arg(&obj_path)
+                    .args(["-o", output_path])
+                    .output();
+                match linked {
+                    // Without wasm-ld the object file is the output: it is a valid
+                    // module that imports its memory and stack pointer from `env`.
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        fs::copy(&obj_path, output_path).map_err(|e| {
+                            CodegenError::Emit(format!("failed to write output: {}", e))
+                        })?;
+                        if let (Some(recorded), Some(start)) = (&mut self.timings, link_start) {
+                            recorded.record(timings::LINKING, start.elapsed());
+                        }
+                        written.push(PathBuf::from(output_path));
+                        self.unlinked_output = Some(PathBuf::from(output_path));
+                        return Ok(written);
+                    }
+                    linked => linked,
+                }
+            }
+        }
+        .map_err(|e| CodegenError::Emit(format!("failed to run linker: {}", e)))?;
+
+        if let (Some(recorded), Some(start)) = (&mut self.timings, link_start) {
+            recorded.record(timings::LINKING, start.elapsed());
+        }
+        if !link_result.status.success() {
+            return Err(CodegenError::Emit(format!(
+                "linking failed: {}",
+                String::from_utf8_lossy(&link_result.stderr)
+            )));
+        }
+
+        // On macOS the linker leaves the DWARF in the object file and only records
+        // its path in the executable, so it has to be collected into a .dSYM bundle
+        // before the object goes away with the scratch directory.
+        if cfg!(target_os = "macos")
+            && self.target == CompileTarget::Native
+            && self.debug_info.is_some()
+        {
+            let dsym = std::process::Command::new("dsymutil")
+                .arg(output_path)
+                .output()
+                .map_err(|e| CodegenError::Emit(format!("failed to run dsymutil: {}", e)))?;
+            if !dsym.status.success() {
+                return Err(CodegenError::Emit(format!(
+                    "dsymutil failed: {}",
+                    String::from_utf8_lossy(&dsym.stderr)
+                )));
+            }
+        }
+
+        // Unless the object file was asked for, the scratch directory (and the
+        // object file in it) is removed when `scratch` goes out of scope, on
+        // success and on every error path above.
+        Ok(written)
+    }
+
+    /// Executes the compiled program and returns the exit code.
+    pub fn execute_program(&mut self, expr: &Expr) -> Result<i64, CodegenError> {
+        Ok(self.run(expr)?.result)
+    }
+
+    /// Compiles the program with the JIT and runs it, like `execute_program`,
+    /// timing each step and measuring the IR, for tools and for profiling.
+    pub fn run(&mut self, expr: &Expr) -> Result<ExecutionReport, CodegenError> {
+        let start = Instant::now();
+        let program = self.compile_to_callable(expr)?;
+        let compile_time = start.elapsed();
+
+        let start = Instant::now();
+        let result = program.run();
+        let run_time = start.elapsed();
+        // The program borrows the module through the execution engine
+        drop(program);
+        let result = result?;
+
+        Ok(ExecutionReport {
+            result,
+            compile_time,
+            run_time,
+            ir_size_bytes: self.module.print_to_string().to_bytes().len(),
+        })
+    }
+
+    /// Compiles the program with the JIT and returns it ready to be run, as
+    /// many times as needed, without compiling it again.
+    pub fn compile_to_callable(
+        &mut self,
+        expr: &Expr,
+    ) -> Result<CompiledProgram<'_>, CodegenError> {
+        Ok(CompiledProgram {
+            main: self.compile_program(expr)?,
+        })
+    }
+
+    /// Like `execute_program`, but stops the program once it has run for
+    /// `max_iterations` loop iterations and function calls in total, and
+    /// reports `ExecutionBudgetExceeded` instead of its result.
+    pub fn execute_with_limit(
+        &mut self,
+        expr: &Expr,
+        max_iterations: u64,
+    ) -> Result<i64, CodegenError> {
+        // As with captured output, only this program is instrumented
+        self.fuel_limited = true;
+        let reset = self.reset();
+        self.fuel_limited = false;
+        reset?;
+
+        FUEL.with(|fuel| fuel.set(i64::try_from(max_iterations).unwrap_or(i64::MAX)));
+        let result = self.execute_program(expr)?;
+        if FUEL.with(Cell::get) < 0 {
+            return Err(CodegenError::ExecutionBudgetExceeded {
+                limit: max_iterations,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Like `execute_program`, but what the program prints is collected
+    /// instead of written to stdout, and returned along with its result.
+    /// This is how the tests check a program's output.
+    pub fn execute_program_captured(&mut self, expr: &Expr) -> Result<(i64, String), CodegenError> {
+        self.execute_captured(expr, "", None)
+    }
+
+    /// Like `execute_program_captured`, with `input` as what `read_line`
+    /// reads, and with an execution budget of `max_iterations` like
+    /// `execute_with_limit` if there is one. This is how `mlia test` runs
+    /// programs.
+    pub fn execute_captured(
+        &mut self,
+        expr: &Expr,
+        input: &str,
+        max_iterations: Option<u64>,
+    ) -> Result<(i64, String), CodegenError> {
+        // The print runtime function differs, so this needs a module of its own.
+        // Only this one gets it: the next program compiled resets to the usual one.
+        self.capture_output = true;
+        self.fuel_limited = max_iterations.is_some();
+        let reset = self.reset();
+        self.capture_output = false;
+        self.fuel_limited = false;
+        reset?;
+        let main_func = self.compile_program(expr)?;
+
+        CAPTURED_OUTPUT.with(|output| output.borrow_mut().clear());
+        CAPTURED_INPUT.with(|captured| *captured.borrow_mut() = input.bytes().collect());
+        if let Some(limit) = max_iterations {
+            FUEL.with(|fuel| fuel.set(i64::try_from(limit).unwrap_or(i64::MAX)));
+        }
+        clear_runtime_error();
+        let result = unsafe { main_func.call() };
+        let output = CAPTURED_OUTPUT.with(|output| output.take());
+        CAPTURED_INPUT.with(|captured| captured.borrow_mut().clear());
+        take_runtime_error()?;
+        if let Some(limit) = max_iterations
+            && FUEL.with(Cell::get) < 0
+        {
+            return Err(CodegenError::ExecutionBudgetExceeded { limit });
+        }
+        Ok((result, output))
+    }
+
+    /// Get the LLVM IR as a string. The compiler itself streams the IR with
+    /// `write_ir`, since the IR of big programs is large.
+    pub fn get_ir_string(&self) -> String {
+        self.module.print_to_string().to_string()
+    }
+
+    /// Writes the LLVM IR to `out` without holding all of it in memory: LLVM
+    /// prints it to a temporary file, which is then copied over in chunks (the
+    /// IR of big programs is large).
+    pub fn write_ir(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        let scratch = ScratchDir::new()?;
+        let ir_path = scratch.path().join("module.ll");
+        self.module
+            .print_to_file(&ir_path)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        std::io::copy(&mut fs::File::open(&ir_path)?, out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Expr;
+
+    #[test]
+    fn test_simple_number() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        let expr = Expr::Number(42);
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 42);
+    }
+
+    #[test]
+    fn test_variable_declaration() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // decl x <- 5 in x
+        let expr = Expr::Decl(
+            "x".into(),
+            vec![],
+            Box::new(Expr::Number(5)),
+            Box::new(Expr::Ident("x".into(), Span::default())),
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 5);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // 1; 2
+        let expr = Expr::Block(vec![Expr::Number(1), Expr::Number(2)]);
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 2);
+    }
+
+    // T011: Test addition operator (US1)
+    #[test]
+    fn test_addition() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // + 5 3 should equal 8
+        let expr = Expr::Call(
+            "+".into(),
+            vec![Expr::Number(5), Expr::Number(3)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 8, "5 + 3 should equal 8");
+    }
+
+    // T012: Test subtraction operator (US1)
+    #[test]
+    fn test_subtraction() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // - 10 4 should equal 6
+        let expr = Expr::Call(
+            "-".into(),
+            vec![Expr::Number(10), Expr::Number(4)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 6, "10 - 4 should equal 6");
+    }
+
+    // T013: Test multiplication operator (US1)
+    #[test]
+    fn test_multiplication() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // * 6 7 should equal 42
+        let expr = Expr::Call(
+            "*".into(),
+            vec![Expr::Number(6), Expr::Number(7)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 42, "6 * 7 should equal 42");
+    }
+
+    // T014: Test division operator (US1)
+    #[test]
+    fn test_division() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // / 17 5 should equal 3 (integer division)
+        let expr = Expr::Call(
+            "/".into(),
+            vec![Expr::Number(17), Expr::Number(5)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 3, "17 / 5 should equal 3 (integer division)");
+    }
+
+    // T015: Test modulo operator (US1)
+    #[test]
+    fn test_modulo() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // % 17 5 should equal 2
+        let expr = Expr::Call(
+            "%".into(),
+            vec![Expr::Number(17), Expr::Number(5)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 2, "17 % 5 should equal 2");
+    }
+
+    #[test]
+    fn test_euclidean_modulo() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut emod = |a: i64, b: i64| {
+            // Through variables, so that nothing is folded
+            let source = format!("decl a <- {} in decl b <- {} in emod a b", a, b);
+            let ast = crate::parser::parse_program(source).unwrap();
+            codegen.execute_program(&ast).unwrap()
+        };
+
+        assert_eq!(emod(-7, 3), 2);
+        assert_eq!(emod(7, -3), 1);
+        assert_eq!(emod(-7, -3), 2);
+        assert_eq!(emod(7, 3), 1);
+        assert_eq!(emod(-6, 3), 0);
+        for (a, b) in [(-7, 3), (7, -3), (-7, -3), (13, 5), (-13, -5)] {
+            assert_eq!(emod(a, b), a.rem_euclid(b), "emod {} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_floor_division() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut fdiv = |a: i64, b: i64| {
+            let source = format!("decl a <- {} in decl b <- {} in fdiv a b", a, b);
+            let ast = crate::parser::parse_program(source).unwrap();
+            codegen.execute_program(&ast).unwrap()
+        };
+
+        assert_eq!(fdiv(7, 2), 3);
+        assert_eq!(fdiv(-7, 2), -4);
+        assert_eq!(fdiv(7, -2), -4);
+        assert_eq!(fdiv(-7, -2), 3);
+        // Exact divisions need no adjustment
+        assert_eq!(fdiv(-8, 2), -4);
+        assert_eq!(fdiv(8, -2), -4);
+        assert_eq!(fdiv(0, -3), 0);
+        // The quotient that doesn't fit wraps around, like `- 0 i64::MIN`
+        assert_eq!(fdiv(i64::MIN, -1), i64::MIN);
+        assert_eq!(fdiv(i64::MIN + 1, -1), i64::MAX);
+        assert_eq!(fdiv(i64::MIN, 1), i64::MIN);
+    }
+
+    #[test]
+    fn test_sum_and_product() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        // Through variables, so that nothing is folded
+        let mut run = |body: &str| {
+            let source = format!("decl a <- 2 in decl b <- 3 in decl c <- -4 in {}", body);
+            let ast = crate::parser::parse_program(source).unwrap();
+            codegen.execute_program(&ast).unwrap()
+        };
+
+        assert_eq!(run("(sum)"), 0);
+        assert_eq!(run("(product)"), 1);
+        assert_eq!(run("(sum c)"), -4);
+        assert_eq!(run("(product c)"), -4);
+        assert_eq!(run("(sum a b)"), 5);
+        assert_eq!(run("(product a b)"), 6);
+        assert_eq!(run("(sum a b c a b c)"), 2);
+        assert_eq!(run("(product a b c a b c)"), 576);
+        assert_eq!(run("(sum 1 (product a b) 4)"), 11);
+        // Arguments are evaluated left to right
+        assert_eq!(run("(sum (a <- 10) (product a b))"), 40);
+    }
+
+    // T016: Test negative number handling (US1)
+    #[test]
+    fn test_negative_numbers() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // + (-5) 3 should equal -2
+        let expr = Expr::Call(
+            "+".into(),
+            vec![Expr::Number(-5), Expr::Number(3)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, -2, "-5 + 3 should equal -2");
+    }
+
+    // T020: Test less than operator - true case (US2)
+    #[test]
+    fn test_less_than_true() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // < 5 10 should equal 1 (true)
+        let expr = Expr::Call(
+            "<".into(),
+            vec![Expr::Number(5), Expr::Number(10)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 1, "5 < 10 should be true (1)");
+    }
+
+    // T021: Test less than operator - false case (US2)
+    #[test]
+    fn test_less_than_false() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // < 10 5 should equal 0 (false)
+        let expr = Expr::Call(
+            "<".into(),
+            vec![Expr::Number(10), Expr::Number(5)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 0, "10 < 5 should be false (0)");
+    }
+
+    // T022: Test greater than operator (US2)
+    #[test]
+    fn test_greater_than() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // > 10 5 should equal 1 (true)
+        let expr = Expr::Call(
+            ">".into(),
+            vec![Expr::Number(10), Expr::Number(5)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 1, "10 > 5 should be true (1)");
+    }
+
+    // T023: Test equality operator - true case (US2)
+    #[test]
+    fn test_equality_true() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // = 7 7 should equal 1 (true)
+        let expr = Expr::Call(
+            "=".into(),
+            vec![Expr::Number(7), Expr::Number(7)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 1, "7 = 7 should be true (1)");
+    }
+
+    // T024: Test equality operator - false case (US2)
+    #[test]
+    fn test_equality_false() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // = 5 10 should equal 0 (false)
+        let expr = Expr::Call(
+            "=".into(),
+            vec![Expr::Number(5), Expr::Number(10)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 0, "5 = 10 should be false (0)");
+    }
+
+    // T025: Test not equal operator (US2)
+    #[test]
+    fn test_not_equal() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // != 5 10 should equal 1 (true)
+        let expr = Expr::Call(
+            "!=".into(),
+            vec![Expr::Number(5), Expr::Number(10)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 1, "5 != 10 should be true (1)");
+    }
+
+    #[test]
+    fn test_booleans_used_as_numbers() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast).unwrap()
+        };
+
+        assert_eq!(run("decl x <- 2 in + (< x 3) (> x 3)"), 1);
+        assert_eq!(run("decl x <- 2 in * 10 (& x (! (= x 0)))"), 10);
+        assert_eq!(run("decl x <- 2 in - 0 (| (= x 5) (!= x 2))"), 0);
+        // Any non-zero operand of a logical operator is true
+        assert_eq!(run("decl x <- 7 in + (& x (- 0 3)) (! x)"), 1);
+        assert_eq!(run("decl x <- (< 1 2) in + x x"), 2);
+    }
+
+    #[test]
+    fn test_conditions_stay_i1() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        let source = "decl i <- 0 in while & (< i 10) (! (= i 5)) do i <- + i 1 done; i";
+        let ast = crate::parser::parse_program(source.to_string()).unwrap();
+        codegen.compile_program(&ast).unwrap();
+
+        // The loop branches on the comparisons directly, without extending
+        // them to i64 and comparing that with 0
+        let ir = codegen.get_ir_string();
+        assert!(!ir.contains("zext"), "IR: {}", ir);
+        assert!(!ir.contains("icmp ne i64"), "IR: {}", ir);
+        assert!(ir.contains("and i1"), "IR: {}", ir);
+        assert_eq!(codegen.execute_program(&ast).unwrap(), 5);
+    }
+
+    // T030: Test while loop countdown (US3)
+    #[test]
+    fn test_while_loop_countdown() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // decl x <- 3 in while x do x <- - x 1 done
+        // Should loop 3 times, decrementing x each time
+        let expr = Expr::Decl(
+            "x".into(),
+            vec![],
+            Box::new(Expr::Number(3)),
+            Box::new(Expr::While(
+                Box::new(Expr::Ident("x".into(), Span::default())),
+                Box::new(Expr::Assign(
+                    "x".into(),
+                    Box::new(Expr::Call(
+                        "-".into(),
+                        vec![Expr::Ident("x".into(), Span::default()), Expr::Number(1)],
+                        Span::default(),
+                    )),
+                    Span::default(),
+                )),
+                Span::default(),
+            )),
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(
+            result, 0,
+            "While loop should return 0 when condition becomes false"
+        );
+    }
+
+    // T031: Test while loop with zero iterations (US3)
+    #[test]
+    fn test_while_loop_zero_iterations() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // while 0 do 42 done
+        // Should not execute body at all
+        let expr = Expr::While(
+            Box::new(Expr::Number(0)),
+            Box::new(Expr::Number(42)),
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(
+            result, 0,
+            "While loop with false condition should return 0 immediately"
+        );
+    }
+
+    #[test]
+    fn test_while_yield_evaluates_to_last_body_value() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast).unwrap()
+        };
+
+        assert_eq!(
+            run("decl i <- 0 in decl r <- while < i 4 do i <- + i 1; * i 10 yield done in r"),
+            40
+        );
+        // The body never runs
+        assert_eq!(run("while 0 do 42 yield done"), 0);
+        // An inner loop starts again from 0 each time it is entered
+        assert_eq!(
+            run("decl i <- 2 in decl total <- 0 in \
+                 while i do \
+                   total <- + total (while (- i 1) do i <- - i 1; 7 yield done); \
+                   i <- - i 1 \
+                 done; total"),
+            7
+        );
+        // Without yield the loop is still 0
+        assert_eq!(run("decl i <- 3 in while i do i <- - i 1; 5 done"), 0);
+    }
+
+    #[test]
+    fn test_loop_evaluates_to_break_value() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
+
+        // On the first iteration, and without a value
+        assert_eq!(run("loop break 7 end"), Ok(7));
+        assert_eq!(run("+ 1 loop break end"), Ok(1));
+        // After several iterations, from a match arm
+        assert_eq!(
+            run("decl i <- 0 in decl acc <- 0 in \
+                 loop \
+                   i <- + i 1; \
+                   match i with | 5 -> break acc | _ -> acc <- + acc i \
+                 end"),
+            Ok(10)
+        );
+        // A break leaves the innermost loop only
+        assert_eq!(
+            run("decl n <- 0 in \
+                 decl r <- loop n <- + n 1; loop break 100 end; match n with | 3 -> break n | _ -> 0 end in \
+                 + r (loop break 1; 2 end)"),
+            Ok(4)
+        );
+        assert_eq!(
+            run("while 1 do break done").unwrap_err().to_string(),
+            "'break' outside of a loop at line 1, column 12"
+        );
+    }
+
+    // T032: Test while loop with accumulator (US3)
+    #[test]
+    fn test_while_loop_accumulator() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // decl sum <- 0 in decl i <- 5 in
+        // while i do (print (sum <- + sum i); i <- - i 1) done; sum
+        let expr = Expr::Decl(
+            "sum".into(),
+            vec![],
+            Box::new(Expr::Number(0)),
+            Box::new(Expr::Decl(
+                "i".into(),
+                vec![],
+                Box::new(Expr::Number(5)),
+                Box::new(Expr::Block(vec![
+                    Expr::While(
+                        Box::new(Expr::Ident("i".into(), Span::default())),
+                        Box::new(Expr::Block(vec![
+                            Expr::Call(
+                                "print".into(),
+                                vec![Expr::Assign(
+                                    "sum".into(),
+                                    Box::new(Expr::Call(
+                                        "+".into(),
+                                        vec![
+                                            Expr::Ident("sum".into(), Span::default()),
+                                            Expr::Ident("i".into(), Span::default()),
+                                        ],
+                                        Span::default(),
+                                    )),
+                                    Span::default(),
+                                )],
+                                Span::default(),
+                            ),
+                            Expr::Assign(
+                                "i".into(),
+                                Box::new(Expr::Call(
+                                    "-".into(),
+                                    vec![Expr::Ident("i".into(), Span::default()), Expr::Number(1)],
+                                    Span::default(),
+                                )),
+                                Span::default(),
+                            ),
+                        ])),
+                        Span::default(),
+                    ),
+                    Expr::Ident("sum".into(), Span::default()),
+                ])),
+                Span::default(),
+            )),
+            Span::default(),
+        );
+
+        let (result, output) = codegen.execute_program_captured(&expr).unwrap();
+        assert_eq!(result, 15, "Sum of 5+4+3+2+1 should be 15");
+        assert_eq!(output, "5\n9\n12\n14\n15\n");
+    }
+
+    // T033: Test nested while loops (US3)
+    #[test]
+    fn test_nested_while_loops() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // decl outer <- 2 in while outer do (
+        //   decl inner <- 2 in while inner do inner <- - inner 1 done;
+        //   outer <- - outer 1
+        // ) done
+        let expr = Expr::Decl(
+            "outer".into(),
+            vec![],
+            Box::new(Expr::Number(2)),
+            Box::new(Expr::While(
+                Box::new(Expr::Ident("outer".into(), Span::default())),
+                Box::new(Expr::Block(vec![
+                    Expr::Decl(
+                        "inner".into(),
+                        vec![],
+                        Box::new(Expr::Number(2)),
+                        Box::new(Expr::While(
+                            Box::new(Expr::Ident("inner".into(), Span::default())),
+                            Box::new(Expr::Assign(
+                                "inner".into(),
+                                Box::new(Expr::Call(
+                                    "-".into(),
+                                    vec![
+                                        Expr::Ident("inner".into(), Span::default()),
+                                        Expr::Number(1),
+                                    ],
+                                    Span::default(),
+                                )),
+                                Span::default(),
+                            )),
+                            Span::default(),
+                        )),
+                        Span::default(),
+                    ),
+                    Expr::Assign(
+                        "outer".into(),
+                        Box::new(Expr::Call(
+                            "-".into(),
+                            vec![
+                                Expr::Ident("outer".into(), Span::default()),
+                                Expr::Number(1),
+                            ],
+                            Span::default(),
+                        )),
+                        Span::default(),
+                    ),
+                ])),
+                Span::default(),
+            )),
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 0, "Nested while loops should complete successfully");
+    }
+
+    // T038: Test match expression - first pattern matches (US4)
+    #[test]
+    fn test_match_first_pattern() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // match 1 with | 1 -> 100 | 2 -> 200 | _ -> 300
+        let expr = Expr::Match(
+            Box::new(Expr::Number(1)),
+            vec![
+                (Pattern::Literal(1), Expr::Number(100)),
+                (Pattern::Literal(2), Expr::Number(200)),
+                (Pattern::Wildcard, Expr::Number(300)),
+            ],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 100, "match 1 should return 100");
+    }
+
+    // T039: Test match expression - second pattern matches (US4)
+    #[test]
+    fn test_match_second_pattern() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // match 2 with | 1 -> 100 | 2 -> 200 | _ -> 300
+        let expr = Expr::Match(
+            Box::new(Expr::Number(2)),
+            vec![
+                (Pattern::Literal(1), Expr::Number(100)),
+                (Pattern::Literal(2), Expr::Number(200)),
+                (Pattern::Wildcard, Expr::Number(300)),
+            ],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 200, "match 2 should return 200");
+    }
+
+    // T040: Test match expression - wildcard matches (US4)
+    #[test]
+    fn test_match_wildcard() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // match 5 with | 1 -> 100 | 2 -> 200 | _ -> 300
+        let expr = Expr::Match(
+            Box::new(Expr::Number(5)),
+            vec![
+                (Pattern::Literal(1), Expr::Number(100)),
+                (Pattern::Literal(2), Expr::Number(200)),
+                (Pattern::Wildcard, Expr::Number(300)),
+            ],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 300, "match 5 should return 300 (wildcard)");
+    }
+
+    // T041: Test match expression with computed result expressions (US4)
+    #[test]
+    fn test_match_computed_results() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // match 1 with | 1 -> (+ 10 20) | _ -> 0
+        let expr = Expr::Match(
+            Box::new(Expr::Number(1)),
+            vec![
+                (
+                    Pattern::Literal(1),
+                    Expr::Call(
+                        "+".into(),
+                        vec![Expr::Number(10), Expr::Number(20)],
+                        Span::default(),
+                    ),
+                ),
+                (Pattern::Wildcard, Expr::Number(0)),
+            ],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 30, "match 1 should compute 10 + 20 = 30");
+    }
+
+    // T042: Test match as subexpression (US4)
+    #[test]
+    fn test_match_as_subexpression() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // + (match 2 with | 1 -> 10 | 2 -> 20 | _ -> 30) 5
+        let match_expr = Expr::Match(
+            Box::new(Expr::Number(2)),
+            vec![
+                (Pattern::Literal(1), Expr::Number(10)),
+                (Pattern::Literal(2), Expr::Number(20)),
+                (Pattern::Wildcard, Expr::Number(30)),
+            ],
+            Span::default(),
+        );
+
+        let expr = Expr::Call(
+            "+".into(),
+            vec![match_expr, Expr::Number(5)],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 25, "20 + 5 should equal 25");
+    }
+
+    // T043: Test match with variable in scrutinee (US4)
+    #[test]
+    fn test_match_with_variable() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // (defvar x 2 in (match x with | 1 -> print 100 | 2 -> print 200 | _ -> print 300))
+        let expr = Expr::Decl(
+            "x".into(),
+            vec![],
+            Box::new(Expr::Number(2)),
+            Box::new(Expr::Match(
+                Box::new(Expr::Ident("x".into(), Span::default())),
+                [
+                    (Pattern::Literal(1), 100),
+                    (Pattern::Literal(2), 200),
+                    (Pattern::Wildcard, 300),
+                ]
+                .into_iter()
+                .map(|(pattern, n)| {
+                    let print = Expr::Call("print".into(), vec![Expr::Number(n)], Span::default());
+                    (pattern, print)
+                })
+                .collect(),
+                Span::default(),
+            )),
+            Span::default(),
+        );
+
+        let (result, output) = codegen.execute_program_captured(&expr).unwrap();
+        assert_eq!(result, 200, "match x (where x=2) should return 200");
+        assert_eq!(output, "200\n", "Only the matching arm prints");
+    }
+
+    // T044: Test match without wildcard but with all cases covered (US4)
+    #[test]
+    fn test_match_explicit_patterns() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+
+        // match 0 with | 0 -> 42 | _ -> 0
+        let expr = Expr::Match(
+            Box::new(Expr::Number(0)),
+            vec![
+                (Pattern::Literal(0), Expr::Number(42)),
+                (Pattern::Wildcard, Expr::Number(0)),
+            ],
+            Span::default(),
+        );
+
+        let result = codegen.execute_program(&expr).unwrap();
+        assert_eq!(result, 42, "match 0 should return 42");
+    }
+
+    #[test]
+    fn test_at_bindings() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
+
+        let classify = |value: i64| {
+            format!(
+                "decl special n <- * n 2 in decl normal n <- + n 1 in\n\
+                 match {value} with | 0 -> 0 | n @ 100 -> (special n) | n -> (normal n)"
+            )
+        };
+        assert_eq!(run(&classify(0)), Ok(0));
+        assert_eq!(run(&classify(100)), Ok(200));
+        assert_eq!(run(&classify(7)), Ok(8));
+        // Over the payload of an option, and over a whole option
+        assert_eq!(
+            run("match some 4 with | some (n @ 4) -> + n 1 | _ -> 0"),
+            Ok(5)
+        );
+        assert_eq!(
+            run("match some 4 with | o @ some n -> + n 1 | _ -> 0"),
+            Ok(5)
+        );
+        // A name for a wildcard matches anything
+        assert_eq!(run("match 9 with | 1 -> 0 | n @ _ -> n"), Ok(9));
+    }
+
+    #[test]
+    fn test_relational_patterns() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
+
+        let bucket = |score: i64| {
+            format!("decl score <- {score} in match score with | < 60 -> 0 | < 80 -> 1 | _ -> 2")
+        };
+        assert_eq!(run(&bucket(59)), Ok(0));
+        assert_eq!(run(&bucket(60)), Ok(1));
+        assert_eq!(run(&bucket(79)), Ok(1));
+        assert_eq!(run(&bucket(80)), Ok(2));
+
+        // With literals, the first arm that matches wins
+        let mixed = |x: i64| {
+            format!(
+                "decl x <- {x} in match x with \
+                 | 0 -> 10 | <= -1 -> 20 | 100 -> 30 | >= 100 -> 40 | > 50 -> 50 | _ -> 60"
+            )
+        };
+        assert_eq!(run(&mixed(0)), Ok(10));
+        assert_eq!(run(&mixed(-5)), Ok(20));
+        assert_eq!(run(&mixed(100)), Ok(30));
+        assert_eq!(run(&mixed(101)), Ok(40));
+        assert_eq!(run(&mixed(51)), Ok(50));
+        assert_eq!(run(&mixed(50)), Ok(60));
+
+        // Comparisons that take in every int need no wildcard
+        assert_eq!(
+            run("decl x <- 3 in match x with | < 0 -> 0 | >= 0 -> 1"),
+            Ok(1)
+        );
+        assert_eq!(
+            run("decl x <- 3 in match x with | < 0 -> 0 | > 0 -> 1")
+                .unwrap_err()
+                .to_string(),
+            "match expression doesn't cover every value: value 0 is not handled; \
+             add '| _ ->' to handle the rest at line 1, column 16"
+        );
+        // In payloads
+        assert_eq!(run("match some 3 with | some (> 2) -> 1 | _ -> 0"), Ok(1));
     }
 
-    /// Compiles the program to an object file and creates an executable.
-    pub fn compile_to_executable(
-        &mut self,
-        expr: &Expr,
-        output_path: &str,
-    ) -> Result<(), Box<dyn Error>> {
-        // Initialize LLVM targets
-        Target::initialize_native(&InitializationConfig::default())?;
-
-        // Create main function
-        let i64_type = self.context.i64_type();
-        let fn_type = i64_type.fn_type(&[], false);
-        let main_function = self.module.add_function("main", fn_type, None);
+    #[test]
+    fn test_match_on_a_comparison_needs_no_wildcard() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
 
-        // Create entry basic block
-        let entry_block = self.context.append_basic_block(main_function, "entry");
-        self.builder.position_at_end(entry_block);
+        let bucket = |x: i64| format!("decl x <- {x} in match (< x 10) with | 0 -> 20 | 1 -> 10");
+        assert_eq!(run(&bucket(3)), Ok(10));
+        assert_eq!(run(&bucket(30)), Ok(20));
+        assert_eq!(run("match (! 0) with | 1 -> 1 | 0 -> 0"), Ok(1));
+        assert_eq!(
+            run("decl x <- 3 in match (< x 10) with | 0 -> 20")
+                .unwrap_err()
+                .to_string(),
+            "match expression doesn't cover every value: missing case: 1; \
+             add '| _ ->' to handle the rest at line 1, column 16"
+        );
+        // Other scrutinees can be any int
+        assert_eq!(
+            run("decl x <- 3 in match (+ x 10) with | 0 -> 20 | 1 -> 10")
+                .unwrap_err()
+                .to_string(),
+            "match expression doesn't cover every value: value 2 is not handled \
+             (the arms cover 0, 1); add '| _ ->' to handle the rest at line 1, column 16"
+        );
+    }
 
-        // Set current function
-        self.current_function = Some(main_function);
+    #[test]
+    fn test_undefined_variable_error_names_variable() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
 
-        // Compile the expression
-        let result = self.compile_expr(expr)?;
+        let span = Span {
+            line: 2,
+            column: 7,
+            len: 5,
+        };
+        let expr = Expr::Call(
+            "+".into(),
+            vec![Expr::Number(1), Expr::Ident("ghost".into(), span)],
+            Span::default(),
+        );
+        let err = codegen.execute_program(&expr).unwrap_err();
 
-        // Return the result
-        self.builder.build_return(Some(&result)).unwrap();
+        assert_eq!(
+            err,
+            CodegenError::UndefinedVariable {
+                name: "ghost".to_string(),
+                span: Some(span),
+                expr: Some("+ 1 ghost".to_string()),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "undefined variable 'ghost' at line 2, column 7, in `+ 1 ghost`"
+        );
 
-        // Verify the function
-        if !main_function.verify(true) {
-            return Err("Function verification failed".into());
-        }
+        // Only the start of a long expression
+        let mut args = vec![Expr::Ident("ghost".into(), span)];
+        args.extend((1..=30).map(Expr::Number));
+        let expr = Expr::Call("sum".into(), args, Span::default());
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let err = codegen.execute_program(&expr).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "undefined variable 'ghost' at line 2, column 7, \
+             in `(sum ghost 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 1...`"
+        );
+    }
 
-        // Get the target triple
-        let target_triple = TargetMachine::get_default_triple();
-        let target = Target::from_triple(&target_triple)
-            .map_err(|e| format!("Failed to create target from triple: {}", e))?;
+    #[test]
+    fn test_assign_to_undeclared_error_names_variable() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
 
-        // Create target machine
-        let target_machine = target
-            .create_target_machine(
-                &target_triple,
-                "generic",
-                "",
-                OptimizationLevel::None,
-                RelocMode::Default,
-                CodeModel::Default,
-            )
-            .ok_or("Failed to create target machine")?;
+        // counter <- 5
+        let expr = Expr::Assign("counter".into(), Box::new(Expr::Number(5)), Span::default());
+        let err = codegen.execute_program(&expr).unwrap_err();
 
-        // Generate object file
-        let obj_path = format!("{}.o", output_path);
-        target_machine
-            .write_to_file(&self.module, FileType::Object, Path::new(&obj_path))
-            .map_err(|e| format!("Failed to write object file: {}", e))?;
+        assert!(matches!(
+            err,
+            CodegenError::AssignToUndeclared { ref name, .. } if name == "counter"
+        ));
+        assert!(err.to_string().contains("'counter'"), "Message: {}", err);
+    }
 
-        // Link the object file to create an executable
-        let link_result = std::process::Command::new("gcc")
-            .args(&[&obj_path, "-o", output_path])
-            .output()
-            .map_err(|e| format!("Failed to run linker: {}", e))?;
+    #[test]
+    fn test_call_errors_name_function() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
 
-        if !link_result.status.success() {
-            return Err(format!(
-                "Linking failed: {}",
-                String::from_utf8_lossy(&link_result.stderr)
-            )
-            .into());
-        }
+        // (missing 1 2)
+        let expr = Expr::Call(
+            "missing".into(),
+            vec![Expr::Number(1), Expr::Number(2)],
+            Span::default(),
+        );
+        let err = codegen.execute_program(&expr).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown function 'missing' called with 2 argument(s), in `(missing 1 2)`"
+        );
 
-        // Clean up object file
-        fs::remove_file(&obj_path).ok();
+        // decl id x <- x in (id 1 2)
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let expr = Expr::Decl(
+            "id".into(),
+            vec![("x".into(), None)],
+            Box::new(Expr::Ident("x".into(), Span::default())),
+            Box::new(Expr::Call(
+                "id".into(),
+                vec![Expr::Number(1), Expr::Number(2)],
+                Span::default(),
+            )),
+            Span::default(),
+        );
+        let err = codegen.execute_program(&expr).unwrap_err();
+        assert_eq!(
+            err,
+            CodegenError::WrongArgumentCount {
+                name: "id".to_string(),
+                expected: 1,
+                found: 2,
+            }
+        );
 
-        println!("Successfully compiled to executable: {}", output_path);
-        Ok(())
+        // A builtin doesn't fall back to looking for a user function
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let expr = Expr::Call("print".into(), vec![], Span::default());
+        assert_eq!(
+            codegen.execute_program(&expr).unwrap_err().to_string(),
+            "function 'print' expects 1 argument(s) but was called with 0"
+        );
     }
 
-    /// Executes the compiled program and returns the exit code.
-    pub fn execute_program(&mut self, expr: &Expr) -> Result<i64, Box<dyn Error>> {
-        let main_func = self.compile_program(expr)?;
+    #[test]
+    fn test_compile_two_programs_with_one_codegen() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
 
-        unsafe {
-            let result = main_func.call();
-            Ok(result)
-        }
+        // decl f x <- * x 2 in (f 21)
+        let first = Expr::Decl(
+            "f".into(),
+            vec![("x".into(), None)],
+            Box::new(Expr::Call(
+                "*".into(),
+                vec![Expr::Ident("x".into(), Span::default()), Expr::Number(2)],
+                Span::default(),
+            )),
+            Box::new(Expr::Call(
+                "f".into(),
+                vec![Expr::Number(21)],
+                Span::default(),
+            )),
+            Span::default(),
+        );
+        assert_eq!(codegen.execute_program(&first).unwrap(), 42);
+
+        // decl f x <- + x 1 in (f 6): a new `f`, not the previous program's
+        let second = Expr::Decl(
+            "f".into(),
+            vec![("x".into(), None)],
+            Box::new(Expr::Call(
+                "+".into(),
+                vec![Expr::Ident("x".into(), Span::default()), Expr::Number(1)],
+                Span::default(),
+            )),
+            Box::new(Expr::Call(
+                "f".into(),
+                vec![Expr::Number(6)],
+                Span::default(),
+            )),
+            Span::default(),
+        );
+        assert_eq!(codegen.execute_program(&second).unwrap(), 7);
+
+        // Only one main in the module
+        let ir = codegen.get_ir_string();
+        assert_eq!(ir.matches("define i64 @main").count(), 1);
+        assert!(
+            !ir.contains("@f.1"),
+            "User functions don't carry over: {}",
+            ir
+        );
     }
 
-    /// Prints the generated LLVM IR to stdout (useful for debugging).
-    pub fn print_ir(&self) {
-        self.module.print_to_stderr();
+    #[test]
+    fn test_verification_failure_dumps_module_ir() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        codegen.break_main = true;
+
+        let err = codegen.execute_program(&Expr::Number(1)).unwrap_err();
+
+        let CodegenError::VerificationFailed {
+            ref function,
+            ref messages,
+            ir_dump: Some(ref path),
+        } = err
+        else {
+            panic!(
+                "Expected a verification failure with an IR dump, got {:?}",
+                err
+            );
+        };
+        assert_eq!(function, "main");
+        assert!(
+            messages.contains("does not have terminator"),
+            "Messages: {}",
+            messages
+        );
+        let dumped = fs::read_to_string(path).expect("IR dump should exist");
+        assert!(dumped.contains("define i64 @main"));
+        // The verifier's own words are in the error, not only on stderr
+        assert!(
+            err.to_string().contains("does not have terminator"),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains(&path.display().to_string()));
+        let _ = fs::remove_file(path);
     }
 
-    /// Get the LLVM IR as a string
-    pub fn get_ir_string(&self) -> String {
-        self.module.print_to_string().to_string()
+    #[test]
+    fn test_verification_failure_dumps_ir_next_to_output() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        codegen.break_main = true;
+
+        let dir = env::temp_dir().join(format!("mlia_failed_ir_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("app");
+        let err = codegen
+            .compile_to_executable(&Expr::Number(1), output.to_str().unwrap())
+            .unwrap_err();
+
+        let dump = dir.join("app_failed.ll");
+        assert!(dump.exists(), "The module IR should be dumped");
+        assert!(err.to_string().contains(&dump.display().to_string()));
+        assert!(!output.exists(), "Nothing is linked");
+        let _ = fs::remove_dir_all(&dir);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::parser::Expr;
 
     #[test]
-    fn test_simple_number() {
+    fn test_no_verify_emits_the_broken_ir() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        codegen.break_main = true;
+        codegen.set_verify(false);
+        codegen.set_emit(Emit::parse("ir").unwrap());
+
+        let dir = env::temp_dir().join(format!("mlia_no_verify_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("app");
+        let written = codegen
+            .compile_to_executable(&Expr::Number(1), output.to_str().unwrap())
+            .unwrap();
 
-        let expr = Expr::Number(42);
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 42);
+        assert_eq!(written, [dir.join("app.ll")]);
+        let ir = fs::read_to_string(&written[0]).unwrap();
+        let main = &ir[ir.find("define i64 @main").unwrap()..];
+        let main = &main[..main.find("\n}\n").unwrap()];
+        assert!(!main.contains("ret "), "main has no return: {}", main);
+        assert!(!dir.join("app_failed.ll").exists());
+        let _ = fs::remove_dir_all(&dir);
     }
 
     #[test]
-    fn test_variable_declaration() {
+    fn test_executable_needs_no_execution_engine() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        assert!(!codegen.has_execution_engine());
 
-        // decl x <- 5 in x
-        let expr = Expr::Decl(
-            "x".to_string(),
-            vec![],
-            Box::new(Expr::Number(5)),
-            Box::new(Expr::Ident("x".to_string())),
+        let dir = env::temp_dir().join(format!("mlia_no_engine_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("app");
+        codegen
+            .compile_to_executable(&Expr::Number(7), output.to_str().unwrap())
+            .unwrap();
+        assert!(!codegen.has_execution_engine());
+        let status = process::Command::new(&output).status().unwrap();
+        assert_eq!(status.code(), Some(7));
+        let _ = fs::remove_dir_all(&dir);
+
+        // The JIT creates it on first use, and a new program starts without one
+        assert_eq!(codegen.execute_program(&Expr::Number(3)), Ok(3));
+        assert!(codegen.has_execution_engine());
+        codegen.reset().unwrap();
+        assert!(!codegen.has_execution_engine());
+    }
+
+    #[test]
+    fn test_module_is_named_after_its_source() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        codegen.compile_program(&Expr::Number(1)).unwrap();
+        let ir = codegen.get_ir_string();
+        assert!(ir.contains("source_filename = \"mlia_module\""), "{}", ir);
+
+        codegen.set_source_name("examples/foo.mlia").unwrap();
+        codegen.compile_program(&Expr::Number(1)).unwrap();
+        let ir = codegen.get_ir_string();
+        assert!(ir.starts_with("; ModuleID = 'examples/foo.mlia'"), "{}", ir);
+        assert!(
+            ir.contains("source_filename = \"examples/foo.mlia\""),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("!llvm.ident = !{!"), "{}", ir);
+        let ident = format!(
+            "!{{!\"mlia {} (examples/foo.mlia)\"}}",
+            env!("CARGO_PKG_VERSION")
         );
+        assert!(ir.contains(&ident), "{}", ir);
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 5);
+        let mut other = CodeGen::new(&context).unwrap();
+        other.set_source_name("bar.mlia").unwrap();
+        assert_eq!(other.module.get_name().to_str(), Ok("bar.mlia"));
+        assert_ne!(other.module.get_name(), codegen.module.get_name());
     }
 
     #[test]
-    fn test_sequence() {
+    fn test_user_functions_are_mangled() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let ast = crate::parser::parse_program(
+            "decl printf x <- * x 2 in decl main x <- + x 1 in print (printf 21); (main 6)"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            codegen.execute_program_captured(&ast).unwrap(),
+            (7, "42\n".to_string())
+        );
 
-        // 1; 2
-        let expr = Expr::Seq(Box::new(Expr::Number(1)), Box::new(Expr::Number(2)));
-
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 2);
+        let ir = codegen.get_ir_string();
+        assert!(ir.contains("define i64 @_mlia_printf(i64"), "{}", ir);
+        assert!(ir.contains("define i64 @_mlia_main(i64"), "{}", ir);
+        assert!(ir.contains("define i64 @main()"), "{}", ir);
+
+        // Errors name the function as it was written
+        let ast =
+            crate::parser::parse_program("decl printf x <- x in (printf 1 2)".to_string()).unwrap();
+        let err = codegen.compile_program(&ast).unwrap_err();
+        assert!(
+            matches!(&err, CodegenError::WrongArgumentCount { name, .. } if name == "printf"),
+            "{:?}",
+            err
+        );
     }
 
-    // T011: Test addition operator (US1)
     #[test]
-    fn test_addition() {
+    fn test_blocks_are_named_after_their_line() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let ast = crate::parser::parse_program(
+            "decl i <- 3 in\n\
+             while i do i <- - i 1 done;\n\
+             decl j <- 2 in\n\
+             while j do j <- - j 1 done;\n\
+             match i with | 0 -> 1 | _ -> 2"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(codegen.execute_program(&ast).unwrap(), 1);
+
+        let ir = codegen.get_ir_string();
+        for block in [
+            "loop_header_l2:",
+            "loop_exit_l2:",
+            "loop_header_l4:",
+            "loop_exit_l4:",
+            "match_check_l5_1:",
+            "match_arm_l5_0:",
+            "match_merge_l5:",
+        ] {
+            assert!(
+                ir.contains(&format!("\n{}", block)),
+                "no {}:\n{}",
+                block,
+                ir
+            );
+        }
+    }
 
-        // + 5 3 should equal 8
-        let expr = Expr::Call("+".to_string(), vec![Expr::Number(5), Expr::Number(3)]);
+    #[test]
+    fn test_default_parameter_values() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 8, "5 + 3 should equal 8");
+        assert_eq!(run("decl f x (y <- 10) <- + x y in (f 5)"), Ok(15));
+        assert_eq!(run("decl f x (y <- 10) <- + x y in (f 5 2)"), Ok(7));
+        assert_eq!(
+            run("decl f (a <- 1) (b <- -2) <- - a b in + (f 7) (f 7 1)"),
+            Ok(15)
+        );
+        // y has no default
+        assert_eq!(
+            run("decl f x y (z <- 10) <- + x (+ y z) in (f 1)").unwrap_err(),
+            CodegenError::WrongArgumentCount {
+                name: "f".to_string(),
+                expected: 2,
+                found: 1,
+            }
+        );
     }
 
-    // T012: Test subtraction operator (US1)
     #[test]
-    fn test_subtraction() {
+    fn test_functions_share_globals() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast).unwrap()
+        };
 
-        // - 10 4 should equal 6
-        let expr = Expr::Call("-".to_string(), vec![Expr::Number(10), Expr::Number(4)]);
-
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 6, "10 - 4 should equal 6");
+        // Two functions incrementing the same counter
+        assert_eq!(
+            run("global count <- 0\n\
+                 decl inc n <- count <- + count n in\n\
+                 decl twice n <- (inc n); (inc n) in\n\
+                 (inc 1); (twice 5); (inc 10); count"),
+            21
+        );
+        // A value that isn't a literal is computed at the start of main
+        assert_eq!(
+            run("global base <- * 6 (+ 3 4)\n\
+                 global offset <- - base 40\n\
+                 decl shift x <- + x offset in (shift base)"),
+            44
+        );
+        // A local declaration or a parameter shadows the global
+        assert_eq!(
+            run("global x <- 1\n\
+                 decl f x <- + x 100 in\n\
+                 decl g n <- x <- + x n in\n\
+                 (g 2); (decl x <- 50 in x <- + x (f x)); + x (f 0)"),
+            103
+        );
     }
 
-    // T013: Test multiplication operator (US1)
     #[test]
-    fn test_multiplication() {
+    fn test_function_declared_inside_loop_and_match_arm() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
 
-        // * 6 7 should equal 42
-        let expr = Expr::Call("*".to_string(), vec![Expr::Number(6), Expr::Number(7)]);
+        // decl i <- 2 in
+        // while i do decl f x <- * x 10 in print (f i); i <- - i 1 done;
+        // match 1 with | 1 -> decl g x <- + x 1 in (g 41) | _ -> 0
+        let ident = |name: &str| Expr::Ident(name.into(), Span::default());
+        let call = |name: &str, args| Expr::Call(name.into(), args, Span::default());
+        let loop_body = Expr::Decl(
+            "f".into(),
+            vec![("x".into(), None)],
+            Box::new(call("*", vec![ident("x"), Expr::Number(10)])),
+            Box::new(Expr::Block(vec![
+                call("print", vec![call("f", vec![ident("i")])]),
+                Expr::Assign(
+                    "i".into(),
+                    Box::new(call("-", vec![ident("i"), Expr::Number(1)])),
+                    Span::default(),
+                ),
+            ])),
+            Span::default(),
+        );
+        let arm = Expr::Decl(
+            "g".into(),
+            vec![("x".into(), None)],
+            Box::new(call("+", vec![ident("x"), Expr::Number(1)])),
+            Box::new(call("g", vec![Expr::Number(41)])),
+            Span::default(),
+        );
+        let expr = Expr::Decl(
+            "i".into(),
+            vec![],
+            Box::new(Expr::Number(2)),
+            Box::new(Expr::Block(vec![
+                Expr::While(Box::new(ident("i")), Box::new(loop_body), Span::default()),
+                Expr::Match(
+                    Box::new(Expr::Number(1)),
+                    vec![
+                        (Pattern::Literal(1), arm),
+                        (Pattern::Wildcard, Expr::Number(0)),
+                    ],
+                    Span::default(),
+                ),
+            ])),
+            Span::default(),
+        );
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 42, "6 * 7 should equal 42");
+        assert_eq!(codegen.execute_program(&expr).unwrap(), 42);
     }
 
-    // T014: Test division operator (US1)
     #[test]
-    fn test_division() {
+    fn test_print_goes_through_runtime_shim() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
 
-        // / 17 5 should equal 3 (integer division)
-        let expr = Expr::Call("/".to_string(), vec![Expr::Number(17), Expr::Number(5)]);
+        // print 1; print 2; print 3
+        let expr = Expr::Block(vec![
+            Expr::Call("print".into(), vec![Expr::Number(1)], Span::default()),
+            Expr::Call("print".into(), vec![Expr::Number(2)], Span::default()),
+            Expr::Call("print".into(), vec![Expr::Number(3)], Span::default()),
+        ]);
+        assert_eq!(codegen.execute_program(&expr).unwrap(), 3);
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 3, "17 / 5 should equal 3 (integer division)");
+        let ir = codegen.get_ir_string();
+        assert_eq!(
+            ir.matches("define internal void @mlia_print_i64").count(),
+            1
+        );
+        assert_eq!(ir.matches("call void @mlia_print_i64").count(), 3);
+        assert_eq!(
+            ir.matches("call i32 (").count(),
+            1,
+            "Only the shim calls printf"
+        );
     }
 
-    // T015: Test modulo operator (US1)
     #[test]
-    fn test_modulo() {
+    fn test_get_ir_string() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
 
-        // % 17 5 should equal 2
-        let expr = Expr::Call("%".to_string(), vec![Expr::Number(17), Expr::Number(5)]);
+        // + 1 2
+        let expr = Expr::Call(
+            "+".into(),
+            vec![Expr::Number(1), Expr::Number(2)],
+            Span::default(),
+        );
+        codegen.compile_program(&expr).unwrap();
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 2, "17 % 5 should equal 2");
+        let ir = codegen.get_ir_string();
+        assert!(ir.contains("define"), "IR: {}", ir);
+        assert!(ir.contains("ret i64"), "IR: {}", ir);
+
+        let mut written = Vec::new();
+        codegen.write_ir(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), ir);
     }
 
-    // T016: Test negative number handling (US1)
     #[test]
-    fn test_negative_numbers() {
+    fn test_optimization_hoists_loop_invariant_code() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        codegen.set_optimize(true);
+
+        // n is a parameter, so n * n can't be folded, only hoisted. The space in
+        // `( *` keeps it from starting a comment.
+        let ast = crate::parser::parse_program(
+            "decl squares n <- (decl i <- 0 in while < i ( * n n) do i <- + i 1 done; i) \
+             in (squares 7)"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(codegen.execute_program(&ast).unwrap(), 49);
+
+        let ir = codegen.get_ir_string();
+        let function = &ir[ir.find("define i64 @_mlia_squares").unwrap()..];
+        let function = &function[..function.find("\n}").unwrap()];
+        let mul = function
+            .find(" mul ")
+            .expect("n * n should still be computed");
+        let header = function
+            .find("\nloop_header")
+            .expect("the loop should remain");
+        assert!(
+            mul < header,
+            "n * n should be hoisted out of the loop:\n{}",
+            function
+        );
+        assert!(
+            !function.contains("alloca"),
+            "mem2reg should run first:\n{}",
+            function
+        );
+    }
 
-        // + (-5) 3 should equal -2
-        let expr = Expr::Call("+".to_string(), vec![Expr::Number(-5), Expr::Number(3)]);
+    #[test]
+    fn test_ord_chr_and_print_char() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program_captured(&ast).unwrap()
+        };
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, -2, "-5 + 3 should equal -2");
+        assert_eq!(run("decl c <- 72 in ord c"), (72, String::new()));
+        // chr wraps into ASCII
+        assert_eq!(run("decl c <- 200 in chr c"), (72, String::new()));
+        assert_eq!(run("decl c <- -1 in chr c"), (127, String::new()));
+        assert_eq!(
+            run("print_char (chr 72); print_char (chr 73)"),
+            (73, "HI".to_string())
+        );
     }
 
-    // T020: Test less than operator - true case (US2)
     #[test]
-    fn test_less_than_true() {
+    fn test_array_get_and_set() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
 
-        // < 5 10 should equal 1 (true)
-        let expr = Expr::Call("<".to_string(), vec![Expr::Number(5), Expr::Number(10)]);
+        // Squares filled in by one loop and summed by another
+        assert_eq!(
+            run("decl a <- [0 0 0 0 0] in decl i <- 0 in\n\
+                 while < i (len a) do set a i ( * i i); i <- + i 1 done;\n\
+                 decl total <- 0 in i <- 0;\n\
+                 while < i (len a) do total <- + total (get a i); i <- + i 1 done; total"),
+            Ok(30)
+        );
+        assert_eq!(run("len []"), Ok(0));
+        assert_eq!(run("get [5 (+ 3 3)] 1"), Ok(6));
+        assert_eq!(
+            run("decl a <- [1 2 3] in get a 3"),
+            Err(CodegenError::IndexOutOfBounds {
+                index: 3,
+                len: 3,
+                span: Some(Span {
+                    line: 1,
+                    column: 22,
+                    len: 3
+                }),
+            })
+        );
+        assert_eq!(
+            run("get [1 2 3] -1").unwrap_err().to_string(),
+            "index -1 out of bounds (len 3) at line 1, column 1"
+        );
+        // An assigned variable may hold an array of another length
+        assert_eq!(run("decl a <- [1 2 3] in a <- [1 2 3 4]; get a 3"), Ok(4));
+        // So may a parameter with the name of an array
+        assert_eq!(
+            run("decl a <- [1] in decl f a <- get a 1 in (f [1 2])"),
+            Ok(2)
+        );
+    }
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 1, "5 < 10 should be true (1)");
+    #[test]
+    fn test_nested_arrays() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
+
+        // A 3x3 matrix built and summed in nested loops
+        assert_eq!(
+            run("decl m <- [[0 0 0] [0 0 0] [0 0 0]] in\n\
+                 decl i <- 0 in decl j <- 0 in decl total <- 0 in\n\
+                 while < i 3 do\n\
+                   j <- 0;\n\
+                   while < j 3 do set (get m i) j (+ ( * 3 i) j); j <- + j 1 done;\n\
+                   i <- + i 1\n\
+                 done;\n\
+                 i <- 0;\n\
+                 while < i (len m) do\n\
+                   j <- 0;\n\
+                   while < j (len (get m i)) do\n\
+                     total <- + total (get (get m i) j); j <- + j 1\n\
+                   done;\n\
+                   i <- + i 1\n\
+                 done;\n\
+                 + total (get (get m 2) 1)"),
+            Ok(36 + 7)
+        );
+        // Rows are arrays of their own, so they may differ in length
+        assert_eq!(run("decl m <- [[1 2] [3 4 5]] in len (get m 1)"), Ok(3));
+        assert_eq!(run("get (get [[1 2] [3 4 5]] 1) 2"), Ok(5));
+        assert_eq!(
+            run("get (get [[1 2] [3 4 5]] 0) 2")
+                .unwrap_err()
+                .to_string(),
+            "index 2 out of bounds (len 2) at line 1, column 1"
+        );
     }
 
-    // T021: Test less than operator - false case (US2)
     #[test]
-    fn test_less_than_false() {
+    fn test_options() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
 
-        // < 10 5 should equal 0 (false)
-        let expr = Expr::Call("<".to_string(), vec![Expr::Number(10), Expr::Number(5)]);
+        let find = "decl find a x <- decl i <- 0 in decl found <- none in\n\
+                    while < i (len a) do\n\
+                        (match (= (get a i) x) with | 1 -> found <- some i | _ -> 0);\n\
+                        i <- + i 1\n\
+                    done; found in\n";
+        let lookup = |x| format!("{find}match (find [5 7 9] {x}) with | some i -> i | none -> -1");
+        assert_eq!(run(&lookup(7)), Ok(1));
+        assert_eq!(run(&lookup(4)), Ok(-1));
+        // The payload goes on being matched
+        let nested = |value| {
+            format!(
+                "match {value} with | some (some 0) -> 0 | some (some n) -> n \
+                 | some none -> -1 | none -> -2"
+            )
+        };
+        assert_eq!(run(&nested("some (some 3)")), Ok(3));
+        assert_eq!(run(&nested("some (some 0)")), Ok(0));
+        assert_eq!(run(&nested("some none")), Ok(-1));
+        assert_eq!(run(&nested("none")), Ok(-2));
+        // The binding shadows a variable only in its arm
+        assert_eq!(
+            run("decl n <- 10 in + (match some 5 with | some n -> n | none -> 0) n"),
+            Ok(15)
+        );
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 0, "10 < 5 should be false (0)");
+        // `some _` and `none` cover everything; `some 1` doesn't
+        assert_eq!(run("match none with | some _ -> 1 | none -> 2"), Ok(2));
+        assert_eq!(
+            run("match none with | some 1 -> 1 | none -> 2")
+                .unwrap_err()
+                .to_string(),
+            "match expression doesn't cover every value: missing case: some 0; \
+             add '| _ ->' to handle the rest at line 1, column 1"
+        );
     }
 
-    // T022: Test greater than operator (US2)
     #[test]
-    fn test_greater_than() {
+    fn test_results_and_try() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program_captured(&ast)
+        };
 
-        // > 10 5 should equal 1 (true)
-        let expr = Expr::Call(">".to_string(), vec![Expr::Number(10), Expr::Number(5)]);
+        let divide = "decl divide a b <- match b with | 0 -> err 1 | _ -> ok (/ a b) in\n\
+                      decl half_of_quotient a b <- decl q <- try (divide a b) in\n\
+                          print q; ok (/ q 2) in\n";
+        let call = |a, b| {
+            format!("{divide}match (half_of_quotient {a} {b}) with | ok v -> v | err e -> - 0 e")
+        };
+        // The happy path unwraps the quotient and goes on
+        assert_eq!(run(&call(20, 2)), Ok((5, "10\n".to_string())));
+        // An err skips the rest of the function: nothing is printed
+        assert_eq!(run(&call(20, 0)), Ok((-1, String::new())));
+        // In main, the program ends with the error
+        assert_eq!(
+            run(&format!(
+                "{divide}print (try (divide 9 3)); try (divide 1 0); print 7; 0"
+            )),
+            Ok((1, "3\n".to_string()))
+        );
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 1, "10 > 5 should be true (1)");
+        // `ok _` and `err _` cover everything; `ok 1` doesn't
+        assert_eq!(
+            run("match ok 3 with | ok n -> n | err _ -> 0"),
+            Ok((3, String::new()))
+        );
+        assert!(run("match ok 3 with | ok 1 -> 1 | err _ -> 0").is_err());
     }
 
-    // T023: Test equality operator - true case (US2)
     #[test]
-    fn test_equality_true() {
+    fn test_linked_list_on_the_heap() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
-
-        // = 7 7 should equal 1 (true)
-        let expr = Expr::Call("=".to_string(), vec![Expr::Number(7), Expr::Number(7)]);
-
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 1, "7 = 7 should be true (1)");
+        let source = "decl node v next <- decl p <- alloc 2 in store8 p 0 v; store8 p 1 next; p in\n\
+                      decl list <- (node 1 (node 2 (node 3 0))) in\n\
+                      decl total <- 0 in\n\
+                      decl p <- list in\n\
+                      while != p 0 do total <- + total (load8 p 0); p <- load8 p 1 done;\n\
+                      while != list 0 do decl next <- load8 list 1 in free list; list <- next done;\n\
+                      total";
+        let ast = crate::parser::parse_program(source.to_string()).unwrap();
+        assert_eq!(codegen.execute_program(&ast), Ok(6));
     }
 
-    // T024: Test equality operator - false case (US2)
     #[test]
-    fn test_equality_false() {
+    fn test_swap_through_references() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program_captured(&ast)
+        };
 
-        // = 5 10 should equal 0 (false)
-        let expr = Expr::Call("=".to_string(), vec![Expr::Number(5), Expr::Number(10)]);
-
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 0, "5 = 10 should be false (0)");
+        assert_eq!(
+            run(
+                "decl swap a b <- decl t <- deref a in store a (deref b); store b t in\n\
+                 decl x <- 1 in decl y <- 2 in\n\
+                 (swap (ref x) (ref y)); print x; print y; 0"
+            ),
+            Ok((0, "2\n1\n".to_string()))
+        );
+        assert_eq!(
+            run("global counter <- 0\n\
+                 decl bump p <- store p (+ (deref p) 1) in\n\
+                 (bump (ref counter)); (bump (ref counter)); counter"),
+            Ok((2, String::new()))
+        );
+        assert_eq!(
+            run("ref (+ 1 2)").unwrap_err().to_string(),
+            "'ref' takes a variable at line 1, column 1"
+        );
     }
 
-    // T025: Test not equal operator (US2)
     #[test]
-    fn test_not_equal() {
+    fn test_slices() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
 
-        // != 5 10 should equal 1 (true)
-        let expr = Expr::Call("!=".to_string(), vec![Expr::Number(5), Expr::Number(10)]);
-
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 1, "5 != 10 should be true (1)");
+        // The middle of an array, summed
+        assert_eq!(
+            run("decl a <- [1 2 3 4 5 6] in decl s <- slice a 2 3 in\n\
+                 decl total <- 0 in decl i <- 0 in\n\
+                 while < i (len s) do total <- + total (get s i); i <- + i 1 done; total"),
+            Ok(3 + 4 + 5)
+        );
+        assert_eq!(run("len (slice [1 2 3] 3 0)"), Ok(0));
+        // A copy: setting the slice leaves the array alone
+        assert_eq!(
+            run("decl a <- [1 2 3] in decl s <- slice a 0 3 in set s 0 9; + (get a 0) (get s 0)"),
+            Ok(10)
+        );
+        assert_eq!(
+            run("slice [1 2 3] 2 2").unwrap_err().to_string(),
+            "slice of 2 elements from 2 out of bounds (len 3) at line 1, column 1"
+        );
+        // The length of a constant slice is known too
+        assert_eq!(
+            run("decl s <- slice [1 2 3] 1 2 in get s 2")
+                .unwrap_err()
+                .to_string(),
+            "index 2 out of bounds (len 2) at line 1, column 32"
+        );
     }
 
-    // T030: Test while loop countdown (US3)
     #[test]
-    fn test_while_loop_countdown() {
+    fn test_vectors_grow_and_shrink() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |source: &str| {
+            let ast = crate::parser::parse_program(source.to_string()).unwrap();
+            codegen.execute_program(&ast)
+        };
 
-        // decl x <- 3 in while x do x <- - x 1 done
-        // Should loop 3 times, decrementing x each time
-        let expr = Expr::Decl(
-            "x".to_string(),
-            vec![],
-            Box::new(Expr::Number(3)),
-            Box::new(Expr::While(
-                Box::new(Expr::Ident("x".to_string())),
-                Box::new(Expr::Assign(
-                    "x".to_string(),
-                    Box::new(Expr::Call(
-                        "-".to_string(),
-                        vec![Expr::Ident("x".to_string()), Expr::Number(1)],
-                    )),
-                )),
-            )),
+        // 1000 pushes go through many reallocations
+        assert_eq!(
+            run("decl v <- vec_new in decl i <- 0 in\n\
+                 while < i 1000 do push v i; i <- + i 1 done;\n\
+                 decl total <- 0 in\n\
+                 while vlen v do total <- + total (pop v) done;\n\
+                 + total (vlen v)"),
+            Ok(499500)
         );
-
-        let result = codegen.execute_program(&expr).unwrap();
         assert_eq!(
-            result, 0,
-            "While loop should return 0 when condition becomes false"
+            run("decl v <- vec_new in push v 1; push v 2; push v 3;\n\
+                 vset v 0 (+ (vget v 1) (vget v 2)); pop v; + ( * 10 (vget v 0)) (vlen v)"),
+            Ok(52)
         );
+        assert_eq!(run("vlen vec_new"), Ok(0));
     }
 
-    // T031: Test while loop with zero iterations (US3)
     #[test]
-    fn test_while_loop_zero_iterations() {
+    fn test_print_str_and_strlen() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        // A string handle is the address of its bytes
+        let text = std::ffi::CString::new("hello").unwrap();
+        let source = format!(
+            "decl s <- {} in print_str s; (strlen s)",
+            text.as_ptr() as i64
+        );
+        let ast = crate::parser::parse_program(source).unwrap();
 
-        // while 0 do 42 done
-        // Should not execute body at all
-        let expr = Expr::While(Box::new(Expr::Number(0)), Box::new(Expr::Number(42)));
-
-        let result = codegen.execute_program(&expr).unwrap();
         assert_eq!(
-            result, 0,
-            "While loop with false condition should return 0 immediately"
+            codegen.execute_program_captured(&ast).unwrap(),
+            (5, "hello\n".to_string())
         );
     }
 
-    // T032: Test while loop with accumulator (US3)
     #[test]
-    fn test_while_loop_accumulator() {
+    fn test_print_with_interpolation() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let ast = crate::parser::parse_program(
+            "decl x <- 3 in decl y <- -4 in\n\
+             print \"x is {x} and y is {y}\";\n\
+             print \"{{x}} is {x}, 100%\";\n\
+             print \"{x}{y}{x}\""
+                .to_string(),
+        )
+        .unwrap();
+
+        let (result, output) = codegen.execute_program_captured(&ast).unwrap();
+        assert_eq!(output, "x is 3 and y is -4\n{x} is 3, 100%\n3-43\n");
+        assert_eq!(result, 0);
+        // Without capture, one printf call with %lld for each value
+        codegen.compile_program(&ast).unwrap();
+        assert!(codegen.get_ir_string().contains("{x} is %lld, 100%%\\0A"));
+    }
 
-        // decl sum <- 0 in decl i <- 5 in
-        // while i do (sum <- + sum i; i <- - i 1) done; sum
-        let expr = Expr::Decl(
-            "sum".to_string(),
-            vec![],
-            Box::new(Expr::Number(0)),
-            Box::new(Expr::Decl(
-                "i".to_string(),
-                vec![],
-                Box::new(Expr::Number(5)),
-                Box::new(Expr::Seq(
-                    Box::new(Expr::While(
-                        Box::new(Expr::Ident("i".to_string())),
-                        Box::new(Expr::Seq(
-                            Box::new(Expr::Assign(
-                                "sum".to_string(),
-                                Box::new(Expr::Call(
-                                    "+".to_string(),
-                                    vec![
-                                        Expr::Ident("sum".to_string()),
-                                        Expr::Ident("i".to_string()),
-                                    ],
-                                )),
-                            )),
-                            Box::new(Expr::Assign(
-                                "i".to_string(),
-                                Box::new(Expr::Call(
-                                    "-".to_string(),
-                                    vec![Expr::Ident("i".to_string()), Expr::Number(1)],
-                                )),
-                            )),
-                        )),
-                    )),
-                    Box::new(Expr::Ident("sum".to_string())),
-                )),
-            )),
-        );
+    #[test]
+    fn test_print_format_follows_target_triple() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 15, "Sum of 5+4+3+2+1 should be 15");
+        for (triple, format) in [
+            ("x86_64-unknown-linux-gnu", "c\"%lld\\0A\\00\""),
+            ("x86_64-pc-windows-msvc", "c\"%I64d\\0A\\00\""),
+            ("x86_64-w64-windows-gnu", "c\"%I64d\\0A\\00\""),
+        ] {
+            codegen.module = context.create_module(triple);
+            codegen.module.set_triple(&TargetTriple::create(triple));
+            codegen.declare_print_function();
+
+            let ir = codegen.get_ir_string();
+            let global = ir
+                .lines()
+                .find(|line| line.starts_with("@fmt_str"))
+                .unwrap();
+            assert!(global.contains(format), "{}: {}", triple, global);
+        }
     }
 
-    // T033: Test nested while loops (US3)
     #[test]
-    fn test_nested_while_loops() {
+    fn test_execution_budget_stops_infinite_loop() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
 
-        // decl outer <- 2 in while outer do (
-        //   decl inner <- 2 in while inner do inner <- - inner 1 done;
-        //   outer <- - outer 1
-        // ) done
-        let expr = Expr::Decl(
-            "outer".to_string(),
-            vec![],
-            Box::new(Expr::Number(2)),
-            Box::new(Expr::While(
-                Box::new(Expr::Ident("outer".to_string())),
-                Box::new(Expr::Seq(
-                    Box::new(Expr::Decl(
-                        "inner".to_string(),
-                        vec![],
-                        Box::new(Expr::Number(2)),
-                        Box::new(Expr::While(
-                            Box::new(Expr::Ident("inner".to_string())),
-                            Box::new(Expr::Assign(
-                                "inner".to_string(),
-                                Box::new(Expr::Call(
-                                    "-".to_string(),
-                                    vec![Expr::Ident("inner".to_string()), Expr::Number(1)],
-                                )),
-                            )),
-                        )),
-                    )),
-                    Box::new(Expr::Assign(
-                        "outer".to_string(),
-                        Box::new(Expr::Call(
-                            "-".to_string(),
-                            vec![Expr::Ident("outer".to_string()), Expr::Number(1)],
-                        )),
-                    )),
-                )),
-            )),
+        let start = std::time::Instant::now();
+        let ast = crate::parser::parse_program("while 1 do 0 done".to_string()).unwrap();
+        assert_eq!(
+            codegen.execute_with_limit(&ast, 1_000_000),
+            Err(CodegenError::ExecutionBudgetExceeded { limit: 1_000_000 })
         );
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 0, "Nested while loops should complete successfully");
+        // Unbounded recursion runs out too, unwinding through every call
+        let ast =
+            crate::parser::parse_program("decl f n <- (f (+ n 1)) in (f 0)".to_string()).unwrap();
+        assert_eq!(
+            codegen.execute_with_limit(&ast, 10_000),
+            Err(CodegenError::ExecutionBudgetExceeded { limit: 10_000 })
+        );
     }
 
-    // T038: Test match expression - first pattern matches (US4)
     #[test]
-    fn test_match_first_pattern() {
+    fn test_execution_budget_leaves_terminating_programs_alone() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
-
-        // match 1 with | 1 -> 100 | 2 -> 200 | _ -> 300
-        let expr = Expr::Match(
-            Box::new(Expr::Number(1)),
-            vec![
-                (Pattern::Literal(1), Expr::Number(100)),
-                (Pattern::Literal(2), Expr::Number(200)),
-                (Pattern::Wildcard, Expr::Number(300)),
-            ],
-        );
-
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 100, "match 1 should return 100");
+        let ast = crate::parser::parse_program(
+            "decl twice n <- * n 2 in decl i <- 0 in while < i 10 do i <- (twice (+ i 1)) done; i"
+                .to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(codegen.execute_with_limit(&ast, 1_000), Ok(14));
+        // The program after it runs without instrumentation again
+        assert_eq!(codegen.execute_program(&ast).unwrap(), 14);
+        assert!(!codegen.get_ir_string().contains("mlia_fuel"));
     }
 
-    // T039: Test match expression - second pattern matches (US4)
     #[test]
-    fn test_match_second_pattern() {
+    fn test_sandbox_rejects_undeclared_externals() {
         let context = Context::create();
+        let parse = |source: &str| crate::parser::parse_program(source.to_string()).unwrap();
+
+        // The runtime's own functions are allowed
         let mut codegen = CodeGen::new(&context).unwrap();
+        codegen.set_sandboxed(true);
+        let ast = parse("decl s <- read_line in print (strlen s); print_char 65; 0");
+        assert_eq!(
+            codegen.execute_program_captured(&ast),
+            Ok((0, "0\nA".to_string()))
+        );
 
-        // match 2 with | 1 -> 100 | 2 -> 200 | _ -> 300
-        let expr = Expr::Match(
-            Box::new(Expr::Number(2)),
-            vec![
-                (Pattern::Literal(1), Expr::Number(100)),
-                (Pattern::Literal(2), Expr::Number(200)),
-                (Pattern::Wildcard, Expr::Number(300)),
-            ],
+        // A fresh module keeps what is declared in it before compiling
+        let sneaky = |codegen: &CodeGen| {
+            let i32_type = codegen.context.i32_type();
+            let ptr_type = codegen.context.ptr_type(inkwell::AddressSpace::default());
+            codegen.libc_function("system", i32_type.fn_type(&[ptr_type.into()], false));
+        };
+        let ast = parse("+ 40 2");
+        let mut codegen = CodeGen::new(&context).unwrap();
+        codegen.set_sandboxed(true);
+        sneaky(&codegen);
+        assert_eq!(
+            codegen.execute_program(&ast),
+            Err(CodegenError::DisallowedExternal("system".to_string()))
         );
+        assert!(!codegen.has_execution_engine());
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 200, "match 2 should return 200");
+        // Permissive, the JIT resolves it like any other
+        let mut codegen = CodeGen::new(&context).unwrap();
+        sneaky(&codegen);
+        assert_eq!(codegen.execute_program(&ast), Ok(42));
+
+        // Nor can a module end the process, or reach files unless let in
+        let declared = |name: &str, allow_io: bool| {
+            let mut codegen = CodeGen::new(&context).unwrap();
+            codegen.set_sandboxed(true);
+            codegen.set_sandbox_io(allow_io);
+            let i32_type = codegen.context.i32_type();
+            let ptr_type = codegen.context.ptr_type(inkwell::AddressSpace::default());
+            let fn_type = i32_type.fn_type(&[ptr_type.into(), ptr_type.into()], false);
+            codegen.libc_function(name, fn_type);
+            codegen.execute_program(&ast)
+        };
+        let disallowed = |name: &str| Err(CodegenError::DisallowedExternal(name.to_string()));
+        assert_eq!(declared("exit", false), disallowed("exit"));
+        assert_eq!(declared("exit", true), disallowed("exit"));
+        assert_eq!(declared("fopen", false), disallowed("fopen"));
+        assert_eq!(declared("fopen", true), Ok(42));
+
+        let env = parse("env_int \"MLIA_SANDBOX_UNSET\"");
+        let mut codegen = CodeGen::new(&context).unwrap();
+        codegen.set_sandboxed(true);
+        assert_eq!(codegen.execute_program(&env), disallowed("getenv"));
+        codegen.set_sandbox_io(true);
+        assert_eq!(codegen.execute_program(&env), Ok(0));
     }
 
-    // T040: Test match expression - wildcard matches (US4)
     #[test]
-    fn test_match_wildcard() {
+    fn test_execution_report() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
-
-        // match 5 with | 1 -> 100 | 2 -> 200 | _ -> 300
-        let expr = Expr::Match(
-            Box::new(Expr::Number(5)),
-            vec![
-                (Pattern::Literal(1), Expr::Number(100)),
-                (Pattern::Literal(2), Expr::Number(200)),
-                (Pattern::Wildcard, Expr::Number(300)),
-            ],
-        );
-
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 300, "match 5 should return 300 (wildcard)");
+        let trivial = crate::parser::parse_program("+ 40 2".to_string()).unwrap();
+        let long = crate::parser::parse_program(
+            "decl i <- 0 in while < i 1000000 do i <- + i 1 done; i".to_string(),
+        )
+        .unwrap();
+
+        let quick = codegen.run(&trivial).unwrap();
+        assert_eq!(quick.result, codegen.execute_program(&trivial).unwrap());
+        assert_eq!(quick.result, 42);
+        assert_eq!(quick.ir_size_bytes, codegen.get_ir_string().len());
+
+        let slow = codegen.run(&long).unwrap();
+        assert_eq!(slow.result, codegen.execute_program(&long).unwrap());
+        assert_eq!(slow.result, 1_000_000);
+        assert!(slow.run_time > quick.run_time, "{:?} {:?}", slow, quick);
+        assert!(slow.ir_size_bytes > quick.ir_size_bytes);
     }
 
-    // T041: Test match expression with computed result expressions (US4)
     #[test]
-    fn test_match_computed_results() {
+    fn test_captured_programs_read_the_given_input() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let ast = crate::parser::parse_program(
+            "print_str read_line; print_str read_line; strlen read_line".to_string(),
+        )
+        .unwrap();
 
-        // match 1 with | 1 -> (+ 10 20) | _ -> 0
-        let expr = Expr::Match(
-            Box::new(Expr::Number(1)),
-            vec![
-                (
-                    Pattern::Literal(1),
-                    Expr::Call("+".to_string(), vec![Expr::Number(10), Expr::Number(20)]),
-                ),
-                (Pattern::Wildcard, Expr::Number(0)),
-            ],
+        // The third line is past the end of the input
+        assert_eq!(
+            codegen.execute_captured(&ast, "ab\ncde\n", None),
+            Ok((0, "ab\ncde\n".to_string()))
+        );
+        assert_eq!(
+            codegen.execute_program_captured(&ast),
+            Ok((0, "\n\n".to_string()))
         );
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 30, "match 1 should compute 10 + 20 = 30");
+        let ast = crate::parser::parse_program("print 1; while 1 do 0 done".to_string()).unwrap();
+        assert_eq!(
+            codegen.execute_captured(&ast, "", Some(1_000)),
+            Err(CodegenError::ExecutionBudgetExceeded { limit: 1_000 })
+        );
     }
 
-    // T042: Test match as subexpression (US4)
     #[test]
-    fn test_match_as_subexpression() {
+    fn test_compiled_program_runs_many_times() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
+        let ast = crate::parser::parse_program(
+            "decl total <- 0 in decl i <- 10 in while i do total <- + total i; i <- - i 1 done; total"
+                .to_string(),
+        )
+        .unwrap();
+
+        let program = codegen.compile_to_callable(&ast).unwrap();
+        for _ in 0..1000 {
+            assert_eq!(program.run(), Ok(55));
+        }
+    }
 
-        // + (match 2 with | 1 -> 10 | 2 -> 20 | _ -> 30) 5
-        let match_expr = Expr::Match(
-            Box::new(Expr::Number(2)),
-            vec![
-                (Pattern::Literal(1), Expr::Number(10)),
-                (Pattern::Literal(2), Expr::Number(20)),
-                (Pattern::Wildcard, Expr::Number(30)),
-            ],
+    #[test]
+    fn test_failed_runtime_checks_return_an_error() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let ast = crate::parser::parse_program(
+            "decl at i <- get [10 20 30] i in print (at 2); (at 7); print 1; 0".to_string(),
+        )
+        .unwrap();
+
+        let program = codegen.compile_to_callable(&ast).unwrap();
+        let error = CodegenError::RuntimeError("index 7 out of bounds (len 3)".to_string());
+        // Every run starts without the error of the one before
+        assert_eq!(program.run(), Err(error.clone()));
+        assert_eq!(program.run(), Err(error.clone()));
+        drop(program);
+        assert_eq!(codegen.execute_program_captured(&ast), Err(error));
+        assert_eq!(
+            codegen.execute_program(&Expr::Number(4)),
+            Ok(4),
+            "the process goes on"
         );
+    }
 
-        let expr = Expr::Call("+".to_string(), vec![match_expr, Expr::Number(5)]);
+    #[test]
+    fn test_env_int_reads_environment_variables() {
+        // SAFETY: no other test touches these variables
+        unsafe {
+            env::set_var("MLIA_X", "41");
+            env::set_var("MLIA_NOT_A_NUMBER", "abc");
+            env::remove_var("MLIA_UNSET");
+        }
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context).unwrap();
+        let mut run = |code: &str| {
+            let ast = crate::parser::parse_program(code.to_string()).unwrap();
+            codegen.execute_program(&ast).unwrap()
+        };
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 25, "20 + 5 should equal 25");
+        assert_eq!(run("+ (env_int \"MLIA_X\") 1"), 42);
+        assert_eq!(run("+ (env_int \"MLIA_UNSET\") 1"), 1);
+        assert_eq!(run("env_int \"MLIA_NOT_A_NUMBER\""), 0);
+        // getenv and atoll are declared once however many times it is used
+        assert_eq!(run("+ (env_int \"MLIA_X\") (env_int \"MLIA_X\")"), 82);
+        let ir = codegen.get_ir_string();
+        let declarations = ir.lines().filter(|line| line.starts_with("declare"));
+        assert_eq!(
+            declarations
+                .filter(|line| line.contains("@getenv("))
+                .count(),
+            1
+        );
     }
 
-    // T043: Test match with variable in scrutinee (US4)
     #[test]
-    fn test_match_with_variable() {
+    fn test_long_chains_of_statements_compile() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
 
-        // (defvar x 2 in (match x with | 1 -> 100 | 2 -> 200 | _ -> 300))
+        // decl x <- 0 in x <- + x 1; decl y <- x in x <- + y 1; ... x
+        let at = Span::default();
+        let x = || Expr::Ident("x".into(), at);
+        let mut expr = x();
+        for i in 0..50_000 {
+            let increment = |of: Expr| Expr::Call("+".into(), vec![of, Expr::Number(1)], at);
+            expr = if i % 2 == 0 {
+                Expr::Block(vec![
+                    Expr::Assign("x".into(), Box::new(increment(x())), at),
+                    expr,
+                ])
+            } else {
+                let y = Expr::Ident("y".into(), at);
+                let body = Expr::Assign("x".into(), Box::new(increment(y)), at);
+                Expr::Decl(
+                    "y".into(),
+                    vec![],
+                    Box::new(x()),
+                    Box::new(Expr::Block(vec![body, expr])),
+                    at,
+                )
+            };
+        }
         let expr = Expr::Decl(
-            "x".to_string(),
+            "x".into(),
             vec![],
-            Box::new(Expr::Number(2)),
-            Box::new(Expr::Match(
-                Box::new(Expr::Ident("x".to_string())),
-                vec![
-                    (Pattern::Literal(1), Expr::Number(100)),
-                    (Pattern::Literal(2), Expr::Number(200)),
-                    (Pattern::Wildcard, Expr::Number(300)),
-                ],
-            )),
+            Box::new(Expr::Number(0)),
+            Box::new(expr),
+            at,
         );
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 200, "match x (where x=2) should return 200");
+        assert_eq!(codegen.execute_program(&expr), Ok(50_000));
     }
 
-    // T044: Test match without wildcard but with all cases covered (US4)
     #[test]
-    fn test_match_explicit_patterns() {
+    fn test_long_scripts_are_one_block() {
         let context = Context::create();
         let mut codegen = CodeGen::new(&context).unwrap();
 
-        // match 0 with | 0 -> 42 | _ -> 0
-        let expr = Expr::Match(
-            Box::new(Expr::Number(0)),
-            vec![
-                (Pattern::Literal(0), Expr::Number(42)),
-                (Pattern::Wildcard, Expr::Number(0)),
-            ],
+        // decl x <- 0 in x <- + x 0; x <- + x 1; x <- + x 2; x <- + x 0; ... x
+        let statements = 50_000;
+        let mut source = "decl x <- 0 in\n".to_string();
+        for i in 0..statements {
+            source.push_str(&format!("x <- + x {};\n", i % 3));
+        }
+        source.push('x');
+
+        let started = std::time::Instant::now();
+        let ast = crate::parser::parse_program(source).unwrap();
+        let Expr::Decl(_, _, _, body, _) = &ast else {
+            panic!("Expected Decl expression, got {:?}", ast);
+        };
+        assert!(matches!(&**body, Expr::Block(block) if block.len() == statements + 1));
+        assert_eq!(codegen.execute_program(&ast), Ok(49_999));
+        assert!(
+            started.elapsed() < std::time::Duration::from_secs(60),
+            "{:?} to compile {} statements",
+            started.elapsed(),
+            statements
         );
+    }
 
-        let result = codegen.execute_program(&expr).unwrap();
-        assert_eq!(result, 42, "match 0 should return 42");
+    #[test]
+    fn test_deeply_nested_expressions_are_an_error() {
+        // + 1 (+ 1 (... 0)), `depth` calls deep, compiled on a stack the size
+        // of the main thread's, where the compiler runs
+        let run_nested = |depth: usize| {
+            let compile = std::thread::Builder::new()
+                .stack_size(8 << 20)
+                .spawn(move || {
+                    let context = Context::create();
+                    let mut codegen = CodeGen::new(&context).unwrap();
+                    let mut expr = Expr::Number(0);
+                    for _ in 0..depth {
+                        expr = Expr::Call("+".into(), vec![Expr::Number(1), expr], Span::default());
+                    }
+                    codegen.execute_program(&expr)
+                });
+            compile.unwrap().join().unwrap()
+        };
+
+        match run_nested(100_000) {
+            Err(CodegenError::TooDeep { limit, .. }) => assert_eq!(limit, MAX_DEPTH),
+            other => panic!("Expected TooDeep, got {:?}", other),
+        }
+        // Right under the limit is fine
+        assert_eq!(run_nested(MAX_DEPTH - 1), Ok(MAX_DEPTH as i64 - 1));
+    }
+
+    #[test]
+    fn test_checking_never_starts_llvm() {
+        use crate::{Options, Source, check_str, load_sources};
+
+        let source = "decl f n <- match n with | 0 -> 1 | _ -> * n (f (- n 1)) in (f 5)";
+        assert_eq!(check_str(source), []);
+        let sources = [Source {
+            name: "factorial.mlia".to_string(),
+            code: source.to_string(),
+        }];
+        let program = load_sources(&sources, &Options::default()).unwrap();
+        assert_eq!(CREATED.with(Cell::get), 0);
+
+        assert_eq!(program.run(&Options::default()).unwrap(), 120);
+        assert_eq!(CREATED.with(Cell::get), 1);
     }
 }
@@ -0,0 +1,885 @@
+//! Diagnostics: what went wrong (or might have) in a program, from whichever
+//! phase found it, and how it shows on the terminal: the severity, code and
+//! message, the file and position, the source line with the span underlined,
+//! and any notes.
+//!
+//! ```text
+//! warning[W0002]: declaration of 'x' at line 2, column 6 shadows the one at line 1, column 6
+//!  --> main.mlia:2:6
+//!   |
+//! 2 | decl x <- 2 in x
+//!   |      ^
+//! note: 'x' is first declared here
+//!  --> main.mlia:1:6
+//!   |
+//! 1 | decl x <- 1 in
+//!   |      ^
+//! ```
+
+use crate::codegen::CodegenError;
+use crate::parser::{ParseError, Span};
+use crate::semantic::{AssignHint, SemanticError, SemanticWarning};
+use crate::tokenizer::LexError;
+use crate::types::TypeError;
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const GREEN: &str = "\x1b[1;32m";
+
+/// The kind of problem a diagnostic reports, with a code that stays the same
+/// from one version to the next (`E0001`, `W0002`...) so that course notes
+/// and scripts can refer to it. `mlia --explain <code>` prints what it means,
+/// with an example program and how to fix it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    UndefinedVariable,
+    AssignToUndeclared,
+    WrongArgumentCount,
+    NamedArgument,
+    NonExhaustiveMatch,
+    BreakOutsideLoop,
+    TypeMismatch,
+    IndexOutOfBounds,
+    InvalidCharacter,
+    SyntaxError,
+    UnusedVariable,
+    Shadowing,
+    UnreachableArm,
+    ReservedName,
+    FutureKeyword,
+}
+
+impl ErrorCode {
+    /// Every code, in order.
+    pub const ALL: [ErrorCode; 15] = [
+        ErrorCode::UndefinedVariable,
+        ErrorCode::AssignToUndeclared,
+        ErrorCode::WrongArgumentCount,
+        ErrorCode::NamedArgument,
+        ErrorCode::NonExhaustiveMatch,
+        ErrorCode::BreakOutsideLoop,
+        ErrorCode::TypeMismatch,
+        ErrorCode::IndexOutOfBounds,
+        ErrorCode::InvalidCharacter,
+        ErrorCode::SyntaxError,
+        ErrorCode::UnusedVariable,
+        ErrorCode::Shadowing,
+        ErrorCode::UnreachableArm,
+        ErrorCode::ReservedName,
+        ErrorCode::FutureKeyword,
+    ];
+
+    /// The code as written: `E` for errors, `W` for warnings.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::UndefinedVariable => "E0001",
+            ErrorCode::AssignToUndeclared => "E0002",
+            ErrorCode::WrongArgumentCount => "E0003",
+            ErrorCode::NamedArgument => "E0004",
+            ErrorCode::NonExhaustiveMatch => "E0005",
+            ErrorCode::BreakOutsideLoop => "E0006",
+            ErrorCode::TypeMismatch => "E0007",
+            ErrorCode::IndexOutOfBounds => "E0008",
+            ErrorCode::InvalidCharacter => "E0009",
+            ErrorCode::SyntaxError => "E0010",
+            ErrorCode::UnusedVariable => "W0001",
+            ErrorCode::Shadowing => "W0002",
+            ErrorCode::UnreachableArm => "W0003",
+            ErrorCode::ReservedName => "W0004",
+            ErrorCode::FutureKeyword => "W0005",
+        }
+    }
+
+    /// What the code is about, in a few words.
+    pub fn title(self) -> &'static str {
+        match self {
+            ErrorCode::UndefinedVariable => "undefined variable",
+            ErrorCode::AssignToUndeclared => "assignment to an undeclared variable",
+            ErrorCode::WrongArgumentCount => "wrong number of arguments",
+            ErrorCode::NamedArgument => "named argument that doesn't fit the function",
+            ErrorCode::NonExhaustiveMatch => "match that doesn't cover every value",
+            ErrorCode::BreakOutsideLoop => "break outside of a loop",
+            ErrorCode::TypeMismatch => "mismatched types",
+            ErrorCode::IndexOutOfBounds => "array index out of bounds",
+            ErrorCode::InvalidCharacter => "unexpected character",
+            ErrorCode::SyntaxError => "syntax error",
+            ErrorCode::UnusedVariable => "unused variable",
+            ErrorCode::Shadowing => "shadowed variable",
+            ErrorCode::UnreachableArm => "unreachable match arm",
+            ErrorCode::ReservedName => "name of the entry point",
+            ErrorCode::FutureKeyword => "identifier that will become a keyword",
+        }
+    }
+
+    /// The longer explanation `--explain` prints: what the problem is, a
+    /// program that has it, and the same program fixed.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            ErrorCode::UndefinedVariable => include_str!("explanations/E0001.md"),
+            ErrorCode::AssignToUndeclared => include_str!("explanations/E0002.md"),
+            ErrorCode::WrongArgumentCount => include_str!("explanations/E0003.md"),
+            ErrorCode::NamedArgument => include_str!("explanations/E0004.md"),
+            ErrorCode::NonExhaustiveMatch => include_str!("explanations/E0005.md"),
+            ErrorCode::BreakOutsideLoop => include_str!("explanations/E0006.md"),
+            ErrorCode::TypeMismatch => include_str!("explanations/E0007.md"),
+            ErrorCode::IndexOutOfBounds => include_str!("explanations/E0008.md"),
+            ErrorCode::InvalidCharacter => include_str!("explanations/E0009.md"),
+            ErrorCode::SyntaxError => include_str!("explanations/E0010.md"),
+            ErrorCode::UnusedVariable => include_str!("explanations/W0001.md"),
+            ErrorCode::Shadowing => include_str!("explanations/W0002.md"),
+            ErrorCode::UnreachableArm => include_str!("explanations/W0003.md"),
+            ErrorCode::ReservedName => include_str!("explanations/W0004.md"),
+            ErrorCode::FutureKeyword => include_str!("explanations/W0005.md"),
+        }
+    }
+
+    /// The code written `code` (`e0001` too), if there is one.
+    pub fn parse(code: &str) -> Option<Self> {
+        ErrorCode::ALL
+            .into_iter()
+            .find(|known| known.as_str().eq_ignore_ascii_case(code))
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a program, by any phase: the lexer, the parser, the
+/// semantic checks, type inference or codegen. Every error and warning the
+/// compiler reports is one of these.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What kind of problem it is. Diagnostics about the program have one;
+    /// the ones about files, LLVM or the compiler's own limits don't.
+    pub code: Option<ErrorCode>,
+    pub message: String,
+    /// Where in the source, when known.
+    pub span: Option<Span>,
+    /// More about the problem, each at the place it is about if it has one:
+    /// where a shadowed variable was declared, what the verifier said.
+    pub notes: Vec<(Option<Span>, String)>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: None,
+            message: message.into(),
+            span,
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message, span)
+        }
+    }
+
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_note(mut self, span: Option<Span>, note: impl Into<String>) -> Self {
+        self.notes.push((span, note.into()));
+        self
+    }
+}
+
+impl From<&LexError> for Diagnostic {
+    fn from(error: &LexError) -> Self {
+        Diagnostic::error(format!("Tokenization error: {}", error), Some(error.span))
+            .with_code(ErrorCode::InvalidCharacter)
+    }
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(error: &ParseError) -> Self {
+        Diagnostic::error(error.to_string(), error.span).with_code(ErrorCode::SyntaxError)
+    }
+}
+
+impl From<&SemanticError> for Diagnostic {
+    fn from(error: &SemanticError) -> Self {
+        let (code, span) = match error {
+            SemanticError::UndefinedVariable { span, .. } => (ErrorCode::UndefinedVariable, span),
+            SemanticError::AssignToUndeclared { span, .. } => (ErrorCode::AssignToUndeclared, span),
+            SemanticError::WrongArgumentCount { span, .. } => (ErrorCode::WrongArgumentCount, span),
+            SemanticError::UnknownNamedArgument { span, .. }
+            | SemanticError::DuplicateNamedArgument { span, .. }
+            | SemanticError::MissingArgument { span, .. } => (ErrorCode::NamedArgument, span),
+            SemanticError::NonExhaustiveMatch { span, .. } => (ErrorCode::NonExhaustiveMatch, span),
+            SemanticError::BreakOutsideLoop { span } => (ErrorCode::BreakOutsideLoop, span),
+        };
+        let diagnostic = Diagnostic::error(error.to_string(), Some(*span)).with_code(code);
+        match error {
+            SemanticError::AssignToUndeclared {
+                name,
+                hint: AssignHint::DeclaredLater(decl),
+                ..
+            } => diagnostic.with_note(Some(*decl), format!("'{}' is declared here", name)),
+            _ => diagnostic,
+        }
+    }
+}
+
+impl From<&TypeError> for Diagnostic {
+    fn from(error: &TypeError) -> Self {
+        Diagnostic::error(error.to_string(), Some(error.span())).with_code(ErrorCode::TypeMismatch)
+    }
+}
+
+impl From<&SemanticWarning> for Diagnostic {
+    fn from(warning: &SemanticWarning) -> Self {
+        let (code, span) = match warning {
+            SemanticWarning::UnusedVariable { span, .. }
+            | SemanticWarning::NeverRead { span, .. } => (ErrorCode::UnusedVariable, span),
+            SemanticWarning::Shadowing { span, .. } => (ErrorCode::Shadowing, span),
+            SemanticWarning::ReservedName { span, .. } => (ErrorCode::ReservedName, span),
+            SemanticWarning::FutureKeyword { span, .. } => (ErrorCode::FutureKeyword, span),
+            SemanticWarning::UnreachableArm { span, .. }
+            | SemanticWarning::DuplicateArm { span, .. } => (ErrorCode::UnreachableArm, span),
+        };
+        let diagnostic = Diagnostic::warning(warning.to_string(), Some(*span)).with_code(code);
+        match warning {
+            SemanticWarning::Shadowing { name, shadowed, .. } => diagnostic.with_note(
+                Some(*shadowed),
+                format!("'{}' is first declared here", name),
+            ),
+            _ => diagnostic,
+        }
+    }
+}
+
+impl From<&CodegenError> for Diagnostic {
+    fn from(error: &CodegenError) -> Self {
+        match error {
+            // The verifier's report and the dump go in notes of their own
+            CodegenError::VerificationFailed {
+                function,
+                messages,
+                ir_dump,
+            } => {
+                let message = format!("verification of function '{}' failed", function);
+                let mut diagnostic = Diagnostic::error(message, None);
+                for line in messages.lines().filter(|line| !line.trim().is_empty()) {
+                    diagnostic = diagnostic.with_note(None, line.trim());
+                }
+                if let Some(path) = ir_dump {
+                    let note = format!("module IR written to {}", path.display());
+                    diagnostic = diagnostic.with_note(None, note);
+                }
+                diagnostic
+            }
+            error => {
+                let diagnostic = Diagnostic::error(error.to_string(), error.span());
+                // The same mistakes as the semantic checks find, for trees
+                // that didn't go through them
+                let code = match error {
+                    CodegenError::UndefinedVariable { .. }
+                    | CodegenError::UnknownFunction { .. } => ErrorCode::UndefinedVariable,
+                    CodegenError::AssignToUndeclared { .. } => ErrorCode::AssignToUndeclared,
+                    CodegenError::WrongArgumentCount { .. } => ErrorCode::WrongArgumentCount,
+                    CodegenError::NonExhaustiveMatch { .. } => ErrorCode::NonExhaustiveMatch,
+                    CodegenError::BreakOutsideLoop { .. } => ErrorCode::BreakOutsideLoop,
+                    CodegenError::IndexOutOfBounds { .. }
+                    | CodegenError::SliceOutOfBounds { .. } => ErrorCode::IndexOutOfBounds,
+                    _ => return diagnostic,
+                };
+                diagnostic.with_code(code)
+            }
+        }
+    }
+}
+
+/// When to color diagnostics (`--color`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color when stderr is a terminal and `NO_COLOR` isn't set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether diagnostics written to stderr get colors.
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            // https://no-color.org: any non-empty value turns colors off
+            ColorChoice::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && io::stderr().is_terminal()
+            }
+        }
+    }
+}
+
+/// How diagnostics are written (`--error-format`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// For people to read: see `render`.
+    #[default]
+    Human,
+    /// A JSON object per line, for editors: see `render_json`.
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "human" => Some(ErrorFormat::Human),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+fn paint(color: bool, style: &str, text: &str) -> String {
+    if color {
+        format!("{}{}{}", style, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Renders `diagnostic`, found in `file` (when it comes from one). The source
+/// line is shown when `source` is the code of `file` and the diagnostic has a
+/// span in it, and so is the line of each note with a span. The result ends
+/// with a newline.
+pub fn render(
+    diagnostic: &Diagnostic,
+    file: Option<&str>,
+    source: Option<&str>,
+    color: bool,
+) -> String {
+    let (label, style) = match diagnostic.severity {
+        Severity::Error => ("error", RED),
+        Severity::Warning => ("warning", YELLOW),
+    };
+    let label = match diagnostic.code {
+        Some(code) => format!("{}[{}]", label, code),
+        None => label.to_string(),
+    };
+    let mut out = header(&label, style, &diagnostic.message, color);
+    if let Some(file) = file {
+        out.push_str(&location(file, diagnostic.span, source, style, color));
+    }
+    for (span, note) in &diagnostic.notes {
+        match (file, span) {
+            // A note about a place in the file points at it the same way
+            (Some(file), Some(span)) => {
+                out.push_str(&header("note", GREEN, note, color));
+                out.push_str(&location(file, Some(*span), source, GREEN, color));
+            }
+            _ => out.push_str(&format!(
+                "{} {}\n",
+                paint(color, BLUE, "  ="),
+                paint(color, BOLD, &format!("note: {}", note))
+            )),
+        }
+    }
+    out
+}
+
+/// `label: message`, on a line of its own.
+fn header(label: &str, style: &str, message: &str, color: bool) -> String {
+    format!(
+        "{}{}\n",
+        paint(color, style, &format!("{}:", label)),
+        paint(color, BOLD, &format!(" {}", message))
+    )
+}
+
+/// Where `span` is in `file`, and its line underlined in `style` when
+/// `source` has it.
+fn location(
+    file: &str,
+    span: Option<Span>,
+    source: Option<&str>,
+    style: &str,
+    color: bool,
+) -> String {
+    let Some(span) = span else {
+        return format!("{} {}\n", paint(color, BLUE, " -->"), file);
+    };
+    let mut out = format!(
+        "{} {}:{}:{}\n",
+        paint(color, BLUE, " -->"),
+        file,
+        span.line,
+        span.column
+    );
+
+    let line = source.and_then(|source| source.lines().nth(span.line.wrapping_sub(1)));
+    let Some(line) = line else {
+        return out;
+    };
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    // The underline starts under the span's first character (keeping tabs so
+    // that it lines up) and doesn't go past the end of the line
+    let before: String = line
+        .chars()
+        .take(span.column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let rest = line
+        .chars()
+        .count()
+        .saturating_sub(span.column.saturating_sub(1));
+    let carets = "^".repeat(span.len.min(rest).max(1));
+    out.push_str(&format!(
+        "{}\n",
+        paint(color, BLUE, &format!("{} |", gutter))
+    ));
+    out.push_str(&format!(
+        "{} {}\n",
+        paint(color, BLUE, &format!("{} |", number)),
+        line
+    ));
+    out.push_str(&format!(
+        "{} {}{}\n",
+        paint(color, BLUE, &format!("{} |", gutter)),
+        before,
+        paint(color, style, &carets)
+    ));
+    out
+}
+
+/// Renders `diagnostic`, found in `file` (when it comes from one), as a JSON
+/// object on a line of its own, for editors and other tools:
+///
+/// ```text
+/// {"type":"diagnostic","severity":"error","code":"E0001",
+///  "message":"undefined variable 'y' at line 2, column 5","file":"main.mlia",
+///  "span":{"start":{"line":2,"column":5},"end":{"line":2,"column":6}},
+///  "notes":[{"message":"...","span":null}]}
+/// ```
+///
+/// `severity` is `error` or `warning`; `code`, `file` and `span` are null when
+/// there is none. A span ends at the column after its last character, and
+/// the spans of notes are in the same file.
+pub fn render_json(diagnostic: &Diagnostic, file: Option<&str>) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    let code = match diagnostic.code {
+        Some(code) => json_string(code.as_str()),
+        None => "null".to_string(),
+    };
+    let file = match file {
+        Some(file) => json_string(file),
+        None => "null".to_string(),
+    };
+    let notes: Vec<String> = diagnostic
+        .notes
+        .iter()
+        .map(|(span, note)| {
+            format!(
+                "{{\"message\":{},\"span\":{}}}",
+                json_string(note),
+                json_span(*span)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"type\":\"diagnostic\",\"severity\":\"{}\",\"code\":{},\"message\":{},\
+         \"file\":{},\"span\":{},\"notes\":[{}]}}\n",
+        severity,
+        code,
+        json_string(&diagnostic.message),
+        file,
+        json_span(diagnostic.span),
+        notes.join(",")
+    )
+}
+
+/// The JSON object that follows the diagnostics of a run (see `render_json`):
+/// `{"type":"summary","errors":1,"warnings":0}`, on a line of its own.
+pub fn render_json_summary(errors: usize, warnings: usize) -> String {
+    format!(
+        "{{\"type\":\"summary\",\"errors\":{},\"warnings\":{}}}\n",
+        errors, warnings
+    )
+}
+
+fn json_span(span: Option<Span>) -> String {
+    let Some(span) = span else {
+        return "null".to_string();
+    };
+    format!(
+        "{{\"start\":{{\"line\":{},\"column\":{}}},\"end\":{{\"line\":{},\"column\":{}}}}}",
+        span.line,
+        span.column,
+        span.line,
+        span.column + span.len
+    )
+}
+
+/// `text` as a JSON string, quotes included.
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Span;
+
+    fn undefined_y() -> Diagnostic {
+        let span = Span {
+            line: 2,
+            column: 5,
+            len: 1,
+        };
+        Diagnostic::error("undefined variable 'y' at line 2, column 5", Some(span))
+    }
+
+    fn at(line: usize, column: usize, len: usize) -> Option<Span> {
+        Some(Span { line, column, len })
+    }
+
+    #[test]
+    fn test_render_plain() {
+        let source = "decl x <- 1 in\n+ x y";
+        assert_eq!(
+            render(&undefined_y(), Some("main.mlia"), Some(source), false),
+            "error: undefined variable 'y' at line 2, column 5\n \
+             --> main.mlia:2:5\n  \
+             |\n\
+             2 | + x y\n  \
+             |     ^\n"
+        );
+
+        // Without the source, or a position, what is known is still shown
+        assert_eq!(
+            render(&undefined_y(), Some("main.mlia"), None, false),
+            "error: undefined variable 'y' at line 2, column 5\n --> main.mlia:2:5\n"
+        );
+        let warning = Diagnostic::warning("something", None);
+        assert_eq!(render(&warning, None, None, false), "warning: something\n");
+
+        // The code goes with the severity
+        let coded = undefined_y().with_code(ErrorCode::UndefinedVariable);
+        assert_eq!(
+            render(&coded, None, None, false),
+            "error[E0001]: undefined variable 'y' at line 2, column 5\n"
+        );
+    }
+
+    #[test]
+    fn test_render_notes() {
+        let source = "decl x <- 1 in\ndecl x <- 2 in x";
+        let warning = Diagnostic::from(&SemanticWarning::Shadowing {
+            name: "x".to_string(),
+            span: at(2, 6, 1).unwrap(),
+            shadowed: at(1, 6, 1).unwrap(),
+        });
+        assert_eq!(
+            render(&warning, Some("main.mlia"), Some(source), false),
+            "warning[W0002]: declaration of 'x' at line 2, column 6 shadows the one at line 1, column 6\n \
+             --> main.mlia:2:6\n  \
+             |\n\
+             2 | decl x <- 2 in x\n  \
+             |      ^\n\
+             note: 'x' is first declared here\n \
+             --> main.mlia:1:6\n  \
+             |\n\
+             1 | decl x <- 1 in\n  \
+             |      ^\n"
+        );
+
+        // Notes without a place (or a file to find it in) go on a line each
+        let error = undefined_y()
+            .with_note(None, "one")
+            .with_note(at(1, 1, 1), "two");
+        assert_eq!(
+            render(&error, None, None, false),
+            "error: undefined variable 'y' at line 2, column 5\n  = note: one\n  = note: two\n"
+        );
+    }
+
+    #[test]
+    fn test_every_phase_reports_diagnostics() {
+        use crate::parser::parse_tokens;
+        use crate::tokenizer::Lexer;
+
+        let lex = Lexer::new("+ x $".to_string()).tokenize().unwrap_err();
+        let diagnostic = Diagnostic::from(&lex);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.span, at(1, 5, 1));
+        assert!(
+            diagnostic.message.starts_with("Tokenization error: "),
+            "{:?}",
+            diagnostic
+        );
+
+        let tokens = Lexer::new("print )".to_string()).tokenize().unwrap();
+        let diagnostic = Diagnostic::from(&parse_tokens(tokens).unwrap_err());
+        assert_eq!(
+            diagnostic,
+            Diagnostic::error(
+                "Parse error: unexpected ')' at line 1, column 7",
+                at(1, 7, 1)
+            )
+            .with_code(ErrorCode::SyntaxError)
+        );
+
+        // Assigned before its declaration: the note points at the declaration
+        let diagnostics = crate::check_str("x <- 5;\ndecl x <- 0 in x");
+        let error = diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.severity == Severity::Error)
+            .unwrap();
+        assert_eq!(error.span, at(1, 1, 1));
+        assert_eq!(
+            error.notes,
+            [(at(2, 6, 1), "'x' is declared here".to_string())]
+        );
+
+        let ast = crate::parser::parse_program("decl x <- 1 in\ndecl x <- + x 1 in x".to_string());
+        let warnings = crate::semantic::lint(&ast.unwrap());
+        let diagnostic = Diagnostic::from(&warnings[0]);
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.span, at(2, 6, 1));
+        assert_eq!(diagnostic.notes[0].0, at(1, 6, 1));
+
+        let diagnostics = crate::check_str("try (err read_line)");
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(
+            diagnostic.message,
+            "expected int but found string at line 1, column 1"
+        );
+        assert_eq!(
+            diagnostic.span.map(|span| (span.line, span.column)),
+            Some((1, 1))
+        );
+
+        let error = crate::run_str("decl a <- [1 2 3] in get a 3").unwrap_err();
+        assert_eq!(
+            error.diagnostics(),
+            [Diagnostic::error(
+                "index 3 out of bounds (len 3) at line 1, column 22",
+                at(1, 22, 3)
+            )
+            .with_code(ErrorCode::IndexOutOfBounds)]
+        );
+
+        // The verifier's report goes in notes
+        let error = CodegenError::VerificationFailed {
+            function: "main".to_string(),
+            messages: "Terminator found in the middle of a basic block!\n".to_string(),
+            ir_dump: Some("main.ll".into()),
+        };
+        let diagnostic = Diagnostic::from(&error);
+        assert_eq!(diagnostic.message, "verification of function 'main' failed");
+        assert_eq!(diagnostic.code, None);
+        assert_eq!(
+            diagnostic.notes,
+            [
+                (
+                    None,
+                    "Terminator found in the middle of a basic block!".to_string()
+                ),
+                (None, "module IR written to main.ll".to_string()),
+            ]
+        );
+    }
+
+    /// The example programs of an explanation: the one with the problem and
+    /// the one without.
+    fn examples(explanation: &str) -> Vec<&str> {
+        explanation
+            .split("```mlia\n")
+            .skip(1)
+            .map(|block| &block[..block.find("```").unwrap()])
+            .collect()
+    }
+
+    /// What building and running `source` reports, warnings included.
+    fn diagnostics_of(source: &str) -> Vec<Diagnostic> {
+        let options = crate::Options::default();
+        let sources = [crate::Source {
+            name: "example.mlia".to_string(),
+            code: source.to_string(),
+        }];
+        let program = match crate::load_sources(&sources, &options) {
+            Ok(program) => program,
+            Err(error) => return error.diagnostics(),
+        };
+        let mut diagnostics: Vec<Diagnostic> =
+            program.warnings.iter().map(Diagnostic::from).collect();
+        if let Err(error) = program.run(&options) {
+            diagnostics.extend(error.diagnostics());
+        }
+        diagnostics
+    }
+
+    #[test]
+    fn test_every_code_is_explained_and_reported() {
+        for code in ErrorCode::ALL {
+            assert_eq!(ErrorCode::parse(code.as_str()), Some(code));
+            assert_eq!(
+                ErrorCode::ALL
+                    .iter()
+                    .filter(|other| other.as_str() == code.as_str())
+                    .count(),
+                1,
+                "{} is used twice",
+                code
+            );
+
+            let [wrong, right] = examples(code.explanation())[..] else {
+                panic!("the explanation of {} needs two examples", code);
+            };
+            let reported = diagnostics_of(wrong);
+            assert!(
+                reported
+                    .iter()
+                    .any(|diagnostic| diagnostic.code == Some(code)),
+                "the example of {} reports {:#?}",
+                code,
+                reported
+            );
+            for diagnostic in &reported {
+                assert!(diagnostic.code.is_some(), "{:?} has no code", diagnostic);
+            }
+            assert_eq!(diagnostics_of(right), [], "the fix of {}", code);
+        }
+        assert_eq!(
+            ErrorCode::parse("e0005"),
+            Some(ErrorCode::NonExhaustiveMatch)
+        );
+        assert_eq!(ErrorCode::parse("E0000"), None);
+    }
+
+    #[test]
+    fn test_render_json() {
+        let error = undefined_y()
+            .with_code(ErrorCode::UndefinedVariable)
+            .with_note(at(1, 6, 1), "'x' is \"here\"\tor\\there");
+        assert_eq!(
+            render_json(&error, Some("main.mlia")),
+            "{\"type\":\"diagnostic\",\"severity\":\"error\",\"code\":\"E0001\",\
+             \"message\":\"undefined variable 'y' at line 2, column 5\",\"file\":\"main.mlia\",\
+             \"span\":{\"start\":{\"line\":2,\"column\":5},\"end\":{\"line\":2,\"column\":6}},\
+             \"notes\":[{\"message\":\"'x' is \\\"here\\\"\\tor\\\\there\",\
+             \"span\":{\"start\":{\"line\":1,\"column\":6},\"end\":{\"line\":1,\"column\":7}}}]}\n"
+        );
+
+        // What isn't known is null
+        let warning = Diagnostic::warning("line\nbreak \u{1}", None);
+        assert_eq!(
+            render_json(&warning, None),
+            "{\"type\":\"diagnostic\",\"severity\":\"warning\",\"code\":null,\
+             \"message\":\"line\\nbreak \\u0001\",\"file\":null,\"span\":null,\"notes\":[]}\n"
+        );
+        assert_eq!(
+            render_json_summary(2, 1),
+            "{\"type\":\"summary\",\"errors\":2,\"warnings\":1}\n"
+        );
+        assert_eq!(ErrorFormat::parse("json"), Some(ErrorFormat::Json));
+        assert_eq!(ErrorFormat::parse("xml"), None);
+    }
+
+    #[test]
+    fn test_render_underlines_the_span_within_the_line() {
+        let diagnostic = Diagnostic {
+            span: Some(Span {
+                line: 1,
+                column: 3,
+                len: 40,
+            }),
+            ..undefined_y()
+        };
+        let rendered = render(&diagnostic, Some("f.mlia"), Some("\tx \"abc"), false);
+        assert!(
+            rendered.ends_with("1 | \tx \"abc\n  | \t ^^^^^\n"),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn test_render_colored() {
+        let rendered = render(
+            &undefined_y(),
+            Some("main.mlia"),
+            Some("decl x <- 1 in\n+ x y"),
+            true,
+        );
+        assert!(
+            rendered.starts_with("\x1b[1;31merror:\x1b[0m\x1b[1m undefined"),
+            "{:?}",
+            rendered
+        );
+        assert!(
+            rendered.contains("\x1b[1;34m -->\x1b[0m main.mlia:2:5"),
+            "{:?}",
+            rendered
+        );
+        assert!(rendered.contains("\x1b[1;31m^\x1b[0m"), "{:?}", rendered);
+
+        let warning = Diagnostic {
+            severity: Severity::Warning,
+            ..undefined_y()
+        };
+        assert!(render(&warning, None, None, true).starts_with("\x1b[1;33mwarning:"));
+        assert_eq!(
+            ColorChoice::parse("always").map(ColorChoice::enabled),
+            Some(true)
+        );
+        assert_eq!(
+            ColorChoice::parse("never").map(ColorChoice::enabled),
+            Some(false)
+        );
+        assert_eq!(ColorChoice::parse("sometimes"), None);
+    }
+}
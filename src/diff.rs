@@ -0,0 +1,206 @@
+//! Line diffs in the unified format of `diff -u`, for `mlia test` to show how
+//! the output of a program differs from the expected one.
+//!
+//! Outputs are small, so the lines are matched with the textbook longest
+//! common subsequence table, after setting aside the lines both texts start
+//! and end with.
+
+use std::ops::Range;
+
+/// Lines of context around each change.
+const CONTEXT: usize = 3;
+
+/// A line of one text or the other, with its `\n` if it has one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Both(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// How `new` differs from `old`, as `diff -u` would show it with the files
+/// named `old_name` and `new_name`; empty when they are the same.
+pub fn unified(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    let lines = diff_lines(old, new);
+    let mut diff = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for hunk in hunks(&lines) {
+        let before = &lines[..hunk.start];
+        let lines = &lines[hunk];
+        let count = |lines: &[Line], removed: bool| {
+            lines
+                .iter()
+                .filter(|line| match line {
+                    Line::Both(_) => true,
+                    Line::Removed(_) => removed,
+                    Line::Added(_) => !removed,
+                })
+                .count()
+        };
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(count(before, true), count(lines, true)),
+            range(count(before, false), count(lines, false))
+        ));
+        for line in lines {
+            let (sign, text) = match line {
+                Line::Both(text) => (' ', text),
+                Line::Removed(text) => ('-', text),
+                Line::Added(text) => ('+', text),
+            };
+            diff.push(sign);
+            diff.push_str(text);
+            if !text.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    diff
+}
+
+/// The lines of `old` and `new` in order, each one marked as in both, only
+/// in `old` or only in `new`.
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<Line<'a>> {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    // common[i][j] is how many lines a[i..] and b[j..] have in common
+    let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<Line> = old[..prefix].iter().map(|&line| Line::Both(line)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push(Line::Both(a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || common[i + 1][j] >= common[i][j + 1]) {
+            // Removals go before the additions that replace them
+            lines.push(Line::Removed(a[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(b[j]));
+            j += 1;
+        }
+    }
+    lines.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|&line| Line::Both(line)),
+    );
+    lines
+}
+
+/// The parts of `lines` to show: every change with `CONTEXT` lines around
+/// it, joining changes whose context would overlap or touch.
+fn hunks(lines: &[Line]) -> Vec<Range<usize>> {
+    let mut hunks: Vec<Range<usize>> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if matches!(line, Line::Both(_)) {
+            continue;
+        }
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + 1 + CONTEXT).min(lines.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.end => hunk.end = end,
+            _ => hunks.push(start..end),
+        }
+    }
+    hunks
+}
+
+/// A hunk header's range of `count` lines after the first `before` ones: the
+/// first line and how many there are, or only the line when there is one.
+/// An empty range is given by the line before it.
+fn range(before: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_texts_have_no_diff() {
+        assert_eq!(unified("a", "b", "1\n2\n", "1\n2\n"), "");
+        assert_eq!(unified("a", "b", "", ""), "");
+    }
+
+    #[test]
+    fn test_changed_line() {
+        assert_eq!(
+            unified("expected", "output", "1\n2\n3\n", "1\n20\n3\n"),
+            "--- expected\n+++ output\n@@ -1,3 +1,3 @@\n 1\n-2\n+20\n 3\n"
+        );
+    }
+
+    #[test]
+    fn test_lines_added_and_removed() {
+        assert_eq!(
+            unified("a", "b", "", "1\n2\n"),
+            "--- a\n+++ b\n@@ -0,0 +1,2 @@\n+1\n+2\n"
+        );
+        assert_eq!(
+            unified("a", "b", "1\n2\n3\n", "1\n3\n"),
+            "--- a\n+++ b\n@@ -1,3 +1,2 @@\n 1\n-2\n 3\n"
+        );
+    }
+
+    #[test]
+    fn test_distant_changes_get_a_hunk_each() {
+        // The numbers 1 to 20, a line each, with some replaced by words
+        fn numbers(words: &[(u32, &str)]) -> String {
+            (1..=20)
+                .map(|n| match words.iter().find(|(number, _)| *number == n) {
+                    Some((_, word)) => format!("{}\n", word),
+                    None => format!("{}\n", n),
+                })
+                .collect()
+        }
+        let old = numbers(&[]);
+        let new = numbers(&[(2, "two"), (19, "nineteen")]);
+        assert_eq!(
+            unified("a", "b", &old, &new),
+            "--- a\n+++ b\n\
+             @@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -16,5 +16,5 @@\n 16\n 17\n 18\n-19\n+nineteen\n 20\n"
+        );
+
+        // With six lines between them, their context touches
+        let new = numbers(&[(2, "two"), (9, "nine")]);
+        let diff = unified("a", "b", &old, &new);
+        assert_eq!(diff.matches("@@ -").count(), 1, "{}", diff);
+    }
+
+    #[test]
+    fn test_missing_newline_at_the_end() {
+        assert_eq!(
+            unified("a", "b", "1\n", "1"),
+            "--- a\n+++ b\n@@ -1 +1 @@\n-1\n+1\n\\ No newline at end of file\n"
+        );
+    }
+}
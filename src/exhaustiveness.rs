@@ -0,0 +1,251 @@
+//! Exhaustiveness of `match`: whether the arms of a match have a pattern for
+//! every value the scrutinee can have, and if not, which values they leave
+//! out. `semantic::resolve` reports what it finds, and codegen refuses to
+//! compile a match that isn't exhaustive.
+//!
+//! A pattern that matches anything (`_`, a name) makes any match exhaustive.
+//! Otherwise, options need `some` and `none`, results `ok` and `err` (with
+//! payloads that are exhaustive in turn), and ints a literal for each value
+//! when the scrutinee can only have a few (see `Expr::known_values`), or
+//! comparisons that together take in every int.
+
+use crate::parser::{CmpOp, Expr, Pattern};
+use crate::printer::pattern_source;
+use std::fmt;
+
+/// What a match that isn't exhaustive leaves out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonExhaustive {
+    /// The int literals the arms match, in order.
+    pub covered: Vec<i64>,
+    pub missing: Missing,
+}
+
+/// The values no arm matches.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Missing {
+    /// Cases of an option or a result, or of a scrutinee that can only have
+    /// a few values, that no arm matches.
+    Cases(Vec<Pattern>),
+    /// An int that no arm matches, when any int is possible.
+    Value(i64),
+}
+
+impl fmt::Display for NonExhaustive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "match expression doesn't cover every value: ")?;
+        match &self.missing {
+            Missing::Cases(cases) => {
+                let cases: Vec<String> = cases.iter().map(pattern_source).collect();
+                let plural = if cases.len() == 1 { "" } else { "s" };
+                write!(f, "missing case{}: {}", plural, cases.join(", "))?;
+            }
+            Missing::Value(value) if self.covered.is_empty() => {
+                write!(f, "value {} is not handled", value)?;
+            }
+            Missing::Value(value) => {
+                let covered: Vec<String> = self.covered.iter().map(i64::to_string).collect();
+                write!(
+                    f,
+                    "value {} is not handled (the arms cover {})",
+                    value,
+                    covered.join(", ")
+                )?;
+            }
+        }
+        write!(f, "; add '| _ ->' to handle the rest")
+    }
+}
+
+/// Checks that the arms of `match scrutinee with arms` cover every value of
+/// the scrutinee.
+pub fn check(scrutinee: &Expr, patterns: &[&Pattern]) -> Result<(), NonExhaustive> {
+    match missing(patterns, scrutinee.known_values()) {
+        None => Ok(()),
+        Some(missing) => Err(NonExhaustive {
+            covered: patterns
+                .iter()
+                .filter_map(|pattern| match pattern.unbound() {
+                    Pattern::Literal(n) => Some(*n),
+                    _ => None,
+                })
+                .collect(),
+            missing,
+        }),
+    }
+}
+
+/// What `patterns` leave out of the values of a scrutinee that can only be
+/// `values` (any value, if `None`).
+fn missing(patterns: &[&Pattern], values: Option<&[i64]>) -> Option<Missing> {
+    if patterns.iter().any(|pattern| pattern.is_irrefutable()) {
+        return None;
+    }
+    let patterns: Vec<&Pattern> = patterns.iter().map(|pattern| pattern.unbound()).collect();
+    let is_option = |p: &&Pattern| matches!(p, Pattern::Some(_) | Pattern::None);
+    let is_result = |p: &&Pattern| matches!(p, Pattern::Ok(_) | Pattern::Err(_));
+    let constructors: [fn(Box<Pattern>) -> Pattern; 2] = if patterns.iter().any(is_option) {
+        [Pattern::Some, |_| Pattern::None]
+    } else if patterns.iter().any(is_result) {
+        [Pattern::Ok, Pattern::Err]
+    } else {
+        return missing_ints(&patterns, values);
+    };
+
+    // Every constructor needs an arm, and the payloads of its arms have to
+    // be exhaustive in turn
+    let wildcard = || Box::new(Pattern::Wildcard);
+    let mut cases = Vec::new();
+    for constructor in constructors {
+        let tag = std::mem::discriminant(&constructor(wildcard()));
+        let with_tag: Vec<&Pattern> = (patterns.iter().copied())
+            .filter(|pattern| std::mem::discriminant(*pattern) == tag)
+            .collect();
+        if with_tag.is_empty() {
+            cases.push(constructor(wildcard()));
+            continue;
+        }
+        let payloads: Vec<&Pattern> = with_tag.iter().filter_map(|p| p.payload()).collect();
+        if payloads.is_empty() {
+            continue;
+        }
+        let left_out = match missing(&payloads, None) {
+            None => continue,
+            Some(Missing::Value(value)) => vec![Pattern::Literal(value)],
+            Some(Missing::Cases(left_out)) => left_out,
+        };
+        cases.extend(left_out.into_iter().map(|case| constructor(Box::new(case))));
+    }
+    (!cases.is_empty()).then_some(Missing::Cases(cases))
+}
+
+/// What the literals and comparisons in `patterns` leave out of the ints a
+/// scrutinee can be.
+fn missing_ints(patterns: &[&Pattern], values: Option<&[i64]>) -> Option<Missing> {
+    let ranges: Vec<(i64, i64)> = patterns.iter().filter_map(|p| int_range(p)).collect();
+    let is_covered = |n: i64| {
+        ranges
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&n))
+    };
+    if let Some(values) = values {
+        let cases: Vec<Pattern> = (values.iter())
+            .filter(|value| !is_covered(**value))
+            .map(|value| Pattern::Literal(*value))
+            .collect();
+        return (!cases.is_empty()).then_some(Missing::Cases(cases));
+    }
+    // The smallest int from 0 up that the ranges don't take in (or else the
+    // one closest to 0 below) is 0 or right before or after a range
+    let mut candidates = vec![0];
+    for (first, last) in &ranges {
+        candidates.extend(first.checked_sub(1));
+        candidates.extend(last.checked_add(1));
+    }
+    candidates.sort_by_key(|n| (*n < 0, n.unsigned_abs()));
+    candidates
+        .into_iter()
+        .find(|n| !is_covered(*n))
+        .map(Missing::Value)
+}
+
+/// The first and the last of the ints a literal or relational pattern matches.
+pub fn int_range(pattern: &Pattern) -> Option<(i64, i64)> {
+    match pattern.unbound() {
+        Pattern::Literal(n) => Some((*n, *n)),
+        Pattern::Cmp(CmpOp::Less, n) => Some((i64::MIN, n.checked_sub(1)?)),
+        Pattern::Cmp(CmpOp::LessEq, n) => Some((i64::MIN, *n)),
+        Pattern::Cmp(CmpOp::Greater, n) => Some((n.checked_add(1)?, i64::MAX)),
+        Pattern::Cmp(CmpOp::GreaterEq, n) => Some((*n, i64::MAX)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    /// The message about the match `source`, if it isn't exhaustive.
+    fn check_source(source: &str) -> Result<(), String> {
+        let ast = parse_program(source.to_string()).unwrap();
+        let Expr::Match(scrutinee, arms, _) = &ast else {
+            panic!("Expected Match expression");
+        };
+        let patterns: Vec<&Pattern> = arms.iter().map(|(pattern, _)| pattern).collect();
+        check(scrutinee, &patterns).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn test_exhaustive_matches() {
+        assert_eq!(check_source("match x with | 1 -> 1 | _ -> 2"), Ok(()));
+        assert_eq!(check_source("match x with | 1 -> 1 | n -> n"), Ok(()));
+        assert_eq!(check_source("match (< x 1) with | 1 -> 1 | 0 -> 2"), Ok(()));
+        let nested = "match x with | some (some _) -> 1 | some none -> 2 | none -> 3";
+        assert_eq!(check_source(nested), Ok(()));
+        assert_eq!(
+            check_source("match x with | ok n -> n | err _ -> 0"),
+            Ok(())
+        );
+        assert_eq!(
+            check_source("match x with | < 0 -> 0 | 0 -> 1 | >= 1 -> 2"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_ints_left_out() {
+        assert_eq!(
+            check_source("match x with | 0 -> 1 | 1 -> 2 | 3 -> 4"),
+            Err(
+                "match expression doesn't cover every value: value 2 is not handled \
+                 (the arms cover 0, 1, 3); add '| _ ->' to handle the rest"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check_source("match x with | < 0 -> 0 | > 0 -> 1"),
+            Err(
+                "match expression doesn't cover every value: value 0 is not handled; \
+                 add '| _ ->' to handle the rest"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check_source("match x with | >= 0 -> 0 | -1 -> 1"),
+            Err(
+                "match expression doesn't cover every value: value -2 is not handled \
+                 (the arms cover -1); add '| _ ->' to handle the rest"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_cases_left_out() {
+        assert_eq!(
+            check_source("match (= x 1) with | 0 -> 1"),
+            Err(
+                "match expression doesn't cover every value: missing case: 1; \
+                 add '| _ ->' to handle the rest"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check_source("match x with | some 1 -> 1 | some (> 5) -> 2"),
+            Err(
+                "match expression doesn't cover every value: missing cases: some 0, none; \
+                 add '| _ ->' to handle the rest"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check_source("match x with | ok (some n) -> n | err _ -> 0"),
+            Err(
+                "match expression doesn't cover every value: missing case: ok none; \
+                 add '| _ ->' to handle the rest"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,36 @@
+//! Entry points for fuzzers (see `fuzz/`): each one takes arbitrary bytes
+//! through the first phases of the compiler, without LLVM.
+//!
+//! None of them may panic, abort or run for longer than the size of the
+//! input warrants, whatever the input: every problem with it must come out
+//! as an error. The bytes are read as UTF-8, with invalid sequences replaced.
+
+use crate::parser::{Expr, Import, ParseError, Token, parse_tokens};
+use crate::semantic;
+use crate::tokenizer::{LexError, Lexer};
+
+/// Tokenizes `data`.
+pub fn fuzz_lex(data: &[u8]) {
+    let _ = lex(data);
+}
+
+/// Tokenizes and parses `data`.
+pub fn fuzz_parse(data: &[u8]) {
+    let _ = parse(data);
+}
+
+/// Tokenizes, parses and checks `data`.
+pub fn fuzz_check(data: &[u8]) {
+    if let Ok(Ok((_, expr))) = parse(data) {
+        let _ = semantic::resolve(&expr);
+        let _ = semantic::lint(&expr);
+    }
+}
+
+fn lex(data: &[u8]) -> Result<Vec<Token>, LexError> {
+    Lexer::new(String::from_utf8_lossy(data).into_owned()).tokenize()
+}
+
+fn parse(data: &[u8]) -> Result<Result<(Vec<Import>, Expr), ParseError>, LexError> {
+    lex(data).map(parse_tokens)
+}
@@ -0,0 +1,1089 @@
+//! The MLIA compiler as a library.
+//!
+//! The modules are the compiler's phases: `tokenizer` and `parser` turn source
+//! code into an [`Expr`], `semantic` checks it (with `exhaustiveness` for
+//! matches), `types` infers its types, `optimizer` simplifies it and
+//! `codegen` turns it into LLVM IR, to run with the JIT or link into an
+//! executable; every phase reports what went wrong as a [`Diagnostic`] (with
+//! an [`ErrorCode`] when it is about the program), which `diagnostics` shows
+//! on the terminal, and `printer` turns an [`Expr`] back into source code.
+//! The functions at the top level run the whole pipeline:
+//!
+//! - [`compile_file`] builds an executable from a source file, and
+//!   [`build_each`] one from each of several sources, in parallel,
+//! - [`run_str`] runs a program given as a string with the JIT,
+//! - [`check_str`] only reports what is wrong with a program, without LLVM,
+//! - [`LoadedProgram::bench`] runs a program many times and times the runs
+//!   (see `bench`),
+//! - [`LoadedProgram::run_captured`] runs a program on a given input and
+//!   returns what it printed, for `mlia test` to compare with `diff`,
+//! - [`tokenize_file`] and [`parse_file`] stop after the first phases,
+//!
+//! and [`load_files`] (or [`load_sources`]) with [`LoadedProgram::build`] /
+//! [`LoadedProgram::run`] do the same in steps, for programs made of several
+//! files (this is what the `mlia` binary uses).
+
+#[cfg(test)]
+mod arbitrary;
+pub mod bench;
+pub mod codegen;
+pub mod diagnostics;
+pub mod diff;
+pub mod exhaustiveness;
+pub mod fuzz;
+pub mod optimizer;
+pub mod parser;
+pub mod printer;
+pub mod program;
+pub mod semantic;
+pub mod timings;
+pub mod tokenizer;
+pub mod types;
+
+use bench::{BenchReport, Runner};
+use codegen::{CodeGen, CodegenError, CompileTarget, Emit};
+pub use diagnostics::{Diagnostic, ErrorCode, Severity};
+use inkwell::context::Context;
+use parser::{
+    Expr, ParseError, Span, Token, parse_tokens, parse_tokens_with_limit, without_imports,
+};
+use semantic::{SemanticError, SemanticWarning};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{panic, process, slice, thread};
+use timings::Timings;
+use tokenizer::{FutureKeyword, LexError, Lexer};
+use types::{TypeError, TypeTable};
+
+/// How a program is compiled. The default builds an optimized native
+/// executable named after the (last) input file, in the current directory.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Path of the executable; `None` derives it from the input file name
+    /// (`out` for standard input).
+    pub output: Option<PathBuf>,
+    /// Directory every file is written to: relative `output` and
+    /// `verbose_file` paths are relative to it. Created if missing; `None` is
+    /// the current directory.
+    pub out_dir: Option<PathBuf>,
+    pub target: CompileTarget,
+    /// Propagate constants and run LLVM's optimization passes.
+    pub optimize: bool,
+    /// Emit DWARF debug info for the (last) input file.
+    pub debug_info: bool,
+    /// The files a build writes (see `codegen::Emit`).
+    pub emit: Emit,
+    /// Don't report declarations that shadow another variable.
+    pub allow_shadowing: bool,
+    /// When running with the JIT, stop the program after this many loop
+    /// iterations and function calls.
+    pub max_iterations: Option<u64>,
+    /// Write the tokens, the AST as parsed and the LLVM IR to `verbose_file`.
+    pub verbose: bool,
+    /// Where `verbose` writes; `None` is next to the executable, as
+    /// `<name>_verbose.txt`.
+    pub verbose_file: Option<PathBuf>,
+    /// The same, each one to its own file next to the executable.
+    pub dump: Dump,
+    /// Print `dump` to standard output instead of writing files.
+    pub dump_to_stdout: bool,
+    /// Time each phase (see `timings`).
+    pub timings: bool,
+    /// Stop the program when an array index is out of bounds.
+    pub bounds_checks: bool,
+    /// Report the blocks of `alloc` that were never freed when the program
+    /// ends.
+    pub debug_heap: bool,
+    /// How deeply parentheses, brackets, loops and matches can be nested (see
+    /// `parser::parse_tokens_with_limit`); generated code may need more than
+    /// the default.
+    pub max_nesting: usize,
+    /// Run LLVM's verifier on the generated code (see `CodeGen::set_verify`).
+    pub verify: bool,
+    /// The version of the language sources are read as, unless they ask for
+    /// another one with a pragma (see `tokenizer::STAGED_KEYWORDS`).
+    pub lang_version: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            output: None,
+            out_dir: None,
+            target: CompileTarget::Native,
+            optimize: true,
+            debug_info: false,
+            emit: Emit::default(),
+            allow_shadowing: false,
+            max_iterations: None,
+            verbose: false,
+            verbose_file: None,
+            dump: Dump::default(),
+            dump_to_stdout: false,
+            timings: false,
+            bounds_checks: true,
+            debug_heap: false,
+            max_nesting: parser::MAX_NESTING,
+            verify: true,
+            lang_version: tokenizer::LANG_VERSION,
+        }
+    }
+}
+
+impl Options {
+    /// Where a file written at `path` ends up, given `out_dir`.
+    pub fn out_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        match &self.out_dir {
+            Some(dir) => dir.join(path),
+            None => path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Creates `out_dir` (and its parents) if it doesn't exist yet.
+    pub fn create_out_dir(&self) -> Result<(), Error> {
+        match &self.out_dir {
+            Some(dir) => fs::create_dir_all(dir).map_err(|error| Error::Io {
+                path: dir.clone(),
+                error,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What to dump about a program: its tokens, its AST as parsed and its LLVM IR.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Dump {
+    pub tokens: bool,
+    pub ast: bool,
+    pub ir: bool,
+}
+
+impl Dump {
+    pub const KINDS: [&'static str; 3] = ["tokens", "ast", "ir"];
+
+    /// Everything the verbose file has.
+    pub const ALL: Dump = Dump {
+        tokens: true,
+        ast: true,
+        ir: true,
+    };
+
+    /// Adds the kinds in `list`, separated by commas (`--dump=tokens,ir`).
+    pub fn add(&mut self, list: &str) -> Result<(), String> {
+        for kind in list.split(',') {
+            match kind {
+                "tokens" => self.tokens = true,
+                "ast" => self.ast = true,
+                "ir" => self.ir = true,
+                _ => {
+                    return Err(format!(
+                        "invalid --dump value '{}'; valid values are {}",
+                        kind,
+                        Self::KINDS.join(", ")
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sections(self) -> Vec<Section> {
+        [
+            (self.tokens, Section::Tokens),
+            (self.ast, Section::Ast),
+            (self.ir, Section::Ir),
+        ]
+        .into_iter()
+        .filter_map(|(wanted, section)| wanted.then_some(section))
+        .collect()
+    }
+}
+
+/// A part of the verbose file, and of `--dump`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Tokens,
+    Ast,
+    Ir,
+}
+
+impl Section {
+    fn title(self) -> &'static str {
+        match self {
+            Section::Tokens => "TOKENS",
+            Section::Ast => "ABSTRACT SYNTAX TREE",
+            Section::Ir => "LLVM IR CODE",
+        }
+    }
+
+    /// What the file it is dumped to ends with, after the executable's name.
+    fn extension(self) -> &'static str {
+        match self {
+            Section::Tokens => "tokens.txt",
+            Section::Ast => "ast.txt",
+            Section::Ir => "ll",
+        }
+    }
+}
+
+/// The files written by a build.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Artifacts {
+    /// `None` when `options.emit` leaves the executable out.
+    pub executable: Option<PathBuf>,
+    /// The IR, assembly and object files asked for with `options.emit`.
+    pub intermediates: Vec<PathBuf>,
+    pub verbose_file: Option<PathBuf>,
+    /// The files written for `options.dump`.
+    pub dumps: Vec<PathBuf>,
+    /// How long each phase took, from lexing to linking, if `options.timings`.
+    pub timings: Option<Timings>,
+    /// What the build went on without: a wasm32 module is left an unlinked
+    /// object (in `intermediates`, with no `executable`) if there's no
+    /// `wasm-ld`.
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Why a program couldn't be compiled or run.
+#[derive(Debug)]
+pub enum Error {
+    /// A file couldn't be read or written.
+    Io { path: PathBuf, error: io::Error },
+    /// A file doesn't tokenize or parse; `span` is where, when the input
+    /// didn't just end too early.
+    Parse {
+        file: String,
+        message: String,
+        span: Option<Span>,
+        code: Option<ErrorCode>,
+    },
+    /// The files of a program don't fit together (see `program::link_files`).
+    Link(String),
+    /// An `import` names a file that can't be read, or files import each other.
+    Import(String),
+    /// The program uses undeclared names. `warnings` are the ones found along
+    /// the way, so that they can be reported with the errors.
+    Semantic {
+        errors: Vec<SemanticError>,
+        warnings: Vec<SemanticWarning>,
+    },
+    /// Values of one type are used where values of another are needed (see
+    /// `types::infer`).
+    Types {
+        errors: Vec<TypeError>,
+        warnings: Vec<SemanticWarning>,
+    },
+    /// Code generation, linking or the JIT-ed program itself failed.
+    Codegen(CodegenError),
+    /// The options don't make sense together.
+    InvalidOptions(String),
+    /// The program returned `first` on its first run but `result` on run
+    /// number `run` (see `bench::measure`).
+    UnstableResult { run: usize, first: i64, result: i64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            Error::Parse { message, .. } => write!(f, "{}", message),
+            Error::Link(message) | Error::Import(message) | Error::InvalidOptions(message) => {
+                write!(f, "{}", message)
+            }
+            Error::Semantic { errors, .. } => {
+                write!(f, "{} error(s) found, nothing was compiled", errors.len())
+            }
+            Error::Types { errors, .. } => {
+                write!(
+                    f,
+                    "{} type error(s) found, nothing was compiled",
+                    errors.len()
+                )
+            }
+            Error::Codegen(error) => write!(f, "{}", error),
+            Error::UnstableResult { run, first, result } => write!(
+                f,
+                "run {} returned {}, but the first run returned {}",
+                run, result, first
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { error, .. } => Some(error),
+            Error::Codegen(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    fn tokenization(file: &str, error: LexError) -> Self {
+        Error::parse(file, Diagnostic::from(&error))
+    }
+
+    fn syntax(file: &str, error: ParseError) -> Self {
+        Error::parse(file, Diagnostic::from(&error))
+    }
+
+    fn parse(file: &str, diagnostic: Diagnostic) -> Self {
+        Error::Parse {
+            file: file.to_string(),
+            message: diagnostic.message,
+            span: diagnostic.span,
+            code: diagnostic.code,
+        }
+    }
+
+    /// What to report for the error: for a program with errors, the warnings
+    /// and then the errors found in it. How many errors there were is what
+    /// the error displays as.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Error::Parse {
+                message,
+                span,
+                code,
+                ..
+            } => vec![Diagnostic {
+                code: *code,
+                ..Diagnostic::error(message.clone(), *span)
+            }],
+            Error::Semantic { errors, warnings } => warnings
+                .iter()
+                .map(Diagnostic::from)
+                .chain(errors.iter().map(Diagnostic::from))
+                .collect(),
+            Error::Types { errors, warnings } => warnings
+                .iter()
+                .map(Diagnostic::from)
+                .chain(errors.iter().map(Diagnostic::from))
+                .collect(),
+            Error::Codegen(error) => vec![Diagnostic::from(error)],
+            Error::Io { .. }
+            | Error::Link(_)
+            | Error::Import(_)
+            | Error::InvalidOptions(_)
+            | Error::UnstableResult { .. } => vec![Diagnostic::error(self.to_string(), None)],
+        }
+    }
+}
+
+impl From<CodegenError> for Error {
+    fn from(error: CodegenError) -> Self {
+        Error::Codegen(error)
+    }
+}
+
+/// A program that was parsed and checked, ready to be built or run.
+#[derive(Debug, Clone)]
+pub struct LoadedProgram {
+    /// The program as parsed (and linked, for several files).
+    pub ast: Expr,
+    /// Warnings about the program; they don't stop it from compiling.
+    pub warnings: Vec<SemanticWarning>,
+    /// The type of every declaration.
+    pub types: TypeTable,
+    /// The files it was loaded from; the last one is the program itself.
+    pub files: Vec<String>,
+    /// How long lexing, parsing and checking took, if `options.timings`.
+    pub timings: Option<Timings>,
+    /// The tokens of every file, only collected for the verbose file.
+    tokens: Vec<Token>,
+}
+
+/// The source code of one file of a program, under the name messages use for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub name: String,
+    pub code: String,
+}
+
+impl Source {
+    /// The name of the source read from standard input.
+    pub const STDIN: &'static str = "<stdin>";
+    /// The name of the source given on the command line (see `inline`).
+    pub const CMDLINE: &'static str = "<cmdline>";
+
+    /// The program made of `expressions` given on the command line (`mlia
+    /// -e`), one after the other as a sequence.
+    pub fn inline(expressions: &[String]) -> Self {
+        Source {
+            name: Self::CMDLINE.to_string(),
+            code: expressions.join("; "),
+        }
+    }
+
+    /// Reads the file at `path`, or standard input when `path` is `-`.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            let mut code = String::new();
+            io::stdin()
+                .read_to_string(&mut code)
+                .map_err(|error| Error::Io {
+                    path: PathBuf::from(Self::STDIN),
+                    error,
+                })?;
+            return Ok(Source {
+                name: Self::STDIN.to_string(),
+                code,
+            });
+        }
+        let code = fs::read_to_string(path).map_err(|error| Error::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Ok(Source {
+            name: path.display().to_string(),
+            code,
+        })
+    }
+}
+
+/// Reads, parses and checks a program made of `files` (`-` is standard input);
+/// see `load_sources`.
+pub fn load_files<P: AsRef<Path>>(files: &[P], options: &Options) -> Result<LoadedProgram, Error> {
+    let sources = files
+        .iter()
+        .map(Source::read)
+        .collect::<Result<Vec<_>, _>>()?;
+    load_sources(&sources, options)
+}
+
+/// Parses and checks a program made of `sources`: every source but the last
+/// one contributes declarations, the last one is the program (see
+/// `program::link_files`). The files a source imports come right before it,
+/// each one only once. Tokens are kept for `options.verbose_file`. Nothing
+/// here touches LLVM; that waits for `build` or `run`.
+pub fn load_sources(sources: &[Source], options: &Options) -> Result<LoadedProgram, Error> {
+    let mut loader = Loader {
+        keep_tokens: options.verbose || options.dump.tokens,
+        max_nesting: options.max_nesting,
+        lang_version: options.lang_version,
+        timings: options.timings.then(Timings::default),
+        ..Loader::default()
+    };
+    for source in sources {
+        loader.load(source.clone())?;
+    }
+    let names = loader.files.iter().map(|(name, _)| name.clone()).collect();
+    let ast = program::link_files(loader.files).map_err(Error::Link)?;
+
+    let mut timings = loader.timings;
+    let warnings = loader.warnings;
+    let program = timings::time(timings.as_mut(), timings::SEMANTIC, || {
+        check(ast, warnings, options)
+    })?;
+    Ok(LoadedProgram {
+        files: names,
+        timings,
+        tokens: loader.tokens,
+        ..program
+    })
+}
+
+/// Parses sources along with the files they import.
+struct Loader {
+    keep_tokens: bool,
+    max_nesting: usize,
+    lang_version: u32,
+    /// Files already parsed, so that a file imported twice is only parsed once.
+    loaded: HashSet<PathBuf>,
+    /// The files being loaded, each one imported by the one before it.
+    stack: Vec<(Option<PathBuf>, String)>,
+    /// Parsed files, every one after the files it imports.
+    files: Vec<(String, Expr)>,
+    tokens: Vec<Token>,
+    /// Warnings from the lexer, about every file.
+    warnings: Vec<SemanticWarning>,
+    timings: Option<Timings>,
+}
+
+impl Default for Loader {
+    fn default() -> Self {
+        Loader {
+            keep_tokens: false,
+            max_nesting: parser::MAX_NESTING,
+            lang_version: tokenizer::LANG_VERSION,
+            loaded: HashSet::new(),
+            stack: Vec::new(),
+            files: Vec::new(),
+            tokens: Vec::new(),
+            warnings: Vec::new(),
+            timings: None,
+        }
+    }
+}
+
+impl Loader {
+    fn load(&mut self, source: Source) -> Result<(), Error> {
+        // Sources that aren't files on disk (standard input, code given as a
+        // string) can't be imported, so there's nothing to track for them
+        let path = fs::canonicalize(&source.name).ok();
+        if let Some(path) = &path {
+            if let Some(start) = self
+                .stack
+                .iter()
+                .position(|(p, _)| p.as_ref() == Some(path))
+            {
+                let chain: Vec<&str> = self.stack[start..]
+                    .iter()
+                    .map(|(_, name)| name.as_str())
+                    .chain([source.name.as_str()])
+                    .collect();
+                return Err(Error::Import(format!(
+                    "Import cycle: {}",
+                    chain.join(" -> ")
+                )));
+            }
+            if !self.loaded.insert(path.clone()) {
+                return Ok(());
+            }
+        }
+
+        let lang_version = self.lang_version;
+        let (tokens, warnings) = timings::time(self.timings.as_mut(), timings::LEXING, || {
+            lex(&source, lang_version)
+        })?;
+        self.warnings.extend(warnings);
+        if self.keep_tokens {
+            self.tokens.extend(tokens.iter().cloned());
+        }
+        let max_nesting = self.max_nesting;
+        let (imports, ast) = timings::time(self.timings.as_mut(), timings::PARSING, || {
+            parse_tokens_with_limit(tokens, max_nesting)
+        })
+        .map_err(|error| Error::syntax(&source.name, error))?;
+
+        // Imports are relative to the importing file; for standard input and
+        // the command line, to the current directory
+        let dir = match source.name.as_str() {
+            Source::STDIN | Source::CMDLINE => Path::new(""),
+            name => Path::new(name).parent().unwrap_or(Path::new("")),
+        };
+        self.stack.push((path, source.name.clone()));
+        for import in imports {
+            let imported = dir.join(&import.path);
+            let code = fs::read_to_string(&imported).map_err(|error| {
+                Error::Import(format!(
+                    "{}:{}:{}: cannot import '{}': {}",
+                    source.name, import.span.line, import.span.column, import.path, error
+                ))
+            })?;
+            self.load(Source {
+                name: imported.display().to_string(),
+                code,
+            })?;
+        }
+        self.stack.pop();
+
+        self.files.push((source.name, ast));
+        Ok(())
+    }
+}
+
+/// Runs the semantic passes on `ast`: fails with every undefined name at once,
+/// before any LLVM state is built. `lexical` are the warnings from reading
+/// the program, which go before the ones found here.
+fn check(
+    mut ast: Expr,
+    lexical: Vec<SemanticWarning>,
+    options: &Options,
+) -> Result<LoadedProgram, Error> {
+    let (errors, found) = analyze(&mut ast, options);
+    let warnings = [lexical, found].concat();
+    if !errors.is_empty() {
+        return Err(Error::Semantic { errors, warnings });
+    }
+    let types = match types::infer(&ast) {
+        Ok(types) => types,
+        Err(errors) => return Err(Error::Types { errors, warnings }),
+    };
+    Ok(LoadedProgram {
+        ast,
+        warnings,
+        types,
+        files: Vec::new(),
+        timings: None,
+        tokens: Vec::new(),
+    })
+}
+
+/// Lowers the named arguments of `ast`, then lints and resolves it.
+fn analyze(ast: &mut Expr, options: &Options) -> (Vec<SemanticError>, Vec<SemanticWarning>) {
+    let mut errors = semantic::lower_named_arguments(ast);
+    let mut warnings = semantic::lint(ast);
+    if options.allow_shadowing {
+        warnings.retain(|warning| !matches!(warning, SemanticWarning::Shadowing { .. }));
+    }
+    errors.extend(semantic::resolve(ast));
+    (errors, warnings)
+}
+
+impl LoadedProgram {
+    /// Compiles the program to an executable (or a WebAssembly module), and
+    /// whatever else `options.emit` asks for.
+    pub fn build(&self, options: &Options) -> Result<Artifacts, Error> {
+        let executable = self.executable_path(options);
+        options.create_out_dir()?;
+
+        let mut timings = options
+            .timings
+            .then(|| self.timings.clone().unwrap_or_default());
+        let ast = timings::time(timings.as_mut(), timings::AST_OPTIMIZATION, || {
+            self.optimized(options)
+        });
+        let context = Context::create();
+        let mut codegen = self.codegen(&context, options)?;
+        let intermediates = codegen.compile_to_executable(&ast, &executable.to_string_lossy())?;
+        if let (Some(timings), Some(codegen_timings)) = (&mut timings, codegen.take_timings()) {
+            timings.extend(&codegen_timings);
+        }
+        let unlinked = codegen.unlinked_output().map(|output| {
+            Diagnostic::warning(
+                format!(
+                    "wasm-ld not found; {} is an unlinked object",
+                    output.display()
+                ),
+                None,
+            )
+        });
+        let verbose_file = self.write_verbose_file(&codegen, options)?;
+        let dumps = self.write_dumps(&codegen, options)?;
+        Ok(Artifacts {
+            executable: (options.emit.exe && unlinked.is_none()).then_some(executable),
+            intermediates,
+            verbose_file,
+            dumps,
+            timings,
+            warnings: unlinked.into_iter().collect(),
+        })
+    }
+
+    /// Where `build` writes the executable; the other files it writes are
+    /// named after it.
+    pub fn executable_path(&self, options: &Options) -> PathBuf {
+        options.out_path(match &options.output {
+            Some(output) => output.clone(),
+            None => {
+                let stem = match self.files.last() {
+                    Some(file) if file == Source::STDIN || file == Source::CMDLINE => "out",
+                    file => file
+                        .and_then(|file| Path::new(file).file_stem())
+                        .and_then(|s| s.to_str())
+                        .unwrap_or("a.out"),
+                };
+                match options.target {
+                    // WebAssembly modules get their usual extension by default
+                    CompileTarget::Wasm32 => PathBuf::from(format!("{}.wasm", stem)),
+                    CompileTarget::Native => PathBuf::from(stem),
+                }
+            }
+        })
+    }
+
+    /// Runs the program with the JIT and returns its result.
+    pub fn run(&self, options: &Options) -> Result<i64, Error> {
+        if options.target != CompileTarget::Native {
+            return Err(Error::InvalidOptions(
+                "the JIT is only available for the native target".to_string(),
+            ));
+        }
+
+        let context = Context::create();
+        let mut codegen = self.codegen(&context, options)?;
+        let ast = self.optimized(options);
+        let result = match options.max_iterations {
+            Some(limit) => codegen.execute_with_limit(&ast, limit)?,
+            None => codegen.execute_program(&ast)?,
+        };
+        self.write_verbose_file(&codegen, options)?;
+        self.write_dumps(&codegen, options)?;
+        Ok(result)
+    }
+
+    /// Runs the program with the JIT, with `input` as its standard input, and
+    /// returns its result along with what it printed (see
+    /// `CodeGen::execute_captured`). `options.max_iterations` applies as in
+    /// `run`.
+    pub fn run_captured(&self, options: &Options, input: &str) -> Result<(i64, String), Error> {
+        if options.target != CompileTarget::Native {
+            return Err(Error::InvalidOptions(
+                "the JIT is only available for the native target".to_string(),
+            ));
+        }
+
+        let context = Context::create();
+        let mut codegen = self.codegen(&context, options)?;
+        let ast = self.optimized(options);
+        Ok(codegen.execute_captured(&ast, input, options.max_iterations)?)
+    }
+
+    /// Compiles the program once, the way `settings.runner` says, and runs it
+    /// as many times as `settings` asks, timing each run (see
+    /// `bench::measure`). The executable is built to a temporary file, removed
+    /// afterwards.
+    pub fn bench(
+        &self,
+        options: &Options,
+        settings: &bench::Settings,
+    ) -> Result<BenchReport, Error> {
+        if options.target != CompileTarget::Native {
+            return Err(Error::InvalidOptions(
+                "only native programs can be benchmarked".to_string(),
+            ));
+        }
+
+        match settings.runner {
+            Runner::Jit => {
+                let context = Context::create();
+                let mut codegen = self.codegen(&context, options)?;
+                let ast = self.optimized(options);
+                let compiled = codegen.compile_to_callable(&ast)?;
+                bench::measure(settings, bench::wall_clock(), || Ok(compiled.run()?))
+            }
+            Runner::Executable => {
+                let name = self.executable_path(options);
+                let name = name.file_name().unwrap_or_default().to_string_lossy();
+                let executable =
+                    env::temp_dir().join(format!("mlia-bench-{}-{}", process::id(), name));
+                let build_options = Options {
+                    output: Some(executable.clone()),
+                    emit: Emit::default(),
+                    verbose: false,
+                    dump: Dump::default(),
+                    timings: false,
+                    ..options.clone()
+                };
+                self.build(&build_options)?;
+                let report = bench::measure(settings, bench::wall_clock(), || {
+                    let status = process::Command::new(&executable)
+                        .status()
+                        .map_err(|error| Error::Io {
+                            path: executable.clone(),
+                            error,
+                        })?;
+                    Ok(i64::from(exit_code(status)))
+                });
+                let _ = fs::remove_file(&executable);
+                report
+            }
+        }
+    }
+
+    fn optimized(&self, options: &Options) -> Cow<'_, Expr> {
+        if options.optimize {
+            Cow::Owned(optimizer::propagate_constants(&self.ast))
+        } else {
+            Cow::Borrowed(&self.ast)
+        }
+    }
+
+    fn codegen<'ctx>(
+        &self,
+        context: &'ctx Context,
+        options: &Options,
+    ) -> Result<CodeGen<'ctx>, Error> {
+        let mut codegen = CodeGen::new(context)?;
+        codegen.set_emit(options.emit);
+        codegen.set_optimize(options.optimize);
+        codegen.set_bounds_checks(options.bounds_checks);
+        codegen.set_debug_heap(options.debug_heap);
+        codegen.set_verify(options.verify);
+        if options.timings {
+            codegen.enable_timings();
+        }
+        if let Some(file) = self.files.last() {
+            codegen.set_source_name(file)?;
+        }
+        if options.target != CompileTarget::Native {
+            codegen.set_target(options.target)?;
+        }
+        if options.debug_info
+            && let Some(file) = self.files.last()
+        {
+            codegen.enable_debug_info(file);
+        }
+        Ok(codegen)
+    }
+
+    /// Where `options.verbose` writes, if it is on.
+    pub fn verbose_path(&self, options: &Options) -> Option<PathBuf> {
+        if !options.verbose {
+            return None;
+        }
+        Some(match &options.verbose_file {
+            Some(path) => options.out_path(path),
+            None => {
+                let executable = self.executable_path(options);
+                let stem = executable
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("out");
+                executable.with_file_name(format!("{}_verbose.txt", stem))
+            }
+        })
+    }
+
+    /// Writes the tokens, the AST as parsed (not as optimized) and the IR of
+    /// the compiled program to `verbose_path`, if `options.verbose` is on.
+    fn write_verbose_file(
+        &self,
+        codegen: &CodeGen,
+        options: &Options,
+    ) -> Result<Option<PathBuf>, Error> {
+        let Some(path) = &self.verbose_path(options) else {
+            return Ok(None);
+        };
+        options.create_out_dir()?;
+        let io_error = |error| Error::Io {
+            path: path.clone(),
+            error,
+        };
+        let mut verbose_file = fs::File::create(path).map_err(io_error)?;
+        self.write_sections(&mut verbose_file, &Dump::ALL.sections(), codegen)
+            .map_err(io_error)?;
+        Ok(Some(path.clone()))
+    }
+
+    /// Writes what `options.dump` asks for, each part to its own file named
+    /// after the executable (`foo.tokens.txt`, `foo.ast.txt`, `foo.ll`), or
+    /// all of them to standard output.
+    fn write_dumps(&self, codegen: &CodeGen, options: &Options) -> Result<Vec<PathBuf>, Error> {
+        let sections = options.dump.sections();
+        if options.dump_to_stdout {
+            self.write_sections(&mut io::stdout().lock(), &sections, codegen)
+                .map_err(|error| Error::Io {
+                    path: PathBuf::from("<stdout>"),
+                    error,
+                })?;
+            return Ok(Vec::new());
+        }
+
+        let executable = self.executable_path(options);
+        let mut written = Vec::new();
+        for section in sections {
+            let path = PathBuf::from(format!("{}.{}", executable.display(), section.extension()));
+            let write = || -> io::Result<()> {
+                let mut file = fs::File::create(&path)?;
+                // The IR starts with its own header, and stays valid IR
+                if section != Section::Ir {
+                    write_header(&mut file, section)?;
+                }
+                self.write_section(&mut file, section, codegen)
+            };
+            write().map_err(|error| Error::Io {
+                path: path.clone(),
+                error,
+            })?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// Writes `sections` one after the other, each under its header.
+    fn write_sections(
+        &self,
+        out: &mut impl Write,
+        sections: &[Section],
+        codegen: &CodeGen,
+    ) -> io::Result<()> {
+        for &section in sections {
+            write_header(out, section)?;
+            self.write_section(out, section, codegen)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn write_section(
+        &self,
+        out: &mut impl Write,
+        section: Section,
+        codegen: &CodeGen,
+    ) -> io::Result<()> {
+        match section {
+            Section::Tokens => {
+                for (i, token) in self.tokens.iter().enumerate() {
+                    writeln!(out, "{:4}: {:?}", i + 1, token)?;
+                }
+                Ok(())
+            }
+            Section::Ast => writeln!(out, "{:#?}", self.ast),
+            Section::Ir => codegen.write_ir(out),
+        }
+    }
+}
+
+/// The status to exit with for a program that exited with `status`: its exit
+/// code, or 128 plus the signal that killed it, like shells do.
+pub fn exit_code(status: process::ExitStatus) -> u8 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return (128 + signal) as u8;
+    }
+    status.code().unwrap_or(1) as u8
+}
+
+fn write_header(out: &mut impl Write, section: Section) -> io::Result<()> {
+    writeln!(out, "{}", "=".repeat(80))?;
+    writeln!(out, "{}", section.title())?;
+    writeln!(out, "{}", "=".repeat(80))
+}
+
+/// Compiles the source file at `path` to an executable.
+pub fn compile_file(path: impl AsRef<Path>, options: Options) -> Result<Artifacts, Error> {
+    load_files(&[path], &options)?.build(&options)
+}
+
+/// What `build_each` did with one of its sources.
+#[derive(Debug)]
+pub struct FileBuild {
+    /// The name of the source.
+    pub file: String,
+    /// The warnings about the program, if it got as far as being checked
+    /// (`Error::Semantic` and `Error::Types` carry their own).
+    pub warnings: Vec<SemanticWarning>,
+    pub result: Result<Artifacts, Error>,
+}
+
+/// Builds each of `sources` as a program of its own, with its own LLVM
+/// context, on as many threads as the machine can run at once: each thread
+/// takes the next source nobody has taken yet until there are none left. A
+/// source that fails doesn't stop the others; the builds come back in the
+/// order of `sources`, whichever finishes first. Every executable is named
+/// after its source, so `options.output` and `options.verbose_file` only
+/// make sense for one.
+pub fn build_each(sources: &[Source], options: &Options) -> Vec<FileBuild> {
+    let build = |source: &Source| {
+        let mut warnings = Vec::new();
+        let result = load_sources(slice::from_ref(source), options).and_then(|program| {
+            warnings = program.warnings.clone();
+            program.build(options)
+        });
+        FileBuild {
+            file: source.name.clone(),
+            warnings,
+            result,
+        }
+    };
+    let next = AtomicUsize::new(0);
+    let work = || {
+        let mut builds = Vec::new();
+        loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let Some(source) = sources.get(index) else {
+                return builds;
+            };
+            builds.push((index, build(source)));
+        }
+    };
+    let parallelism = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut builds: Vec<(usize, FileBuild)> = thread::scope(|scope| {
+        let threads: Vec<_> = (0..parallelism.min(sources.len()))
+            .map(|_| scope.spawn(work))
+            .collect();
+        (threads.into_iter())
+            .flat_map(|thread| thread.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    });
+    builds.sort_by_key(|(index, _)| *index);
+    builds.into_iter().map(|(_, build)| build).collect()
+}
+
+/// Splits `source` into tokens, without parsing them.
+pub fn tokenize(source: &Source) -> Result<Vec<Token>, Error> {
+    tokenize_with(source, &Options::default())
+}
+
+/// The same as `tokenize`, with the language version in `options`.
+pub fn tokenize_with(source: &Source, options: &Options) -> Result<Vec<Token>, Error> {
+    let (tokens, _) = lex(source, options.lang_version)?;
+    Ok(tokens)
+}
+
+/// The tokens of `source` read as version `version` of the language, and the
+/// identifiers in it that are keywords in a later version.
+fn lex(source: &Source, version: u32) -> Result<(Vec<Token>, Vec<SemanticWarning>), Error> {
+    let mut lexer = Lexer::new(source.code.clone()).with_version(version);
+    let tokens = lexer
+        .tokenize()
+        .map_err(|error| Error::tokenization(&source.name, error))?;
+    let warnings = lexer.future_keywords().iter().map(future_keyword).collect();
+    Ok((tokens, warnings))
+}
+
+fn future_keyword(keyword: &FutureKeyword) -> SemanticWarning {
+    SemanticWarning::FutureKeyword {
+        name: keyword.word.to_string(),
+        version: keyword.version,
+        span: keyword.span,
+    }
+}
+
+/// Parses `source`, without checking the program or following its imports.
+pub fn parse(source: &Source) -> Result<Expr, Error> {
+    parse_with(source, &Options::default())
+}
+
+/// The same as `parse`, with the limits in `options` (`max_nesting`) and its
+/// language version.
+pub fn parse_with(source: &Source, options: &Options) -> Result<Expr, Error> {
+    let (_, ast) = parse_tokens_with_limit(tokenize_with(source, options)?, options.max_nesting)
+        .map_err(|error| Error::syntax(&source.name, error))?;
+    Ok(ast)
+}
+
+/// Splits the source file at `path` into tokens, without parsing them.
+pub fn tokenize_file(path: impl AsRef<Path>) -> Result<Vec<Token>, Error> {
+    tokenize(&Source::read(path)?)
+}
+
+/// Parses the source file at `path`, without checking the program.
+pub fn parse_file(path: impl AsRef<Path>) -> Result<Expr, Error> {
+    parse(&Source::read(path)?)
+}
+
+/// Runs the program in `source` with the JIT and returns its result.
+pub fn run_str(source: &str) -> Result<i64, Error> {
+    let options = Options::default();
+    let (ast, warnings) = parse_str(source)?;
+    check(ast, warnings, &options)?.run(&options)
+}
+
+/// Parses a program given as a string, which can't import anything, along
+/// with the warnings from reading it.
+fn parse_str(code: &str) -> Result<(Expr, Vec<SemanticWarning>), Error> {
+    let source = Source {
+        name: "<string>".to_string(),
+        code: code.to_string(),
+    };
+    let (tokens, warnings) = lex(&source, tokenizer::LANG_VERSION)?;
+    let ast = parse_tokens(tokens)
+        .and_then(without_imports)
+        .map_err(|error| Error::syntax(&source.name, error))?;
+    Ok((ast, warnings))
+}
+
+/// Checks the program in `source` without compiling it, and returns every
+/// error and warning found: none when the program is fine, and it compiles
+/// unless one is an error. Only the lexer, the parser, the semantic checks and
+/// type inference run, so LLVM is never started.
+pub fn check_str(source: &str) -> Vec<Diagnostic> {
+    let options = Options::default();
+    match parse_str(source).and_then(|(ast, warnings)| check(ast, warnings, &options)) {
+        Ok(program) => program.warnings.iter().map(Diagnostic::from).collect(),
+        Err(error) => error.diagnostics(),
+    }
+}
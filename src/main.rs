@@ -1,127 +1,1162 @@
-mod codegen;
-mod parser;
-mod tokenizer;
-
-use codegen::CodeGen;
-use inkwell::context::Context;
-use parser::{parse_program, parse_program_verbose};
-use std::env::args;
+use mlia::bench::{self, Runner};
+use mlia::codegen::{CompileTarget, Emit};
+use mlia::diagnostics::{ColorChoice, ErrorFormat, render, render_json, render_json_summary};
+use mlia::parser::{Expr, MAX_NESTING, Token};
+use mlia::tokenizer::{LANG_VERSION, LATEST_LANG_VERSION};
+use mlia::{
+    Diagnostic, Dump, Error, ErrorCode, Options, Severity, Source, build_each, diff, exit_code,
+    load_sources, parse_with, tokenize_with,
+};
+use std::cell::Cell;
+use std::env::{self, args};
 use std::fs;
-use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::{process, slice};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = args().collect();
+const USAGE: &str = "\
+Usage: mlia [build] <file>... [-o <output>] [--emit=ir,asm,obj,exe] [options]
+       mlia build --each <file>... [options]
+       mlia -e <expression>... [-o <output>] [options]
+       mlia run <file>... [options] [-- <args>...]
+       mlia test <file>... --expect <file> [--input <file>] [options]
+       mlia check <file>...
+       mlia bench <file>... [--iterations <n>] [--warmup <n>] [--runner <runner>] [--json]
+       mlia types <file>...
+       mlia tokens [--json] <file>
+       mlia ast [--json] <file>
+       mlia --explain <code>
 
-    if args.len() < 2 {
-        return Err("Please provide an input file as a command line argument.".into());
+Options:
+  -e <expression>           Use <expression> as the program, after any files, and
+                            run it unless -o or --emit ask for a build (repeatable;
+                            the expressions are joined with ;)
+  -o, --output <path>       Path of the executable
+  --out-dir <dir>           Write every file to <dir> (created if missing)
+  --emit <list>             Files to build: ir, asm, obj, exe (default: exe)
+  --keep-intermediates      Same as adding obj to --emit
+  --target <triple>         native (default) or wasm32-unknown-unknown
+  -g                        Emit debug info
+  --no-opt                  Don't optimize
+  --bounds-checks           Stop the program on array indices out of bounds
+                            (the default for run and --no-opt)
+  --no-bounds-checks        Don't check array indices at runtime
+  --debug-heap              Report blocks of alloc never freed when the program ends
+  --no-verify               Don't run LLVM's verifier, to see the IR a broken
+                            part of the compiler produces (for developers)
+  --max-iterations <n>      Stop `run` or `test` after n loop iterations and calls
+                            (`test` stops after 100000000 unless told otherwise)
+  --max-nesting <n>         Allow parentheses, loops and matches n levels deep
+                            (default: 1000)
+  --lang-version <n>        Read the program as version n of the language: 1 (the
+                            default), or 2, where `until` is a keyword
+  --allow-shadowing         Don't warn about shadowed variables
+  --timings                 Print how long each phase of build or check took
+  -q, --quiet               Only print errors and warnings
+  --color <when>            Color errors and warnings: auto (default), always, never
+  --error-format <format>   human (default), or json: a JSON object per error or
+                            warning and a summary at the end, without status lines
+  --verbose                 Write tokens, AST and IR to <output>_verbose.txt
+  --verbose-out <path>      Same as --verbose, writing to <path>
+  --dump <list>             Write tokens, ast and/or ir to <output>.tokens.txt,
+                            <output>.ast.txt and <output>.ll (repeatable)
+  --dump-to <where>         file (default) or stdout
+  --ast-json <path>         Also write the parsed program as JSON (- for stdout)
+  --tokens-json <path>      Also write the tokens of the program as JSON (- for stdout)
+  --jit                     Same as `run`
+  --run                     With `build`, run the executable once built
+  --each                    With `build`, build every file as a program of its own,
+                            in parallel
+  --expect <file>           With `test`, what the program must print
+  --input <file>            With `test`, what the program reads from standard input
+                            (by default, nothing)
+  --keep                    Keep the executable that `run -- <args>` or `--run` built
+  --tokens                  Same as `tokens`
+  --types                   Same as `types`
+  --json                    With `ast`, `tokens` or `bench`, print JSON
+  --iterations <n>          Runs `bench` times (default: 10)
+  --warmup <n>              Runs `bench` makes first without timing them (default: 0)
+  --runner <runner>         How `bench` runs the program: jit (default), or exe to
+                            build an executable and start it for each run
+  --explain <code>          Explain an error or warning code, such as E0001
+  -h, --help                Print this help
+  -V, --version             Print the version
+
+Subcommands:
+  build    Compile the program to an executable (the default)
+  run      Run the program with the JIT and print its result
+  test     Run the program with the JIT and compare what it prints with --expect,
+           showing the differences as a unified diff
+  check    Report errors and warnings without compiling
+  bench    Compile the program once, run it many times and print how long it took
+  types    Print the inferred type of every declaration
+  tokens   Print the tokens of a file, with their line and column
+  ast      Print the syntax tree of a file
+
+The JSON options but `bench --json` need mlia built with `--features serde`.
+Every file but the last one contributes declarations; the last one is the program
+(with --each, every file is a program, and its executable is named after it).
+A file named - is read from standard input. With arguments after --, `run` builds
+an executable (removed afterwards unless --keep or -o) and runs it with them.";
+
+/// How many loop iterations and function calls `test` lets a program run for
+/// when `--max-iterations` doesn't say, so that one stuck in a loop fails
+/// instead of hanging.
+const TEST_MAX_ITERATIONS: u64 = 100_000_000;
+
+/// What the compiler was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Build,
+    Run,
+    Test,
+    Check,
+    Bench,
+    Types,
+    Tokens,
+    Ast,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "build" => Some(Command::Build),
+            "run" => Some(Command::Run),
+            "test" => Some(Command::Test),
+            "check" => Some(Command::Check),
+            "bench" => Some(Command::Bench),
+            "types" => Some(Command::Types),
+            "tokens" => Some(Command::Tokens),
+            "ast" => Some(Command::Ast),
+            _ => None,
+        }
+    }
+}
+
+/// What the command line asks for besides the compiler's `Options`.
+struct Cli {
+    quiet: bool,
+    /// Print the AST or the tokens as JSON (`ast --json`, `tokens --json`).
+    json: bool,
+    /// Also write the AST as JSON here (`-` is stdout).
+    ast_json: Option<String>,
+    /// Also write the tokens of the program as JSON here (`-` is stdout).
+    tokens_json: Option<String>,
+    report: Reporter,
+    /// Run the executable once built, with these arguments (`run -- <args>`,
+    /// `build --run`).
+    run_args: Option<Vec<String>>,
+    /// Don't remove the executable `run_args` runs.
+    keep: bool,
+    /// Build every file as a program of its own (`build --each`).
+    each: bool,
+    /// How many times `bench` runs the program, and how.
+    bench: bench::Settings,
+    /// The file with the output `test` expects, and the one the program reads
+    /// as its standard input.
+    expect: Option<PathBuf>,
+    input: Option<PathBuf>,
+}
+
+/// Why the compiler stopped: the command line itself is wrong, or the program
+/// couldn't be compiled or run (which was already reported, see `Reporter`).
+enum CliError {
+    Usage(String),
+    Failed,
+}
+
+fn main() {
+    match run() {
+        Ok(status) => process::exit(status.into()),
+        Err(error) => report(error),
+    }
+}
+
+/// Exits with status 2 for command line errors, after printing them, and 1
+/// for everything else.
+fn report(error: CliError) -> ! {
+    match error {
+        CliError::Usage(message) => {
+            eprintln!("Error: {}", message);
+            eprintln!("Try 'mlia --help' for more information.");
+            process::exit(2);
+        }
+        CliError::Failed => process::exit(1),
+    }
+}
+
+/// Writes diagnostics to stderr in the format `--error-format` asks for,
+/// counting them for the summary that ends the JSON ones.
+struct Reporter {
+    format: ErrorFormat,
+    color: bool,
+    errors: Cell<usize>,
+    warnings: Cell<usize>,
+}
+
+impl Reporter {
+    fn new(format: ErrorFormat, color: bool) -> Self {
+        Reporter {
+            format,
+            color,
+            errors: Cell::new(0),
+            warnings: Cell::new(0),
+        }
+    }
+
+    /// Prints the diagnostics of `error`, with the source lines they point at.
+    fn print_failure(&self, error: &Error, sources: &[Source]) {
+        for diagnostic in error.diagnostics() {
+            let file = match error {
+                Error::Parse { file, .. } => Some(file.as_str()),
+                Error::Semantic { .. } | Error::Types { .. } | Error::Codegen(_)
+                    if diagnostic.span.is_some() =>
+                {
+                    program_file(sources)
+                }
+                _ => None,
+            };
+            self.print(&diagnostic, file, sources);
+        }
+        // How many errors there were; JSON has it in the summary
+        if matches!(error, Error::Semantic { .. } | Error::Types { .. })
+            && self.format == ErrorFormat::Human
+        {
+            let count = Diagnostic::error(error.to_string(), None);
+            eprint!("{}", render(&count, None, None, self.color));
+        }
     }
 
-    let input_file = &args[1];
-    // By default we will compile to an executable whose name is the input file's
-    // basename (without extension). The user can override this with --output/-o.
-    let input_path = std::path::Path::new(input_file);
-    let default_out = input_path
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("a.out")
-        .to_string();
-    let mut output_file: Option<String> = Some(default_out);
+    /// Prints `diagnostic` about `file`, which is one of `sources` or a file
+    /// it imports.
+    fn print(&self, diagnostic: &Diagnostic, file: Option<&str>, sources: &[Source]) {
+        let count = match diagnostic.severity {
+            Severity::Error => &self.errors,
+            Severity::Warning => &self.warnings,
+        };
+        count.set(count.get() + 1);
+        if self.format == ErrorFormat::Json {
+            eprint!("{}", render_json(diagnostic, file));
+            return;
+        }
+        let code = file.and_then(
+            |file| match sources.iter().find(|source| source.name == file) {
+                Some(source) => Some(source.code.clone()),
+                None => fs::read_to_string(file).ok(),
+            },
+        );
+        eprint!("{}", render(diagnostic, file, code.as_deref(), self.color));
+    }
+
+    /// Ends the JSON diagnostics with how many errors and warnings there were.
+    fn finish(&self) {
+        if self.format == ErrorFormat::Json {
+            eprint!(
+                "{}",
+                render_json_summary(self.errors.get(), self.warnings.get())
+            );
+        }
+    }
+}
+
+/// The file semantic diagnostics are about: spans don't record their file, and
+/// the last one is the program, which sees the declarations of the others.
+fn program_file(sources: &[Source]) -> Option<&str> {
+    sources.last().map(|source| source.name.as_str())
+}
+
+/// Does what the command line asks and returns the exit status: the result of
+/// the program for `run`, clamped to 0..=255 (a program can return 1 like a
+/// failed compile does; stderr tells them apart), 1 for a `test` whose output
+/// isn't the expected one, and 0 otherwise.
+fn run() -> Result<u8, CliError> {
+    let args: Vec<String> = args().skip(1).collect();
+
+    // What comes after `--` is for the program
+    let ours = args.iter().take_while(|arg| *arg != "--");
+    if ours.clone().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return Ok(0);
+    }
+    if ours.clone().any(|arg| arg == "--version" || arg == "-V") {
+        println!("mlia {}", env!("CARGO_PKG_VERSION"));
+        return Ok(0);
+    }
+    if let Some(i) = ours
+        .clone()
+        .position(|arg| arg == "--explain" || arg.starts_with("--explain="))
+    {
+        let code = match args[i].strip_prefix("--explain=") {
+            Some(code) => code,
+            None => args
+                .get(i + 1)
+                .map(String::as_str)
+                .ok_or_else(|| usage("--explain requires an error code, such as E0001"))?,
+        };
+        return explain(code);
+    }
+
+    // `mlia file.mlia` is short for `mlia build file.mlia`. A first argument
+    // that can't be a file is taken for a misspelled subcommand.
+    let subcommand = args
+        .first()
+        .is_some_and(|first| Command::from_name(first).is_some());
+    let (mut command, args) = match args.first() {
+        None => {
+            return Err(usage(
+                "Please provide an input file as a command line argument.",
+            ));
+        }
+        Some(first) => match Command::from_name(first) {
+            Some(command) => (command, &args[1..]),
+            None if looks_like_subcommand(first) => {
+                return Err(usage(&format!("Unknown subcommand: {}", first)));
+            }
+            None => (Command::Build, &args[..]),
+        },
+    };
+
+    // Every file but the last one contributes declarations; the last one is
+    // the program (see `program::link_files`).
+    let mut input_files = Vec::new();
+    let mut expressions = Vec::new();
+    let mut output_file: Option<String> = None;
+    let mut out_dir: Option<String> = None;
     let mut verbose = false;
+    let mut verbose_file: Option<PathBuf> = None;
+    let mut emit: Option<Emit> = None;
+    let mut dump: Option<Dump> = None;
+    let mut dump_to_stdout = false;
+    let mut keep_intermediates = false;
+    let mut debug_info = false;
+    let mut optimize = true;
+    let mut bounds_checks: Option<bool> = None;
+    let mut debug_heap = false;
+    let mut verify = true;
+    let mut allow_shadowing = false;
+    let mut json = false;
+    let mut quiet = false;
+    let mut timings = false;
+    let mut color = ColorChoice::default();
+    let mut error_format = ErrorFormat::default();
+    let mut ast_json: Option<String> = None;
+    let mut tokens_json: Option<String> = None;
+    let mut max_iterations: Option<u64> = None;
+    let mut max_nesting = MAX_NESTING;
+    let mut lang_version = LANG_VERSION;
+    let mut target = CompileTarget::Native;
+    let mut run_built = false;
+    let mut program_args: Option<Vec<String>> = None;
+    let mut keep = false;
+    let mut each = false;
+    let mut iterations: Option<usize> = None;
+    let mut warmup: Option<usize> = None;
+    let mut runner: Option<Runner> = None;
+    let mut expect: Option<PathBuf> = None;
+    let mut test_input: Option<PathBuf> = None;
 
     // Parse command line arguments
-    let mut i = 2;
+    let mut i = 0;
     while i < args.len() {
         match args[i].as_str() {
+            "-e" => {
+                if i + 1 < args.len() {
+                    expressions.push(args[i + 1].clone());
+                    i += 2;
+                } else {
+                    return Err(usage("-e requires an expression"));
+                }
+            }
             "--output" | "-o" => {
                 if i + 1 < args.len() {
                     output_file = Some(args[i + 1].clone());
                     i += 2;
                 } else {
-                    return Err("--output requires a filename".into());
+                    return Err(usage("--output requires a filename"));
+                }
+            }
+            "--out-dir" => {
+                if i + 1 < args.len() {
+                    out_dir = Some(args[i + 1].clone());
+                    i += 2;
+                } else {
+                    return Err(usage("--out-dir requires a directory"));
+                }
+            }
+            "--tokens" if command == Command::Build => {
+                command = Command::Tokens;
+                i += 1;
+            }
+            "--types" if command == Command::Build => {
+                command = Command::Types;
+                i += 1;
+            }
+            // The old spelling of `mlia run`
+            "--jit" if command == Command::Build => {
+                command = Command::Run;
+                i += 1;
+            }
+            "--color" => {
+                if i + 1 < args.len() {
+                    color = parse_color(&args[i + 1])?;
+                    i += 2;
+                } else {
+                    return Err(usage("--color requires always, never or auto"));
+                }
+            }
+            arg if arg.starts_with("--color=") => {
+                color = parse_color(&arg["--color=".len()..])?;
+                i += 1;
+            }
+            "--error-format" => {
+                if i + 1 < args.len() {
+                    error_format = parse_error_format(&args[i + 1])?;
+                    i += 2;
+                } else {
+                    return Err(usage("--error-format requires human or json"));
                 }
             }
-            "--jit" => {
-                output_file = None; // Disable output file, use JIT execution
+            arg if arg.starts_with("--error-format=") => {
+                error_format = parse_error_format(&arg["--error-format=".len()..])?;
+                i += 1;
+            }
+            "--timings" => {
+                timings = true;
+                i += 1;
+            }
+            "--run" if command == Command::Build => {
+                run_built = true;
+                i += 1;
+            }
+            "--expect" => {
+                if i + 1 < args.len() {
+                    expect = Some(PathBuf::from(&args[i + 1]));
+                    i += 2;
+                } else {
+                    return Err(usage("--expect requires a filename"));
+                }
+            }
+            "--input" => {
+                if i + 1 < args.len() {
+                    test_input = Some(PathBuf::from(&args[i + 1]));
+                    i += 2;
+                } else {
+                    return Err(usage("--input requires a filename"));
+                }
+            }
+            "--keep" => {
+                keep = true;
+                i += 1;
+            }
+            "--each" if command == Command::Build => {
+                each = true;
+                i += 1;
+            }
+            "--" => {
+                program_args = Some(args[i + 1..].to_vec());
+                break;
+            }
+            "--quiet" | "-q" => {
+                quiet = true;
                 i += 1;
             }
             "--verbose" => {
                 verbose = true;
                 i += 1;
             }
+            "--verbose-out" => {
+                if i + 1 < args.len() {
+                    verbose = true;
+                    verbose_file = Some(PathBuf::from(&args[i + 1]));
+                    i += 2;
+                } else {
+                    return Err(usage("--verbose-out requires a filename"));
+                }
+            }
+            "--emit" => {
+                if i + 1 < args.len() {
+                    emit = Some(Emit::parse(&args[i + 1]).map_err(|e| usage(&e))?);
+                    i += 2;
+                } else {
+                    return Err(usage("--emit requires a list of outputs"));
+                }
+            }
+            arg if arg.starts_with("--emit=") => {
+                emit = Some(Emit::parse(&arg["--emit=".len()..]).map_err(|e| usage(&e))?);
+                i += 1;
+            }
+            "--dump" => {
+                if i + 1 < args.len() {
+                    dump.get_or_insert_default()
+                        .add(&args[i + 1])
+                        .map_err(|e| usage(&e))?;
+                    i += 2;
+                } else {
+                    return Err(usage("--dump requires a list of tokens, ast, ir"));
+                }
+            }
+            arg if arg.starts_with("--dump=") => {
+                let list = &arg["--dump=".len()..];
+                dump.get_or_insert_default()
+                    .add(list)
+                    .map_err(|e| usage(&e))?;
+                i += 1;
+            }
+            "--dump-to" => {
+                if i + 1 < args.len() {
+                    dump_to_stdout = parse_dump_to(&args[i + 1])?;
+                    i += 2;
+                } else {
+                    return Err(usage("--dump-to requires file or stdout"));
+                }
+            }
+            arg if arg.starts_with("--dump-to=") => {
+                dump_to_stdout = parse_dump_to(&arg["--dump-to=".len()..])?;
+                i += 1;
+            }
+            // Short for adding `obj` to --emit
+            "--keep-intermediates" => {
+                keep_intermediates = true;
+                i += 1;
+            }
+            "--target" => {
+                if i + 1 < args.len() {
+                    target = CompileTarget::from_triple(&args[i + 1])
+                        .ok_or_else(|| usage(&format!("Unsupported target: {}", args[i + 1])))?;
+                    i += 2;
+                } else {
+                    return Err(usage("--target requires a target triple"));
+                }
+            }
+            "-g" => {
+                debug_info = true;
+                i += 1;
+            }
+            "--no-opt" => {
+                optimize = false;
+                i += 1;
+            }
+            "--bounds-checks" => {
+                bounds_checks = Some(true);
+                i += 1;
+            }
+            "--no-bounds-checks" => {
+                bounds_checks = Some(false);
+                i += 1;
+            }
+            "--debug-heap" => {
+                debug_heap = true;
+                i += 1;
+            }
+            "--no-verify" => {
+                verify = false;
+                i += 1;
+            }
+            "--max-iterations" => {
+                if i + 1 < args.len() {
+                    let limit = args[i + 1].parse().map_err(|_| {
+                        usage(&format!("Invalid --max-iterations: {}", args[i + 1]))
+                    })?;
+                    max_iterations = Some(limit);
+                    i += 2;
+                } else {
+                    return Err(usage("--max-iterations requires a number"));
+                }
+            }
+            "--max-nesting" => {
+                if i + 1 < args.len() {
+                    max_nesting = args[i + 1]
+                        .parse()
+                        .map_err(|_| usage(&format!("Invalid --max-nesting: {}", args[i + 1])))?;
+                    i += 2;
+                } else {
+                    return Err(usage("--max-nesting requires a number"));
+                }
+            }
+            "--lang-version" => {
+                if i + 1 < args.len() {
+                    lang_version = parse_lang_version(&args[i + 1])?;
+                    i += 2;
+                } else {
+                    return Err(usage("--lang-version requires a version"));
+                }
+            }
+            // Written by hand, so it works without the feature
+            "--json" if command == Command::Bench => {
+                json = true;
+                i += 1;
+            }
+            "--json" if matches!(command, Command::Ast | Command::Tokens) => {
+                require_json("--json")?;
+                json = true;
+                i += 1;
+            }
+            "--iterations" => {
+                if i + 1 < args.len() {
+                    iterations =
+                        Some(args[i + 1].parse().map_err(|_| {
+                            usage(&format!("Invalid --iterations: {}", args[i + 1]))
+                        })?);
+                    i += 2;
+                } else {
+                    return Err(usage("--iterations requires a number"));
+                }
+            }
+            "--warmup" => {
+                if i + 1 < args.len() {
+                    warmup = Some(
+                        args[i + 1]
+                            .parse()
+                            .map_err(|_| usage(&format!("Invalid --warmup: {}", args[i + 1])))?,
+                    );
+                    i += 2;
+                } else {
+                    return Err(usage("--warmup requires a number"));
+                }
+            }
+            "--runner" => {
+                if i + 1 < args.len() {
+                    runner = Some(parse_runner(&args[i + 1])?);
+                    i += 2;
+                } else {
+                    return Err(usage("--runner requires jit or exe"));
+                }
+            }
+            arg if arg.starts_with("--runner=") => {
+                runner = Some(parse_runner(&arg["--runner=".len()..])?);
+                i += 1;
+            }
+            "--ast-json" => {
+                require_json("--ast-json")?;
+                if i + 1 < args.len() {
+                    ast_json = Some(args[i + 1].clone());
+                    i += 2;
+                } else {
+                    return Err(usage("--ast-json requires a filename (- for stdout)"));
+                }
+            }
+            "--tokens-json" => {
+                require_json("--tokens-json")?;
+                if i + 1 < args.len() {
+                    tokens_json = Some(args[i + 1].clone());
+                    i += 2;
+                } else {
+                    return Err(usage("--tokens-json requires a filename (- for stdout)"));
+                }
+            }
+            "--allow-shadowing" => {
+                allow_shadowing = true;
+                i += 1;
+            }
+            // `-` reads the program from standard input
+            arg if arg == "-" || !arg.starts_with('-') => {
+                if arg == "-" && input_files.iter().any(|file| file == "-") {
+                    return Err(usage("standard input (-) can only be read once"));
+                }
+                input_files.push(arg.to_string());
+                i += 1;
+            }
             _ => {
-                return Err(format!("Unknown argument: {}", args[i]).into());
+                return Err(usage(&format!("Unknown argument: {}", args[i])));
             }
         }
     }
 
-    // Read the source file
-    let source_code = fs::read_to_string(input_file)?;
+    if input_files.is_empty() && expressions.is_empty() {
+        return Err(usage(
+            "Please provide an input file as a command line argument.",
+        ));
+    }
+    // Without a subcommand, an expression is run rather than built, unless
+    // what to build was given
+    if !expressions.is_empty()
+        && !subcommand
+        && command == Command::Build
+        && output_file.is_none()
+        && emit.is_none()
+        && !run_built
+    {
+        command = Command::Run;
+    }
+    // Optimized builds leave them out unless asked for
+    let bounds_checks =
+        bounds_checks.unwrap_or(matches!(command, Command::Run | Command::Test) || !optimize);
+    // Given arguments, `run` builds an executable to pass them to instead of
+    // using the JIT
+    let run_args = match (command, program_args) {
+        (Command::Run, Some(program_args)) => {
+            command = Command::Build;
+            Some(program_args)
+        }
+        (Command::Build, program_args) if run_built => Some(program_args.unwrap_or_default()),
+        (_, Some(_)) => return Err(usage("arguments after -- need mlia run or --run")),
+        (_, None) => None,
+    };
+    let json_out = ast_json.is_some() || tokens_json.is_some();
+    if each && (output_file.is_some() || verbose_file.is_some() || json_out) {
+        return Err(usage(
+            "--each names every file after its program; it can't take -o, --verbose-out, \
+             --ast-json or --tokens-json",
+        ));
+    }
+    if each && run_args.is_some() {
+        return Err(usage("--each only builds; it can't run the programs"));
+    }
+    if keep && run_args.is_none() {
+        return Err(usage("--keep only applies to mlia run -- <args> and --run"));
+    }
+    if run_args.is_some() && target != CompileTarget::Native {
+        return Err(usage("only native executables can be run"));
+    }
+    if run_args.is_some() && emit.is_some_and(|emit| !emit.exe) {
+        return Err(usage("--run needs exe in --emit"));
+    }
+    if let Some(missing) = input_files
+        .iter()
+        .find(|file| *file != "-" && !Path::new(file).exists())
+    {
+        return Err(usage(&format!("Input file not found: {}", missing)));
+    }
+    let programs = input_files.len() + usize::from(!expressions.is_empty());
+    if matches!(command, Command::Tokens | Command::Ast) && programs > 1 {
+        return Err(usage("tokens and ast take a single file"));
+    }
+    if matches!(command, Command::Run | Command::Test) && target != CompileTarget::Native {
+        return Err(usage("the JIT is only available for the native target"));
+    }
+    if emit.is_some() && command != Command::Build {
+        return Err(usage("--emit only applies to mlia build"));
+    }
+    if dump.is_some() && !matches!(command, Command::Build | Command::Run) {
+        return Err(usage("--dump only applies to mlia build and mlia run"));
+    }
+    if timings && !matches!(command, Command::Build | Command::Check) {
+        return Err(usage("--timings only applies to mlia build and mlia check"));
+    }
+    if max_iterations.is_some() && !matches!(command, Command::Run | Command::Test) {
+        return Err(usage(
+            "--max-iterations only applies to mlia run (or --jit) and mlia test",
+        ));
+    }
+    if command == Command::Test && expect.is_none() {
+        return Err(usage("mlia test requires --expect <file>"));
+    }
+    if (expect.is_some() || test_input.is_some()) && command != Command::Test {
+        return Err(usage("--expect and --input only apply to mlia test"));
+    }
+    let bench_options = iterations.is_some() || warmup.is_some() || runner.is_some();
+    if bench_options && command != Command::Bench {
+        return Err(usage(
+            "--iterations, --warmup and --runner only apply to mlia bench",
+        ));
+    }
+    if iterations == Some(0) {
+        return Err(usage("--iterations must be at least 1"));
+    }
+    if command == Command::Bench && target != CompileTarget::Native {
+        return Err(usage("only native programs can be benchmarked"));
+    }
 
-    println!("Parsing source code from {}...", input_file);
+    let mut emit = emit.unwrap_or_default();
+    emit.obj |= keep_intermediates;
+    let options = Options {
+        output: output_file.map(PathBuf::from),
+        out_dir: out_dir.map(PathBuf::from),
+        target,
+        optimize,
+        debug_info,
+        emit,
+        allow_shadowing,
+        max_iterations: max_iterations
+            .or((command == Command::Test).then_some(TEST_MAX_ITERATIONS)),
+        verbose,
+        verbose_file,
+        dump: dump.unwrap_or_default(),
+        dump_to_stdout,
+        timings,
+        bounds_checks,
+        debug_heap,
+        max_nesting,
+        verify,
+        lang_version,
+    };
 
-    // Parse the program (with or without verbose mode)
-    let (ast, tokens_opt) = if verbose {
-        let (ast, tokens) = parse_program_verbose(source_code)?;
-        (ast, Some(tokens))
-    } else {
-        (parse_program(source_code)?, None)
+    // From here on the program's own errors are reported as diagnostics, which
+    // show the source lines they point at
+    let cli = Cli {
+        // Status lines would get in the way of the JSON
+        quiet: quiet || error_format == ErrorFormat::Json,
+        json,
+        ast_json,
+        tokens_json,
+        report: Reporter::new(error_format, color.enabled()),
+        run_args,
+        keep,
+        each,
+        bench: bench::Settings {
+            iterations: iterations.unwrap_or(bench::Settings::default().iterations),
+            warmup: warmup.unwrap_or_default(),
+            runner: runner.unwrap_or_default(),
+        },
+        expect,
+        input: test_input,
     };
+    // The expressions given with -e come last, so they are the program
+    let inline = (!expressions.is_empty()).then(|| Source::inline(&expressions));
+    let sources = input_files
+        .iter()
+        .map(Source::read)
+        .chain(inline.map(Ok))
+        .collect::<Result<Vec<_>, _>>();
+    let result = match sources {
+        Ok(sources) => execute(command, &sources, &options, &cli).map_err(|error| {
+            cli.report.print_failure(&error, &sources);
+            CliError::Failed
+        }),
+        Err(error) => {
+            cli.report.print_failure(&error, &[]);
+            Err(CliError::Failed)
+        }
+    };
+    cli.report.finish();
+    result
+}
 
-    println!("Compiling...");
-
-    // Create LLVM context and codegen
-    let context = Context::create();
-    let mut codegen = CodeGen::new(&context)?;
-
-    // Compile to generate IR (needed for both execution and verbose output)
-    let _ = codegen.compile_program(&ast)?;
-
-    // If verbose mode is enabled, write debug info to file
-    if verbose {
-        let verbose_filename = format!("{}_verbose.txt", 
-            input_path.file_stem().and_then(|s| s.to_str()).unwrap_or("output"));
-        
-        let mut verbose_file = fs::File::create(&verbose_filename)?;
-        
-        // Write tokens
-        writeln!(verbose_file, "{}", "=".repeat(80))?;
-        writeln!(verbose_file, "TOKENS")?;
-        writeln!(verbose_file, "{}", "=".repeat(80))?;
-        if let Some(tokens) = &tokens_opt {
-            for (i, token) in tokens.iter().enumerate() {
-                writeln!(verbose_file, "{:4}: {:?}", i + 1, token)?;
-            }
-        }
-        writeln!(verbose_file)?;
-        
-        // Write AST
-        writeln!(verbose_file, "{}", "=".repeat(80))?;
-        writeln!(verbose_file, "ABSTRACT SYNTAX TREE")?;
-        writeln!(verbose_file, "{}", "=".repeat(80))?;
-        writeln!(verbose_file, "{:#?}", ast)?;
-        writeln!(verbose_file)?;
-        
-        // Write LLVM IR
-        writeln!(verbose_file, "{}", "=".repeat(80))?;
-        writeln!(verbose_file, "LLVM IR CODE")?;
-        writeln!(verbose_file, "{}", "=".repeat(80))?;
-        writeln!(verbose_file, "{}", codegen.get_ir_string())?;
-        
-        println!("Verbose output written to: {}", verbose_filename);
-    }
-
-    if let Some(out) = output_file {
-        // Compile to executable file
-        codegen.compile_to_executable(&ast, &out)?;
-        println!("Wrote executable: {}", out);
-        return Ok(());
+/// Runs `command` on the program made of `sources`; see `run`.
+fn execute(
+    command: Command,
+    sources: &[Source],
+    options: &Options,
+    cli: &Cli,
+) -> Result<u8, Error> {
+    let source = sources.last().expect("checked by run");
+    match command {
+        // Only the lexer runs, so this works on files that don't parse
+        Command::Tokens if cli.json => {
+            println!("{}", tokens_json(&tokenize_with(source, options)?));
+            return Ok(0);
+        }
+        Command::Tokens => {
+            for (i, token) in tokenize_with(source, options)?.iter().enumerate() {
+                let span = token.extra();
+                println!("{:4}  {}:{}  {}", i + 1, span.line, span.column, token);
+            }
+            return Ok(0);
+        }
+        Command::Ast => {
+            let ast = parse_with(source, options)?;
+            if cli.json {
+                println!("{}", ast_json(&ast));
+            } else {
+                println!("{:#?}", ast);
+            }
+            return Ok(0);
+        }
+        Command::Build
+        | Command::Run
+        | Command::Test
+        | Command::Check
+        | Command::Bench
+        | Command::Types => {}
     }
 
-    // No output path requested: execute via JIT
-    let result = codegen.execute_program(&ast)?;
+    // Status lines go to stderr, so that stdout only has what the program prints
+    let status = |line: String| {
+        if !cli.quiet {
+            eprintln!("{}", line);
+        }
+    };
+
+    for source in sources {
+        status(format!("Parsing source code from {}...", source.name));
+    }
+    if cli.each {
+        return Ok(build_every_source(sources, options, cli));
+    }
+    let program = load_sources(sources, options)?;
+    for warning in &program.warnings {
+        cli.report
+            .print(&warning.into(), program_file(sources), sources);
+    }
+    if let Some(path) = &cli.tokens_json {
+        // The program's own tokens, not those of the files before it
+        write_json(path, tokens_json(&tokenize_with(source, options)?), options)?;
+    }
+    if let Some(path) = &cli.ast_json {
+        write_json(path, ast_json(&program.ast), options)?;
+    }
+
+    // Run the program via JIT, compile it to an executable, or stop at the checks
+    match command {
+        Command::Run => {
+            status("Compiling...".to_string());
+            let result = program.run(options)?;
+            if let Some(verbose_file) = program.verbose_path(options) {
+                status(format!(
+                    "Verbose output written to: {}",
+                    verbose_file.display()
+                ));
+            }
+            status("Program executed successfully.".to_string());
+            status(format!("Result: {}", result));
+            return Ok(result.clamp(0, 255) as u8);
+        }
+        Command::Test => {
+            status("Compiling...".to_string());
+            let read = |path: &PathBuf| {
+                fs::read_to_string(path).map_err(|error| Error::Io {
+                    path: path.clone(),
+                    error,
+                })
+            };
+            let expect = cli.expect.as_ref().expect("checked by run");
+            let expected = read(expect)?;
+            let input = match &cli.input {
+                Some(input) => read(input)?,
+                None => String::new(),
+            };
+            let (_, output) = program.run_captured(options, &input)?;
+            let expect = expect.display().to_string();
+            let differences = diff::unified(&expect, "output", &expected, &output);
+            if !differences.is_empty() {
+                print!("{}", differences);
+                status(format!("Test failed: the output differs from {}", expect));
+                return Ok(1);
+            }
+            status(format!("Test passed: the output matches {}", expect));
+        }
+        Command::Build => {
+            status("Compiling...".to_string());
+            // An executable that is only built to be run goes to a temporary
+            // file, unless it was named or is to be kept
+            let temporary =
+                (cli.run_args.is_some() && !cli.keep && options.output.is_none()).then(|| {
+                    let executable = program.executable_path(options);
+                    let name = executable.file_name().unwrap_or_default().to_string_lossy();
+                    env::temp_dir().join(format!("mlia-run-{}-{}", process::id(), name))
+                });
+            let build_options = Options {
+                output: temporary.clone().or_else(|| options.output.clone()),
+                ..options.clone()
+            };
+            let artifacts = program.build(&build_options)?;
+            for warning in &artifacts.warnings {
+                cli.report.print(warning, None, sources);
+            }
+            if let Some(verbose_file) = &artifacts.verbose_file {
+                status(format!(
+                    "Verbose output written to: {}",
+                    verbose_file.display()
+                ));
+            }
+            for file in artifacts.intermediates.iter().chain(&artifacts.dumps) {
+                status(format!("Wrote {}", file.display()));
+            }
+            if let Some(executable) = artifacts
+                .executable
+                .as_ref()
+                .filter(|_| temporary.is_none())
+            {
+                status(format!("Wrote executable: {}", executable.display()));
+            }
+            if let Some(timings) = &artifacts.timings {
+                eprint!("{}", timings);
+            }
+            if let (Some(run_args), Some(executable)) = (&cli.run_args, &artifacts.executable) {
+                // The program's output goes straight through. A bare name would
+                // be looked up in PATH
+                let ran = process::Command::new(Path::new(".").join(executable))
+                    .args(run_args)
+                    .status();
+                if temporary.is_some() {
+                    let _ = fs::remove_file(executable);
+                }
+                let status = ran.map_err(|error| Error::Io {
+                    path: executable.clone(),
+                    error,
+                })?;
+                return Ok(exit_code(status));
+            }
+        }
+        Command::Bench => {
+            status("Compiling...".to_string());
+            let report = program.bench(options, &cli.bench)?;
+            if cli.json {
+                println!("{}", report.to_json());
+            } else {
+                print!("{}", report);
+            }
+        }
+        Command::Types => print!("{}", program.types),
+        Command::Check => {
+            status("No errors found.".to_string());
+            if let Some(timings) = &program.timings {
+                eprint!("{}", timings);
+            }
+        }
+        Command::Tokens | Command::Ast => unreachable!("handled above"),
+    }
+
+    Ok(0)
+}
+
+/// Builds each of `sources` as a program of its own (`build --each`), and
+/// reports what happened to each one, in the order they were given. Returns
+/// the status to exit with: 1 if any of them failed.
+fn build_every_source(sources: &[Source], options: &Options, cli: &Cli) -> u8 {
+    if !cli.quiet {
+        eprintln!("Compiling...");
+    }
+    let mut failed = false;
+    for (source, build) in sources.iter().zip(build_each(sources, options)) {
+        for warning in &build.warnings {
+            cli.report
+                .print(&warning.into(), Some(&build.file), sources);
+        }
+        if let Ok(artifacts) = &build.result {
+            for warning in &artifacts.warnings {
+                cli.report.print(warning, Some(&build.file), sources);
+            }
+        }
+        match build.result {
+            Ok(artifacts) if !cli.quiet => {
+                for file in artifacts.intermediates.iter().chain(&artifacts.dumps) {
+                    eprintln!("Wrote {}", file.display());
+                }
+                if let Some(executable) = &artifacts.executable {
+                    eprintln!("Wrote executable: {}", executable.display());
+                }
+                if let Some(timings) = &artifacts.timings {
+                    eprint!("{}", timings);
+                }
+            }
+            Ok(_) => {}
+            // Errors that don't point into the source still say which one
+            // they are about
+            Err(error @ (Error::Parse { .. } | Error::Semantic { .. } | Error::Types { .. })) => {
+                cli.report.print_failure(&error, slice::from_ref(source));
+                failed = true;
+            }
+            Err(error) => {
+                let diagnostic = Diagnostic::error(error.to_string(), None);
+                cli.report.print(&diagnostic, Some(&build.file), sources);
+                failed = true;
+            }
+        }
+    }
+    u8::from(failed)
+}
 
-    println!("Program executed successfully.");
-    println!("Result: {}", result);
+fn parse_error_format(value: &str) -> Result<ErrorFormat, CliError> {
+    ErrorFormat::parse(value).ok_or_else(|| {
+        usage(&format!(
+            "invalid --error-format value '{}'; valid values are human, json",
+            value
+        ))
+    })
+}
+
+fn parse_runner(value: &str) -> Result<Runner, CliError> {
+    Runner::parse(value).ok_or_else(|| {
+        usage(&format!(
+            "invalid --runner value '{}'; valid values are jit, exe",
+            value
+        ))
+    })
+}
+
+fn parse_lang_version(value: &str) -> Result<u32, CliError> {
+    match value.parse() {
+        Ok(version @ 1..=LATEST_LANG_VERSION) => Ok(version),
+        _ => {
+            let versions: Vec<String> = (1..=LATEST_LANG_VERSION).map(|v| v.to_string()).collect();
+            Err(usage(&format!(
+                "invalid --lang-version value '{}'; valid values are {}",
+                value,
+                versions.join(", ")
+            )))
+        }
+    }
+}
+
+fn parse_dump_to(value: &str) -> Result<bool, CliError> {
+    match value {
+        "file" => Ok(false),
+        "stdout" => Ok(true),
+        _ => Err(usage(&format!(
+            "invalid --dump-to value '{}'; valid values are file, stdout",
+            value
+        ))),
+    }
+}
+
+/// Prints what the diagnostics with `code` are about, with an example.
+fn explain(code: &str) -> Result<u8, CliError> {
+    let code =
+        ErrorCode::parse(code).ok_or_else(|| usage(&format!("Unknown error code: {}", code)))?;
+    println!("{}: {}\n", code, code.title());
+    print!("{}", code.explanation());
+    Ok(0)
+}
+
+fn parse_color(value: &str) -> Result<ColorChoice, CliError> {
+    ColorChoice::parse(value).ok_or_else(|| {
+        usage(&format!(
+            "invalid --color value '{}'; valid values are auto, always, never",
+            value
+        ))
+    })
+}
+
+/// Whether the first argument is a word like `biuld` rather than a file name.
+fn looks_like_subcommand(arg: &str) -> bool {
+    !arg.starts_with('-') && !arg.contains(['.', '/', '\\']) && !Path::new(arg).exists()
+}
+
+/// JSON output is only built with the `serde` feature; `flag` asks for it.
+fn require_json(flag: &str) -> Result<(), CliError> {
+    if cfg!(feature = "serde") {
+        Ok(())
+    } else {
+        Err(usage(&format!(
+            "{} needs mlia built with JSON support (cargo build --features serde)",
+            flag
+        )))
+    }
+}
+
+#[cfg(feature = "serde")]
+fn ast_json(ast: &Expr) -> String {
+    ast.to_json()
+}
+
+#[cfg(feature = "serde")]
+fn tokens_json(tokens: &[Token]) -> String {
+    mlia::tokenizer::tokens_to_json(tokens)
+}
+
+// Without the feature `require_json` turns every JSON option down first
+#[cfg(not(feature = "serde"))]
+fn ast_json(_: &Expr) -> String {
+    unreachable!("JSON output without the serde feature")
+}
+
+#[cfg(not(feature = "serde"))]
+fn tokens_json(_: &[Token]) -> String {
+    unreachable!("JSON output without the serde feature")
+}
+
+/// Writes the output of `--ast-json` or `--tokens-json`: to stdout for `-`,
+/// otherwise to `path` in the output directory.
+fn write_json(path: &str, json: String, options: &Options) -> Result<(), Error> {
+    if path == "-" {
+        println!("{}", json);
+        return Ok(());
+    }
+    let path = options.out_path(path);
+    options.create_out_dir()?;
+    fs::write(&path, json).map_err(|error| Error::Io { path, error })
+}
 
-    Ok(())
+fn usage(message: &str) -> CliError {
+    CliError::Usage(message.to_string())
 }
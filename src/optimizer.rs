@@ -0,0 +1,627 @@
+use crate::parser::{Expr, Name, Span};
+use std::collections::{HashMap, HashSet};
+use std::ptr;
+
+/// Constant propagation on the AST.
+///
+/// A variable declared with a literal value (or one that folds to a literal)
+/// and never assigned in its scope is replaced by that literal everywhere it
+/// is read, and its `Decl` is removed. Builtin operators applied to literals
+/// are folded, so `decl x <- 5 in + x x` becomes `10`.
+///
+/// Anything with side effects stays as it is: a `Decl` whose value prints is
+/// never propagated, and a single assignment to a variable anywhere in its
+/// scope (including loop bodies, match arms and function bodies) keeps it a
+/// variable.
+pub fn propagate_constants(expr: &Expr) -> Expr {
+    let mut propagation = Propagation {
+        constants: HashMap::new(),
+        undo: Vec::new(),
+        assigned: assigned_in_bodies(expr),
+    };
+    propagation.propagate(expr)
+}
+
+/// Where `propagate_constants` is in the tree.
+struct Propagation {
+    /// The variables in scope known to be constants, and their values
+    constants: HashMap<Name, i64>,
+    /// What each change to `constants` replaced, latest last, to undo the
+    /// changes of a scope when it ends (see `restore`)
+    undo: Vec<(Name, Option<i64>)>,
+    /// The declarations (by address) whose variable is assigned in their body
+    assigned: HashSet<*const Expr>,
+}
+
+/// What is left to build of a block, declaration or global on the chain
+/// `Propagation::propagate` follows, once the rest of the chain is built.
+enum Pending<'a> {
+    Block(Vec<Expr>),
+    Decl(&'a Name, &'a [(Name, Option<i64>)], Expr, Span),
+    Global(&'a Name, Expr, Span),
+}
+
+impl Propagation {
+    /// Propagates constants into `expr`. A long program is one long chain of
+    /// blocks, declarations and globals, each ending in the next (see
+    /// `CodeGen::compile_chain`), so the chain is followed in a loop and built
+    /// back up from its end; the rest recurses only as deep as the parser
+    /// lets constructs nest.
+    fn propagate(&mut self, expr: &Expr) -> Expr {
+        let mark = self.undo.len();
+        let mut pending = Vec::new();
+        let mut rest = expr;
+        let mut result = loop {
+            match rest {
+                // A declaration that goes away leaves its body, which may be a
+                // block of its own: its statements join this one's, here and
+                // not as the chain is built back up, where each block would
+                // move every statement after it again
+                Expr::Block(statements) => {
+                    let Some((last, statements)) = statements.split_last() else {
+                        break Expr::Block(vec![]);
+                    };
+                    let mut block = match pending.pop() {
+                        Some(Pending::Block(block)) => block,
+                        other => {
+                            pending.extend(other);
+                            Vec::with_capacity(statements.len() + 1)
+                        }
+                    };
+                    for statement in statements {
+                        join(&mut block, self.propagate(statement));
+                    }
+                    pending.push(Pending::Block(block));
+                    rest = last;
+                }
+
+                Expr::Decl(var_name, params, value, body, span) if params.is_empty() => {
+                    match self.propagate(value) {
+                        // Every read gets replaced, so the binding itself can go
+                        Expr::Number(n) if !self.assigned.contains(&ptr::from_ref(rest)) => {
+                            self.bind(var_name, Some(n));
+                        }
+                        // The new binding shadows any outer constant with the same name
+                        value => {
+                            self.bind(var_name, None);
+                            pending.push(Pending::Decl(var_name, params, value, *span));
+                        }
+                    }
+                    rest = &**body;
+                }
+
+                Expr::Decl(func_name, params, func_body, continuation, span) => {
+                    // Parameters shadow outer constants inside the function body
+                    let outer = self.undo.len();
+                    for (param, _) in params {
+                        self.bind(param, None);
+                    }
+                    let func_body = self.propagate(func_body);
+                    self.restore(outer);
+                    pending.push(Pending::Decl(func_name, params, func_body, *span));
+                    rest = &**continuation;
+                }
+
+                // Any function can assign a global, so it always stays a variable
+                Expr::Global(var_name, value, body, span) => {
+                    let value = self.propagate(value);
+                    self.bind(var_name, None);
+                    pending.push(Pending::Global(var_name, value, *span));
+                    rest = &**body;
+                }
+
+                last => break self.propagate_node(last),
+            }
+        };
+        self.restore(mark);
+
+        while let Some(node) = pending.pop() {
+            result = match node {
+                Pending::Block(mut block) => {
+                    join(&mut block, result);
+                    Expr::Block(block)
+                }
+                Pending::Decl(name, params, value, span) => Expr::Decl(
+                    name.clone(),
+                    params.to_vec(),
+                    Box::new(value),
+                    Box::new(result),
+                    span,
+                ),
+                Pending::Global(name, value, span) => {
+                    Expr::Global(name.clone(), Box::new(value), Box::new(result), span)
+                }
+            };
+        }
+        result
+    }
+
+    /// Propagates constants into an expression that doesn't go on with a
+    /// chain (see `propagate`).
+    fn propagate_node(&mut self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Number(n) => Expr::Number(*n),
+
+            Expr::Ident(name, span) => match self.constants.get(name) {
+                Some(value) => Expr::Number(*value),
+                None => Expr::Ident(name.clone(), *span),
+            },
+
+            Expr::Call(func_name, args, span) => {
+                let args: Vec<Expr> = args.iter().map(|arg| self.propagate(arg)).collect();
+                match fold(func_name, &args) {
+                    Some(value) => Expr::Number(value),
+                    None => Expr::Call(func_name.clone(), args, *span),
+                }
+            }
+
+            Expr::Block(..) | Expr::Decl(..) | Expr::Global(..) => self.propagate(expr),
+
+            Expr::Assign(var_name, value, span) => {
+                Expr::Assign(var_name.clone(), Box::new(self.propagate(value)), *span)
+            }
+
+            Expr::While(cond, body, span) => Expr::While(
+                Box::new(self.propagate(cond)),
+                Box::new(self.propagate(body)),
+                *span,
+            ),
+
+            Expr::WhileYield(cond, body, span) => Expr::WhileYield(
+                Box::new(self.propagate(cond)),
+                Box::new(self.propagate(body)),
+                *span,
+            ),
+
+            Expr::Match(scrutinee, arms, span) => {
+                let scrutinee = self.propagate(scrutinee);
+                let arms = arms
+                    .iter()
+                    .map(|(pattern, arm)| {
+                        // The names the pattern binds shadow outer constants in the arm
+                        let outer = self.undo.len();
+                        for (name, _) in pattern.bindings() {
+                            self.bind(name, None);
+                        }
+                        let arm = self.propagate(arm);
+                        self.restore(outer);
+                        (pattern.clone(), arm)
+                    })
+                    .collect();
+                Expr::Match(Box::new(scrutinee), arms, *span)
+            }
+
+            Expr::Loop(body, span) => Expr::Loop(Box::new(self.propagate(body)), *span),
+
+            Expr::Break(value, span) => Expr::Break(
+                value.as_ref().map(|value| Box::new(self.propagate(value))),
+                *span,
+            ),
+
+            // Read when the program runs, so never a constant
+            Expr::EnvInt(name, span) => Expr::EnvInt(name.clone(), *span),
+            Expr::Str(text, span) => Expr::Str(text.clone(), *span),
+            Expr::Array(elements, span) => Expr::Array(
+                elements
+                    .iter()
+                    .map(|element| self.propagate(element))
+                    .collect(),
+                *span,
+            ),
+
+            Expr::NamedArg(name, value, span) => {
+                Expr::NamedArg(name.clone(), Box::new(self.propagate(value)), *span)
+            }
+
+            Expr::PrintFormat(pieces, args, span) => Expr::PrintFormat(
+                pieces.clone(),
+                args.iter().map(|arg| self.propagate(arg)).collect(),
+                *span,
+            ),
+        }
+    }
+
+    /// Makes `name` the constant `value`, or not a constant, until the scope
+    /// that does it is restored.
+    fn bind(&mut self, name: &Name, value: Option<i64>) {
+        let replaced = match value {
+            Some(value) => self.constants.insert(name.clone(), value),
+            None => self.constants.remove(name),
+        };
+        self.undo.push((name.clone(), replaced));
+    }
+
+    /// Undoes the changes to `constants` made since `undo` was `mark` long,
+    /// the latest first.
+    fn restore(&mut self, mark: usize) {
+        for (name, replaced) in self.undo.drain(mark..).rev() {
+            match replaced {
+                Some(value) => self.constants.insert(name, value),
+                None => self.constants.remove(&name),
+            };
+        }
+    }
+}
+
+/// Adds `statement` to the end of `block`, or its statements if it is a
+/// block itself.
+fn join(block: &mut Vec<Expr>, mut statement: Expr) {
+    match &mut statement {
+        Expr::Block(inner) => block.append(inner),
+        _ => block.push(statement),
+    }
+}
+
+/// The parameterless declarations in `expr` (by address) whose variable is
+/// assigned in their body (see `assigns_to`). The names assigned in each
+/// subtree are gathered once, from the leaves up, each node's into the
+/// largest set of its children's.
+fn assigned_in_bodies(expr: &Expr) -> HashSet<*const Expr> {
+    let mut assigned = HashSet::new();
+    // The nodes to visit, and whether their children have been
+    let mut pending = vec![(expr, false)];
+    // The names assigned in each subtree done whose parent isn't, in order
+    let mut names: Vec<HashSet<Name>> = Vec::new();
+    while let Some((expr, visited)) = pending.pop() {
+        if !visited {
+            pending.push((expr, true));
+            pending.extend(expr.children().rev().map(|child| (child, false)));
+            continue;
+        }
+
+        let first = names.len() - expr.children().count();
+        if let Expr::Decl(var_name, params, ..) = expr
+            && params.is_empty()
+            && names.last().is_some_and(|body| body.contains(var_name))
+        {
+            assigned.insert(ptr::from_ref(expr));
+        }
+        if let Some(largest) = (first..names.len()).max_by_key(|&i| names[i].len()) {
+            names.swap(first, largest);
+        }
+        let mut children = names.drain(first..);
+        let mut subtree = children.next().unwrap_or_default();
+        for child in children {
+            subtree.extend(child);
+        }
+        match expr {
+            Expr::Assign(var_name, ..) => {
+                subtree.insert(var_name.clone());
+            }
+            Expr::Call(func_name, args, _) if func_name == "ref" => {
+                if let [Expr::Ident(var_name, _)] = args.as_slice() {
+                    subtree.insert(var_name.clone());
+                }
+            }
+            _ => {}
+        }
+        names.push(subtree);
+    }
+    assigned
+}
+
+/// Whether `expr` contains an assignment to `name` anywhere, even to a
+/// different variable that shadows it (being conservative is fine here).
+/// Taking its address with `ref` counts: it can be stored through.
+pub(crate) fn assigns_to(expr: &Expr, name: &str) -> bool {
+    let mut pending = vec![expr];
+    while let Some(expr) = pending.pop() {
+        match expr {
+            Expr::Assign(var_name, ..) if var_name == name => return true,
+            Expr::Call(func_name, args, _)
+                if func_name == "ref"
+                    && matches!(args.as_slice(), [Expr::Ident(var, _)] if var == name) =>
+            {
+                return true;
+            }
+            _ => pending.extend(expr.children()),
+        }
+    }
+    false
+}
+
+/// Evaluates a builtin operator applied to literals, the same way the
+/// generated code would. Returns `None` when it can't be folded (non-literal
+/// arguments, user functions, division by zero or overflow).
+fn fold(func_name: &str, args: &[Expr]) -> Option<i64> {
+    let values: Vec<i64> = args
+        .iter()
+        .map(|arg| match arg {
+            Expr::Number(n) => Some(*n),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+
+    match (func_name, values.as_slice()) {
+        ("sum", values) => Some(values.iter().fold(0, |a, b| a.wrapping_add(*b))),
+        ("product", values) => Some(values.iter().fold(1, |a, b| a.wrapping_mul(*b))),
+        ("+", [a, b]) => Some(a.wrapping_add(*b)),
+        ("-", [a, b]) => Some(a.wrapping_sub(*b)),
+        ("*", [a, b]) => Some(a.wrapping_mul(*b)),
+        ("/", [a, b]) => a.checked_div(*b),
+        ("%", [a, b]) => a.checked_rem(*b),
+        ("emod", [a, b]) => a.checked_rem_euclid(*b),
+        ("fdiv", [a, b]) => {
+            let quotient = a.checked_div(*b)?;
+            let round_down = a % b != 0 && (*a < 0) != (*b < 0);
+            Some(quotient - round_down as i64)
+        }
+        ("<", [a, b]) => Some((a < b) as i64),
+        (">", [a, b]) => Some((a > b) as i64),
+        ("=", [a, b]) => Some((a == b) as i64),
+        ("!=", [a, b]) => Some((a != b) as i64),
+        ("&", [a, b]) => Some((*a != 0 && *b != 0) as i64),
+        ("|", [a, b]) => Some((*a != 0 || *b != 0) as i64),
+        ("!", [a]) => Some((*a == 0) as i64),
+        ("ord", [a]) => Some(*a),
+        ("chr", [a]) => Some(a.rem_euclid(128)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Pattern, parse_program};
+
+    /// Parses and optimizes `source`, with spans stripped for comparison.
+    fn optimize(source: &str) -> Expr {
+        propagate_constants(&parse_program(source.to_string()).unwrap()).without_spans()
+    }
+
+    fn ident(name: &str) -> Expr {
+        Expr::Ident(name.into(), Span::default())
+    }
+
+    fn call(func_name: &str, args: Vec<Expr>) -> Expr {
+        Expr::Call(func_name.into(), args, Span::default())
+    }
+
+    fn decl(name: &str, params: &[&str], value: Expr, body: Expr) -> Expr {
+        Expr::Decl(
+            name.into(),
+            params.iter().map(|p| (Name::from(*p), None)).collect(),
+            Box::new(value),
+            Box::new(body),
+            Span::default(),
+        )
+    }
+
+    fn assign(name: &str, value: Expr) -> Expr {
+        Expr::Assign(name.into(), Box::new(value), Span::default())
+    }
+
+    #[test]
+    fn test_literal_binding_becomes_constant() {
+        assert_eq!(optimize("decl x <- 5 in + x x"), Expr::Number(10));
+    }
+
+    #[test]
+    fn test_propagation_cascades_through_folded_values() {
+        assert_eq!(
+            optimize("decl x <- 2 in decl y <- * x 3 in - y x"),
+            Expr::Number(4)
+        );
+    }
+
+    #[test]
+    fn test_assignment_in_loop_blocks_propagation() {
+        assert_eq!(
+            optimize("decl x <- 5 in while x do x <- - x 1 done"),
+            decl(
+                "x",
+                &[],
+                Expr::Number(5),
+                Expr::While(
+                    Box::new(ident("x")),
+                    Box::new(assign("x", call("-", vec![ident("x"), Expr::Number(1)]))),
+                    Span::default(),
+                ),
+            )
+        );
+    }
+
+    #[test]
+    fn test_assignment_in_match_arm_blocks_propagation() {
+        assert_eq!(
+            optimize("decl x <- 1 in match 0 with | 0 -> x <- 2 | _ -> x"),
+            decl(
+                "x",
+                &[],
+                Expr::Number(1),
+                Expr::Match(
+                    Box::new(Expr::Number(0)),
+                    vec![
+                        (Pattern::Literal(0), assign("x", Expr::Number(2))),
+                        (Pattern::Wildcard, ident("x")),
+                    ],
+                    Span::default(),
+                ),
+            )
+        );
+    }
+
+    #[test]
+    fn test_blocks_left_by_removed_declarations_are_joined() {
+        assert_eq!(
+            optimize("decl x <- 1 in print x; decl y <- 2 in print y; + x y"),
+            Expr::Block(vec![
+                call("print", vec![Expr::Number(1)]),
+                call("print", vec![Expr::Number(2)]),
+                Expr::Number(3),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_folding_inside_kept_code() {
+        assert_eq!(
+            optimize("decl x <- read_line in print (+ x ( * 2 3)); - 10 (/ 9 3)"),
+            decl(
+                "x",
+                &[],
+                call("read_line", vec![]),
+                Expr::Block(vec![
+                    call("print", vec![call("+", vec![ident("x"), Expr::Number(6)])]),
+                    Expr::Number(7),
+                ]),
+            )
+        );
+    }
+
+    #[test]
+    fn test_reference_blocks_propagation() {
+        // The function may store through the reference
+        let source = "decl x <- 1 in (f (ref x)); x";
+        assert_eq!(
+            optimize(source),
+            parse_program(source.to_string()).unwrap().without_spans()
+        );
+    }
+
+    #[test]
+    fn test_side_effects_are_kept() {
+        // The value prints, so the Decl stays and x is still read
+        assert_eq!(
+            optimize("decl x <- print 5 in + x 1"),
+            decl(
+                "x",
+                &[],
+                call("print", vec![Expr::Number(5)]),
+                call("+", vec![ident("x"), Expr::Number(1)]),
+            )
+        );
+
+        // A propagated constant still gets printed
+        assert_eq!(
+            optimize("decl x <- 5 in print x"),
+            call("print", vec![Expr::Number(5)])
+        );
+    }
+
+    #[test]
+    fn test_shadowing_binding_is_not_replaced() {
+        // The inner x prints, so reads of it must not see the outer constant
+        assert_eq!(
+            optimize("decl x <- 1 in decl x <- print 2 in x"),
+            decl("x", &[], call("print", vec![Expr::Number(2)]), ident("x"))
+        );
+    }
+
+    #[test]
+    fn test_function_parameter_shadows_constant() {
+        // Inside f, x is the parameter; outside it is the constant 10
+        assert_eq!(
+            optimize("decl x <- 10 in decl f x <- + x 1 in (f x)"),
+            decl(
+                "f",
+                &["x"],
+                call("+", vec![ident("x"), Expr::Number(1)]),
+                call("f", vec![Expr::Number(10)]),
+            )
+        );
+    }
+
+    #[test]
+    fn test_division_by_zero_is_not_folded() {
+        assert_eq!(
+            optimize("/ 1 0"),
+            call("/", vec![Expr::Number(1), Expr::Number(0)])
+        );
+        assert_eq!(
+            optimize("emod 1 0"),
+            call("emod", vec![Expr::Number(1), Expr::Number(0)])
+        );
+    }
+
+    #[test]
+    fn test_euclidean_modulo_is_folded() {
+        assert_eq!(optimize("emod -7 3"), Expr::Number(2));
+        assert_eq!(optimize("emod 7 -3"), Expr::Number(1));
+        assert_eq!(optimize("emod -7 -3"), Expr::Number(2));
+    }
+
+    #[test]
+    fn test_floor_division_is_folded() {
+        assert_eq!(optimize("fdiv 7 2"), Expr::Number(3));
+        assert_eq!(optimize("fdiv -7 2"), Expr::Number(-4));
+        assert_eq!(optimize("fdiv 7 -2"), Expr::Number(-4));
+        assert_eq!(optimize("fdiv -7 -2"), Expr::Number(3));
+        assert_eq!(optimize("fdiv -8 2"), Expr::Number(-4));
+        assert_eq!(
+            optimize("fdiv 1 0"),
+            call("fdiv", vec![Expr::Number(1), Expr::Number(0)])
+        );
+    }
+
+    #[test]
+    fn test_ord_and_chr_are_folded() {
+        assert_eq!(optimize("ord 65"), Expr::Number(65));
+        assert_eq!(optimize("chr 65"), Expr::Number(65));
+        assert_eq!(optimize("chr 193"), Expr::Number(65));
+        assert_eq!(optimize("chr -63"), Expr::Number(65));
+    }
+
+    #[test]
+    fn test_reductions_are_folded() {
+        assert_eq!(optimize("(sum)"), Expr::Number(0));
+        assert_eq!(optimize("(product)"), Expr::Number(1));
+        assert_eq!(optimize("(sum 1 (product 2 3) 4)"), Expr::Number(11));
+        assert_eq!(
+            optimize("(product 2 x)"),
+            call("product", vec![Expr::Number(2), ident("x")])
+        );
+    }
+
+    #[test]
+    fn test_long_chains_of_declarations_are_propagated() {
+        // Each declaration is in the body of the one before it, far deeper
+        // than the stack would let a recursive pass go
+        let count = 100_000;
+        let chain: String = (0..count)
+            .map(|i| format!("decl v{} <- + x {} in ", i, i))
+            .collect();
+
+        let program = parse_program(format!("decl x <- 1 in {}v{}", chain, count - 1)).unwrap();
+        let optimized = propagate_constants(&program);
+        assert_eq!(optimized, Expr::Number(count));
+
+        // Assigned at the very end, x is no constant, and neither is any v
+        let program =
+            parse_program(format!("decl x <- 1 in {}x <- 2; v{}", chain, count - 1)).unwrap();
+        let optimized = propagate_constants(&program);
+        let mut kept = 0;
+        let mut rest = &optimized;
+        while let Expr::Decl(_, _, value, body, _) = rest {
+            assert!(!matches!(**value, Expr::Number(_)) || kept == 0);
+            kept += 1;
+            rest = &**body;
+        }
+        assert_eq!(kept, count + 1);
+        assert_eq!(
+            rest.clone().without_spans(),
+            Expr::Block(vec![
+                assign("x", Expr::Number(2)),
+                ident(&format!("v{}", count - 1)),
+            ])
+        );
+
+        // With a statement before each, the declarations that go away leave
+        // one block of all the statements
+        let statements: String = (0..count)
+            .map(|i| format!("print {}; decl v{} <- + x {} in\n", i, i, i))
+            .collect();
+        let program =
+            parse_program(format!("decl x <- 1 in\n{}v{}", statements, count - 1)).unwrap();
+        let optimized = propagate_constants(&program);
+        let Expr::Block(block) = &optimized else {
+            panic!("expected a block");
+        };
+        assert_eq!(block.len(), count as usize + 1);
+        assert_eq!(
+            block[1].clone().without_spans(),
+            call("print", vec![Expr::Number(1)])
+        );
+        assert_eq!(block[count as usize], Expr::Number(count));
+    }
+}
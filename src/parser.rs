@@ -1,126 +1,1011 @@
 pub(crate) use pomelo::pomelo;
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
 
-#[derive(Debug, Clone, PartialEq)]
+pub use crate::tokenizer::Span;
+
+/// A name in the tree: of a variable, a function, a parameter or a builtin.
+/// Names are interned, so every use of a name shares the text of the first
+/// one (see `Lexer` and `builtin`), and cloning one doesn't allocate. Apart
+/// from that, a name works like the `str` it holds.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Name(Arc<str>);
+
+impl Name {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether `self` and `other` share their text, as the uses of a name
+    /// interned together do.
+    pub fn ptr_eq(&self, other: &Name) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Deref for Name {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Name {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Name {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Name {
+    fn from(name: &str) -> Self {
+        Name(Arc::from(name))
+    }
+}
+
+impl From<String> for Name {
+    fn from(name: String) -> Self {
+        Name(Arc::from(name))
+    }
+}
+
+impl PartialEq<str> for Name {
+    fn eq(&self, other: &str) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl PartialEq<&str> for Name {
+    fn eq(&self, other: &&str) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl PartialEq<String> for Name {
+    fn eq(&self, other: &String) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl PartialEq<Name> for str {
+    fn eq(&self, other: &Name) -> bool {
+        *self == *other.0
+    }
+}
+
+impl PartialEq<Name> for &str {
+    fn eq(&self, other: &Name) -> bool {
+        **self == *other.0
+    }
+}
+
+// Like a string, so that the AST prints the same as before names were interned
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", content = "fields"))]
 pub enum Pattern {
     Literal(i64),
     Wildcard,
+    /// A name, bound to the value in the arm (matches anything).
+    Bind(Name, Span),
+    /// `some p`: an option with a payload that matches `p`.
+    Some(Box<Pattern>),
+    /// `none`: an option without a payload.
+    None,
+    /// `ok p`: a result with a value that matches `p`.
+    Ok(Box<Pattern>),
+    /// `err p`: a result with an error that matches `p`.
+    Err(Box<Pattern>),
+    /// `name @ p`: matches what `p` matches, and binds the value to `name`.
+    At(Name, Box<Pattern>, Span),
+    /// `< n`, `<= n`, `> n`, `>= n`: the values that compare so with `n`.
+    Cmp(CmpOp, i64),
+}
+
+/// The comparison of a relational pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CmpOp {
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+}
+
+impl CmpOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            CmpOp::Less => "<",
+            CmpOp::LessEq => "<=",
+            CmpOp::Greater => ">",
+            CmpOp::GreaterEq => ">=",
+        }
+    }
+}
+
+impl Pattern {
+    /// Whether the pattern matches every value.
+    pub fn is_irrefutable(&self) -> bool {
+        matches!(self.unbound(), Pattern::Wildcard | Pattern::Bind(..))
+    }
+
+    /// The pattern without the names `@` gives to the values it matches.
+    pub fn unbound(&self) -> &Pattern {
+        match self {
+            Pattern::At(_, inner, _) => inner.unbound(),
+            _ => self,
+        }
+    }
+
+    /// The pattern for the payload of an option or result, if it has one.
+    pub fn payload(&self) -> Option<&Pattern> {
+        match self.unbound() {
+            Pattern::Some(inner) | Pattern::Ok(inner) | Pattern::Err(inner) => Some(inner),
+            _ => None,
+        }
+    }
+
+    /// The names the pattern binds, in order, with where they are.
+    pub fn bindings(&self) -> Vec<(&Name, Span)> {
+        match self {
+            Pattern::Bind(name, span) => vec![(name, *span)],
+            Pattern::At(name, inner, span) => {
+                let mut bindings = vec![(name, *span)];
+                bindings.extend(inner.bindings());
+                bindings
+            }
+            _ => self.payload().map_or(vec![], Pattern::bindings),
+        }
+    }
+
+    #[cfg(test)]
+    fn without_spans(self) -> Pattern {
+        match self {
+            Pattern::Bind(name, _) => Pattern::Bind(name, Span::default()),
+            Pattern::Some(inner) => Pattern::Some(Box::new(inner.without_spans())),
+            Pattern::Ok(inner) => Pattern::Ok(Box::new(inner.without_spans())),
+            Pattern::Err(inner) => Pattern::Err(Box::new(inner.without_spans())),
+            Pattern::At(name, inner, _) => {
+                Pattern::At(name, Box::new(inner.without_spans()), Span::default())
+            }
+            other => other,
+        }
+    }
+}
+
+/// An `import "path"` at the top of a file; `span` is where the path is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    pub path: String,
+    pub span: Span,
+}
+
+/// A syntax error, at the token the parser couldn't take (`None` when the
+/// input ended too early).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Parse error: {}", self.message)
+    }
 }
 
-#[derive(Debug, Clone)]
+impl std::error::Error for ParseError {}
+
+// The `Span` in a node is where its name, keyword or operator appears in the source.
+// In JSON (see `to_json`, with the `serde` feature) a node is an object with its
+// variant name under "type" and its fields under "fields", as an array when
+// there's more than one: `{"type": "Call", "fields": ["+", [...], {"line": 1,
+// ...}]}`. Patterns and tokens look the same.
+// Trees compare by shape, spans included (see `without_spans`). Every literal
+// is an integer, so equality is total and `Eq` holds; a float literal, if the
+// language gets one, is to compare by its bits (`f64::to_bits`), so that a
+// tree holding NaN still equals itself and `Eq` can stay.
+#[derive(Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", content = "fields"))]
 pub enum Expr {
     Number(i64),
-    Ident(String),
-    Call(String, Vec<Expr>),
-    Seq(Box<Expr>, Box<Expr>),
-    Assign(String, Box<Expr>),
-    Decl(String, Vec<String>, Box<Expr>, Box<Expr>),
-    While(Box<Expr>, Box<Expr>),            // (condition, body)
-    Match(Box<Expr>, Vec<(Pattern, Expr)>), // (scrutinee, arms)
+    Ident(Name, Span),
+    Call(Name, Vec<Expr>, Span),
+    Block(Vec<Expr>), // a; b; c: the value of the last one
+    Assign(Name, Box<Expr>, Span),
+    Decl(Name, Vec<(Name, Option<i64>)>, Box<Expr>, Box<Expr>, Span), // params with defaults
+    While(Box<Expr>, Box<Expr>, Span), // (condition, body, position of `while`)
+    WhileYield(Box<Expr>, Box<Expr>, Span), // while ... do ... yield done
+    Loop(Box<Expr>, Span),             // loop body end
+    Break(Option<Box<Expr>>, Span),    // break [value]
+    Global(Name, Box<Expr>, Box<Expr>, Span), // global name <- value, then the rest
+    Match(Box<Expr>, Vec<(Pattern, Expr)>, Span), // (scrutinee, arms, position)
+    EnvInt(String, Span),              // env_int "NAME"
+    NamedArg(Name, Box<Expr>, Span),   // name: value, until lower_named_arguments
+    PrintFormat(Vec<String>, Vec<Expr>, Span), // print "text {x}": text pieces around the values
+    Str(String, Span),                 // "text", the address of its bytes
+    Array(Vec<Expr>, Span),            // [a b c]
+}
+
+impl Expr {
+    /// Source position of this node, for the nodes that record one.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Expr::Ident(_, span)
+            | Expr::Call(_, _, span)
+            | Expr::Assign(_, _, span)
+            | Expr::Decl(_, _, _, _, span)
+            | Expr::Global(_, _, _, span)
+            | Expr::While(_, _, span)
+            | Expr::WhileYield(_, _, span)
+            | Expr::Loop(_, span)
+            | Expr::Match(_, _, span)
+            | Expr::EnvInt(_, span)
+            | Expr::NamedArg(_, _, span)
+            | Expr::PrintFormat(_, _, span)
+            | Expr::Str(_, span)
+            | Expr::Array(_, span)
+            | Expr::Break(_, span) => Some(*span),
+            Expr::Number(_) | Expr::Block(..) => None,
+        }
+    }
+
+    /// Every value the expression can have, when there are only a few:
+    /// comparisons and logical operators are 0 or 1, and so is a block that
+    /// ends in one.
+    pub fn known_values(&self) -> Option<&'static [i64]> {
+        match self {
+            Expr::Call(name, args, _) => match (name.as_str(), args.len()) {
+                ("<" | ">" | "=" | "!=" | "&" | "|", 2) | ("!", 1) => Some(&[0, 1]),
+                _ => None,
+            },
+            Expr::Block(statements) => statements.last()?.known_values(),
+            _ => None,
+        }
+    }
+
+    /// The expressions right inside this one, in the order they're evaluated
+    /// (the arms of a match after its scrutinee).
+    pub fn children(&self) -> impl DoubleEndedIterator<Item = &Expr> {
+        let (first, second, rest, arms): (_, _, &[Expr], &[(Pattern, Expr)]) = match self {
+            Expr::Number(_) | Expr::Ident(..) | Expr::EnvInt(..) | Expr::Str(..) => {
+                (None, None, &[], &[])
+            }
+            Expr::Call(_, args, _)
+            | Expr::Block(args)
+            | Expr::PrintFormat(_, args, _)
+            | Expr::Array(args, _) => (None, None, args.as_slice(), &[]),
+            Expr::Assign(_, value, _) | Expr::NamedArg(_, value, _) | Expr::Loop(value, _) => {
+                (Some(&**value), None, &[], &[])
+            }
+            Expr::Decl(_, _, first, second, _)
+            | Expr::While(first, second, _)
+            | Expr::WhileYield(first, second, _)
+            | Expr::Global(_, first, second, _) => (Some(&**first), Some(&**second), &[], &[]),
+            Expr::Break(value, _) => (value.as_deref(), None, &[], &[]),
+            Expr::Match(scrutinee, arms, _) => (Some(&**scrutinee), None, &[], arms.as_slice()),
+        };
+        first
+            .into_iter()
+            .chain(second)
+            .chain(rest)
+            .chain(arms.iter().map(|(_, arm)| arm))
+    }
+
+    /// The tree as JSON, for external tools.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("an AST is always valid JSON")
+    }
+
+    /// The expression on one line, like `Display`, cut to `width` characters
+    /// with `...` at the end when longer.
+    pub fn display_truncated(&self, width: usize) -> String {
+        crate::printer::one_line(self, Some(width))
+    }
+
+    /// The same tree with every span reset, so tests can compare ASTs by shape.
+    #[cfg(test)]
+    pub fn without_spans(mut self) -> Expr {
+        let mut pending = vec![&mut self];
+        while let Some(expr) = pending.pop() {
+            match expr {
+                Expr::Number(_) => {}
+                Expr::Block(statements) => pending.extend(statements),
+                Expr::Ident(_, span) | Expr::EnvInt(_, span) | Expr::Str(_, span) => {
+                    *span = Span::default()
+                }
+                Expr::Call(_, args, span)
+                | Expr::PrintFormat(_, args, span)
+                | Expr::Array(args, span) => {
+                    *span = Span::default();
+                    pending.extend(args);
+                }
+                Expr::Assign(_, value, span)
+                | Expr::NamedArg(_, value, span)
+                | Expr::Loop(value, span) => {
+                    *span = Span::default();
+                    pending.push(value);
+                }
+                Expr::Decl(_, _, first, second, span)
+                | Expr::While(first, second, span)
+                | Expr::WhileYield(first, second, span)
+                | Expr::Global(_, first, second, span) => {
+                    *span = Span::default();
+                    pending.push(first);
+                    pending.push(second);
+                }
+                Expr::Break(value, span) => {
+                    *span = Span::default();
+                    pending.extend(value.as_deref_mut());
+                }
+                Expr::Match(scrutinee, arms, span) => {
+                    *span = Span::default();
+                    pending.push(scrutinee);
+                    for (pattern, arm) in arms {
+                        *pattern = std::mem::replace(pattern, Pattern::Wildcard).without_spans();
+                        pending.push(arm);
+                    }
+                }
+            }
+        }
+        self
+    }
+
+    /// Moves the children of the node onto `pending`, leaving it without any.
+    fn take_children(&mut self, pending: &mut Vec<Expr>) {
+        let take = |expr: &mut Box<Expr>| std::mem::replace(&mut **expr, Expr::Number(0));
+        match self {
+            Expr::Number(_) | Expr::Ident(..) | Expr::EnvInt(..) | Expr::Str(..) => {}
+            Expr::Call(_, args, _)
+            | Expr::Block(args)
+            | Expr::PrintFormat(_, args, _)
+            | Expr::Array(args, _) => pending.append(args),
+            Expr::Assign(_, value, _) | Expr::NamedArg(_, value, _) | Expr::Loop(value, _) => {
+                pending.push(take(value))
+            }
+            Expr::Decl(_, _, first, second, _)
+            | Expr::While(first, second, _)
+            | Expr::WhileYield(first, second, _)
+            | Expr::Global(_, first, second, _) => {
+                pending.push(take(first));
+                pending.push(take(second));
+            }
+            Expr::Break(value, _) => pending.extend(value.take().map(|value| *value)),
+            Expr::Match(scrutinee, arms, _) => {
+                pending.push(take(scrutinee));
+                pending.extend(arms.drain(..).map(|(_, arm)| arm));
+            }
+        }
+    }
+
+    /// A copy of the node with `children` in place of its own, in the order
+    /// `children` gives them.
+    fn with_children(&self, children: Vec<Expr>) -> Expr {
+        let mut children = children.into_iter();
+        let mut next = || Box::new(children.next().expect("a child for each of the node's"));
+        match self {
+            Expr::Number(n) => Expr::Number(*n),
+            Expr::Ident(name, span) => Expr::Ident(name.clone(), *span),
+            Expr::EnvInt(name, span) => Expr::EnvInt(name.clone(), *span),
+            Expr::Str(text, span) => Expr::Str(text.clone(), *span),
+            Expr::Call(name, _, span) => Expr::Call(name.clone(), children.collect(), *span),
+            Expr::Block(_) => Expr::Block(children.collect()),
+            Expr::PrintFormat(pieces, _, span) => {
+                Expr::PrintFormat(pieces.clone(), children.collect(), *span)
+            }
+            Expr::Array(_, span) => Expr::Array(children.collect(), *span),
+            Expr::Assign(name, _, span) => Expr::Assign(name.clone(), next(), *span),
+            Expr::NamedArg(name, _, span) => Expr::NamedArg(name.clone(), next(), *span),
+            Expr::Loop(_, span) => Expr::Loop(next(), *span),
+            Expr::Decl(name, params, _, _, span) => {
+                let value = next();
+                Expr::Decl(name.clone(), params.clone(), value, next(), *span)
+            }
+            Expr::While(_, _, span) => {
+                let condition = next();
+                Expr::While(condition, next(), *span)
+            }
+            Expr::WhileYield(_, _, span) => {
+                let condition = next();
+                Expr::WhileYield(condition, next(), *span)
+            }
+            Expr::Global(name, _, _, span) => {
+                let value = next();
+                Expr::Global(name.clone(), value, next(), *span)
+            }
+            Expr::Break(value, span) => Expr::Break(value.as_ref().map(|_| next()), *span),
+            Expr::Match(_, arms, span) => {
+                let scrutinee = next();
+                let arms = arms
+                    .iter()
+                    .map(|(pattern, _)| (pattern.clone(), *next()))
+                    .collect();
+                Expr::Match(scrutinee, arms, *span)
+            }
+        }
+    }
+
+    /// Whether the nodes are the same apart from their children (but with as
+    /// many).
+    fn eq_node(&self, other: &Expr) -> bool {
+        match (self, other) {
+            (Expr::Number(a), Expr::Number(b)) => a == b,
+            (Expr::Block(a), Expr::Block(b)) => a.len() == b.len(),
+            (Expr::Ident(a, a_span), Expr::Ident(b, b_span))
+            | (Expr::Assign(a, _, a_span), Expr::Assign(b, _, b_span))
+            | (Expr::NamedArg(a, _, a_span), Expr::NamedArg(b, _, b_span))
+            | (Expr::Global(a, _, _, a_span), Expr::Global(b, _, _, b_span)) => {
+                (a, a_span) == (b, b_span)
+            }
+            (Expr::EnvInt(a, a_span), Expr::EnvInt(b, b_span))
+            | (Expr::Str(a, a_span), Expr::Str(b, b_span)) => (a, a_span) == (b, b_span),
+            (Expr::Call(a, a_args, a_span), Expr::Call(b, b_args, b_span)) => {
+                (a, a_args.len(), a_span) == (b, b_args.len(), b_span)
+            }
+            (Expr::PrintFormat(a, a_args, a_span), Expr::PrintFormat(b, b_args, b_span)) => {
+                (a, a_args.len(), a_span) == (b, b_args.len(), b_span)
+            }
+            (Expr::Array(a, a_span), Expr::Array(b, b_span)) => {
+                (a.len(), a_span) == (b.len(), b_span)
+            }
+            (Expr::Decl(a, a_params, _, _, a_span), Expr::Decl(b, b_params, _, _, b_span)) => {
+                (a, a_params, a_span) == (b, b_params, b_span)
+            }
+            (Expr::While(_, _, a), Expr::While(_, _, b))
+            | (Expr::WhileYield(_, _, a), Expr::WhileYield(_, _, b))
+            | (Expr::Loop(_, a), Expr::Loop(_, b)) => a == b,
+            (Expr::Break(a, a_span), Expr::Break(b, b_span)) => {
+                (a.is_some(), a_span) == (b.is_some(), b_span)
+            }
+            (Expr::Match(_, a_arms, a_span), Expr::Match(_, b_arms, b_span)) => {
+                a_span == b_span
+                    && a_arms.len() == b_arms.len()
+                    && a_arms.iter().zip(b_arms).all(|((a, _), (b, _))| a == b)
+            }
+            _ => false,
+        }
+    }
+
+    /// What `Debug` writes for the node, with its children still to expand.
+    fn debug_pieces(&self) -> Vec<DebugPiece<'_>> {
+        use DebugPiece::{Node, Value};
+        let (name, fields): (_, Vec<Vec<DebugPiece<'_>>>) = match self {
+            Expr::Number(n) => ("Number", vec![vec![Value(n)]]),
+            Expr::Ident(name, span) => ("Ident", vec![vec![Value(name)], vec![Value(span)]]),
+            Expr::Call(name, args, span) => (
+                "Call",
+                vec![vec![Value(name)], debug_list(args), vec![Value(span)]],
+            ),
+            Expr::Block(statements) => ("Block", vec![debug_list(statements)]),
+            Expr::Assign(name, value, span) => (
+                "Assign",
+                vec![vec![Value(name)], vec![Node(value)], vec![Value(span)]],
+            ),
+            Expr::Decl(name, params, value, body, span) => (
+                "Decl",
+                vec![
+                    vec![Value(name)],
+                    vec![Value(params)],
+                    vec![Node(value)],
+                    vec![Node(body)],
+                    vec![Value(span)],
+                ],
+            ),
+            Expr::While(condition, body, span) => (
+                "While",
+                vec![vec![Node(condition)], vec![Node(body)], vec![Value(span)]],
+            ),
+            Expr::WhileYield(condition, body, span) => (
+                "WhileYield",
+                vec![vec![Node(condition)], vec![Node(body)], vec![Value(span)]],
+            ),
+            Expr::Loop(body, span) => ("Loop", vec![vec![Node(body)], vec![Value(span)]]),
+            Expr::Break(value, span) => {
+                let value = match value {
+                    Some(value) => debug_sequence("Some", "(", ")", [vec![Node(value)]]),
+                    None => vec![DebugPiece::Text("None")],
+                };
+                ("Break", vec![value, vec![Value(span)]])
+            }
+            Expr::Global(name, value, body, span) => (
+                "Global",
+                vec![
+                    vec![Value(name)],
+                    vec![Node(value)],
+                    vec![Node(body)],
+                    vec![Value(span)],
+                ],
+            ),
+            Expr::Match(scrutinee, arms, span) => {
+                let arms = match arms.as_slice() {
+                    [] => vec![DebugPiece::Text("[]")],
+                    arms => debug_sequence(
+                        "",
+                        "[",
+                        "]",
+                        arms.iter().map(|(pattern, arm)| {
+                            debug_sequence("", "(", ")", [vec![Value(pattern)], vec![Node(arm)]])
+                        }),
+                    ),
+                };
+                (
+                    "Match",
+                    vec![vec![Node(scrutinee)], arms, vec![Value(span)]],
+                )
+            }
+            Expr::EnvInt(name, span) => ("EnvInt", vec![vec![Value(name)], vec![Value(span)]]),
+            Expr::NamedArg(name, value, span) => (
+                "NamedArg",
+                vec![vec![Value(name)], vec![Node(value)], vec![Value(span)]],
+            ),
+            Expr::PrintFormat(pieces, args, span) => (
+                "PrintFormat",
+                vec![vec![Value(pieces)], debug_list(args), vec![Value(span)]],
+            ),
+            Expr::Str(text, span) => ("Str", vec![vec![Value(text)], vec![Value(span)]]),
+            Expr::Array(elements, span) => ("Array", vec![debug_list(elements), vec![Value(span)]]),
+        };
+        debug_sequence(name, "(", ")", fields)
+    }
+}
+
+// Dropping, cloning, comparing and printing a tree all go through its nodes
+// with a work list rather than by recursion, so that none of them depends on
+// how deep the tree is: a generated program with a million `decl`s, each in
+// the body of the last, is too deep for the stack otherwise.
+
+impl Drop for Expr {
+    // The children of each node are moved out onto the work list before the
+    // node is dropped, so dropping it doesn't reach them
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        while let Some(mut expr) = pending.pop() {
+            expr.take_children(&mut pending);
+        }
+    }
+}
+
+impl Clone for Expr {
+    // Each node is visited twice: first to queue its children, then, once
+    // their copies are done, to put its own together from them
+    fn clone(&self) -> Self {
+        enum Step<'a> {
+            Visit(&'a Expr),
+            Build(&'a Expr),
+        }
+        let mut steps = vec![Step::Visit(self)];
+        let mut copies = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Visit(expr) => {
+                    steps.push(Step::Build(expr));
+                    steps.extend(expr.children().rev().map(Step::Visit));
+                }
+                Step::Build(expr) => {
+                    let children = copies.split_off(copies.len() - expr.children().count());
+                    copies.push(expr.with_children(children));
+                }
+            }
+        }
+        copies
+            .pop()
+            .expect("the root is the last node put together")
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some((a, b)) = pending.pop() {
+            if !a.eq_node(b) {
+                return false;
+            }
+            pending.extend(a.children().zip(b.children()));
+        }
+        true
+    }
+}
+
+/// A piece of the `Debug` output of a tree.
+enum DebugPiece<'a> {
+    Node(&'a Expr),
+    Text(&'static str),
+    Value(&'a dyn fmt::Debug),
+    Open(&'static str),
+    Next,
+    Close(&'static str),
+}
+
+/// The pieces of a list of expressions.
+fn debug_list(items: &[Expr]) -> Vec<DebugPiece<'_>> {
+    match items {
+        [] => vec![DebugPiece::Text("[]")],
+        items => debug_sequence(
+            "",
+            "[",
+            "]",
+            items.iter().map(|item| vec![DebugPiece::Node(item)]),
+        ),
+    }
+}
+
+/// `name`, then `fields` between `open` and `close`, the way `Debug` writes
+/// a tuple or a list.
+fn debug_sequence<'a>(
+    name: &'static str,
+    open: &'static str,
+    close: &'static str,
+    fields: impl IntoIterator<Item = Vec<DebugPiece<'a>>>,
+) -> Vec<DebugPiece<'a>> {
+    let mut pieces = vec![DebugPiece::Text(name), DebugPiece::Open(open)];
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            pieces.push(DebugPiece::Next);
+        }
+        pieces.extend(field);
+    }
+    pieces.push(DebugPiece::Close(close));
+    pieces
+}
+
+// What `#[derive(Debug)]` would write, `{:#?}` included
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pretty = f.alternate();
+        let mut depth = 0;
+        let mut line_start = false;
+        // With `{:#?}`, every line inside a tuple or a list is indented
+        let mut write = |f: &mut fmt::Formatter<'_>, depth: usize, text: &str| {
+            for line in text.split_inclusive('\n') {
+                if line_start {
+                    f.write_str(&"    ".repeat(depth))?;
+                }
+                f.write_str(line)?;
+                line_start = line.ends_with('\n');
+            }
+            Ok(())
+        };
+        let mut pending = vec![DebugPiece::Node(self)];
+        while let Some(piece) = pending.pop() {
+            match piece {
+                DebugPiece::Node(expr) => pending.extend(expr.debug_pieces().into_iter().rev()),
+                DebugPiece::Text(text) => write(f, depth, text)?,
+                DebugPiece::Value(value) if pretty => write(f, depth, &format!("{:#?}", value))?,
+                DebugPiece::Value(value) => write!(f, "{:?}", value)?,
+                DebugPiece::Open(open) if pretty => {
+                    write(f, depth, open)?;
+                    write(f, depth, "\n")?;
+                    depth += 1;
+                }
+                DebugPiece::Next if pretty => write(f, depth, ",\n")?,
+                DebugPiece::Close(close) if pretty => {
+                    write(f, depth, ",\n")?;
+                    depth -= 1;
+                    write(f, depth, close)?;
+                }
+                DebugPiece::Open(open) => f.write_str(open)?,
+                DebugPiece::Next => f.write_str(", ")?,
+                DebugPiece::Close(close) => f.write_str(close)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// The code of the expression on one line: `decl x <- 1 in print x; + x 1`
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&crate::printer::one_line(self, None))
+    }
 }
 
 pomelo! {
     %include {
-        use crate::parser::{Expr, Pattern};
+        use crate::parser::{
+            CmpOp, Expr, Import, Name, ParseError, Pattern, Span, around_globals, block,
+            builtin, interpolate,
+        };
+        use crate::tokenizer::Literal;
     }
 
-    %token #[derive(Debug, Clone, PartialEq)] pub enum Token {};
+    %token
+        #[derive(Debug, Clone, PartialEq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "serde", serde(tag = "type", content = "fields"))]
+        pub enum Token {};
+    %extra_token Span;
+    %error ParseError;
+    // Unbounded: the stack grows with every statement of a sequence and every
+    // `decl` of a chain, and `Nesting` bounds everything else
+    %stack_size 0;
+
+    %syntax_error {
+        match token {
+            Some(token) => {
+                let span = *token.extra();
+                Err(ParseError {
+                    message: format!("unexpected {} at line {}, column {}", token, span.line, span.column),
+                    span: Some(span),
+                })
+            }
+            None => Err(ParseError { message: "unexpected end of input".to_string(), span: None }),
+        }
+    }
+
+    // Reached when the input ends where the grammar can't, which doesn't go
+    // through %syntax_error
+    %parse_fail {
+        ParseError { message: "unexpected end of input".to_string(), span: None }
+    }
 
     // Precedence rules to resolve conflicts
     %right Semicolon;  // Right-associative to continue building sequences
     %left Assign;
     %left With;
-    %left Identifier IntegerLiteral ParenL While Match;  // Atom tokens
+    %left Identifier IntegerLiteral ParenL While Until Match Loop ReadLine BracketL VecNew None;  // Atom tokens
     %right Pipe;
     %right In;
 
     %type IntegerLiteral i64;
-    %type Identifier String;
+    %type Identifier Name;
+    %type StringLiteral Literal;
     %type expr Expr;
-    %type seq_expr Expr;
+    %type decl_expr Expr;
+    %type seq_expr Vec<Expr>;
     %type atom_expr Expr;
     %type assign_expr Expr;
     %type call_expr Expr;
-    %type program Expr;
+    %type program (Vec<Import>, Expr);
+    %type imports Vec<Import>;
+    %type globals Vec<(Name, Expr, Span)>;
     %type pattern Pattern;
     %type match_arms Vec<(Pattern, Expr)>;
-    %type param_list Vec<String>;
+    %type param_list Vec<(Name, Option<i64>)>;
+    %type param (Name, Option<i64>);
     %type arg_list Vec<Expr>;
+    %type named_args Vec<Expr>;
+    %type reduction (Span, Name);
 
     // Start symbol
     %start_symbol program;
 
-    // Program is just an expression
-    program ::= expr(e) { e }
+    // A program is an expression, after the files it imports and its globals
+    program ::= expr(e) { (vec![], e) }
+    program ::= globals(g) expr(e) { (vec![], around_globals(g, e)) }
+    program ::= imports(i) expr(e) { (i, e) }
+    program ::= imports(i) globals(g) expr(e) { (i, around_globals(g, e)) }
+
+    imports ::= Import StringLiteral((span, path)) { vec![Import { path: path.text, span }] }
+    imports ::= imports(mut list) Import StringLiteral((span, path)) {
+        list.push(Import { path: path.text, span });
+        list
+    }
+
+    globals ::= Global Identifier((span, name)) Assign assign_expr(value) {
+        vec![(name, value, span)]
+    }
+    globals ::= globals(mut list) Global Identifier((span, name)) Assign assign_expr(value) {
+        list.push((name, value, span));
+        list
+    }
+
+    expr ::= decl_expr(e) { e }
+    expr ::= seq_expr(statements) { block(statements) }
 
     // Declaration expressions (lowest precedence - captures everything after In)
-    expr ::= Decl Identifier(var) Assign expr(val) In expr(body) {
-        Expr::Decl(var, vec![], Box::new(val), Box::new(body))
+    decl_expr ::= Decl Identifier((span, var)) Assign expr(val) In expr(body) {
+        Expr::Decl(var, vec![], Box::new(val), Box::new(body), span)
     }
-    expr ::= Decl Identifier(var) param_list(params) Assign expr(val) In expr(body) {
-        Expr::Decl(var, params, Box::new(val), Box::new(body))
+    decl_expr ::= Decl Identifier((span, var)) param_list(params) Assign expr(val) In expr(body) {
+        Expr::Decl(var, params, Box::new(val), Box::new(body), span)
     }
-    expr ::= seq_expr(e) { e }
 
-    param_list ::= Identifier(param) { 
+    param_list ::= param(param) {
         vec![param]
     }
-    param_list ::= param_list(mut list) Identifier(param) { 
-        list.push(param); 
-        list 
+    param_list ::= param_list(mut list) param(param) {
+        list.push(param);
+        list
+    }
+
+    // A parameter, or one with a default value: (name <- literal)
+    param ::= Identifier((_, name)) { (name, None) }
+    param ::= ParenL Identifier((_, name)) Assign IntegerLiteral((_, default)) ParenR {
+        (name, Some(default))
     }
 
     // Sequence expressions - make semicolon right-associative to avoid conflict
-    // Allow any expr (including declarations) in sequences
-    seq_expr ::= assign_expr(first) Semicolon expr(second) { Expr::Seq(Box::new(first), Box::new(second)) }
-    seq_expr ::= assign_expr(e) [Semicolon] { e }
+    // Allow any expr (including declarations) in sequences. The statements are
+    // collected last first, so that each `;` adds to the end of one vector
+    // (see `block`)
+    seq_expr ::= assign_expr(first) Semicolon seq_expr(mut rest) {
+        rest.push(first);
+        rest
+    }
+    seq_expr ::= assign_expr(first) Semicolon decl_expr(last) { vec![last, first] }
+    seq_expr ::= assign_expr(e) [Semicolon] { vec![e] }
 
     // Assignment expressions
-    assign_expr ::= Identifier(var) Assign assign_expr(val) { Expr::Assign(var, Box::new(val)) }
+    assign_expr ::= Identifier((span, var)) Assign assign_expr(val) { Expr::Assign(var, Box::new(val), span) }
     assign_expr ::= call_expr(e) [Assign] { e }
 
     // Function call expressions - reorder to prefer call over plain identifier
-    call_expr ::= Print atom_expr(arg) { Expr::Call("print".to_string(), vec![arg]) }
-    call_expr ::= Plus atom_expr(arg1) atom_expr(arg2) { Expr::Call("+".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Minus atom_expr(arg1) atom_expr(arg2) { Expr::Call("-".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Star atom_expr(arg1) atom_expr(arg2) { Expr::Call("*".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Slash atom_expr(arg1) atom_expr(arg2) { Expr::Call("/".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Percent atom_expr(arg1) atom_expr(arg2) { Expr::Call("%".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Less atom_expr(arg1) atom_expr(arg2) { Expr::Call("<".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Greater atom_expr(arg1) atom_expr(arg2) { Expr::Call(">".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Equals atom_expr(arg1) atom_expr(arg2) { Expr::Call("=".to_string(), vec![arg1, arg2]) }
-    call_expr ::= NotEquals atom_expr(arg1) atom_expr(arg2) { Expr::Call("!=".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Ampersand atom_expr(arg1) atom_expr(arg2) { Expr::Call("&".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Pipe atom_expr(arg1) atom_expr(arg2) { Expr::Call("|".to_string(), vec![arg1, arg2]) }
-    call_expr ::= Exclam atom_expr(arg) { Expr::Call("!".to_string(), vec![arg]) }
+    call_expr ::= Print(span) atom_expr(arg) { Expr::Call(builtin("print"), vec![arg], span) }
+    call_expr ::= Print(span) StringLiteral((text_span, text)) { interpolate(&text.raw, text_span, span)? }
+    call_expr ::= Plus(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("+"), vec![arg1, arg2], span) }
+    call_expr ::= Minus(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("-"), vec![arg1, arg2], span) }
+    call_expr ::= Star(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("*"), vec![arg1, arg2], span) }
+    call_expr ::= Slash(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("/"), vec![arg1, arg2], span) }
+    call_expr ::= Percent(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("%"), vec![arg1, arg2], span) }
+    call_expr ::= Fdiv(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("fdiv"), vec![arg1, arg2], span) }
+    call_expr ::= Emod(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("emod"), vec![arg1, arg2], span) }
+    call_expr ::= Less(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("<"), vec![arg1, arg2], span) }
+    call_expr ::= Greater(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin(">"), vec![arg1, arg2], span) }
+    call_expr ::= Equals(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("="), vec![arg1, arg2], span) }
+    call_expr ::= NotEquals(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("!="), vec![arg1, arg2], span) }
+    call_expr ::= Ampersand(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("&"), vec![arg1, arg2], span) }
+    call_expr ::= Pipe(span) atom_expr(arg1) atom_expr(arg2) { Expr::Call(builtin("|"), vec![arg1, arg2], span) }
+    call_expr ::= Exclam(span) atom_expr(arg) { Expr::Call(builtin("!"), vec![arg], span) }
+    call_expr ::= Ord(span) atom_expr(arg) { Expr::Call(builtin("ord"), vec![arg], span) }
+    call_expr ::= Chr(span) atom_expr(arg) { Expr::Call(builtin("chr"), vec![arg], span) }
+    call_expr ::= PrintChar(span) atom_expr(arg) { Expr::Call(builtin("print_char"), vec![arg], span) }
+    call_expr ::= Strlen(span) atom_expr(arg) { Expr::Call(builtin("strlen"), vec![arg], span) }
+    call_expr ::= PrintStr(span) atom_expr(arg) { Expr::Call(builtin("print_str"), vec![arg], span) }
+    call_expr ::= EnvInt(span) StringLiteral((_, name)) { Expr::EnvInt(name.text, span) }
+    call_expr ::= Fopen(span) StringLiteral((path_span, path)) StringLiteral((mode_span, mode)) {
+        let args = vec![Expr::Str(path.text, path_span), Expr::Str(mode.text, mode_span)];
+        Expr::Call(builtin("fopen"), args, span)
+    }
+    call_expr ::= WriteLine(span) atom_expr(file) atom_expr(value) {
+        Expr::Call(builtin("write_line"), vec![file, value], span)
+    }
+    call_expr ::= FreadInt(span) atom_expr(file) { Expr::Call(builtin("fread_int"), vec![file], span) }
+    call_expr ::= Fclose(span) atom_expr(file) { Expr::Call(builtin("fclose"), vec![file], span) }
+    call_expr ::= Get(span) atom_expr(array) atom_expr(index) {
+        Expr::Call(builtin("get"), vec![array, index], span)
+    }
+    call_expr ::= Set(span) atom_expr(array) atom_expr(index) atom_expr(value) {
+        Expr::Call(builtin("set"), vec![array, index, value], span)
+    }
+    call_expr ::= Len(span) atom_expr(array) { Expr::Call(builtin("len"), vec![array], span) }
+    call_expr ::= Slice(span) atom_expr(array) atom_expr(start) atom_expr(len) {
+        Expr::Call(builtin("slice"), vec![array, start, len], span)
+    }
+    call_expr ::= Ref(span) atom_expr(variable) { Expr::Call(builtin("ref"), vec![variable], span) }
+    call_expr ::= Deref(span) atom_expr(pointer) { Expr::Call(builtin("deref"), vec![pointer], span) }
+    call_expr ::= Store(span) atom_expr(pointer) atom_expr(value) {
+        Expr::Call(builtin("store"), vec![pointer, value], span)
+    }
+    call_expr ::= Alloc(span) atom_expr(n) { Expr::Call(builtin("alloc"), vec![n], span) }
+    call_expr ::= Load8(span) atom_expr(block) atom_expr(index) {
+        Expr::Call(builtin("load8"), vec![block, index], span)
+    }
+    call_expr ::= Store8(span) atom_expr(block) atom_expr(index) atom_expr(value) {
+        Expr::Call(builtin("store8"), vec![block, index, value], span)
+    }
+    call_expr ::= Free(span) atom_expr(block) { Expr::Call(builtin("free"), vec![block], span) }
+    call_expr ::= Some(span) atom_expr(value) { Expr::Call(builtin("some"), vec![value], span) }
+    call_expr ::= Ok(span) atom_expr(value) { Expr::Call(builtin("ok"), vec![value], span) }
+    call_expr ::= Err(span) atom_expr(error) { Expr::Call(builtin("err"), vec![error], span) }
+    call_expr ::= Try(span) atom_expr(result) { Expr::Call(builtin("try"), vec![result], span) }
+    call_expr ::= Push(span) atom_expr(vector) atom_expr(value) {
+        Expr::Call(builtin("push"), vec![vector, value], span)
+    }
+    call_expr ::= Pop(span) atom_expr(vector) { Expr::Call(builtin("pop"), vec![vector], span) }
+    call_expr ::= Vlen(span) atom_expr(vector) { Expr::Call(builtin("vlen"), vec![vector], span) }
+    call_expr ::= Vget(span) atom_expr(vector) atom_expr(index) {
+        Expr::Call(builtin("vget"), vec![vector, index], span)
+    }
+    call_expr ::= Vset(span) atom_expr(vector) atom_expr(index) atom_expr(value) {
+        Expr::Call(builtin("vset"), vec![vector, index, value], span)
+    }
+    call_expr ::= Break(span) atom_expr(value) { Expr::Break(Some(Box::new(value)), span) }
+    // Lower than the atom tokens: a value right after `break` is its value
+    call_expr ::= Break(span) [Assign] { Expr::Break(None, span) }
     call_expr ::= atom_expr(e) { e }
-    
-    arg_list ::= atom_expr(arg) { 
+
+    arg_list ::= atom_expr(arg) {
         vec![arg]
     }
-    arg_list ::= arg_list(mut list) atom_expr(arg) { 
-        list.push(arg); 
-        list 
+    arg_list ::= arg_list(mut list) atom_expr(arg) {
+        list.push(arg);
+        list
+    }
+
+    // Named arguments come after the positional ones: (f 1 y: 2)
+    named_args ::= Identifier((span, name)) Colon atom_expr(value) {
+        vec![Expr::NamedArg(name, Box::new(value), span)]
+    }
+    named_args ::= named_args(mut list) Identifier((span, name)) Colon atom_expr(value) {
+        list.push(Expr::NamedArg(name, Box::new(value), span));
+        list
     }
 
     // Atomic expressions (highest precedence)
-    atom_expr ::= IntegerLiteral(n) { Expr::Number(n) }
-    atom_expr ::= Identifier(id) { Expr::Ident(id) }
-    atom_expr ::= ParenL Identifier(func) arg_list(args) ParenR { Expr::Call(func, args) }
+    atom_expr ::= IntegerLiteral((_, n)) { Expr::Number(n) }
+    atom_expr ::= Identifier((span, id)) { Expr::Ident(id, span) }
+    atom_expr ::= ReadLine(span) { Expr::Call(builtin("read_line"), vec![], span) }
+    atom_expr ::= VecNew(span) { Expr::Call(builtin("vec_new"), vec![], span) }
+    atom_expr ::= None(span) { Expr::Call(builtin("none"), vec![], span) }
+    atom_expr ::= BracketL(span) BracketR { Expr::Array(vec![], span) }
+    atom_expr ::= BracketL(span) arg_list(elements) BracketR { Expr::Array(elements, span) }
+    atom_expr ::= ParenL Identifier((span, func)) arg_list(args) ParenR { Expr::Call(func, args, span) }
+    atom_expr ::= ParenL Identifier((span, func)) named_args(args) ParenR { Expr::Call(func, args, span) }
+    atom_expr ::= ParenL Identifier((span, func)) arg_list(mut args) named_args(named) ParenR {
+        args.extend(named);
+        Expr::Call(func, args, span)
+    }
     atom_expr ::= ParenL expr(e) ParenR { e }
 
+    // sum and product take any number of arguments, so they need parentheses
+    atom_expr ::= ParenL reduction((span, name)) ParenR { Expr::Call(name, vec![], span) }
+    atom_expr ::= ParenL reduction((span, name)) arg_list(args) ParenR { Expr::Call(name, args, span) }
+    reduction ::= Sum(span) { (span, builtin("sum")) }
+    reduction ::= Product(span) { (span, builtin("product")) }
+
     // While loop
-    atom_expr ::= While expr(cond) Do expr(body) Done {
-        Expr::While(Box::new(cond), Box::new(body))
+    atom_expr ::= While(span) expr(cond) Do expr(body) Done {
+        Expr::While(Box::new(cond), Box::new(body), span)
+    }
+    atom_expr ::= While(span) expr(cond) Do expr(body) Yield Done {
+        Expr::WhileYield(Box::new(cond), Box::new(body), span)
     }
+    // Until loop, a while loop on the negated condition (a keyword from
+    // version 2 of the language on, see `tokenizer::STAGED_KEYWORDS`)
+    atom_expr ::= Until(span) expr(cond) Do expr(body) Done {
+        let cond = Expr::Call(builtin("!"), vec![cond], span);
+        Expr::While(Box::new(cond), Box::new(body), span)
+    }
+
+    // Loop, left with break
+    atom_expr ::= Loop(span) expr(body) End { Expr::Loop(Box::new(body), span) }
 
     // Match expression
-    atom_expr ::= Match expr(scrutinee) With match_arms(arms) [With] {
-        Expr::Match(Box::new(scrutinee), arms)
+    atom_expr ::= Match(span) expr(scrutinee) With match_arms(arms) [With] {
+        Expr::Match(Box::new(scrutinee), arms, span)
     }
 
     // Pattern rules
-    pattern ::= IntegerLiteral(n) { Pattern::Literal(n) }
+    pattern ::= IntegerLiteral((_, n)) { Pattern::Literal(n) }
     pattern ::= Underscore { Pattern::Wildcard }
+    pattern ::= Identifier((span, name)) { Pattern::Bind(name, span) }
+    pattern ::= Identifier((span, name)) At pattern(inner) { Pattern::At(name, Box::new(inner), span) }
+    pattern ::= None { Pattern::None }
+    pattern ::= Some pattern(inner) { Pattern::Some(Box::new(inner)) }
+    pattern ::= Ok pattern(inner) { Pattern::Ok(Box::new(inner)) }
+    pattern ::= Err pattern(inner) { Pattern::Err(Box::new(inner)) }
+    pattern ::= ParenL pattern(p) ParenR { p }
+    pattern ::= Less IntegerLiteral((_, n)) { Pattern::Cmp(CmpOp::Less, n) }
+    pattern ::= LessEq IntegerLiteral((_, n)) { Pattern::Cmp(CmpOp::LessEq, n) }
+    pattern ::= Greater IntegerLiteral((_, n)) { Pattern::Cmp(CmpOp::Greater, n) }
+    pattern ::= GreaterEq IntegerLiteral((_, n)) { Pattern::Cmp(CmpOp::GreaterEq, n) }
 
     // Match arms
     match_arms ::= Pipe pattern(p) Arrow expr(e) [Pipe] {
@@ -132,47 +1017,483 @@ pomelo! {
     }
 }
 
+/// The name of the builtin `name`. Builtins are written with their own
+/// tokens, so they don't go through the lexer's interner; every call to one
+/// shares the name this returns instead.
+pub fn builtin(name: &str) -> Name {
+    thread_local! {
+        static BUILTINS: RefCell<BTreeSet<Name>> = const { RefCell::new(BTreeSet::new()) };
+    }
+    BUILTINS.with_borrow_mut(|builtins| match builtins.get(name) {
+        Some(interned) => interned.clone(),
+        None => {
+            let interned = Name::from(name);
+            builtins.insert(interned.clone());
+            interned
+        }
+    })
+}
+
+/// The number of arguments the builtin `name` takes, or `None` if no builtin
+/// has that name or it takes any number (see `is_reduction`). Builtins are
+/// written with their own tokens, so the grammar already gives them the right
+/// number of arguments; a tree built some other way may not.
+pub fn builtin_arity(name: &str) -> Option<usize> {
+    match name {
+        "read_line" | "vec_new" | "none" => Some(0),
+        "print" | "!" | "ord" | "chr" | "print_char" | "strlen" | "print_str" => Some(1),
+        "fread_int" | "fclose" | "len" | "pop" | "vlen" | "ref" | "deref" | "alloc" | "free" => {
+            Some(1)
+        }
+        "some" | "ok" | "err" | "try" => Some(1),
+        "+" | "-" | "*" | "/" | "%" | "emod" | "fdiv" | "<" | ">" | "=" | "!=" | "&" | "|" => {
+            Some(2)
+        }
+        "fopen" | "write_line" | "get" | "push" | "vget" | "store" | "load8" => Some(2),
+        "set" | "vset" | "slice" | "store8" => Some(3),
+        _ => None,
+    }
+}
+
+/// Whether `name` is a builtin that folds `+` (`sum`) or `*` (`product`) over
+/// any number of arguments.
+pub fn is_reduction(name: &str) -> bool {
+    matches!(name, "sum" | "product")
+}
+
+/// How many arguments a function with `params` takes at least (up to its last
+/// parameter without a default value) and at most.
+pub fn arity(params: &[(Name, Option<i64>)]) -> (usize, usize) {
+    let required = params
+        .iter()
+        .rposition(|(_, default)| default.is_none())
+        .map_or(0, |last| last + 1);
+    (required, params.len())
+}
+
+/// `print "text"`, with each `{name}` in the text replaced by the value of the
+/// variable `name`; `{{` and `}}` stand for `{` and `}`. `raw` is the text as
+/// written, escapes and all, and `at` is where the literal is (its opening
+/// quote), so each variable gets its position in the source.
+fn interpolate(raw: &str, at: Span, print: Span) -> Result<Expr, ParseError> {
+    let mut pieces = vec![String::new()];
+    let mut args = Vec::new();
+    let mut chars = decoded_chars(raw, at).into_iter().peekable();
+    while let Some((c, start)) = chars.next() {
+        match c {
+            '{' | '}' if chars.peek().is_some_and(|&(next, _)| next == c) => {
+                chars.next();
+                pieces.last_mut().unwrap().push(c);
+            }
+            '{' => {
+                let name_at = chars.peek().map_or(start, |&(_, at)| at);
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some(('}', _)) => break,
+                        Some((c, _)) => name.push(c),
+                        None => {
+                            return Err(ParseError {
+                                message: format!(
+                                    "unclosed '{{' in string at line {}, column {}",
+                                    start.line, start.column
+                                ),
+                                span: Some(start),
+                            });
+                        }
+                    }
+                }
+                let is_identifier = name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+                    && name.chars().all(crate::tokenizer::is_identifier_char)
+                    && !crate::tokenizer::KEYWORDS.contains_key(name.as_str());
+                if !is_identifier {
+                    return Err(ParseError {
+                        message: format!(
+                            "'{{{}}}' in string at line {}, column {} is not a variable",
+                            name, start.line, start.column
+                        ),
+                        span: Some(start),
+                    });
+                }
+                let span = Span {
+                    len: name.chars().count(),
+                    ..name_at
+                };
+                args.push(Expr::Ident(name.into(), span));
+                pieces.push(String::new());
+            }
+            c => pieces.last_mut().unwrap().push(c),
+        }
+    }
+    Ok(Expr::PrintFormat(pieces, args, print))
+}
+
+/// The characters of a literal, with its escapes decoded, each with where it
+/// starts in the source: `raw` is its text as written and `at` its opening
+/// quote. An escape takes two columns for its one character.
+fn decoded_chars(raw: &str, at: Span) -> Vec<(char, Span)> {
+    let mut position = Span {
+        column: at.column + 1,
+        len: 1,
+        ..at
+    };
+    let mut decoded = Vec::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        let start = position;
+        advance(&mut position, c);
+        // The lexer has already rejected the escapes that don't exist
+        let c = match c {
+            '\\' => match chars.next() {
+                Some(escaped) => {
+                    advance(&mut position, escaped);
+                    crate::tokenizer::unescape(escaped).unwrap_or(escaped)
+                }
+                None => c,
+            },
+            c => c,
+        };
+        decoded.push((c, start));
+    }
+    decoded
+}
+
+/// Moves `position` past the character `c`.
+fn advance(position: &mut Span, c: char) {
+    if c == '\n' {
+        position.line += 1;
+        position.column = 1;
+    } else {
+        position.column += 1;
+    }
+}
+
+/// The statements of a sequence, collected last first, as one block; a
+/// single statement is just itself.
+fn block(mut statements: Vec<Expr>) -> Expr {
+    if statements.len() == 1 {
+        return statements.pop().expect("one statement");
+    }
+    statements.reverse();
+    Expr::Block(statements)
+}
+
+/// `body` in the scope of `globals`, the first one outermost.
+fn around_globals(globals: Vec<(Name, Expr, Span)>, body: Expr) -> Expr {
+    globals
+        .into_iter()
+        .rev()
+        .fold(body, |body, (name, value, span)| {
+            Expr::Global(name, Box::new(value), Box::new(body), span)
+        })
+}
+
 // Re-export the Token enum from the generated parser module
 pub use parser::Token;
 
+impl Token {
+    /// The text of the token, for the ones always written the same way: its
+    /// keyword or symbol.
+    pub fn lexeme(&self) -> Option<&'static str> {
+        let lexeme = match self {
+            Token::Identifier(_) | Token::IntegerLiteral(_) | Token::StringLiteral(_) => {
+                return None;
+            }
+            Token::Decl(_) => "decl",
+            Token::While(_) => "while",
+            Token::Until(_) => "until",
+            Token::Do(_) => "do",
+            Token::Done(_) => "done",
+            Token::Yield(_) => "yield",
+            Token::Loop(_) => "loop",
+            Token::End(_) => "end",
+            Token::Break(_) => "break",
+            Token::Global(_) => "global",
+            Token::Match(_) => "match",
+            Token::With(_) => "with",
+            Token::In(_) => "in",
+            Token::Import(_) => "import",
+            Token::Print(_) => "print",
+            Token::EnvInt(_) => "env_int",
+            Token::Ord(_) => "ord",
+            Token::Chr(_) => "chr",
+            Token::PrintChar(_) => "print_char",
+            Token::ReadLine(_) => "read_line",
+            Token::Strlen(_) => "strlen",
+            Token::PrintStr(_) => "print_str",
+            Token::Fopen(_) => "fopen",
+            Token::WriteLine(_) => "write_line",
+            Token::FreadInt(_) => "fread_int",
+            Token::Fclose(_) => "fclose",
+            Token::Get(_) => "get",
+            Token::Set(_) => "set",
+            Token::Len(_) => "len",
+            Token::Slice(_) => "slice",
+            Token::Ref(_) => "ref",
+            Token::Deref(_) => "deref",
+            Token::Store(_) => "store",
+            Token::Alloc(_) => "alloc",
+            Token::Load8(_) => "load8",
+            Token::Store8(_) => "store8",
+            Token::Free(_) => "free",
+            Token::Some(_) => "some",
+            Token::None(_) => "none",
+            Token::Ok(_) => "ok",
+            Token::Err(_) => "err",
+            Token::Try(_) => "try",
+            Token::VecNew(_) => "vec_new",
+            Token::Push(_) => "push",
+            Token::Pop(_) => "pop",
+            Token::Vlen(_) => "vlen",
+            Token::Vget(_) => "vget",
+            Token::Vset(_) => "vset",
+            Token::Sum(_) => "sum",
+            Token::Product(_) => "product",
+            Token::Emod(_) => "emod",
+            Token::Fdiv(_) => "fdiv",
+            Token::Less(_) => "<",
+            Token::Greater(_) => ">",
+            Token::Equals(_) => "=",
+            Token::NotEquals(_) => "!=",
+            Token::LessEq(_) => "<=",
+            Token::GreaterEq(_) => ">=",
+            Token::Plus(_) => "+",
+            Token::Minus(_) => "-",
+            Token::Star(_) => "*",
+            Token::Slash(_) => "/",
+            Token::Percent(_) => "%",
+            Token::Pipe(_) => "|",
+            Token::Ampersand(_) => "&",
+            Token::Exclam(_) => "!",
+            Token::Arrow(_) => "->",
+            Token::Assign(_) => "<-",
+            Token::Underscore(_) => "_",
+            Token::Semicolon(_) => ";",
+            Token::Colon(_) => ":",
+            Token::ParenL(_) => "(",
+            Token::ParenR(_) => ")",
+            Token::BracketL(_) => "[",
+            Token::BracketR(_) => "]",
+            Token::At(_) => "@",
+        };
+        Some(lexeme)
+    }
+}
+
+// The token as messages mention it: keywords bare, symbols quoted, and
+// literals with what they hold (`decl`, `'<-'`, `identifier 'x'`, `integer 5`)
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Identifier((_, name)) => write!(f, "identifier '{}'", name),
+            Token::IntegerLiteral((_, value)) => write!(f, "integer {}", value),
+            Token::StringLiteral((_, literal)) => write!(f, "string {:?}", literal.text),
+            token => {
+                let lexeme = token.lexeme().expect("only literals have no fixed text");
+                if lexeme.starts_with(|c: char| c.is_ascii_lowercase()) {
+                    f.write_str(lexeme)
+                } else {
+                    write!(f, "'{}'", lexeme)
+                }
+            }
+        }
+    }
+}
+
+/// How deeply parentheses, brackets, loops and matches can be nested in one
+/// another by default (see `parse_tokens_with_limit`).
+pub const MAX_NESTING: usize = 1_000;
+
+/// Parses a tokenized file into its imports and its expression, with nesting
+/// up to `MAX_NESTING` levels deep.
+pub fn parse_tokens(tokens: Vec<Token>) -> Result<(Vec<Import>, Expr), ParseError> {
+    parse_tokens_with_limit(tokens, MAX_NESTING)
+}
+
+/// Parses a tokenized file into its imports and its expression. Past
+/// `max_nesting` levels of nesting, it stops with an error at the token that
+/// goes too deep: the parser's stack, and the tree it builds, would grow as
+/// deep as the input, and every phase after it goes down the tree recursively.
+pub fn parse_tokens_with_limit(
+    tokens: Vec<Token>,
+    max_nesting: usize,
+) -> Result<(Vec<Import>, Expr), ParseError> {
+    let mut parser = parser::Parser::new();
+    let mut nesting = Nesting::default();
+    // Between a `decl` or `global` and its `<-`, where names are declared
+    let mut naming = false;
+    for token in tokens {
+        nesting.track(&token, max_nesting)?;
+        let span = *token.extra();
+        let builtin = token.lexeme().filter(|lexeme| {
+            naming
+                && lexeme.starts_with(|c: char| c.is_ascii_lowercase())
+                && !LANGUAGE_KEYWORDS.contains(lexeme)
+        });
+        naming = match token {
+            Token::Decl(_) | Token::Global(_) => true,
+            Token::Assign(_) => false,
+            _ => naming,
+        };
+        parser.parse(token).map_err(|error| match builtin {
+            Some(builtin) => redefined_builtin(builtin, span),
+            None => error,
+        })?;
+    }
+    parser.end_of_input()
+}
+
+/// The keywords of the language itself; the others name builtins.
+const LANGUAGE_KEYWORDS: [&str; 14] = [
+    "decl", "while", "until", "do", "done", "yield", "loop", "end", "break", "global", "match",
+    "with", "in", "import",
+];
+
+/// The error for a declaration of a variable, function or parameter named
+/// after `builtin` at `span`. The builtins are keywords, so the parser
+/// would only say that it didn't expect one there.
+fn redefined_builtin(builtin: &str, span: Span) -> ParseError {
+    ParseError {
+        message: format!(
+            "'{}' is a builtin and cannot be redefined at line {}, column {}; \
+             try another name, like 'my_{}'",
+            builtin, span.line, span.column, builtin
+        ),
+        span: Some(span),
+    }
+}
+
+/// The constructs open at a point of the input, innermost last: the tokens
+/// that opened them (`(`, `[`, `while`, `loop`, `match`, `decl`), and how
+/// many of them count towards the depth (all but the `decl`s).
+#[derive(Default)]
+struct Nesting {
+    open: Vec<Opener>,
+    depth: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opener {
+    Paren,
+    Bracket,
+    While,
+    Loop,
+    Match,
+    /// A `decl` waiting for its `in`, only kept to know which `match`es the
+    /// `in` ends
+    Decl,
+}
+
+impl Nesting {
+    fn track(&mut self, token: &Token, max_nesting: usize) -> Result<(), ParseError> {
+        let (opener, closes) = match token {
+            Token::ParenL(_) => (Some(Opener::Paren), None),
+            Token::BracketL(_) => (Some(Opener::Bracket), None),
+            Token::While(_) | Token::Until(_) => (Some(Opener::While), None),
+            Token::Loop(_) => (Some(Opener::Loop), None),
+            Token::Match(_) => (Some(Opener::Match), None),
+            Token::Decl(_) => (Some(Opener::Decl), None),
+            Token::ParenR(_) => (None, Some(Opener::Paren)),
+            Token::BracketR(_) => (None, Some(Opener::Bracket)),
+            Token::Done(_) => (None, Some(Opener::While)),
+            Token::End(_) => (None, Some(Opener::Loop)),
+            Token::In(_) => (None, Some(Opener::Decl)),
+            _ => (None, None),
+        };
+        // A match has no token of its own to end it: its last arm goes on up
+        // to whatever closes the construct around it, or to a `;` or `in` at
+        // the level it started at
+        if matches!(token, Token::Semicolon(_) | Token::In(_)) {
+            let open = self.open.iter().rev();
+            let matches = open.take_while(|open| **open == Opener::Match).count();
+            self.close(self.open.len() - matches);
+        }
+        if let Some(closes) = closes
+            && let Some(at) = self.open.iter().rposition(|open| *open == closes)
+        {
+            // An `in` only ends the `decl` it belongs to, not any construct
+            // still open inside its value
+            if closes != Opener::Decl || at + 1 == self.open.len() {
+                self.close(at);
+            }
+        }
+        if opener == Some(Opener::Decl) {
+            self.open.push(Opener::Decl);
+        } else if let Some(opener) = opener {
+            if self.depth >= max_nesting {
+                let span = *token.extra();
+                return Err(ParseError {
+                    message: format!(
+                        "maximum nesting depth {} exceeded at line {}, column {}",
+                        max_nesting, span.line, span.column
+                    ),
+                    span: Some(span),
+                });
+            }
+            self.open.push(opener);
+            self.depth += 1;
+        }
+        Ok(())
+    }
+
+    /// Closes the constructs open from the `at`th on.
+    fn close(&mut self, at: usize) {
+        let closed = self.open[at..].iter().filter(|open| **open != Opener::Decl);
+        self.depth -= closed.count();
+        self.open.truncate(at);
+    }
+}
+
+/// The expression of a program that doesn't import anything: without a file
+/// there is nowhere to look for imports (the compiler resolves them while
+/// loading files).
+pub fn without_imports((imports, expr): (Vec<Import>, Expr)) -> Result<Expr, ParseError> {
+    match imports.first() {
+        None => Ok(expr),
+        Some(import) => Err(ParseError {
+            message: format!(
+                "import at line {}, column {}: imports are only allowed in source files",
+                import.span.line, import.span.column
+            ),
+            span: Some(import.span),
+        }),
+    }
+}
+
 /// Parse a complete MLIA program from source code string
 pub fn parse_program(input: String) -> Result<Expr, String> {
     use crate::tokenizer::Lexer;
-    
+
     // Tokenize the input
     let mut lexer = Lexer::new(input);
-    let tokens = lexer.tokenize().map_err(|e| format!("Tokenization error: {}", e))?;
-    
-    // Parse the tokens
-    let mut parser = parser::Parser::new();
-    for token in tokens {
-        parser.parse(token).map_err(|e| format!("Parse error: {:?}", e))?;
-    }
-    
-    // Finish parsing and return the AST
-    parser.end_of_input().map_err(|e| format!("Parse error at end of input: {:?}", e))
+    let tokens = lexer
+        .tokenize()
+        .map_err(|e| format!("Tokenization error: {}", e))?;
+
+    // Parse the tokens and return the AST
+    parse_tokens(tokens)
+        .and_then(without_imports)
+        .map_err(|e| e.to_string())
 }
 
 /// Parse program with verbose output: returns (AST, tokens)
 pub fn parse_program_verbose(input: String) -> Result<(Expr, Vec<Token>), String> {
     use crate::tokenizer::Lexer;
-    
+
     // Tokenize the input
     let mut lexer = Lexer::new(input);
-    let tokens = lexer.tokenize().map_err(|e| format!("Tokenization error: {}", e))?;
-    
+    let tokens = lexer
+        .tokenize()
+        .map_err(|e| format!("Tokenization error: {}", e))?;
+
     // Clone tokens for verbose output
     let tokens_for_output = tokens.clone();
-    
-    // Parse the tokens
-    let mut parser = parser::Parser::new();
-    for token in tokens {
-        parser.parse(token).map_err(|e| format!("Parse error: {:?}", e))?;
-    }
-    
-    // Finish parsing and return the AST with tokens
-    let ast = parser.end_of_input().map_err(|e| format!("Parse error at end of input: {:?}", e))?;
-    
+
+    // Parse the tokens and return the AST with tokens
+    let ast = parse_tokens(tokens)
+        .and_then(without_imports)
+        .map_err(|e| e.to_string())?;
+
     Ok((ast, tokens_for_output))
 }
 
@@ -181,36 +1502,44 @@ mod tests {
     use super::parser::*;
     use super::*;
 
+    fn ident(name: &str) -> Expr {
+        Expr::Ident(name.into(), Span::default())
+    }
+
+    fn print(arg: Expr) -> Expr {
+        Expr::Call("print".into(), vec![arg], Span::default())
+    }
+
+    fn match_expr(scrutinee: Expr, arms: Vec<(Pattern, Expr)>) -> Expr {
+        Expr::Match(Box::new(scrutinee), arms, Span::default())
+    }
+
     // T009: Parser tests for while loops
     #[test]
     fn test_while_loop_simple() {
         // Test: while x do print x done
         let mut parser = Parser::new();
 
-        parser.parse(Token::While).unwrap();
-        parser.parse(Token::Identifier("x".to_string())).unwrap();
-        parser.parse(Token::Do).unwrap();
-        parser.parse(Token::Print).unwrap();
-        parser.parse(Token::Identifier("x".to_string())).unwrap();
-        parser.parse(Token::Done).unwrap();
-        let result = parser.end_of_input();
-
-        assert!(result.is_ok(), "While loop should parse successfully");
-        let expr = result.unwrap();
-
-        match expr {
-            Expr::While(cond, body) => {
-                assert!(
-                    matches!(*cond, Expr::Ident(ref s) if s == "x"),
-                    "Condition should be identifier 'x'"
-                );
-                assert!(
-                    matches!(*body, Expr::Call(ref f, _) if f == "print"),
-                    "Body should be print call"
-                );
-            }
-            _ => panic!("Expected While expression, got {:?}", expr),
-        }
+        parser.parse(Token::While(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "x".into())))
+            .unwrap();
+        parser.parse(Token::Do(Span::default())).unwrap();
+        parser.parse(Token::Print(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "x".into())))
+            .unwrap();
+        parser.parse(Token::Done(Span::default())).unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        assert_eq!(
+            expr,
+            Expr::While(
+                Box::new(ident("x")),
+                Box::new(print(ident("x"))),
+                Span::default()
+            )
+        );
     }
 
     #[test]
@@ -218,23 +1547,78 @@ mod tests {
         // Test: while 1 do 42 done
         let mut parser = Parser::new();
 
-        parser.parse(Token::While).unwrap();
-        parser.parse(Token::IntegerLiteral(1)).unwrap();
-        parser.parse(Token::Do).unwrap();
-        parser.parse(Token::IntegerLiteral(42)).unwrap();
-        parser.parse(Token::Done).unwrap();
-        let result = parser.end_of_input();
+        parser.parse(Token::While(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 1)))
+            .unwrap();
+        parser.parse(Token::Do(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 42)))
+            .unwrap();
+        parser.parse(Token::Done(Span::default())).unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
 
-        assert!(result.is_ok(), "While loop with literals should parse");
-        let expr = result.unwrap();
+        assert_eq!(
+            expr,
+            Expr::While(
+                Box::new(Expr::Number(1)),
+                Box::new(Expr::Number(42)),
+                Span::default()
+            )
+        );
+    }
 
-        match expr {
-            Expr::While(cond, body) => {
-                assert!(matches!(*cond, Expr::Number(1)), "Condition should be 1");
-                assert!(matches!(*body, Expr::Number(42)), "Body should be 42");
-            }
-            _ => panic!("Expected While expression"),
-        }
+    #[test]
+    fn test_while_yield() {
+        // Test: while 1 do 42 yield done
+        let mut parser = Parser::new();
+
+        parser.parse(Token::While(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 1)))
+            .unwrap();
+        parser.parse(Token::Do(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 42)))
+            .unwrap();
+        parser.parse(Token::Yield(Span::default())).unwrap();
+        parser.parse(Token::Done(Span::default())).unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        assert_eq!(
+            expr,
+            Expr::WhileYield(
+                Box::new(Expr::Number(1)),
+                Box::new(Expr::Number(42)),
+                Span::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_until_loop() {
+        // Test: until x do 42 done
+        let mut parser = Parser::new();
+
+        parser.parse(Token::Until(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "x".into())))
+            .unwrap();
+        parser.parse(Token::Do(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 42)))
+            .unwrap();
+        parser.parse(Token::Done(Span::default())).unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        assert_eq!(
+            expr,
+            Expr::While(
+                Box::new(Expr::Call("!".into(), vec![ident("x")], Span::default())),
+                Box::new(Expr::Number(42)),
+                Span::default()
+            )
+        );
     }
 
     #[test]
@@ -242,29 +1626,26 @@ mod tests {
         // Test: while x do while y do 1 done done
         let mut parser = Parser::new();
 
-        parser.parse(Token::While).unwrap();
-        parser.parse(Token::Identifier("x".to_string())).unwrap();
-        parser.parse(Token::Do).unwrap();
-        parser.parse(Token::While).unwrap();
-        parser.parse(Token::Identifier("y".to_string())).unwrap();
-        parser.parse(Token::Do).unwrap();
-        parser.parse(Token::IntegerLiteral(1)).unwrap();
-        parser.parse(Token::Done).unwrap();
-        parser.parse(Token::Done).unwrap();
-        let result = parser.end_of_input();
-
-        assert!(result.is_ok(), "Nested while loops should parse");
-        let expr = result.unwrap();
-
-        match expr {
-            Expr::While(_, body) => {
-                assert!(
-                    matches!(*body, Expr::While(_, _)),
-                    "Body should be another while loop"
-                );
-            }
-            _ => panic!("Expected outer While expression"),
-        }
+        parser.parse(Token::While(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "x".into())))
+            .unwrap();
+        parser.parse(Token::Do(Span::default())).unwrap();
+        parser.parse(Token::While(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "y".into())))
+            .unwrap();
+        parser.parse(Token::Do(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 1)))
+            .unwrap();
+        parser.parse(Token::Done(Span::default())).unwrap();
+        parser.parse(Token::Done(Span::default())).unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        let while_ = |cond, body| Expr::While(Box::new(cond), Box::new(body), Span::default());
+        let inner = while_(ident("y"), Expr::Number(1));
+        assert_eq!(expr, while_(ident("x"), inner));
     }
 
     // T010: Parser tests for match expressions
@@ -273,40 +1654,37 @@ mod tests {
         // Test: match x with | 1 -> 10 | _ -> 20
         let mut parser = Parser::new();
 
-        parser.parse(Token::Match).unwrap();
-        parser.parse(Token::Identifier("x".to_string())).unwrap();
-        parser.parse(Token::With).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::IntegerLiteral(1)).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(10)).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::Underscore).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(20)).unwrap();
-        let result = parser.end_of_input();
-
-        assert!(result.is_ok(), "Match expression should parse successfully");
-        let expr = result.unwrap();
-
-        match expr {
-            Expr::Match(scrutinee, arms) => {
-                assert!(
-                    matches!(*scrutinee, Expr::Ident(ref s) if s == "x"),
-                    "Scrutinee should be 'x'"
-                );
-                assert_eq!(arms.len(), 2, "Should have 2 match arms");
-                assert!(
-                    matches!(arms[0].0, Pattern::Literal(1)),
-                    "First pattern should be literal 1"
-                );
-                assert!(
-                    matches!(arms[1].0, Pattern::Wildcard),
-                    "Second pattern should be wildcard"
-                );
-            }
-            _ => panic!("Expected Match expression, got {:?}", expr),
-        }
+        parser.parse(Token::Match(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "x".into())))
+            .unwrap();
+        parser.parse(Token::With(Span::default())).unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 1)))
+            .unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 10)))
+            .unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser.parse(Token::Underscore(Span::default())).unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 20)))
+            .unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        assert_eq!(
+            expr,
+            match_expr(
+                ident("x"),
+                vec![
+                    (Pattern::Literal(1), Expr::Number(10)),
+                    (Pattern::Wildcard, Expr::Number(20)),
+                ]
+            )
+        );
     }
 
     #[test]
@@ -314,40 +1692,55 @@ mod tests {
         // Test: match 5 with | 1 -> 10 | 2 -> 20 | 3 -> 30 | _ -> 0
         let mut parser = Parser::new();
 
-        parser.parse(Token::Match).unwrap();
-        parser.parse(Token::IntegerLiteral(5)).unwrap();
-        parser.parse(Token::With).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::IntegerLiteral(1)).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(10)).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::IntegerLiteral(2)).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(20)).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::IntegerLiteral(3)).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(30)).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::Underscore).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(0)).unwrap();
-        let result = parser.end_of_input();
-
-        assert!(result.is_ok(), "Match with multiple arms should parse");
-        let expr = result.unwrap();
-
-        match expr {
-            Expr::Match(_, arms) => {
-                assert_eq!(arms.len(), 4, "Should have 4 match arms");
-                assert!(matches!(arms[0].0, Pattern::Literal(1)));
-                assert!(matches!(arms[1].0, Pattern::Literal(2)));
-                assert!(matches!(arms[2].0, Pattern::Literal(3)));
-                assert!(matches!(arms[3].0, Pattern::Wildcard));
-            }
-            _ => panic!("Expected Match expression"),
-        }
+        parser.parse(Token::Match(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 5)))
+            .unwrap();
+        parser.parse(Token::With(Span::default())).unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 1)))
+            .unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 10)))
+            .unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 2)))
+            .unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 20)))
+            .unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 3)))
+            .unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 30)))
+            .unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser.parse(Token::Underscore(Span::default())).unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 0)))
+            .unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        assert_eq!(
+            expr,
+            match_expr(
+                Expr::Number(5),
+                vec![
+                    (Pattern::Literal(1), Expr::Number(10)),
+                    (Pattern::Literal(2), Expr::Number(20)),
+                    (Pattern::Literal(3), Expr::Number(30)),
+                    (Pattern::Wildcard, Expr::Number(0)),
+                ]
+            )
+        );
     }
 
     #[test]
@@ -355,28 +1748,23 @@ mod tests {
         // Test: match x with | _ -> 42
         let mut parser = Parser::new();
 
-        parser.parse(Token::Match).unwrap();
-        parser.parse(Token::Identifier("x".to_string())).unwrap();
-        parser.parse(Token::With).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::Underscore).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(42)).unwrap();
-        let result = parser.end_of_input();
-
-        assert!(result.is_ok(), "Match with only wildcard should parse");
-        let expr = result.unwrap();
-
-        match expr {
-            Expr::Match(_, arms) => {
-                assert_eq!(arms.len(), 1, "Should have 1 match arm");
-                assert!(
-                    matches!(arms[0].0, Pattern::Wildcard),
-                    "Pattern should be wildcard"
-                );
-            }
-            _ => panic!("Expected Match expression"),
-        }
+        parser.parse(Token::Match(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "x".into())))
+            .unwrap();
+        parser.parse(Token::With(Span::default())).unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser.parse(Token::Underscore(Span::default())).unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 42)))
+            .unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        assert_eq!(
+            expr,
+            match_expr(ident("x"), vec![(Pattern::Wildcard, Expr::Number(42))])
+        );
     }
 
     #[test]
@@ -384,53 +1772,641 @@ mod tests {
         // Test: match x with | 1 -> print x | _ -> 0
         let mut parser = Parser::new();
 
-        parser.parse(Token::Match).unwrap();
-        parser.parse(Token::Identifier("x".to_string())).unwrap();
-        parser.parse(Token::With).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::IntegerLiteral(1)).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::Print).unwrap();
-        parser.parse(Token::Identifier("x".to_string())).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::Underscore).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(0)).unwrap();
-        let result = parser.end_of_input();
-
-        assert!(result.is_ok(), "Match with expression results should parse");
-        let expr = result.unwrap();
-
-        match expr {
-            Expr::Match(_, arms) => {
-                assert_eq!(arms.len(), 2);
-                assert!(
-                    matches!(arms[0].1, Expr::Call(ref f, _) if f == "print"),
-                    "First result should be print call"
-                );
-            }
-            _ => panic!("Expected Match expression"),
-        }
+        parser.parse(Token::Match(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "x".into())))
+            .unwrap();
+        parser.parse(Token::With(Span::default())).unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 1)))
+            .unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser.parse(Token::Print(Span::default())).unwrap();
+        parser
+            .parse(Token::Identifier((Span::default(), "x".into())))
+            .unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser.parse(Token::Underscore(Span::default())).unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 0)))
+            .unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        assert_eq!(
+            expr,
+            match_expr(
+                ident("x"),
+                vec![
+                    (Pattern::Literal(1), print(ident("x"))),
+                    (Pattern::Wildcard, Expr::Number(0)),
+                ]
+            )
+        );
     }
 
     #[test]
     fn test_pattern_literal() {
-        // Test that literal patterns parse correctly
+        // Test: match 100 with | 100 -> 1 | _ -> 0
         let mut parser = Parser::new();
 
-        parser.parse(Token::Match).unwrap();
-        parser.parse(Token::IntegerLiteral(100)).unwrap();
-        parser.parse(Token::With).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::IntegerLiteral(100)).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(1)).unwrap();
-        parser.parse(Token::Pipe).unwrap();
-        parser.parse(Token::Underscore).unwrap();
-        parser.parse(Token::Arrow).unwrap();
-        parser.parse(Token::IntegerLiteral(0)).unwrap();
-        let result = parser.end_of_input();
-
-        assert!(result.is_ok(), "Match with literal pattern should parse");
+        parser.parse(Token::Match(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 100)))
+            .unwrap();
+        parser.parse(Token::With(Span::default())).unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 100)))
+            .unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 1)))
+            .unwrap();
+        parser.parse(Token::Pipe(Span::default())).unwrap();
+        parser.parse(Token::Underscore(Span::default())).unwrap();
+        parser.parse(Token::Arrow(Span::default())).unwrap();
+        parser
+            .parse(Token::IntegerLiteral((Span::default(), 0)))
+            .unwrap();
+        let (_, expr) = parser.end_of_input().unwrap();
+
+        assert_eq!(
+            expr,
+            match_expr(
+                Expr::Number(100),
+                vec![
+                    (Pattern::Literal(100), Expr::Number(1)),
+                    (Pattern::Wildcard, Expr::Number(0)),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_program_records_spans() {
+        let ast = parse_program("decl x <- 1 in\nprint x".to_string()).unwrap();
+
+        let Expr::Decl(_, _, _, body, decl_span) = &ast else {
+            panic!("Expected Decl expression, got {:?}", ast);
+        };
+        assert_eq!(
+            (decl_span.line, decl_span.column),
+            (1, 6),
+            "Decl span is its name"
+        );
+        let Expr::Call(_, args, print_span) = &**body else {
+            panic!("Expected print call, got {:?}", body);
+        };
+        assert_eq!((print_span.line, print_span.column), (2, 1));
+        assert_eq!(
+            args[0].span().map(|span| (span.line, span.column)),
+            Some((2, 7))
+        );
+    }
+
+    #[test]
+    fn test_parse_program_ast_shape() {
+        let ast = parse_program("decl x <- 1 in x <- + x 2".to_string()).unwrap();
+        let at = Span::default();
+        let expected = Expr::Decl(
+            "x".into(),
+            vec![],
+            Box::new(Expr::Number(1)),
+            Box::new(Expr::Assign(
+                "x".into(),
+                Box::new(Expr::Call(
+                    "+".into(),
+                    vec![Expr::Ident("x".into(), at), Expr::Number(2)],
+                    at,
+                )),
+                at,
+            )),
+            at,
+        );
+        assert_eq!(ast.without_spans(), expected);
+    }
+
+    #[test]
+    fn test_parse_blocks() {
+        let parse = |source: &str| parse_program(source.to_string()).unwrap().without_spans();
+        assert_eq!(
+            parse("print 1; print 2; 3"),
+            Expr::Block(vec![
+                print(Expr::Number(1)),
+                print(Expr::Number(2)),
+                Expr::Number(3)
+            ])
+        );
+
+        // A declaration takes in the rest of the block as its body
+        let body = Expr::Block(vec![ident("b"), ident("c")]);
+        let decl = Expr::Decl(
+            "x".into(),
+            vec![],
+            Box::new(Expr::Number(1)),
+            Box::new(body),
+            Span::default(),
+        );
+        assert_eq!(
+            parse("a; decl x <- 1 in b; c"),
+            Expr::Block(vec![ident("a"), decl])
+        );
+
+        // A parenthesized block is a statement of its own
+        assert_eq!(
+            parse("(a; b); c"),
+            Expr::Block(vec![Expr::Block(vec![ident("a"), ident("b")]), ident("c")])
+        );
+    }
+
+    #[test]
+    fn test_parse_default_parameter_values() {
+        let ast = parse_program("decl f x (y <- 10) (z <- -1) <- x in 0".to_string()).unwrap();
+        let Expr::Decl(_, params, _, _, _) = &ast else {
+            panic!("Expected Decl expression, got {:?}", ast);
+        };
+        assert_eq!(
+            *params,
+            vec![
+                ("x".into(), None),
+                ("y".into(), Some(10)),
+                ("z".into(), Some(-1)),
+            ]
+        );
+        // Only literals
+        assert!(parse_program("decl f (y <- x) <- y in 0".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_named_arguments() {
+        let ast = parse_program("(f 1 y: 2 x: (g 3))".to_string()).unwrap();
+        let named =
+            |name: &str, value| Expr::NamedArg(name.into(), Box::new(value), Span::default());
+        assert_eq!(
+            ast.without_spans(),
+            Expr::Call(
+                "f".into(),
+                vec![
+                    Expr::Number(1),
+                    named("y", Expr::Number(2)),
+                    named(
+                        "x",
+                        Expr::Call("g".into(), vec![Expr::Number(3)], Span::default())
+                    ),
+                ],
+                Span::default()
+            )
+        );
+        // Positional arguments only come first
+        assert!(parse_program("(f y: 2 1)".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_nested_arrays() {
+        let ast = parse_program("get (get [[1 2] [] [x]] 1) 0".to_string()).unwrap();
+        let row = |elements: Vec<Expr>| Expr::Array(elements, Span::default());
+        let matrix = row(vec![
+            row(vec![Expr::Number(1), Expr::Number(2)]),
+            row(vec![]),
+            row(vec![Expr::Ident("x".into(), Span::default())]),
+        ]);
+        let get = |array: Expr, index: i64| {
+            Expr::Call(
+                "get".into(),
+                vec![array, Expr::Number(index)],
+                Span::default(),
+            )
+        };
+        assert_eq!(ast.without_spans(), get(get(matrix, 1), 0));
+    }
+
+    #[test]
+    fn test_parse_option_patterns() {
+        let ast = parse_program(
+            "match some (some 3) with | some (some n) -> n | some none -> 1 | none -> 2"
+                .to_string(),
+        )
+        .unwrap();
+        let ast = ast.without_spans();
+        let Expr::Match(scrutinee, arms, _) = &ast else {
+            panic!("Expected Match expression");
+        };
+        let some = |value: Expr| Expr::Call("some".into(), vec![value], Span::default());
+        assert_eq!(**scrutinee, some(some(Expr::Number(3))));
+        let patterns: Vec<Pattern> = arms.iter().map(|(pattern, _)| pattern.clone()).collect();
+        assert_eq!(
+            patterns,
+            vec![
+                Pattern::Some(Box::new(Pattern::Some(Box::new(Pattern::Bind(
+                    "n".into(),
+                    Span::default()
+                ))))),
+                Pattern::Some(Box::new(Pattern::None)),
+                Pattern::None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_names_are_interned() {
+        let ast = parse_program("decl x <- 1 in x <- + x 1; + x (f x)".to_string()).unwrap();
+        let Expr::Decl(declared, _, _, body, _) = &ast else {
+            panic!("Expected Decl expression, got {:?}", ast);
+        };
+        let Expr::Block(statements) = &**body else {
+            panic!("Expected Block expression, got {:?}", body);
+        };
+        let [assign, sum] = &statements[..] else {
+            panic!("Expected two statements, got {:?}", statements);
+        };
+        let (Expr::Assign(assigned, increment, _), Expr::Call(plus, args, _)) = (assign, sum)
+        else {
+            panic!("Expected an assignment and a call, got {:?}", body);
+        };
+        let Expr::Call(first_plus, _, _) = &**increment else {
+            panic!("Expected Call expression, got {:?}", increment);
+        };
+        assert!(declared.ptr_eq(assigned));
+        let Expr::Ident(read, _) = &args[0] else {
+            panic!("Expected Ident expression, got {:?}", args[0]);
+        };
+        assert!(declared.ptr_eq(read));
+        // Builtins share their names too
+        assert!(plus.ptr_eq(first_plus));
+    }
+
+    #[test]
+    fn test_parse_at_patterns() {
+        let ast = parse_program("match x with | n @ 100 -> n | o @ some (m @ _) -> m".to_string());
+        let ast = ast.unwrap().without_spans();
+        let Expr::Match(_, arms, _) = &ast else {
+            panic!("Expected Match expression");
+        };
+        let at =
+            |name: &str, inner: Pattern| Pattern::At(name.into(), Box::new(inner), Span::default());
+        let patterns: Vec<Pattern> = arms.iter().map(|(pattern, _)| pattern.clone()).collect();
+        assert_eq!(
+            patterns,
+            vec![
+                at("n", Pattern::Literal(100)),
+                at("o", Pattern::Some(Box::new(at("m", Pattern::Wildcard)))),
+            ]
+        );
+        assert!(patterns[1].payload().is_some_and(Pattern::is_irrefutable));
+    }
+
+    #[test]
+    fn test_parse_relational_patterns() {
+        let source = "match x with | < 60 -> 0 | <= -1 -> 1 | > 5 -> 2 | >= 80 -> 3";
+        let ast = parse_program(source.to_string());
+        let ast = ast.unwrap();
+        let Expr::Match(_, arms, _) = &ast else {
+            panic!("Expected Match expression");
+        };
+        let patterns: Vec<Pattern> = arms.iter().map(|(pattern, _)| pattern.clone()).collect();
+        assert_eq!(
+            patterns,
+            vec![
+                Pattern::Cmp(CmpOp::Less, 60),
+                Pattern::Cmp(CmpOp::LessEq, -1),
+                Pattern::Cmp(CmpOp::Greater, 5),
+                Pattern::Cmp(CmpOp::GreaterEq, 80),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_known_values() {
+        let known = |source: &str| parse_program(source.to_string()).unwrap().known_values();
+        assert_eq!(known("< x 10"), Some(&[0, 1][..]));
+        assert_eq!(known("& (= x 1) y"), Some(&[0, 1][..]));
+        assert_eq!(known("! x"), Some(&[0, 1][..]));
+        assert_eq!(known("print x; != x 2"), Some(&[0, 1][..]));
+        assert_eq!(known("+ x 10"), None);
+        assert_eq!(known("x"), None);
+        assert_eq!(known("1"), None);
+    }
+
+    #[test]
+    fn test_parse_interpolation() {
+        let ast = parse_program("print \"x is {x}, {{y}} is {y}\"".to_string()).unwrap();
+        let Expr::PrintFormat(pieces, args, _) = &ast else {
+            panic!("Expected PrintFormat expression, got {:?}", ast);
+        };
+        assert_eq!(*pieces, vec!["x is ", ", {y} is ", ""]);
+        let span = |column, len| Span {
+            line: 1,
+            column,
+            len,
+        };
+        assert_eq!(
+            *args,
+            vec![
+                Expr::Ident("x".into(), span(14, 1)),
+                Expr::Ident("y".into(), span(28, 1)),
+            ]
+        );
+
+        // Positions are in the source, where an escape takes two columns and
+        // a line break starts a new line
+        let ast = parse_program(r#"print "\t{x}\n\"{y}"#.to_string() + "\n{z}\"").unwrap();
+        let Expr::PrintFormat(pieces, args, _) = &ast else {
+            panic!("Expected PrintFormat expression, got {:?}", ast);
+        };
+        assert_eq!(*pieces, vec!["\t", "\n\"", "\n", ""]);
+        let next_line = Span {
+            line: 2,
+            column: 2,
+            len: 1,
+        };
+        assert_eq!(
+            *args,
+            vec![
+                Expr::Ident("x".into(), span(11, 1)),
+                Expr::Ident("y".into(), span(18, 1)),
+                Expr::Ident("z".into(), next_line),
+            ]
+        );
+
+        let err = parse_program("print \"a {x\"".to_string()).unwrap_err();
+        assert_eq!(
+            err,
+            "Parse error: unclosed '{' in string at line 1, column 10"
+        );
+        let err = parse_program("print \"{1}\"".to_string()).unwrap_err();
+        assert_eq!(
+            err,
+            "Parse error: '{1}' in string at line 1, column 8 is not a variable"
+        );
+    }
+
+    #[test]
+    fn test_parse_error_reports_position() {
+        let err = parse_program("print\n  )".to_string()).unwrap_err();
+        assert_eq!(err, "Parse error: unexpected ')' at line 2, column 3");
+        let err = parse_program("decl x <- 1 x in x".to_string()).unwrap_err();
+        assert_eq!(
+            err,
+            "Parse error: unexpected identifier 'x' at line 1, column 13"
+        );
+        let err = parse_program("print 1 done".to_string()).unwrap_err();
+        assert_eq!(err, "Parse error: unexpected done at line 1, column 9");
+
+        let err = parse_program("decl x <- 1 in while x".to_string()).unwrap_err();
+        assert_eq!(err, "Parse error: unexpected end of input");
+    }
+
+    #[test]
+    fn test_declaring_a_builtin_is_an_error() {
+        let err = parse_program("decl print <- 5 in + print 1".to_string()).unwrap_err();
+        assert_eq!(
+            err,
+            "Parse error: 'print' is a builtin and cannot be redefined at line 1, column 6; \
+             try another name, like 'my_print'"
+        );
+        // Parameters and globals too
+        let err = parse_program("decl f len <- len in (f 1)".to_string()).unwrap_err();
+        assert!(err.contains("'len' is a builtin"), "{}", err);
+        let err = parse_program("global sum <- 0\n1".to_string()).unwrap_err();
+        assert!(err.contains("'sum' is a builtin"), "{}", err);
+
+        // Past the `<-` a builtin is an ordinary mistake
+        let err = parse_program("decl x <- 1 print in x".to_string()).unwrap_err();
+        assert_eq!(err, "Parse error: unexpected print at line 1, column 13");
+        assert!(parse_program("decl printer <- 5 in print printer".to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_token_display() {
+        use crate::tokenizer::{KEYWORDS, Lexer};
+
+        // Every keyword and symbol lexes back into the token that shows it
+        for lexeme in KEYWORDS.keys().copied().chain([":", "[", "]", "@"]) {
+            let tokens = Lexer::new(lexeme.to_string()).tokenize().unwrap();
+            assert_eq!(tokens.len(), 1, "{:?} is {:?}", lexeme, tokens);
+            assert_eq!(tokens[0].lexeme(), Some(lexeme), "{:?}", tokens[0]);
+            let shown = tokens[0].to_string();
+            if lexeme.starts_with(|c: char| c.is_ascii_lowercase()) {
+                assert_eq!(shown, lexeme);
+            } else {
+                assert_eq!(shown, format!("'{}'", lexeme));
+            }
+        }
+
+        let span = Span::default();
+        assert_eq!(Token::Decl(span).to_string(), "decl");
+        assert_eq!(Token::Assign(span).to_string(), "'<-'");
+        assert_eq!(Token::Arrow(span).to_string(), "'->'");
+        assert_eq!(Token::Semicolon(span).to_string(), "';'");
+        assert_eq!(
+            Token::Identifier((span, "x".into())).to_string(),
+            "identifier 'x'"
+        );
+        assert_eq!(Token::IntegerLiteral((span, 5)).to_string(), "integer 5");
+        let text = Token::StringLiteral((span, "a\"b\n".to_string().into()));
+        assert_eq!(text.to_string(), r#"string "a\"b\n""#);
+        assert_eq!(text.lexeme(), None);
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        use crate::tokenizer::Lexer;
+
+        let tokens = |source: String| Lexer::new(source).tokenize().unwrap();
+        let parens = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse_tokens_with_limit(tokens(parens(10)), 10).is_ok());
+        let error = parse_tokens_with_limit(tokens(parens(11)), 10).unwrap_err();
+        assert_eq!(
+            error.message,
+            "maximum nesting depth 10 exceeded at line 1, column 11"
+        );
+        assert_eq!(error.span.map(|span| span.column), Some(11));
+
+        // Only what is still open counts
+        let side_by_side = format!("{}; {}", parens(10), parens(10));
+        assert!(parse_tokens_with_limit(tokens(side_by_side), 10).is_ok());
+
+        let loops = |depth| format!("{}1{}", "while 1 do ".repeat(depth), " done".repeat(depth));
+        assert!(parse_tokens_with_limit(tokens(loops(3)), 3).is_ok());
+        assert!(parse_tokens_with_limit(tokens(loops(4)), 3).is_err());
+
+        // A match ends at the `in` or `;` after it, so matches one after the
+        // other don't add up
+        let matches: String = (0..5)
+            .map(|n| format!("decl f{} x <- match x with | 0 -> 1 | _ -> 2 in\n", n))
+            .collect();
+        assert!(parse_tokens_with_limit(tokens(format!("{}(f0 3)", matches)), 3).is_ok());
+        let statements = "match 1 with | _ -> print 1; ".repeat(5);
+        assert!(parse_tokens_with_limit(tokens(format!("{}0", statements)), 3).is_ok());
+        // But not the `in` of a decl inside one of its arms
+        let arms = |depth| {
+            let arm = "match 1 with | _ -> decl y <- 1 in ";
+            format!("{}0", arm.repeat(depth))
+        };
+        assert!(parse_tokens_with_limit(tokens(arms(3)), 3).is_ok());
+        assert!(parse_tokens_with_limit(tokens(arms(4)), 3).is_err());
+
+        // Stops at the limit, with the parser's stack no deeper than it
+        let error = parse_tokens(tokens("(".repeat(100_000))).unwrap_err();
+        assert_eq!(
+            error.message,
+            format!(
+                "maximum nesting depth {} exceeded at line 1, column {}",
+                MAX_NESTING,
+                MAX_NESTING + 1
+            )
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_ast_json_round_trip() {
+        let source = "decl f n <- * n 2 in\n\
+                      decl x <- (f 3) in\n\
+                      while x do print x; x <- - x 1 done;\n\
+                      match x with | 0 -> 1 | n @ some (< 5) -> n | none -> 2 | _ -> 3";
+        let ast = parse_program(source.to_string()).unwrap();
+        let json = ast.to_json();
+        let back: Expr = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, ast);
+
+        crate::arbitrary::check_exprs(300, |expr| {
+            let back: Expr = serde_json::from_str(&expr.to_json()).map_err(|e| e.to_string())?;
+            if back == *expr {
+                Ok(())
+            } else {
+                Err(format!("{:?}\ncomes back as\n{:?}", expr, back))
+            }
+        });
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_ast_json_format() {
+        // Variant names go under "type" and fields under "fields"
+        let json: serde_json::Value =
+            serde_json::from_str(&parse_program("+ x 1".to_string()).unwrap().to_json()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"type": "Call", "fields": [
+                "+",
+                [
+                    {"type": "Ident", "fields": ["x", {"line": 1, "column": 3, "len": 1}]},
+                    {"type": "Number", "fields": 1}
+                ],
+                {"line": 1, "column": 1, "len": 1}
+            ]})
+        );
+
+        let pattern = Pattern::At(
+            Name::from("n"),
+            Box::new(Pattern::Some(Box::new(Pattern::Cmp(CmpOp::Less, 5)))),
+            Span {
+                line: 2,
+                column: 3,
+                len: 1,
+            },
+        );
+        let json = serde_json::to_value(&pattern).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"type": "At", "fields": [
+                "n",
+                {"type": "Some", "fields": {"type": "Cmp", "fields": ["Less", 5]}},
+                {"line": 2, "column": 3, "len": 1}
+            ]})
+        );
+        assert_eq!(serde_json::from_value::<Pattern>(json).unwrap(), pattern);
+        assert_eq!(
+            serde_json::to_value(Pattern::Wildcard).unwrap(),
+            serde_json::json!({"type": "Wildcard"})
+        );
+    }
+
+    #[test]
+    fn test_imports() {
+        use crate::tokenizer::Lexer;
+
+        let source = "import \"a.mlia\"\nimport \"lib/b.mlia\"\n+ x 1";
+        let tokens = Lexer::new(source.to_string()).tokenize().unwrap();
+        let (imports, expr) = parse_tokens(tokens).unwrap();
+        let paths: Vec<&str> = imports.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(paths, ["a.mlia", "lib/b.mlia"]);
+        assert_eq!((imports[1].span.line, imports[1].span.column), (2, 8));
+        assert!(matches!(expr, Expr::Call(ref op, _, _) if op == "+"));
+
+        // Imports only go at the top, and need a file to be resolved from
+        assert!(parse_program("+ 1 1; import \"a.mlia\"".to_string()).is_err());
+        let error = parse_program(source.to_string()).unwrap_err();
+        assert!(error.contains("line 1, column 8"), "{}", error);
+    }
+
+    #[test]
+    fn test_deep_trees_are_handled_without_recursion() {
+        // Deep enough to overflow the stack of a test thread if dropped,
+        // cloned, compared or printed recursively
+        let mut expr = Expr::Number(0);
+        for i in 0..1_000_000 {
+            expr = Expr::Block(vec![Expr::Number(i), expr]);
+        }
+        drop(expr);
+
+        let deep = |leaf| {
+            let mut expr = Expr::Number(leaf);
+            for i in 0..1_000_000 {
+                expr = match i % 3 {
+                    0 => Expr::Loop(Box::new(expr), Span::default()),
+                    1 => match_expr(expr, vec![(Pattern::Wildcard, Expr::Number(i))]),
+                    _ => {
+                        let value = Box::new(Expr::Number(i));
+                        Expr::Decl("x".into(), vec![], value, Box::new(expr), Span::default())
+                    }
+                };
+            }
+            expr
+        };
+        let expr = deep(0);
+        let copy = expr.clone();
+        assert!(copy == expr);
+        assert!(deep(1) != expr);
+        let debug = format!("{:?}", copy);
+        assert!(
+            debug.starts_with("Loop(Decl(\"x\", [], Number(999998), Match(Loop("),
+            "{}",
+            &debug[..100]
+        );
+    }
+
+    #[test]
+    fn test_debug_is_the_derived_one() {
+        let at = Span {
+            line: 1,
+            column: 2,
+            len: 3,
+        };
+        let expr = Expr::Decl(
+            "f".into(),
+            vec![("x".into(), Some(1))],
+            Box::new(match_expr(
+                Expr::Ident("x".into(), at),
+                vec![(Pattern::Literal(1), Expr::Array(vec![], at))],
+            )),
+            Box::new(Expr::Break(Some(Box::new(Expr::Number(2))), at)),
+            at,
+        );
+        assert_eq!(
+            format!("{:?}", expr),
+            "Decl(\"f\", [(\"x\", Some(1))], Match(Ident(\"x\", Span { line: 1, column: 2, len: 3 }), \
+             [(Literal(1), Array([], Span { line: 1, column: 2, len: 3 }))], \
+             Span { line: 0, column: 0, len: 0 }), Break(Some(Number(2)), \
+             Span { line: 1, column: 2, len: 3 }), Span { line: 1, column: 2, len: 3 })"
+        );
+        assert_eq!(
+            format!("{:#?}", Expr::Call("f".into(), vec![Expr::Number(2)], at)),
+            "Call(\n    \"f\",\n    [\n        Number(\n            2,\n        ),\n    ],\n    \
+             Span {\n        line: 1,\n        column: 2,\n        len: 3,\n    },\n)"
+        );
     }
 }
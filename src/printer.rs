@@ -0,0 +1,608 @@
+//! Turns an AST back into MLIA source code.
+//!
+//! The output parses back into the same tree (spans aside): parentheses are
+//! added wherever the grammar would otherwise read the code differently, and
+//! only there. Declarations, sequences, loops and matches go on several lines.
+//!
+//! ```text
+//! decl x <- 3 in
+//! while x do
+//!   print x;
+//!   x <- - x 1
+//! done
+//! ```
+//!
+//! `one_line` writes the same code on a single line, for messages: that's
+//! what `Display` shows for an [`Expr`], and `Expr::display_truncated` cuts
+//! it short, rendering no more of a large tree than fits.
+
+use crate::parser::{Expr, Pattern, builtin_arity};
+
+const INDENT: &str = "  ";
+
+/// The source code of `expr`.
+pub fn to_source(expr: &Expr) -> String {
+    expr_source(expr)
+}
+
+/// The source code of `expr` on one line, such as `decl x <- 1 in print x; x`.
+/// Longer than `width` characters, it ends in `...` at that width.
+pub fn one_line(expr: &Expr, width: Option<usize>) -> String {
+    let mut line = Line {
+        text: String::new(),
+        chars: 0,
+        width,
+    };
+    // Full means there's no need to render the rest
+    let _ = line_source(expr, &mut line);
+    line.finish()
+}
+
+/// How much of the grammar a position accepts, from the most to the least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    /// `expr`: anything, up to whatever ends the enclosing construct.
+    Expr,
+    /// `assign_expr`: the first expression of a sequence, or an assigned value.
+    Assign,
+    /// `atom_expr`: an operand.
+    Atom,
+}
+
+/// The least a position has to accept for `expr` to go there unparenthesized.
+fn level(expr: &Expr) -> Level {
+    match expr {
+        Expr::Decl(..) | Expr::Block(..) | Expr::Match(..) | Expr::Global(..) => Level::Expr,
+        Expr::Assign(..) | Expr::EnvInt(..) | Expr::Break(..) | Expr::PrintFormat(..) => {
+            Level::Assign
+        }
+        Expr::Call(name, args, _) if is_builtin(name) && !args.is_empty() => Level::Assign,
+        Expr::Number(_) | Expr::Ident(..) | Expr::Call(..) | Expr::While(..) => Level::Atom,
+        Expr::WhileYield(..) | Expr::Loop(..) | Expr::NamedArg(..) | Expr::Str(..) => Level::Atom,
+        Expr::Array(..) => Level::Atom,
+    }
+}
+
+/// Whether calls to `name` are written as `name a b` rather than `(name a b)`.
+fn is_builtin(name: &str) -> bool {
+    builtin_arity(name).is_some()
+}
+
+/// `expr` where the grammar accepts `at`.
+fn source_at(expr: &Expr, at: Level) -> String {
+    if level(expr) < at {
+        parenthesize(&expr_source(expr))
+    } else {
+        expr_source(expr)
+    }
+}
+
+/// An expression that something other than the end of the enclosing construct
+/// follows: a declared value (followed by `in`), a scrutinee (`with`) or a
+/// match arm before another one (`|`). A declaration, sequence or match there
+/// would take in what follows.
+fn closed_source(expr: &Expr) -> String {
+    source_at(expr, Level::Assign)
+}
+
+fn parenthesize(source: &str) -> String {
+    // "(*" would start a comment
+    if source.starts_with('*') {
+        format!("( {})", source)
+    } else {
+        format!("({})", source)
+    }
+}
+
+/// `text` as it's written between the quotes of a string literal.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\0"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Indents every line of `source` but the first one.
+fn indent(source: &str) -> String {
+    source.replace('\n', &format!("\n{}", INDENT))
+}
+
+fn expr_source(expr: &Expr) -> String {
+    match expr {
+        Expr::Number(n) => n.to_string(),
+        Expr::Ident(name, _) => name.to_string(),
+        Expr::EnvInt(name, _) => format!("env_int \"{}\"", escape(name)),
+        Expr::Str(text, _) => format!("\"{}\"", escape(text)),
+        Expr::Array(elements, _) => {
+            let elements: Vec<String> = elements
+                .iter()
+                .map(|element| source_at(element, Level::Atom))
+                .collect();
+            format!("[{}]", elements.join(" "))
+        }
+        Expr::NamedArg(name, value, _) => format!("{}: {}", name, source_at(value, Level::Atom)),
+
+        Expr::PrintFormat(pieces, args, _) => {
+            let piece = |piece: &String| escape(&piece.replace('{', "{{").replace('}', "}}"));
+            let mut text = piece(&pieces[0]);
+            for (arg, next) in args.iter().zip(&pieces[1..]) {
+                // Only variables can be interpolated
+                text.push_str(&format!("{{{}}}{}", expr_source(arg), piece(next)));
+            }
+            format!("print \"{}\"", text)
+        }
+
+        Expr::Call(name, args, _) => {
+            let args: Vec<String> = args.iter().map(|arg| source_at(arg, Level::Atom)).collect();
+            if is_builtin(name) && args.is_empty() {
+                name.to_string()
+            } else if is_builtin(name) {
+                format!("{} {}", name, args.join(" "))
+            } else if args.is_empty() {
+                format!("({})", name)
+            } else {
+                format!("({} {})", name, args.join(" "))
+            }
+        }
+
+        Expr::Block(statements) => {
+            let last = statements.len().saturating_sub(1);
+            let lines: Vec<String> = statements
+                .iter()
+                .enumerate()
+                .map(|(i, statement)| match statement {
+                    // A block at the end would read as more statements of this one
+                    Expr::Block(..) if i == last => parenthesize(&expr_source(statement)),
+                    _ if i == last => expr_source(statement),
+                    _ => source_at(statement, Level::Assign),
+                })
+                .collect();
+            lines.join(";\n")
+        }
+
+        Expr::Assign(name, value, _) => {
+            format!("{} <- {}", name, source_at(value, Level::Assign))
+        }
+
+        Expr::Decl(name, params, value, body, _) => {
+            let mut head = name.to_string();
+            for (param, default) in params {
+                head.push(' ');
+                match default {
+                    Some(default) => head.push_str(&format!("({} <- {})", param, default)),
+                    None => head.push_str(param),
+                }
+            }
+            format!(
+                "decl {} <- {} in\n{}",
+                head,
+                indent(&closed_source(value)),
+                expr_source(body)
+            )
+        }
+
+        // Only valid at the start of a program, where the parser puts it
+        Expr::Global(name, value, body, _) => format!(
+            "global {} <- {}\n{}",
+            name,
+            indent(&closed_source(value)),
+            expr_source(body)
+        ),
+
+        Expr::While(cond, body, _) => format!(
+            "while {} do\n{}{}\ndone",
+            indent(&expr_source(cond)),
+            INDENT,
+            indent(&expr_source(body))
+        ),
+
+        Expr::WhileYield(cond, body, _) => format!(
+            "while {} do\n{}{}\nyield done",
+            indent(&expr_source(cond)),
+            INDENT,
+            indent(&expr_source(body))
+        ),
+
+        Expr::Loop(body, _) => format!("loop\n{}{}\nend", INDENT, indent(&expr_source(body))),
+
+        Expr::Break(None, _) => "break".to_string(),
+        Expr::Break(Some(value), _) => format!("break {}", source_at(value, Level::Atom)),
+
+        Expr::Match(scrutinee, arms, _) => {
+            let mut source = format!("match {} with", indent(&closed_source(scrutinee)));
+            for (i, (pattern, arm)) in arms.iter().enumerate() {
+                let pattern = pattern_source(pattern);
+                // Only the last arm may take in what follows it
+                let arm = if i + 1 == arms.len() {
+                    expr_source(arm)
+                } else {
+                    closed_source(arm)
+                };
+                source.push_str(&format!("\n| {} -> {}", pattern, indent(&arm)));
+            }
+            source
+        }
+    }
+}
+
+/// A line being written by `one_line`.
+struct Line {
+    text: String,
+    chars: usize,
+    width: Option<usize>,
+}
+
+/// The line is longer than its width already.
+struct Full;
+
+impl Line {
+    fn push(&mut self, text: &str) -> Result<(), Full> {
+        self.text.push_str(text);
+        self.chars += text.chars().count();
+        match self.width {
+            Some(width) if self.chars > width => Err(Full),
+            _ => Ok(()),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self.width {
+            Some(width) if self.chars > width => {
+                let mut text: String = self.text.chars().take(width.saturating_sub(3)).collect();
+                text.push_str("...");
+                text
+            }
+            _ => self.text,
+        }
+    }
+}
+
+/// `expr` where the grammar accepts `at`, on one line.
+fn line_at(expr: &Expr, at: Level, line: &mut Line) -> Result<(), Full> {
+    if level(expr) >= at {
+        return line_source(expr, line);
+    }
+    line.push("(")?;
+    let start = line.text.len();
+    let inner = line_source(expr, line);
+    // "(*" would start a comment
+    if line.text[start..].starts_with('*') {
+        line.text.insert(start, ' ');
+        line.chars += 1;
+    }
+    inner?;
+    line.push(")")
+}
+
+/// The same layout as `expr_source`, with a space wherever that has a line
+/// break (and its indentation).
+fn line_source(expr: &Expr, line: &mut Line) -> Result<(), Full> {
+    match expr {
+        Expr::Number(_)
+        | Expr::Ident(..)
+        | Expr::EnvInt(..)
+        | Expr::Str(..)
+        | Expr::PrintFormat(..)
+        | Expr::Break(None, _) => line.push(&expr_source(expr)),
+
+        Expr::Array(elements, _) => {
+            line.push("[")?;
+            for (i, element) in elements.iter().enumerate() {
+                if i > 0 {
+                    line.push(" ")?;
+                }
+                line_at(element, Level::Atom, line)?;
+            }
+            line.push("]")
+        }
+        Expr::NamedArg(name, value, _) => {
+            line.push(&format!("{}: ", name))?;
+            line_at(value, Level::Atom, line)
+        }
+
+        Expr::Call(name, args, _) => {
+            let builtin = is_builtin(name);
+            if !builtin {
+                line.push("(")?;
+            }
+            line.push(name)?;
+            for arg in args {
+                line.push(" ")?;
+                line_at(arg, Level::Atom, line)?;
+            }
+            if !builtin {
+                line.push(")")?;
+            }
+            Ok(())
+        }
+
+        Expr::Block(statements) => {
+            let last = statements.len().saturating_sub(1);
+            for (i, statement) in statements.iter().enumerate() {
+                if i > 0 {
+                    line.push("; ")?;
+                }
+                match statement {
+                    // A block at the end would read as more statements of this one
+                    Expr::Block(..) if i == last => line_at(statement, Level::Atom, line)?,
+                    _ if i == last => line_source(statement, line)?,
+                    _ => line_at(statement, Level::Assign, line)?,
+                }
+            }
+            Ok(())
+        }
+
+        Expr::Assign(name, value, _) => {
+            line.push(&format!("{} <- ", name))?;
+            line_at(value, Level::Assign, line)
+        }
+
+        Expr::Decl(name, params, value, body, _) => {
+            line.push("decl ")?;
+            line.push(name)?;
+            for (param, default) in params {
+                match default {
+                    Some(default) => line.push(&format!(" ({} <- {})", param, default))?,
+                    None => line.push(&format!(" {}", param))?,
+                }
+            }
+            line.push(" <- ")?;
+            line_at(value, Level::Assign, line)?;
+            line.push(" in ")?;
+            line_source(body, line)
+        }
+
+        Expr::Global(name, value, body, _) => {
+            line.push(&format!("global {} <- ", name))?;
+            line_at(value, Level::Assign, line)?;
+            line.push(" ")?;
+            line_source(body, line)
+        }
+
+        Expr::While(cond, body, _) | Expr::WhileYield(cond, body, _) => {
+            line.push("while ")?;
+            line_source(cond, line)?;
+            line.push(" do ")?;
+            line_source(body, line)?;
+            match expr {
+                Expr::WhileYield(..) => line.push(" yield done"),
+                _ => line.push(" done"),
+            }
+        }
+
+        Expr::Loop(body, _) => {
+            line.push("loop ")?;
+            line_source(body, line)?;
+            line.push(" end")
+        }
+
+        Expr::Break(Some(value), _) => {
+            line.push("break ")?;
+            line_at(value, Level::Atom, line)
+        }
+
+        Expr::Match(scrutinee, arms, _) => {
+            line.push("match ")?;
+            line_at(scrutinee, Level::Assign, line)?;
+            line.push(" with")?;
+            for (i, (pattern, arm)) in arms.iter().enumerate() {
+                line.push(&format!(" | {} -> ", pattern_source(pattern)))?;
+                // Only the last arm may take in what follows it
+                if i + 1 == arms.len() {
+                    line_source(arm, line)?;
+                } else {
+                    line_at(arm, Level::Assign, line)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The source of a pattern in a match arm.
+pub fn pattern_source(pattern: &Pattern) -> String {
+    match pattern {
+        Pattern::Literal(n) => n.to_string(),
+        Pattern::Cmp(op, n) => format!("{} {}", op.symbol(), n),
+        Pattern::Wildcard => "_".to_string(),
+        Pattern::Bind(name, _) => name.to_string(),
+        Pattern::At(name, inner, _) => format!("{} @ {}", name, pattern_source(inner)),
+        Pattern::None => "none".to_string(),
+        Pattern::Some(inner) | Pattern::Ok(inner) | Pattern::Err(inner) => {
+            let constructor = match pattern {
+                Pattern::Some(_) => "some",
+                Pattern::Ok(_) => "ok",
+                _ => "err",
+            };
+            match inner.payload() {
+                Some(_) => format!("{} ({})", constructor, pattern_source(inner)),
+                None => format!("{} {}", constructor, pattern_source(inner)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary;
+    use crate::parser::{Span, parse_program};
+
+    fn parse(source: &str) -> Expr {
+        parse_program(source.to_string())
+            .unwrap_or_else(|error| panic!("{}\nin:\n{}", error, source))
+            .without_spans()
+    }
+
+    #[test]
+    fn test_to_source() {
+        let source = "decl x <- 3 in\n\
+                      while x do\n  \
+                        print x;\n  \
+                        x <- - x 1\n\
+                      done";
+        assert_eq!(to_source(&parse(source)), source);
+
+        assert_eq!(
+            to_source(&parse("decl f a b <- + a b in (f 1 ( * 2 3))")),
+            "decl f a b <- + a b in\n(f 1 ( * 2 3))"
+        );
+        assert_eq!(
+            to_source(&parse("match x with | 1 -> 2 | _ -> 3")),
+            "match x with\n| 1 -> 2\n| _ -> 3"
+        );
+        assert_eq!(to_source(&parse("env_int \"HOME\"")), "env_int \"HOME\"");
+        assert_eq!(
+            to_source(&parse("while x do x <- - x 1 yield done")),
+            "while x do\n  x <- - x 1\nyield done"
+        );
+        assert_eq!(
+            to_source(&parse(
+                "loop match x with | 0 -> break | _ -> break (+ x 1) end"
+            )),
+            "loop\n  match x with\n  | 0 -> break\n  | _ -> break (+ x 1)\nend"
+        );
+        assert_eq!(
+            to_source(&parse("decl f x (y <- -3) <- + x y in (f 1)")),
+            "decl f x (y <- -3) <- + x y in\n(f 1)"
+        );
+        assert_eq!(
+            to_source(&parse("(f 1 y: (+ 2 3) x: z)")),
+            "(f 1 y: (+ 2 3) x: z)"
+        );
+        assert_eq!(to_source(&parse("(sum (product) 2)")), "(sum (product) 2)");
+        assert_eq!(
+            to_source(&parse("print_str read_line")),
+            "print_str read_line"
+        );
+        assert_eq!(
+            to_source(&parse("set [] (len [1 (+ 2 3)]) (get [-1] 0)")),
+            "set [] (len [1 (+ 2 3)]) (get [-1] 0)"
+        );
+        assert_eq!(
+            to_source(&parse("push vec_new (pop v)")),
+            "push vec_new (pop v)"
+        );
+        assert_eq!(
+            to_source(&parse(
+                "decl f <- fopen \"a\\tb\" \"w\" in write_line f (fread_int f)"
+            )),
+            "decl f <- fopen \"a\\tb\" \"w\" in\nwrite_line f (fread_int f)"
+        );
+        let globals = "global x <- 1\nglobal y <- + x 1\ny <- x";
+        assert_eq!(to_source(&parse(globals)), globals);
+    }
+
+    #[test]
+    fn test_to_source_parenthesizes_where_needed() {
+        // A declaration or a match before a `;` would take in the rest
+        let seq = parse("(decl x <- 1 in x); (match y with | _ -> 2); 3");
+        assert_eq!(
+            to_source(&seq),
+            "(decl x <- 1 in\nx);\n(match y with\n| _ -> 2);\n3"
+        );
+        // Operands are atoms
+        assert_eq!(to_source(&parse("print (+ 1 (! 0))")), "print (+ 1 (! 0))");
+        assert_eq!(to_source(&parse("x <- (y <- 2)")), "x <- y <- 2");
+    }
+
+    #[test]
+    fn test_one_line() {
+        // Each of these is already the way `Display` writes it
+        let same = [
+            "42",
+            "x",
+            "+ x ( * 2 3)",
+            "print ( * 2 3)",
+            "(f 1 y: (g 2))",
+            "print_str read_line",
+            "print 1; print 2; 3",
+            "(a; b); c",
+            "x <- y <- 2",
+            "decl f x (y <- -3) <- + x y in (f 1)",
+            "decl x <- (decl y <- 1 in y) in x",
+            "while x do print x; x <- - x 1 done",
+            "while x do x yield done",
+            "loop break (+ x 1) end",
+            "loop break end",
+            "match (f x) with | < 0 -> 1 | n @ some 5 -> x <- n | _ -> 0",
+            "env_int \"HOME\"",
+            "[1 [2 3] []]",
+            "print \"x = {x}\\n\"",
+            "fopen \"a\\tb\" \"w\"",
+        ];
+        for source in same {
+            assert_eq!(parse(source).to_string(), source);
+        }
+        assert_eq!(
+            parse("global x <- 1\nglobal y <- 2\n+ x y").to_string(),
+            "global x <- 1 global y <- 2 + x y"
+        );
+    }
+
+    #[test]
+    fn test_display_truncated() {
+        let expr = parse("decl x <- 1 in while x do print x; x <- - x 1 done");
+        assert_eq!(
+            expr.display_truncated(80),
+            "decl x <- 1 in while x do print x; x <- - x 1 done"
+        );
+        assert_eq!(expr.display_truncated(20), "decl x <- 1 in wh...");
+        assert_eq!(expr.display_truncated(2), "...");
+
+        // Past the width, the rest of the tree isn't rendered
+        let mut long = Expr::Number(0);
+        for _ in 0..100_000 {
+            long = Expr::Block(vec![Expr::Number(1), long]);
+        }
+        assert_eq!(long.display_truncated(12), "1; (1; (1...");
+    }
+
+    #[test]
+    fn test_built_tree_round_trip() {
+        // decl i <- 3 in while i do print i; i <- - i 1 done; (f i)
+        let at = Span::default();
+        let i = || Expr::Ident("i".into(), at);
+        let decrement = Expr::Call("-".into(), vec![i(), Expr::Number(1)], at);
+        let body = Expr::Block(vec![
+            Expr::Call("print".into(), vec![i()], at),
+            Expr::Assign("i".into(), Box::new(decrement), at),
+        ]);
+        let program = Expr::Block(vec![
+            Expr::While(Box::new(i()), Box::new(body), at),
+            Expr::Call("f".into(), vec![i()], at),
+        ]);
+        let expr = Expr::Decl(
+            "i".into(),
+            vec![],
+            Box::new(Expr::Number(3)),
+            Box::new(program),
+            at,
+        );
+        assert_eq!(parse(&to_source(&expr)), expr);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        arbitrary::check_exprs(500, |expr| {
+            let source = to_source(expr);
+            match parse_program(source.clone()) {
+                Ok(parsed) if parsed.clone().without_spans() == *expr => Ok(()),
+                Ok(parsed) => Err(format!(
+                    "{}\nparses back as\n{:?}",
+                    source,
+                    parsed.without_spans()
+                )),
+                Err(error) => Err(format!("{}\ndoesn't parse: {}", source, error)),
+            }
+        });
+    }
+}
@@ -0,0 +1,156 @@
+use crate::parser::{Expr, Name, Span};
+
+/// Combines the ASTs of several source files into one program.
+///
+/// Every file but the last contributes declarations: it must be a chain of
+/// `decl ... in` (after its globals, if it has any) whose innermost body is
+/// the placeholder `0`, as in
+///
+/// ```text
+/// global calls <- 0
+/// decl square n <- * n n in
+/// decl cube n <- * n (square n) in
+/// 0
+/// ```
+///
+/// The last file is the program, and it sees every declaration of the files
+/// before it, in order: the placeholder of each file is replaced by the files
+/// that follow. Two files declaring the same top-level name is an error.
+pub fn link_files(files: Vec<(String, Expr)>) -> Result<Expr, String> {
+    // Top-level names declared so far, with the file and position of each
+    let mut declared: Vec<(Name, String, Span)> = Vec::new();
+    for (file, ast) in &files {
+        for (name, span) in top_level_names(ast) {
+            if let Some((_, other_file, other_span)) = declared
+                .iter()
+                .find(|(other, other_file, _)| *other == name && other_file != file)
+            {
+                return Err(format!(
+                    "'{}' is declared in both {} (line {}, column {}) and {} (line {}, column {})",
+                    name,
+                    other_file,
+                    other_span.line,
+                    other_span.column,
+                    file,
+                    span.line,
+                    span.column
+                ));
+            }
+            declared.push((name, file.clone(), span));
+        }
+    }
+
+    let mut files = files.into_iter().rev();
+    let (_, mut program) = files.next().ok_or("no input files")?;
+    for (file, declarations) in files {
+        program =
+            splice(declarations, program).map_err(|message| format!("{}: {}", file, message))?;
+    }
+    Ok(program)
+}
+
+/// The names declared by the globals and the chain of `decl ... in` that
+/// `expr` starts with.
+fn top_level_names(mut expr: &Expr) -> Vec<(Name, Span)> {
+    let mut names = Vec::new();
+    // For variables and globals the chain goes on in their body, for
+    // functions in their continuation; all are the last expression
+    while let Expr::Decl(name, _, _, rest, span) | Expr::Global(name, _, rest, span) = expr {
+        names.push((name.clone(), *span));
+        expr = rest;
+    }
+    names
+}
+
+/// Replaces the placeholder at the end of the declaration chain `declarations`
+/// with `program`.
+fn splice(mut declarations: Expr, program: Expr) -> Result<Expr, String> {
+    let mut end = &mut declarations;
+    loop {
+        match end {
+            Expr::Decl(_, _, _, body, _) | Expr::Global(_, _, body, _) => end = &mut **body,
+            Expr::Number(0) => break,
+            _ => {
+                return Err(
+                    "only the last file can have a program; the others must be a chain of \
+                     `decl ... in` ending in 0"
+                        .to_string(),
+                );
+            }
+        }
+    }
+    *end = program;
+    Ok(declarations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn file(name: &str, source: &str) -> (String, Expr) {
+        (name.to_string(), parse_program(source.to_string()).unwrap())
+    }
+
+    #[test]
+    fn test_declarations_come_before_the_program() {
+        let linked = link_files(vec![
+            file("util.mlia", "decl square n <- * n n in 0"),
+            file("main.mlia", "(square 3)"),
+        ])
+        .unwrap();
+        let expected = parse_program("decl square n <- * n n in (square 3)".to_string()).unwrap();
+        assert_eq!(linked.without_spans(), expected.without_spans());
+    }
+
+    #[test]
+    fn test_globals_of_declaration_files() {
+        let linked = link_files(vec![
+            file(
+                "counter.mlia",
+                "global count <- 0\ndecl tick n <- count <- + count n in 0",
+            ),
+            file("main.mlia", "(tick 2); count"),
+        ])
+        .unwrap();
+        let expected = parse_program(
+            "global count <- 0\ndecl tick n <- count <- + count n in (tick 2); count".to_string(),
+        )
+        .unwrap();
+        assert_eq!(linked.without_spans(), expected.without_spans());
+
+        let err = link_files(vec![
+            file("counter.mlia", "global count <- 0\n0"),
+            file("main.mlia", "global count <- 1\ncount"),
+        ])
+        .unwrap_err();
+        assert!(err.starts_with("'count' is declared in both"), "{}", err);
+    }
+
+    #[test]
+    fn test_duplicate_names_across_files() {
+        let err = link_files(vec![
+            file("util.mlia", "decl square n <- * n n in 0"),
+            file(
+                "main.mlia",
+                "decl x <- 1 in\ndecl square n <- n in (square x)",
+            ),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "'square' is declared in both util.mlia (line 1, column 6) \
+             and main.mlia (line 2, column 6)"
+        );
+    }
+
+    #[test]
+    fn test_declaration_file_without_placeholder() {
+        let err = link_files(vec![
+            file("util.mlia", "decl square n <- * n n in (square 2)"),
+            file("main.mlia", "1"),
+        ])
+        .unwrap_err();
+        assert!(err.starts_with("util.mlia: only the last file"), "{}", err);
+    }
+}
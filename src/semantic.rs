@@ -0,0 +1,1406 @@
+use crate::exhaustiveness::{self, NonExhaustive, int_range};
+use crate::parser::{Expr, Name, Pattern, Span, arity, builtin_arity};
+use crate::printer::pattern_source;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// A name that is used where no declaration of it is in scope, a call with
+/// the wrong arguments, or a `break` with no loop to leave.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SemanticError {
+    /// A variable is read without a declaration in scope.
+    UndefinedVariable {
+        name: String,
+        span: Span,
+        suggestion: Option<String>,
+    },
+    /// A variable is assigned without a declaration in scope.
+    AssignToUndeclared {
+        name: String,
+        span: Span,
+        hint: AssignHint,
+    },
+    /// A call to a builtin, or to a function declared in scope, with a number
+    /// of arguments other than the one it takes.
+    WrongArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// A `break` outside of any `loop` (in its function: a function body
+    /// can't leave a loop around its declaration).
+    BreakOutsideLoop { span: Span },
+    /// A match whose arms leave out some values (see `exhaustiveness`).
+    NonExhaustiveMatch { error: NonExhaustive, span: Span },
+    /// A named argument that names no parameter of the function called (or a
+    /// call by name to a builtin, or to no function in scope).
+    UnknownNamedArgument {
+        function: String,
+        name: String,
+        span: Span,
+    },
+    /// A named argument for a parameter that already has an argument.
+    DuplicateNamedArgument {
+        function: String,
+        name: String,
+        span: Span,
+    },
+    /// A parameter without a default value that is left without an argument
+    /// while a later one is given by name.
+    MissingArgument {
+        function: String,
+        name: String,
+        span: Span,
+    },
+}
+
+/// What an assignment to an undeclared variable was probably meant to be.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssignHint {
+    /// A variable in scope with a similar name.
+    SimilarName(String),
+    /// The same block declares the variable afterwards, at this position.
+    DeclaredLater(Span),
+    /// Nothing similar is around: it needs a declaration. Holds the assigned
+    /// value if it is short enough to show in `decl x <- value in ...`.
+    Declare(Option<String>),
+}
+
+impl fmt::Display for SemanticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SemanticError::UndefinedVariable {
+                name,
+                span,
+                suggestion,
+            } => {
+                write!(
+                    f,
+                    "undefined variable '{}' at line {}, column {}",
+                    name, span.line, span.column
+                )?;
+                if let Some(suggestion) = suggestion {
+                    write!(f, "; did you mean '{}'?", suggestion)?;
+                }
+                Ok(())
+            }
+            SemanticError::AssignToUndeclared { name, span, hint } => {
+                write!(
+                    f,
+                    "cannot assign to undeclared variable '{}' at line {}, column {}",
+                    name, span.line, span.column
+                )?;
+                match hint {
+                    AssignHint::SimilarName(similar) => {
+                        write!(f, "; did you mean '{}'?", similar)
+                    }
+                    AssignHint::DeclaredLater(decl) => write!(
+                        f,
+                        "; '{}' is only declared later, at line {}, column {}: \
+                         declare it before assigning to it",
+                        name, decl.line, decl.column
+                    ),
+                    AssignHint::Declare(value) => write!(
+                        f,
+                        "; did you mean `decl {} <- {} in ...`?",
+                        name,
+                        value.as_deref().unwrap_or("...")
+                    ),
+                }
+            }
+            SemanticError::WrongArgumentCount {
+                name,
+                expected,
+                found,
+                span,
+            } => write!(
+                f,
+                "function '{}' expects {} argument(s) but was called with {} \
+                 at line {}, column {}",
+                name, expected, found, span.line, span.column
+            ),
+            SemanticError::NonExhaustiveMatch { error, span } => {
+                write!(f, "{} at line {}, column {}", error, span.line, span.column)
+            }
+            SemanticError::BreakOutsideLoop { span } => write!(
+                f,
+                "'break' outside of a loop at line {}, column {}",
+                span.line, span.column
+            ),
+            SemanticError::UnknownNamedArgument {
+                function,
+                name,
+                span,
+            } => write!(
+                f,
+                "function '{}' has no parameter named '{}' at line {}, column {}",
+                function, name, span.line, span.column
+            ),
+            SemanticError::DuplicateNamedArgument {
+                function,
+                name,
+                span,
+            } => write!(
+                f,
+                "parameter '{}' of function '{}' is given more than once at line {}, column {}",
+                name, function, span.line, span.column
+            ),
+            SemanticError::MissingArgument {
+                function,
+                name,
+                span,
+            } => write!(
+                f,
+                "call to function '{}' has no argument for parameter '{}' at line {}, column {}",
+                function, name, span.line, span.column
+            ),
+        }
+    }
+}
+
+impl Error for SemanticError {}
+
+/// Something legal that is probably a mistake. Warnings never stop compilation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SemanticWarning {
+    /// A declared variable that is neither read nor assigned in its body.
+    UnusedVariable { name: String, span: Span },
+    /// A declared variable that is assigned in its body but never read.
+    NeverRead { name: String, span: Span },
+    /// A variable or parameter declared where a variable with the same name
+    /// is already in scope, declared at `shadowed`.
+    Shadowing {
+        name: String,
+        span: Span,
+        shadowed: Span,
+    },
+    /// A variable, parameter or function named `main`, like the program's
+    /// entry point. User functions are emitted under a symbol of their own,
+    /// so nothing clashes, but a reader of the program or its IR may think
+    /// it is the one the program starts at.
+    ReservedName { name: String, span: Span },
+    /// An identifier that is a keyword from version `version` of the
+    /// language on, read with an earlier one (see
+    /// `tokenizer::STAGED_KEYWORDS`).
+    FutureKeyword {
+        name: String,
+        version: u32,
+        span: Span,
+    },
+    /// An arm of the match at `span` for a value its scrutinee can't have
+    /// (see `Expr::known_values`).
+    UnreachableArm {
+        value: i64,
+        possible: &'static [i64],
+        span: Span,
+    },
+    /// An arm of the match at `span` whose values an arm before it matches
+    /// first (arms are numbered from 1).
+    DuplicateArm {
+        pattern: Pattern,
+        arm: usize,
+        first: usize,
+        span: Span,
+    },
+}
+
+impl fmt::Display for SemanticWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SemanticWarning::UnusedVariable { name, span } => write!(
+                f,
+                "unused variable '{}' at line {}, column {}",
+                name, span.line, span.column
+            ),
+            SemanticWarning::NeverRead { name, span } => write!(
+                f,
+                "value assigned to '{}' is never read (declared at line {}, column {})",
+                name, span.line, span.column
+            ),
+            SemanticWarning::Shadowing {
+                name,
+                span,
+                shadowed,
+            } => write!(
+                f,
+                "declaration of '{}' at line {}, column {} shadows the one at line {}, column {}",
+                name, span.line, span.column, shadowed.line, shadowed.column
+            ),
+            SemanticWarning::ReservedName { name, span } => write!(
+                f,
+                "'{}' at line {}, column {} is the name of the program's entry point; \
+                 try another name, like 'my_{}'",
+                name, span.line, span.column, name
+            ),
+            SemanticWarning::FutureKeyword {
+                name,
+                version,
+                span,
+            } => write!(
+                f,
+                "identifier '{}' at line {}, column {} will become a keyword in a future \
+                 version (it is one with --lang-version {}); try another name, like 'my_{}'",
+                name, span.line, span.column, version, name
+            ),
+            SemanticWarning::UnreachableArm {
+                value,
+                possible,
+                span,
+            } => {
+                let possible: Vec<String> = possible.iter().map(i64::to_string).collect();
+                write!(
+                    f,
+                    "arm '| {}' of the match at line {}, column {} is unreachable: \
+                     the value matched is always {}",
+                    value,
+                    span.line,
+                    span.column,
+                    possible.join(" or ")
+                )
+            }
+            SemanticWarning::DuplicateArm {
+                pattern,
+                arm,
+                first,
+                span,
+            } => write!(
+                f,
+                "arm {} ('| {}') of the match at line {}, column {} is unreachable: \
+                 arm {} matches all of its values first",
+                arm,
+                pattern_source(pattern),
+                span.line,
+                span.column,
+                first
+            ),
+        }
+    }
+}
+
+/// Checks that every variable read or assigned in `expr` is declared, that
+/// builtins and the functions declared in scope are called with as many
+/// arguments as they take, and that every `break` is in a loop. Returns an
+/// error for each problem (an empty vector if there are none).
+///
+/// Scopes follow codegen: a `Decl` brings its name into scope for its body,
+/// a function declaration brings its parameters into scope for the function
+/// body, which also sees the variables around the declaration (they are
+/// captured), and the function's continuation doesn't see the parameters.
+pub fn resolve(expr: &Expr) -> Vec<SemanticError> {
+    analyze(expr).errors
+}
+
+/// Returns the warnings for `expr`: variables that are never used, or only
+/// ever assigned, declarations that shadow a variable in scope (names
+/// starting with `_` are exempt from those), and declarations named `main`.
+pub fn lint(expr: &Expr) -> Vec<SemanticWarning> {
+    analyze(expr).warnings
+}
+
+/// Replaces the named arguments in `expr` (`(f x: 1 y: 2)`) with positional
+/// ones, in the order of the parameters of the function declared in scope.
+/// Parameters left out before the last argument get their default value.
+/// Arguments are then evaluated in the order of the parameters, not in the
+/// order they are written. Returns an error for each named argument that
+/// can't be placed and each parameter left without a value.
+pub fn lower_named_arguments(expr: &mut Expr) -> Vec<SemanticError> {
+    let mut lowering = Lowering {
+        functions: Vec::new(),
+        errors: Vec::new(),
+    };
+    lowering.visit(expr);
+    lowering.errors
+}
+
+/// A function's name and parameters.
+type Signature = (Name, Vec<(Name, Option<i64>)>);
+
+struct Lowering {
+    /// Functions in scope, innermost last.
+    functions: Vec<Signature>,
+    errors: Vec<SemanticError>,
+}
+
+impl Lowering {
+    /// Lowers the named arguments in `expr`. Blocks, declarations and globals
+    /// on the chain a long program is (see `CodeGen::compile_chain`) are
+    /// followed in a loop rather than recursively.
+    fn visit(&mut self, expr: &mut Expr) {
+        let outer_functions = self.functions.len();
+        let mut rest = expr;
+        loop {
+            match rest {
+                Expr::Block(statements) => {
+                    let Some((last, statements)) = statements.split_last_mut() else {
+                        break;
+                    };
+                    for statement in statements {
+                        self.visit(statement);
+                    }
+                    rest = last;
+                }
+                Expr::Decl(name, params, value, body, _) => {
+                    // A function is in scope in its own body too, like in
+                    // `Resolver`, and until the end of the chain
+                    if !params.is_empty() {
+                        self.functions.push((name.clone(), params.clone()));
+                    }
+                    self.visit(value);
+                    rest = &mut **body;
+                }
+                Expr::Global(_, value, body, _) => {
+                    self.visit(value);
+                    rest = &mut **body;
+                }
+                last => {
+                    self.visit_node(last);
+                    break;
+                }
+            }
+        }
+        self.functions.truncate(outer_functions);
+    }
+
+    /// Lowers the named arguments in an expression that doesn't go on with a
+    /// chain (see `visit`).
+    fn visit_node(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Number(_) | Expr::Ident(..) | Expr::EnvInt(..) | Expr::PrintFormat(..) => {}
+            Expr::Str(..) => {}
+            Expr::Call(name, args, span) => {
+                for arg in args.iter_mut() {
+                    self.visit(arg);
+                }
+                if args.iter().any(|arg| matches!(arg, Expr::NamedArg(..))) {
+                    *args = self.reorder(name, std::mem::take(args), *span);
+                }
+            }
+            Expr::NamedArg(_, value, _) | Expr::Assign(_, value, _) => self.visit(value),
+            Expr::Array(elements, _) => {
+                for element in elements {
+                    self.visit(element);
+                }
+            }
+            Expr::Block(..) | Expr::Decl(..) | Expr::Global(..) => self.visit(expr),
+            Expr::While(first, second, _) | Expr::WhileYield(first, second, _) => {
+                self.visit(first);
+                self.visit(second);
+            }
+            Expr::Loop(body, _) => self.visit(body),
+            Expr::Break(value, _) => {
+                if let Some(value) = value {
+                    self.visit(value);
+                }
+            }
+            Expr::Match(scrutinee, arms, _) => {
+                self.visit(scrutinee);
+                for (_, arm) in arms {
+                    self.visit(arm);
+                }
+            }
+        }
+    }
+
+    /// The arguments of a call to `function` with every named one in the slot
+    /// of its parameter. Positional arguments beyond the parameters stay at
+    /// the end, for `Resolver` to report. After an error, the call gets 0 for
+    /// every argument it lacks, so it isn't reported again for those.
+    fn reorder(&mut self, function: &str, args: Vec<Expr>, call: Span) -> Vec<Expr> {
+        let errors = self.errors.len();
+        let params = self
+            .functions
+            .iter()
+            .rev()
+            .find(|(name, _)| name == function)
+            .map_or(&[][..], |(_, params)| params.as_slice());
+        let mut slots: Vec<Option<Expr>> = vec![None; params.len()];
+        let mut extra = Vec::new();
+        for (i, mut arg) in args.into_iter().enumerate() {
+            let Expr::NamedArg(name, value, span) = &mut arg else {
+                match slots.get_mut(i) {
+                    Some(slot) => *slot = Some(arg),
+                    None => extra.push(arg),
+                }
+                continue;
+            };
+            let (name, span) = (name.clone(), *span);
+            let value = std::mem::replace(&mut **value, Expr::Number(0));
+            let Some(slot) = params.iter().position(|(param, _)| *param == name) else {
+                self.errors.push(SemanticError::UnknownNamedArgument {
+                    function: function.to_string(),
+                    name: name.to_string(),
+                    span,
+                });
+                continue;
+            };
+            if slots[slot].is_some() {
+                self.errors.push(SemanticError::DuplicateNamedArgument {
+                    function: function.to_string(),
+                    name: name.to_string(),
+                    span,
+                });
+                continue;
+            }
+            slots[slot] = Some(value);
+        }
+
+        let given = slots
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |last| last + 1);
+        let mut args = Vec::new();
+        for ((param, default), slot) in params.iter().zip(slots).take(given) {
+            args.push(match (slot, default) {
+                (Some(arg), _) => arg,
+                (None, Some(default)) => Expr::Number(*default),
+                (None, None) => {
+                    self.errors.push(SemanticError::MissingArgument {
+                        function: function.to_string(),
+                        name: param.to_string(),
+                        span: call,
+                    });
+                    Expr::Number(0)
+                }
+            });
+        }
+        if self.errors.len() > errors {
+            let (required, _) = arity(params);
+            args.resize(args.len().max(required), Expr::Number(0));
+        }
+        args.extend(extra);
+        args
+    }
+}
+
+fn analyze(expr: &Expr) -> Resolver<'_> {
+    let mut resolver = Resolver {
+        scope: Vec::new(),
+        positions: HashMap::new(),
+        later: Vec::new(),
+        functions: Vec::new(),
+        loops: 0,
+        errors: Vec::new(),
+        warnings: Vec::new(),
+    };
+    resolver.visit(expr);
+    resolver
+}
+
+/// A name in scope and what the program has done with it so far.
+struct Binding {
+    name: Name,
+    /// Where it was declared; for parameters, the function's name.
+    span: Span,
+    is_param: bool,
+    read: bool,
+    written: bool,
+}
+
+struct Resolver<'a> {
+    /// Names in scope, innermost last; a shadowed name appears more than once.
+    scope: Vec<Binding>,
+    /// Where the bindings of each name in `scope` are, innermost last, so
+    /// that a long program's declarations are not each a scan of the scope.
+    positions: HashMap<Name, Vec<usize>>,
+    /// The statements that follow the current one in the blocks around it.
+    later: Vec<&'a [Expr]>,
+    /// Functions in scope with the least and the most arguments they take,
+    /// innermost last.
+    functions: Vec<(&'a str, (usize, usize))>,
+    /// How many `loop`s are around the current expression in its function.
+    loops: usize,
+    errors: Vec<SemanticError>,
+    warnings: Vec<SemanticWarning>,
+}
+
+impl<'a> Resolver<'a> {
+    /// Resolves the names in `expr`. Blocks, declarations and globals on the
+    /// chain a long program is (see `CodeGen::compile_chain`) are followed in
+    /// a loop, and the scopes they open left at its end, innermost first.
+    fn visit(&mut self, expr: &'a Expr) {
+        let outer_functions = self.functions.len();
+        // Where the binding of each variable declared on the chain is
+        let mut declared = Vec::new();
+        let mut rest = expr;
+        loop {
+            match rest {
+                Expr::Block(statements) => {
+                    let Some((last, init)) = statements.split_last() else {
+                        break;
+                    };
+                    for (i, statement) in init.iter().enumerate() {
+                        self.later.push(&statements[i + 1..]);
+                        self.visit(statement);
+                        self.later.pop();
+                    }
+                    // Nothing follows the last statement in this block; what
+                    // follows the block is already in `later`
+                    rest = last;
+                }
+
+                Expr::Decl(name, params, value, body, span) if params.is_empty() => {
+                    self.visit(value);
+                    declared.push(self.scope.len());
+                    self.declare(name, *span, false, self.scope.len());
+                    rest = &**body;
+                }
+
+                Expr::Global(name, value, body, span) => {
+                    self.visit(value);
+                    declared.push(self.scope.len());
+                    self.declare(name, *span, false, self.scope.len());
+                    rest = &**body;
+                }
+
+                Expr::Decl(name, params, func_body, continuation, span) => {
+                    // In scope in its own body too, for recursion, and until
+                    // the end of the chain
+                    self.functions.push((name, arity(params)));
+                    self.check_name(name, *span);
+                    let outer_len = self.scope.len();
+                    for (param, _) in params {
+                        self.declare(param, *span, true, outer_len);
+                    }
+                    let outer_loops = std::mem::replace(&mut self.loops, 0);
+                    self.visit(func_body);
+                    self.loops = outer_loops;
+                    self.leave_scope(outer_len);
+                    rest = &**continuation;
+                }
+
+                last => {
+                    self.visit_node(last);
+                    break;
+                }
+            }
+        }
+        for len in declared.into_iter().rev() {
+            self.leave_scope(len);
+        }
+        self.functions.truncate(outer_functions);
+    }
+
+    /// Resolves the names in an expression that doesn't go on with a chain
+    /// (see `visit`).
+    fn visit_node(&mut self, expr: &'a Expr) {
+        match expr {
+            Expr::Number(_) | Expr::EnvInt(..) | Expr::Str(..) => {}
+
+            Expr::Block(..) | Expr::Decl(..) | Expr::Global(..) => self.visit(expr),
+
+            Expr::Ident(name, span) => match self.lookup(name) {
+                Some(binding) => binding.read = true,
+                None => self.errors.push(SemanticError::UndefinedVariable {
+                    name: name.to_string(),
+                    span: *span,
+                    suggestion: self.suggest(name),
+                }),
+            },
+
+            Expr::Call(name, args, span) => {
+                let arity = builtin_arity(name).map(|n| (n, n)).or_else(|| {
+                    let function = self.functions.iter().rev().find(|(f, _)| f == name);
+                    function.map(|(_, arity)| *arity)
+                });
+                let found = args.len();
+                if let Some((required, total)) = arity
+                    && (found < required || found > total)
+                {
+                    self.errors.push(SemanticError::WrongArgumentCount {
+                        name: name.to_string(),
+                        expected: if found < required { required } else { total },
+                        found,
+                        span: *span,
+                    });
+                }
+                for arg in args {
+                    self.visit(arg);
+                }
+            }
+
+            Expr::NamedArg(_, value, _) => self.visit(value),
+
+            Expr::PrintFormat(_, args, _) | Expr::Array(args, _) => {
+                for arg in args {
+                    self.visit(arg);
+                }
+            }
+
+            Expr::Assign(name, value, span) => {
+                self.visit(value);
+                match self.lookup(name) {
+                    Some(binding) => binding.written = true,
+                    None => {
+                        let hint = self.assign_hint(name, value);
+                        self.errors.push(SemanticError::AssignToUndeclared {
+                            name: name.to_string(),
+                            span: *span,
+                            hint,
+                        });
+                    }
+                }
+            }
+
+            Expr::While(cond, body, _) | Expr::WhileYield(cond, body, _) => {
+                self.visit(cond);
+                self.visit(body);
+            }
+
+            Expr::Loop(body, _) => {
+                self.loops += 1;
+                self.visit(body);
+                self.loops -= 1;
+            }
+
+            Expr::Break(value, span) => {
+                if let Some(value) = value {
+                    self.visit(value);
+                }
+                if self.loops == 0 {
+                    self.errors
+                        .push(SemanticError::BreakOutsideLoop { span: *span });
+                }
+            }
+
+            Expr::Match(scrutinee, arms, span) => {
+                self.visit(scrutinee);
+                let patterns: Vec<&Pattern> = arms.iter().map(|(pattern, _)| pattern).collect();
+                if let Err(error) = exhaustiveness::check(scrutinee, &patterns) {
+                    self.errors
+                        .push(SemanticError::NonExhaustiveMatch { error, span: *span });
+                }
+                if let Some(possible) = scrutinee.known_values() {
+                    for (pattern, _) in arms {
+                        if let Pattern::Literal(value) = pattern.unbound()
+                            && !possible.contains(value)
+                        {
+                            self.warnings.push(SemanticWarning::UnreachableArm {
+                                value: *value,
+                                possible,
+                                span: *span,
+                            });
+                        }
+                    }
+                }
+                for (i, (pattern, _)) in arms.iter().enumerate() {
+                    let earlier = arms[..i].iter().position(|(p, _)| covers(p, pattern));
+                    if let Some(first) = earlier {
+                        self.warnings.push(SemanticWarning::DuplicateArm {
+                            pattern: pattern.clone(),
+                            arm: i + 1,
+                            first: first + 1,
+                            span: *span,
+                        });
+                    }
+                }
+                for (pattern, arm) in arms {
+                    let outer_len = self.scope.len();
+                    for (name, span) in pattern.bindings() {
+                        self.declare(name, span, false, outer_len);
+                    }
+                    self.visit(arm);
+                    self.leave_scope(outer_len);
+                }
+            }
+        }
+    }
+
+    /// Brings `name` into scope. Only the first `visible` bindings are checked
+    /// for shadowing, so that parameters aren't compared with each other.
+    fn declare(&mut self, name: &Name, span: Span, is_param: bool, visible: usize) {
+        self.check_name(name, span);
+        let positions = self.positions.entry(name.clone()).or_default();
+        if !name.starts_with('_')
+            && let Some(&shadowed) = positions.iter().rev().find(|&&at| at < visible)
+        {
+            let shadowed = &self.scope[shadowed];
+            self.warnings.push(SemanticWarning::Shadowing {
+                name: name.to_string(),
+                span,
+                shadowed: shadowed.span,
+            });
+        }
+        positions.push(self.scope.len());
+        self.scope.push(Binding {
+            name: name.clone(),
+            span,
+            is_param,
+            read: false,
+            written: false,
+        });
+    }
+
+    /// Warns about declaring `name` at `span` if it is reserved for the
+    /// runtime. The builtins are keywords, so the parser already rejects them
+    /// as names; what is left is `main`.
+    fn check_name(&mut self, name: &str, span: Span) {
+        if name == "main" {
+            self.warnings.push(SemanticWarning::ReservedName {
+                name: name.to_string(),
+                span,
+            });
+        }
+    }
+
+    /// Drops the bindings from `len` on, warning about the ones that went unused.
+    fn leave_scope(&mut self, len: usize) {
+        for binding in self.scope.drain(len..) {
+            if let Some(positions) = self.positions.get_mut(&binding.name) {
+                positions.pop();
+            }
+            if binding.is_param || binding.read || binding.name.starts_with('_') {
+                continue;
+            }
+            let name = binding.name.to_string();
+            let span = binding.span;
+            self.warnings.push(if binding.written {
+                SemanticWarning::NeverRead { name, span }
+            } else {
+                SemanticWarning::UnusedVariable { name, span }
+            });
+        }
+    }
+
+    /// The innermost binding of `name`.
+    fn lookup(&mut self, name: &str) -> Option<&mut Binding> {
+        let &at = self.positions.get(name)?.last()?;
+        Some(&mut self.scope[at])
+    }
+
+    /// Works out what an assignment of `value` to the undeclared `name` was
+    /// meant to be: a typo, a declaration that came too late, or a missing one.
+    fn assign_hint(&self, name: &str, value: &Expr) -> AssignHint {
+        if let Some(similar) = self.suggest(name) {
+            return AssignHint::SimilarName(similar);
+        }
+        if let Some(decl) = self
+            .later
+            .iter()
+            .rev()
+            .flat_map(|statements| statements.iter())
+            .find_map(|expr| find_decl(expr, name))
+        {
+            return AssignHint::DeclaredLater(decl);
+        }
+        AssignHint::Declare(match value {
+            Expr::Number(n) => Some(n.to_string()),
+            Expr::Ident(name, _) => Some(name.to_string()),
+            _ => None,
+        })
+    }
+
+    /// The name in scope closest to `name`, if one is close enough to be a typo
+    /// (one or two edits away, depending on its length).
+    fn suggest(&self, name: &str) -> Option<String> {
+        let max_distance = (name.chars().count() / 3).clamp(1, 2);
+        self.scope
+            .iter()
+            .rev()
+            .map(|binding| (edit_distance(name, &binding.name), &binding.name))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate.to_string())
+    }
+}
+
+/// Whether `earlier` matches every value `later` matches (whatever they bind),
+/// so that an arm with `later` after one with `earlier` is never chosen.
+fn covers(earlier: &Pattern, later: &Pattern) -> bool {
+    let (earlier, later) = (earlier.unbound(), later.unbound());
+    if earlier.is_irrefutable() {
+        return true;
+    }
+    match (earlier, later) {
+        (Pattern::None, Pattern::None) => true,
+        (Pattern::Some(x), Pattern::Some(y))
+        | (Pattern::Ok(x), Pattern::Ok(y))
+        | (Pattern::Err(x), Pattern::Err(y)) => covers(x, y),
+        _ => match (int_range(earlier), int_range(later)) {
+            (Some((first, last)), Some((from, to))) => first <= from && to <= last,
+            _ => false,
+        },
+    }
+}
+
+/// Where `expr` declares a variable called `name`, if it does anywhere (the
+/// first declaration in the order the program runs).
+fn find_decl(expr: &Expr, name: &str) -> Option<Span> {
+    let mut pending = vec![expr];
+    while let Some(expr) = pending.pop() {
+        match expr {
+            Expr::Decl(var_name, params, _, _, span) if var_name == name && params.is_empty() => {
+                return Some(*span);
+            }
+            Expr::Global(var_name, _, _, span) if var_name == name => return Some(*span),
+            _ => pending.extend(expr.children().rev()),
+        }
+    }
+    None
+}
+
+/// Levenshtein distance between two names, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn resolve_source(source: &str) -> Vec<SemanticError> {
+        resolve(&parse_program(source.to_string()).unwrap())
+    }
+
+    #[test]
+    fn test_reports_every_undefined_name() {
+        let errors = resolve_source("decl count <- 1 in\nprint cout;\ntotal <- + count y");
+        assert_eq!(
+            errors,
+            vec![
+                SemanticError::UndefinedVariable {
+                    name: "cout".to_string(),
+                    span: Span {
+                        line: 2,
+                        column: 7,
+                        len: 4
+                    },
+                    suggestion: Some("count".to_string()),
+                },
+                SemanticError::UndefinedVariable {
+                    name: "y".to_string(),
+                    span: Span {
+                        line: 3,
+                        column: 18,
+                        len: 1
+                    },
+                    suggestion: None,
+                },
+                SemanticError::AssignToUndeclared {
+                    name: "total".to_string(),
+                    span: Span {
+                        line: 3,
+                        column: 1,
+                        len: 5
+                    },
+                    hint: AssignHint::Declare(None),
+                },
+            ]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "undefined variable 'cout' at line 2, column 7; did you mean 'count'?"
+        );
+    }
+
+    #[test]
+    fn test_shadowing_and_nested_scopes_are_not_flagged() {
+        let source = "decl x <- 1 in\n\
+                      decl x <- + x 1 in\n\
+                      decl f n <- + n x in\n\
+                      match (f x) with | 0 -> x <- 0 | _ -> (decl y <- x in y)";
+        assert_eq!(resolve_source(source), vec![]);
+    }
+
+    #[test]
+    fn test_interpolated_names_are_resolved() {
+        assert_eq!(resolve_source("decl x <- 1 in print \"x = {x}\""), vec![]);
+        assert_eq!(
+            resolve_source("decl count <- 1 in\nprint \"{count} of {total}\"")[0].to_string(),
+            "undefined variable 'total' at line 2, column 20"
+        );
+        // Each escape takes two columns of the source
+        assert_eq!(
+            resolve_source(r#"decl count <- 1 in print "\t{count}\n\"{total}\"""#)[0].to_string(),
+            "undefined variable 'total' at line 1, column 41"
+        );
+    }
+
+    #[test]
+    fn test_names_go_out_of_scope() {
+        // The variable after its Decl body, a parameter in the continuation
+        let errors = resolve_source("(decl x <- 1 in x); x; decl f n <- n in n");
+        let names: Vec<&str> = errors
+            .iter()
+            .map(|error| match error {
+                SemanticError::UndefinedVariable { name, .. } => name.as_str(),
+                SemanticError::AssignToUndeclared { name, .. } => name.as_str(),
+                SemanticError::WrongArgumentCount { name, .. } => name.as_str(),
+                SemanticError::UnknownNamedArgument { name, .. }
+                | SemanticError::DuplicateNamedArgument { name, .. }
+                | SemanticError::MissingArgument { name, .. } => name.as_str(),
+                SemanticError::BreakOutsideLoop { .. } => "break",
+                SemanticError::NonExhaustiveMatch { .. } => "match",
+            })
+            .collect();
+        assert_eq!(names, vec!["x", "n"]);
+    }
+
+    #[test]
+    fn test_pattern_bindings_are_in_scope_in_their_arm() {
+        let source = "match some 1 with | some n -> n | none -> 0";
+        assert_eq!(resolve_source(source), vec![]);
+        let errors = resolve_source("(match none with | some n -> n | none -> 0); n");
+        assert_eq!(
+            errors[0].to_string(),
+            "undefined variable 'n' at line 1, column 46"
+        );
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_at_bindings_are_in_scope_in_their_arm() {
+        let source = "match 3 with | n @ 3 -> n | _ -> 0";
+        assert_eq!(resolve_source(source), vec![]);
+        let errors = resolve_source("match 3 with | n @ 3 -> n | _ -> n");
+        assert_eq!(
+            errors[0].to_string(),
+            "undefined variable 'n' at line 1, column 34"
+        );
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_arms_a_comparison_never_reaches_warn() {
+        let lint_source = |source: &str| lint(&parse_program(source.to_string()).unwrap());
+        assert_eq!(lint_source("match (< 1 2) with | 0 -> 1 | 1 -> 2"), vec![]);
+        let warnings = lint_source("match (< 1 2) with | 0 -> 1 | 5 -> 2 | _ -> 3");
+        assert_eq!(
+            warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>(),
+            vec![
+                "arm '| 5' of the match at line 1, column 1 is unreachable: \
+                 the value matched is always 0 or 1"
+            ]
+        );
+        // Any value is possible for other scrutinees
+        assert_eq!(
+            lint_source("match (+ 1 2) with | 0 -> 1 | 5 -> 2 | _ -> 3"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_arms_warn() {
+        let lint_source = |source: &str| {
+            let warnings = lint(&parse_program(source.to_string()).unwrap());
+            warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lint_source("decl x <- 3 in\nmatch x with | 3 -> 1 | 3 -> 2 | _ -> 3"),
+            vec![
+                "arm 2 ('| 3') of the match at line 2, column 1 is unreachable: \
+                 arm 1 matches all of its values first"
+            ]
+        );
+        assert_eq!(
+            lint_source("decl x <- 3 in match x with | 3 -> 1 | 4 -> 2 | _ -> 3"),
+            Vec::<String>::new()
+        );
+        // Wildcards and names match the same values
+        assert_eq!(
+            lint_source("decl x <- 3 in match x with | 3 -> 1 | _ -> 2 | _n -> 3"),
+            vec![
+                "arm 3 ('| _n') of the match at line 1, column 16 is unreachable: \
+                 arm 2 matches all of its values first"
+            ]
+        );
+        // So does a comparison that takes in a later one
+        assert_eq!(
+            lint_source("decl x <- 3 in match x with | < 80 -> 1 | 50 -> 2 | <= 70 -> 3 | _ -> 4"),
+            vec![
+                "arm 2 ('| 50') of the match at line 1, column 16 is unreachable: \
+                 arm 1 matches all of its values first",
+                "arm 3 ('| <= 70') of the match at line 1, column 16 is unreachable: \
+                 arm 1 matches all of its values first",
+            ]
+        );
+        let buckets = "decl x <- 3 in match x with | < 60 -> 1 | < 80 -> 2 | >= 80 -> 3 | _ -> 0";
+        assert_eq!(lint_source(buckets), Vec::<String>::new());
+        assert_eq!(
+            lint_source("match none with | some (some 1) -> 1 | some (some 1) -> 2 | _ -> 3"),
+            vec![
+                "arm 2 ('| some (some 1)') of the match at line 1, column 1 is unreachable: \
+                 arm 1 matches all of its values first"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_used_variables_do_not_warn() {
+        assert_eq!(
+            lint(&parse_program("decl x <- 1 in x <- + x 1".to_string()).unwrap()),
+            vec![]
+        );
+        // A read from inside a function body counts too
+        assert_eq!(
+            lint(&parse_program("decl x <- 1 in decl f n <- + n x in (f 2)".to_string()).unwrap()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_unused_and_write_only_variables_warn() {
+        let warnings = lint(
+            &parse_program("decl unused <- 1 in\ndecl total <- 0 in\ntotal <- 5".to_string())
+                .unwrap(),
+        );
+        assert_eq!(
+            warnings,
+            vec![
+                SemanticWarning::NeverRead {
+                    name: "total".to_string(),
+                    span: Span {
+                        line: 2,
+                        column: 6,
+                        len: 5
+                    },
+                },
+                SemanticWarning::UnusedVariable {
+                    name: "unused".to_string(),
+                    span: Span {
+                        line: 1,
+                        column: 6,
+                        len: 6
+                    },
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[1].to_string(),
+            "unused variable 'unused' at line 1, column 6"
+        );
+    }
+
+    #[test]
+    fn test_underscore_names_do_not_warn() {
+        assert_eq!(
+            lint(
+                &parse_program("decl _ignored <- print 1 in decl _w <- 0 in _w <- 2".to_string())
+                    .unwrap()
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_nested_declaration_shadowing_warns() {
+        let warnings =
+            lint(&parse_program("decl x <- 1 in\ndecl x <- + x 1 in x".to_string()).unwrap());
+        assert_eq!(
+            warnings,
+            vec![SemanticWarning::Shadowing {
+                name: "x".to_string(),
+                span: Span {
+                    line: 2,
+                    column: 6,
+                    len: 1
+                },
+                shadowed: Span {
+                    line: 1,
+                    column: 6,
+                    len: 1
+                },
+            }]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "declaration of 'x' at line 2, column 6 shadows the one at line 1, column 6"
+        );
+    }
+
+    #[test]
+    fn test_parameter_shadowing_warns() {
+        let warnings =
+            lint(&parse_program("decl n <- 1 in decl f n <- + n 1 in (f n)".to_string()).unwrap());
+        assert!(
+            matches!(warnings.as_slice(), [SemanticWarning::Shadowing { name, .. }] if name == "n"),
+            "Got {:?}",
+            warnings
+        );
+    }
+
+    #[test]
+    fn test_sibling_scopes_do_not_shadow() {
+        let source = "match 1 with\n\
+                      | 0 -> (decl x <- 1 in x)\n\
+                      | _ -> (decl x <- 2 in x)";
+        assert_eq!(lint(&parse_program(source.to_string()).unwrap()), vec![]);
+        assert_eq!(
+            lint(&parse_program("decl _x <- 1 in decl _x <- 2 in _x".to_string()).unwrap()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_declaring_main_warns() {
+        let warnings =
+            lint(&parse_program("decl main x <- + x 1 in (main 2)".to_string()).unwrap());
+        assert_eq!(
+            warnings,
+            vec![SemanticWarning::ReservedName {
+                name: "main".to_string(),
+                span: Span {
+                    line: 1,
+                    column: 6,
+                    len: 4
+                },
+            }]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "'main' at line 1, column 6 is the name of the program's entry point; \
+             try another name, like 'my_main'"
+        );
+
+        // As a variable too, but a name that only contains a builtin's is fine
+        let warnings = lint(&parse_program("decl main <- 1 in main".to_string()).unwrap());
+        assert!(
+            matches!(warnings.as_slice(), [SemanticWarning::ReservedName { .. }]),
+            "Got {:?}",
+            warnings
+        );
+        assert_eq!(
+            lint(
+                &parse_program("decl printer <- 1 in decl mainly <- printer in mainly".to_string())
+                    .unwrap()
+            ),
+            vec![]
+        );
+    }
+
+    fn assign_hint(source: &str) -> AssignHint {
+        match resolve_source(source).as_slice() {
+            [SemanticError::AssignToUndeclared { hint, .. }] => hint.clone(),
+            errors => panic!("Expected one assignment error, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn test_assignment_to_similar_name() {
+        assert_eq!(
+            assign_hint("decl total <- 0 in totl <- 5; total"),
+            AssignHint::SimilarName("total".to_string())
+        );
+    }
+
+    #[test]
+    fn test_assignment_before_declaration() {
+        let source = "x <- 5;\ndecl x <- 0 in x";
+        assert_eq!(
+            assign_hint(source),
+            AssignHint::DeclaredLater(Span {
+                line: 2,
+                column: 6,
+                len: 1
+            })
+        );
+        assert_eq!(
+            resolve_source(source)[0].to_string(),
+            "cannot assign to undeclared variable 'x' at line 1, column 1; \
+             'x' is only declared later, at line 2, column 6: declare it before assigning to it"
+        );
+    }
+
+    #[test]
+    fn test_assignment_without_declaration() {
+        let errors = resolve_source("x <- 5");
+        assert_eq!(
+            errors[0].to_string(),
+            "cannot assign to undeclared variable 'x' at line 1, column 1; \
+             did you mean `decl x <- 5 in ...`?"
+        );
+        assert_eq!(assign_hint("x <- + 1 2"), AssignHint::Declare(None));
+    }
+
+    #[test]
+    fn test_break_outside_loop() {
+        assert_eq!(
+            resolve_source("loop match 1 with | 1 -> break 2 | _ -> 0 end"),
+            vec![]
+        );
+        assert_eq!(
+            resolve_source("decl x <- 1 in\nwhile x do break done"),
+            vec![SemanticError::BreakOutsideLoop {
+                span: Span {
+                    line: 2,
+                    column: 12,
+                    len: 5
+                }
+            }]
+        );
+        // A function body can't leave a loop around its declaration
+        let errors = resolve_source("loop decl f n <- break n in (f 1) end");
+        assert_eq!(
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            vec!["'break' outside of a loop at line 1, column 18"]
+        );
+    }
+
+    #[test]
+    fn test_globals_are_visible_in_functions() {
+        let source = "global count <- 0\ndecl inc n <- count <- + count n in (inc 1)";
+        assert_eq!(resolve_source(source), vec![]);
+        assert_eq!(lint(&parse_program(source.to_string()).unwrap()), vec![]);
+    }
+
+    #[test]
+    fn test_wrong_argument_count() {
+        let source = "decl add x y <- + x y in\n(add 1); (add 1 2 3); (add 1 2)";
+        assert_eq!(
+            resolve_source(source)
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "function 'add' expects 2 argument(s) but was called with 1 at line 2, column 2",
+                "function 'add' expects 2 argument(s) but was called with 3 at line 2, column 11",
+            ]
+        );
+        // Recursive calls, and a declaration shadowing another one
+        assert_eq!(
+            resolve_source("decl f n <- (f n) in decl f a b <- a in (f 1 2)"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_builtin_argument_count() {
+        let call = |name: &str, args: usize| {
+            Expr::Call(name.into(), vec![Expr::Number(1); args], Span::default())
+        };
+        assert_eq!(resolve(&call("+", 2)), vec![]);
+        assert_eq!(
+            resolve(&call("+", 3)),
+            vec![SemanticError::WrongArgumentCount {
+                name: "+".to_string(),
+                expected: 2,
+                found: 3,
+                span: Span::default(),
+            }]
+        );
+        assert_eq!(resolve(&call("print", 0)).len(), 1);
+        assert_eq!(resolve(&call("!", 2)).len(), 1);
+    }
+
+    #[test]
+    fn test_argument_count_with_default_values() {
+        let source = "decl f x (y <- 10) <- + x y in\n(f 1); (f 1 2); (f 1 2 3)";
+        assert_eq!(
+            resolve_source(source)
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>(),
+            vec!["function 'f' expects 2 argument(s) but was called with 3 at line 2, column 18"]
+        );
+        assert_eq!(
+            resolve_source("decl f x y (z <- 1) <- x in (f 1)")[0].to_string(),
+            "function 'f' expects 2 argument(s) but was called with 1 at line 1, column 30"
+        );
+    }
+
+    /// `source` with its named arguments lowered, and the lowering errors.
+    fn lower_source(source: &str) -> (Expr, Vec<String>) {
+        let mut ast = parse_program(source.to_string()).unwrap();
+        let errors = lower_named_arguments(&mut ast);
+        (ast, errors.iter().map(|e| e.to_string()).collect())
+    }
+
+    /// The arguments of the call that is the body of `ast`, a function declaration.
+    fn call_args(ast: Expr) -> Vec<Expr> {
+        match &ast.without_spans() {
+            Expr::Decl(_, _, _, body, _) => match &**body {
+                Expr::Call(_, args, _) => args.clone(),
+                body => panic!("Expected a call, got {:?}", body),
+            },
+            ast => panic!("Expected a declaration, got {:?}", ast),
+        }
+    }
+
+    #[test]
+    fn test_named_arguments_are_reordered() {
+        let (ast, errors) = lower_source("decl f a b c <- a in (f c: 3 a: 1 b: 2)");
+        assert_eq!(errors, Vec::<String>::new());
+        assert_eq!(
+            call_args(ast),
+            vec![Expr::Number(1), Expr::Number(2), Expr::Number(3)]
+        );
+    }
+
+    #[test]
+    fn test_positional_and_named_arguments() {
+        let (ast, errors) = lower_source("decl f a b (c <- 9) (d <- 7) <- a in (f 1 d: 4 b: 2)");
+        assert_eq!(errors, Vec::<String>::new());
+        // The default of c fills the gap before d
+        assert_eq!(
+            call_args(ast),
+            vec![
+                Expr::Number(1),
+                Expr::Number(2),
+                Expr::Number(9),
+                Expr::Number(4)
+            ]
+        );
+        // Trailing defaults are left to the call
+        let (ast, _) = lower_source("decl f a (b <- 9) (c <- 7) <- a in (f a: 1)");
+        assert_eq!(call_args(ast), vec![Expr::Number(1)]);
+    }
+
+    #[test]
+    fn test_duplicate_named_argument() {
+        let (_, errors) = lower_source(
+            "decl f a b <- a in
+(f 1 a: 2 b: 3)",
+        );
+        assert_eq!(
+            errors,
+            vec!["parameter 'a' of function 'f' is given more than once at line 2, column 6"]
+        );
+        let (_, errors) = lower_source("decl f a b <- a in (f b: 1 b: 2 a: 3)");
+        assert_eq!(
+            errors,
+            vec!["parameter 'b' of function 'f' is given more than once at line 1, column 28"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_named_argument() {
+        let (_, errors) = lower_source(
+            "decl f a b <- a in
+(f a: 1 c: 2 b: 3)",
+        );
+        assert_eq!(
+            errors,
+            vec!["function 'f' has no parameter named 'c' at line 2, column 9"]
+        );
+        // Builtins and undeclared functions have no parameter names
+        let (_, errors) = lower_source("(g x: 1)");
+        assert_eq!(
+            errors,
+            vec!["function 'g' has no parameter named 'x' at line 1, column 4"]
+        );
+    }
+
+    #[test]
+    fn test_missing_argument_before_a_named_one() {
+        let (_, errors) = lower_source(
+            "decl f a b <- a in
+(f b: 2)",
+        );
+        assert_eq!(
+            errors,
+            vec!["call to function 'f' has no argument for parameter 'a' at line 2, column 2"]
+        );
+    }
+}
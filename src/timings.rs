@@ -0,0 +1,126 @@
+//! How long each phase of the compiler takes, for `--timings`.
+//!
+//! Timings are only recorded when asked for (`Options::timings`): every phase
+//! checks for an `Option<Timings>` and runs as usual when it is `None`.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub const LEXING: &str = "lexing";
+pub const PARSING: &str = "parsing";
+pub const SEMANTIC: &str = "semantic analysis";
+pub const AST_OPTIMIZATION: &str = "AST optimization";
+pub const CODEGEN: &str = "codegen";
+pub const LLVM_OPTIMIZATION: &str = "LLVM optimization";
+pub const EMISSION: &str = "emission";
+pub const LINKING: &str = "linking";
+
+/// The time spent in each phase, in the order the phases first ran.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    /// Adds `duration` to `phase` (a phase runs once per file for programs
+    /// made of several files).
+    pub fn record(&mut self, phase: &'static str, duration: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += duration,
+            None => self.phases.push((phase, duration)),
+        }
+    }
+
+    /// Adds the phases of `other`.
+    pub fn extend(&mut self, other: &Timings) {
+        for &(phase, duration) in &other.phases {
+            self.record(phase, duration);
+        }
+    }
+
+    pub fn get(&self, phase: &str) -> Option<Duration> {
+        self.phases
+            .iter()
+            .find(|(name, _)| *name == phase)
+            .map(|(_, duration)| *duration)
+    }
+
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, duration)| *duration).sum()
+    }
+}
+
+/// Runs `f`, and adds the time it took to `phase` in `timings` if there are.
+pub fn time<T>(timings: Option<&mut Timings>, phase: &'static str, f: impl FnOnce() -> T) -> T {
+    let Some(timings) = timings else {
+        return f();
+    };
+    let start = Instant::now();
+    let value = f();
+    timings.record(phase, start.elapsed());
+    value
+}
+
+/// A table with the time of each phase and its share of the total.
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total();
+        let row = |f: &mut fmt::Formatter<'_>, phase: &str, duration: Duration| {
+            let share = if total.is_zero() {
+                0.0
+            } else {
+                100.0 * duration.as_secs_f64() / total.as_secs_f64()
+            };
+            writeln!(
+                f,
+                "{:<20} {:>10.3} ms {:>6.1}%",
+                phase,
+                duration.as_secs_f64() * 1000.0,
+                share
+            )
+        };
+        writeln!(f, "{:<20} {:>13} {:>7}", "phase", "time", "share")?;
+        for &(phase, duration) in &self.phases {
+            row(f, phase, duration)?;
+        }
+        row(f, "total", total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_adds_up_phases() {
+        let mut timings = Timings::default();
+        timings.record(LEXING, Duration::from_millis(2));
+        timings.record(PARSING, Duration::from_millis(1));
+        timings.record(LEXING, Duration::from_millis(3));
+        assert_eq!(timings.get(LEXING), Some(Duration::from_millis(5)));
+        assert_eq!(timings.get(LINKING), None);
+        assert_eq!(timings.total(), Duration::from_millis(6));
+
+        let names: Vec<&str> = timings.phases().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, [LEXING, PARSING]);
+        assert_eq!(
+            timings.to_string(),
+            "phase                         time   share\n\
+             lexing                    5.000 ms   83.3%\n\
+             parsing                   1.000 ms   16.7%\n\
+             total                     6.000 ms  100.0%\n"
+        );
+    }
+
+    #[test]
+    fn test_time_only_records_when_asked() {
+        assert_eq!(time(None, CODEGEN, || 7), 7);
+        let mut timings = Timings::default();
+        assert_eq!(time(Some(&mut timings), CODEGEN, || 7), 7);
+        assert!(timings.get(CODEGEN).is_some());
+    }
+}
@@ -1,5 +1,56 @@
-use crate::parser::Token;
-use std::collections::HashMap;
+use crate::parser::{Name, Token};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// Posición de un token en el código fuente (línea y columna empiezan en 1).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+/// Un error léxico: el mensaje (que ya incluye la posición) y dónde está el
+/// caracter o lexema que lo causó.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LexError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LexError {}
+
+/// Un literal de cadena: su texto, con los escapes ya reemplazados, y el texto
+/// tal como está escrito entre las comillas, que es con el que se cuentan las
+/// columnas dentro del literal. En JSON es solo el texto.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "String", into = "String"))]
+pub struct Literal {
+    pub text: String,
+    pub raw: String,
+}
+
+/// El literal que se escribe con `text`, escapando lo que hace falta.
+impl From<String> for Literal {
+    fn from(text: String) -> Self {
+        let raw = crate::printer::escape(&text);
+        Literal { text, raw }
+    }
+}
+
+impl From<Literal> for String {
+    fn from(literal: Literal) -> Self {
+        literal.text
+    }
+}
 
 // Este es el lexer.
 // input es el valor que entra y que va a ser convertido en tokens.
@@ -22,10 +73,13 @@ pub enum State {
     Comment = 9,                           // q9
     MayFinishComment = 10,                 // q10
     ParenR = 11,                           // q11
+    StringLiteral = 12,                    // q12
+    FinishString = 13,                     // q13
+    StringEscape = 14,                     // q14
 }
 
 impl State {
-    pub const COUNT: usize = 12;
+    pub const COUNT: usize = 15;
     pub const fn from_index(index: usize) -> Option<Self> {
         match index {
             0 => Some(Self::Start),
@@ -40,6 +94,9 @@ impl State {
             9 => Some(Self::Comment),
             10 => Some(Self::MayFinishComment),
             11 => Some(Self::ParenR),
+            12 => Some(Self::StringLiteral),
+            13 => Some(Self::FinishString),
+            14 => Some(Self::StringEscape),
             _ => None,
         }
     }
@@ -67,18 +124,25 @@ pub enum CharClass {
     RParen = 16,     // )
     Semicolon = 17,  // ;
     Whitespace = 18, // whitespace (including CR, LF, TAB)
-    PunctGroup = 19, // {, }, [, ], ., :
+    PunctGroup = 19, // {, }, .
     Ampersand = 20,  // &
+    Quote = 21,      // "
+    Colon = 22,      // :
+    Backslash = 23,  // \ (only in strings)
+    BracketL = 24,   // [
+    BracketR = 25,   // ]
+    At = 26,         // @
 }
 
 impl CharClass {
-    pub const COUNT: usize = 21;
+    pub const COUNT: usize = 27;
 }
 
 pub const fn classify_char(c: char) -> Option<CharClass> {
     use CharClass::{
-        Caret, Digit, Equals, Exclam, Greater, LParen, Less, LowerAlpha, Minus, Percent, Pipe,
-        Plus, PunctGroup, RParen, Semicolon, Slash, Star, Underscore, UpperAlpha, Whitespace, Ampersand
+        Ampersand, At, Backslash, BracketL, BracketR, Caret, Colon, Digit, Equals, Exclam, Greater,
+        LParen, Less, LowerAlpha, Minus, Percent, Pipe, Plus, PunctGroup, Quote, RParen, Semicolon,
+        Slash, Star, Underscore, UpperAlpha, Whitespace,
     };
     match c {
         '0'..='9' => Some(Digit),
@@ -100,7 +164,13 @@ pub const fn classify_char(c: char) -> Option<CharClass> {
         '(' => Some(LParen),
         ')' => Some(RParen),
         ';' => Some(Semicolon),
-        '{' | '}' | '[' | ']' | '.' | ':' => Some(PunctGroup),
+        '{' | '}' | '.' => Some(PunctGroup),
+        '[' => Some(BracketL),
+        ']' => Some(BracketR),
+        '@' => Some(At),
+        ':' => Some(Colon),
+        '\\' => Some(Backslash),
+        '"' => Some(Quote),
         _ if c.is_whitespace() => Some(Whitespace),
         _ => None,
     }
@@ -140,29 +210,71 @@ pub const NUM_CLASSES: usize = CharClass::COUNT;
 // -1 means no valid transition from that state with that char class
 pub const STATE_TRANSITIONS: [[i8; NUM_CLASSES]; NUM_STATES] = [
     // q0 (Start)
-    [1, 5, 5, 3, 5, 6, 5, 5, 5, 5, 5, 5, 5, 5, 2, 8, 11, 0, 0, -1, 5],
+    [
+        1, 5, 5, 3, 5, 6, 5, 5, 5, 5, 5, 5, 5, 5, 2, 8, 11, 0, 0, -1, 5, 12, 0, -1, 0, 0, 0,
+    ],
     // q1 (Digit)
-    [1, -2, -2, -2, -2, -2, -2, -2, -2, -2, -2, -2, -2, -2, -1, -1, -1, -1, -1, -1, -2],
+    [
+        1, -2, -2, -2, -2, -2, -2, -2, -2, -2, -2, -2, -2, -2, -1, -1, -1, -1, -1, -1, -2, -1, -1,
+        -1, -1, -1, -1,
+    ],
     // q2 (PipeOrIdentifier)
-    [5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5],
+    [
+        5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5, -1, -1, -1, -1, -1, -1,
+    ],
     // q3 (AssignOrIdentifier)
-    [5, 5, 5, 5, 5, 4, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5],
+    [
+        5, 5, 5, 5, 5, 4, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5, -1, -1, -1, -1, -1, -1,
+    ],
     // q4 (FinishAssignOrIdentifier)
-    [5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5],
+    [
+        5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5, -1, -1, -1, -1, -1, -1,
+    ],
     // q5 (Identifier)
-    [5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5],
+    [
+        5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5, -1, -1, -1, -1, -1, -1,
+    ],
     // q6 (ArrowIdentifierOrNegativeNumber)
-    [1, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5],
+    [
+        1, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5, -1, -1, -1, -1, -1, -1,
+    ],
     // q7 (FinishArrowOrIdentifier)
-    [5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5],
+    [
+        5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, -1, -1, -1, -1, -1, -1, 5, -1, -1, -1, -1, -1, -1,
+    ],
     // q8 (ParenLOrComment)
-    [-1, -1, -1, -1, -1, -1, -1, 9, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [
+        -1, -1, -1, -1, -1, -1, -1, 9, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+        -1, -1, -1, -1,
+    ],
     // q9 (Comment)
-    [9, 9, 9, 9, 9, 9, 9, 10, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9],
+    [
+        9, 9, 9, 9, 9, 9, 9, 10, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9,
+    ],
     // q10 (MayFinishComment)
-    [9, 9, 9, 9, 9, 9, 9, 10, 9, 9, 9, 9, 9, 9, 9, 9, 0, 9, 9, 9, 9],
+    [
+        9, 9, 9, 9, 9, 9, 9, 10, 9, 9, 9, 9, 9, 9, 9, 9, 0, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9,
+    ],
     // q11 (ParenR)
-    [-1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [
+        -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+        -1, -1, -1, -1,
+    ],
+    // q12 (StringLiteral)
+    [
+        12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 13, 12,
+        14, 12, 12, 12,
+    ],
+    // q13 (FinishString)
+    [
+        -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+        -1, -1, -1, -1,
+    ],
+    // q14 (StringEscape)
+    [
+        12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12, 12,
+        12, 12, 12, 12,
+    ],
 ];
 
 pub fn next_state(current: State, class: CharClass) -> Result<Option<State>, String> {
@@ -176,30 +288,78 @@ pub fn next_state(current: State, class: CharClass) -> Result<Option<State>, Str
     }
 }
 
-pub static KEYWORDS: std::sync::LazyLock<HashMap<&'static str, Token>> =
+/// Construye el token de una palabra clave o símbolo a partir de su posición.
+pub type TokenConstructor = fn(Span) -> Token;
+
+pub static KEYWORDS: std::sync::LazyLock<HashMap<&'static str, TokenConstructor>> =
     std::sync::LazyLock::new(|| {
-        const KEYWORDS: &[(&str, Token)] = &[
+        const KEYWORDS: &[(&str, TokenConstructor)] = &[
             ("decl", Token::Decl),
             ("while", Token::While),
             ("do", Token::Do),
             ("done", Token::Done),
+            ("yield", Token::Yield),
+            ("loop", Token::Loop),
+            ("end", Token::End),
+            ("break", Token::Break),
+            ("global", Token::Global),
             ("match", Token::Match),
             ("with", Token::With),
             ("in", Token::In),
+            ("import", Token::Import),
             // funciones built-in
             ("print", Token::Print),
+            ("env_int", Token::EnvInt),
+            ("ord", Token::Ord),
+            ("chr", Token::Chr),
+            ("print_char", Token::PrintChar),
+            ("read_line", Token::ReadLine),
+            ("strlen", Token::Strlen),
+            ("print_str", Token::PrintStr),
+            ("fopen", Token::Fopen),
+            ("write_line", Token::WriteLine),
+            ("fread_int", Token::FreadInt),
+            ("fclose", Token::Fclose),
+            ("get", Token::Get),
+            ("set", Token::Set),
+            ("len", Token::Len),
+            ("slice", Token::Slice),
+            ("ref", Token::Ref),
+            ("deref", Token::Deref),
+            ("store", Token::Store),
+            ("alloc", Token::Alloc),
+            ("load8", Token::Load8),
+            ("store8", Token::Store8),
+            ("free", Token::Free),
+            ("some", Token::Some),
+            ("none", Token::None),
+            ("ok", Token::Ok),
+            ("err", Token::Err),
+            ("try", Token::Try),
+            ("vec_new", Token::VecNew),
+            ("push", Token::Push),
+            ("pop", Token::Pop),
+            ("vlen", Token::Vlen),
+            ("vget", Token::Vget),
+            ("vset", Token::Vset),
+            ("sum", Token::Sum),
+            ("product", Token::Product),
             // Comparison operators (US2)
             ("<", Token::Less),
             (">", Token::Greater),
             ("=", Token::Equals),
             ("!=", Token::NotEquals),
+            ("<=", Token::LessEq),
+            (">=", Token::GreaterEq),
             // Arithmetic operators (US1)
             ("+", Token::Plus),
             ("-", Token::Minus),
             ("*", Token::Star),
             ("/", Token::Slash),
             ("%", Token::Percent),
-            // Logical operators 
+            ("emod", Token::Emod),
+            ("fdiv", Token::Fdiv),
+            // Logical operators
             ("|", Token::Pipe),
             ("&", Token::Ampersand),
             ("!", Token::Exclam),
@@ -211,13 +371,44 @@ pub static KEYWORDS: std::sync::LazyLock<HashMap<&'static str, Token>> =
             ("(", Token::ParenL),
             (")", Token::ParenR),
         ];
-        let mut m: HashMap<&'static str, Token> = HashMap::new();
+        let mut m: HashMap<&'static str, TokenConstructor> = HashMap::new();
         for (k, v) in KEYWORDS {
-            m.insert(k, v.clone());
+            m.insert(k, *v);
         }
         m
     });
 
+/// La versión del lenguaje que se usa si no se elige otra (`--lang-version`
+/// o la pragma de `pragma_version`).
+pub const LANG_VERSION: u32 = 1;
+
+/// La versión más nueva del lenguaje: la primera con todas las palabras
+/// clave de `STAGED_KEYWORDS`.
+pub const LATEST_LANG_VERSION: u32 = 2;
+
+/// Palabras clave que no lo son en todas las versiones del lenguaje, con la
+/// primera versión en que lo son. En las anteriores siguen siendo
+/// identificadores, con un aviso (`FutureKeyword`), para que los programas
+/// que las usan como nombres no dejen de compilar de un día para otro.
+pub static STAGED_KEYWORDS: [(&str, u32, TokenConstructor); 1] = [("until", 2, Token::Until)];
+
+/// Un identificador que es palabra clave desde la versión `version` del
+/// lenguaje, posterior a la que se está usando.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FutureKeyword {
+    pub word: &'static str,
+    pub version: u32,
+    pub span: Span,
+}
+
+/// La versión que pide un programa con la pragma `(* lang-version N *)` en su
+/// primera línea, si la tiene. Gana sobre la versión que se le da al lexer.
+pub fn pragma_version(input: &str) -> Option<&str> {
+    let first = input.lines().next()?.trim();
+    let pragma = first.strip_prefix("(*")?.strip_suffix("*)")?.trim();
+    pragma.strip_prefix("lang-version").map(str::trim)
+}
+
 #[derive(Debug)]
 pub struct Lexer {
     input: String,
@@ -225,7 +416,15 @@ pub struct Lexer {
     line: usize,
     column: usize,
     current_lexeme: String,
+    lexeme_start: Span,
     tokens: Vec<Token>,
+    /// Every identifier seen, so that each use of a name shares its text
+    names: BTreeSet<Name>,
+    /// Versión del lenguaje, que decide qué palabras de `STAGED_KEYWORDS` son
+    /// palabras clave
+    version: u32,
+    /// Las palabras de `STAGED_KEYWORDS` que se leyeron como identificadores
+    future_keywords: Vec<FutureKeyword>,
 }
 
 impl Lexer {
@@ -236,13 +435,54 @@ impl Lexer {
             line: 1,
             column: 1,
             current_lexeme: String::new(),
+            lexeme_start: Span {
+                line: 1,
+                column: 1,
+                len: 0,
+            },
             tokens: Vec::new(),
+            names: BTreeSet::new(),
+            version: LANG_VERSION,
+            future_keywords: Vec::new(),
         }
     }
 
-    pub fn tokenize(&mut self) -> Result<Vec<Token>, String> {
+    /// El mismo lexer, para la versión `version` del lenguaje.
+    pub const fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Los identificadores del último `tokenize` que son palabras clave en
+    /// una versión posterior del lenguaje.
+    pub fn future_keywords(&self) -> &[FutureKeyword] {
+        &self.future_keywords
+    }
+
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, LexError> {
         self.tokens.clear();
         self.current_lexeme.clear();
+        self.future_keywords.clear();
+        if let Some(version) = pragma_version(&self.input) {
+            self.version = match version.parse::<u32>() {
+                Ok(version @ 1..=LATEST_LANG_VERSION) => version,
+                _ => {
+                    let first_line = self.input.lines().next().unwrap_or("");
+                    return Err(LexError {
+                        message: format!(
+                            "unknown language version '{}' in the pragma at line 1; \
+                             the versions are 1 to {}",
+                            version, LATEST_LANG_VERSION
+                        ),
+                        span: Span {
+                            line: 1,
+                            column: 1,
+                            len: first_line.trim_end().chars().count(),
+                        },
+                    });
+                }
+            };
+        }
 
         let chars: Vec<char> = self.input.chars().collect();
         let mut index: usize = 0;
@@ -255,21 +495,31 @@ impl Lexer {
             } else {
                 None
             };
-            let Some(class) = classify_char(c) else {
-                return Err(format!(
-                    "Caracter inesperado '{}' en la línea {}, columna {}",
-                    c, self.line, self.column
-                ));
+            let class = match classify_char(c) {
+                Some(class) => class,
+                // Dentro de una cadena vale cualquier caracter
+                None if state == State::StringLiteral => CharClass::PunctGroup,
+                None => {
+                    return Err(LexError {
+                        message: format!(
+                            "Caracter inesperado '{}' en la línea {}, columna {}",
+                            c, self.line, self.column
+                        ),
+                        span: self.span_here(1),
+                    });
+                }
             };
-            //println!("Estado: {state:?}, Char: '{c}', Clase: {class:?} -> ");
 
             let next = next_state(state, class);
 
             if let Err(e) = next {
-                return Err(format!(
-                    "{} '{}' en la línea {}, columna {}",
-                    e, c, self.line, self.column
-                ));
+                return Err(LexError {
+                    message: format!(
+                        "{} '{}' en la línea {}, columna {}",
+                        e, c, self.line, self.column
+                    ),
+                    span: self.span_here(1),
+                });
             } else if let Ok(Some(next_state_value)) = next {
                 // Execute transition action
                 let action = TRANSITION_ACTIONS[state as usize][class as usize];
@@ -286,6 +536,16 @@ impl Lexer {
 
                 // Move to next state
                 state = next_state_value;
+            } else if state == State::Start {
+                // Ningún token empieza con este caracter: volver a procesarlo
+                // desde Start no avanzaría nunca
+                return Err(LexError {
+                    message: format!(
+                        "Caracter inesperado '{}' en la línea {}, columna {}",
+                        c, self.line, self.column
+                    ),
+                    span: self.span_here(1),
+                });
             } else {
                 // No transition: finalize current lexeme if any (do not consume current char)
                 self.finalize_lexeme(state)?;
@@ -300,6 +560,27 @@ impl Lexer {
         Ok(std::mem::take(&mut self.tokens))
     }
 
+    /// Posición del caracter actual, con el largo dado.
+    const fn span_here(&self, len: usize) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            len,
+        }
+    }
+
+    /// Posición del lexema actual, desde su primer caracter.
+    fn lexeme_span(&self) -> Span {
+        Span {
+            len: self.current_lexeme.chars().count(),
+            ..self.lexeme_start
+        }
+    }
+
+    fn emit(&mut self, token: Token) {
+        self.tokens.push(token);
+    }
+
     fn append_char(&mut self, c: char) {
         self.current_lexeme.push(c);
     }
@@ -308,7 +589,17 @@ impl Lexer {
         self.current_lexeme.clear();
     }
 
-    fn finalize_lexeme(&mut self, state: State) -> Result<(), String> {
+    /// The current lexeme as a name, shared with every earlier use of it.
+    fn intern(&mut self) -> Name {
+        if let Some(name) = self.names.get(self.current_lexeme.as_str()) {
+            return name.clone();
+        }
+        let name = Name::from(self.current_lexeme.as_str());
+        self.names.insert(name.clone());
+        name
+    }
+
+    fn finalize_lexeme(&mut self, state: State) -> Result<(), LexError> {
         if self.current_lexeme.is_empty() {
             return Ok(());
         }
@@ -316,16 +607,38 @@ impl Lexer {
         match state {
             State::Digit => {
                 // Integer literal
-                let parsed = self.current_lexeme.parse::<i64>().map_err(|_| {
-                    format!(
+                let parsed = self.current_lexeme.parse::<i64>().map_err(|_| LexError {
+                    message: format!(
                         "Error al parsear el entero '{}' en la línea {}, columna {}",
                         self.current_lexeme, self.line, self.column
-                    )
+                    ),
+                    span: self.lexeme_span(),
                 })?;
-                self.tokens.push(Token::IntegerLiteral(parsed));
+                self.emit(Token::IntegerLiteral((self.lexeme_span(), parsed)));
                 self.clear_lexeme();
                 Ok(())
             }
+            State::FinishString => {
+                // Cadena: el lexema incluye las comillas
+                let span = self.lexeme_span();
+                let quoted = std::mem::take(&mut self.current_lexeme);
+                let start = Span {
+                    column: span.column + 1,
+                    len: 0,
+                    ..span
+                };
+                let raw = quoted[1..quoted.len() - 1].to_string();
+                let text = decode_escapes(&raw, start)?;
+                self.emit(Token::StringLiteral((span, Literal { text, raw })));
+                Ok(())
+            }
+            State::StringLiteral | State::StringEscape => Err(LexError {
+                message: format!(
+                    "Cadena sin terminar que empieza en la línea {}, columna {}",
+                    self.lexeme_start.line, self.lexeme_start.column
+                ),
+                span: self.lexeme_span(),
+            }),
             State::PipeOrIdentifier
             | State::AssignOrIdentifier
             | State::FinishAssignOrIdentifier
@@ -333,11 +646,27 @@ impl Lexer {
             | State::FinishArrowOrIdentifier
             | State::ArrowOrIdentifierOrNegativeNumber => {
                 // Identifier or keyword
+                let span = self.lexeme_span();
+                let staged = STAGED_KEYWORDS
+                    .iter()
+                    .find(|(word, _, _)| *word == self.current_lexeme);
                 if let Some(keyword_token) = KEYWORDS.get(self.current_lexeme.as_str()) {
-                    self.tokens.push(keyword_token.clone());
+                    self.emit(keyword_token(span));
+                } else if let Some(&(word, version, keyword_token)) = staged {
+                    if self.version >= version {
+                        self.emit(keyword_token(span));
+                    } else {
+                        self.future_keywords.push(FutureKeyword {
+                            word,
+                            version,
+                            span,
+                        });
+                        let name = self.intern();
+                        self.emit(Token::Identifier((span, name)));
+                    }
                 } else {
-                    self.tokens
-                        .push(Token::Identifier(std::mem::take(&mut self.current_lexeme)));
+                    let name = self.intern();
+                    self.emit(Token::Identifier((span, name)));
                 }
                 self.clear_lexeme();
                 Ok(())
@@ -351,10 +680,76 @@ impl Lexer {
     }
 }
 
+/// Reemplaza las secuencias de escape de un literal (`\n`, `\t`, `\\`, `\"`,
+/// `\'` y `\0`) por los caracteres que representan. `raw` es el texto entre
+/// las comillas y `start_span` la posición de su primer caracter, para ubicar
+/// los errores.
+pub fn decode_escapes(raw: &str, start_span: Span) -> Result<String, LexError> {
+    let mut decoded = String::with_capacity(raw.len());
+    let mut position = start_span;
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        let at = Span { len: 2, ..position };
+        if c == '\n' {
+            position.line += 1;
+            position.column = 1;
+        } else {
+            position.column += 1;
+        }
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some(other) => unescape(other).ok_or_else(|| LexError {
+                message: format!(
+                    "unknown escape sequence '\\{}' at line {}, column {}",
+                    other, at.line, at.column
+                ),
+                span: at,
+            })?,
+            None => {
+                return Err(LexError {
+                    message: format!(
+                        "unterminated escape sequence at line {}, column {}",
+                        at.line, at.column
+                    ),
+                    span: Span { len: 1, ..at },
+                });
+            }
+        };
+        position.column += 1;
+        decoded.push(escaped);
+    }
+    Ok(decoded)
+}
+
+/// El caracter que representa la secuencia de escape `\c`, si la hay.
+pub fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        '\\' => Some('\\'),
+        '"' => Some('"'),
+        '\'' => Some('\''),
+        '0' => Some('\0'),
+        _ => None,
+    }
+}
+
+/// Los tokens en JSON, para herramientas externas: una lista de objetos como
+/// los nodos de `Expr::to_json`, con la posición como primer campo, p. ej.
+/// `{"type": "IntegerLiteral", "fields": [{"line": 1, ...}, 42]}`.
+#[cfg(feature = "serde")]
+pub fn tokens_to_json(tokens: &[Token]) -> String {
+    serde_json::to_string_pretty(tokens).expect("los tokens siempre se pueden escribir en JSON")
+}
+
 pub type TransitionAction = fn(&mut Lexer, Option<char>, Option<char>);
 
 const fn action_noop(_: &mut Lexer, _: Option<char>, _: Option<char>) {}
 fn action_start_lexeme(lexer: &mut Lexer, ch: Option<char>, _next_ch: Option<char>) {
+    lexer.lexeme_start = lexer.span_here(0);
     if let Some(c) = ch {
         lexer.append_char(c);
     }
@@ -365,25 +760,45 @@ fn action_append_lexeme(lexer: &mut Lexer, ch: Option<char>, _next_ch: Option<ch
     }
 }
 fn action_emit_semicolon(lexer: &mut Lexer, _: Option<char>, _next_ch: Option<char>) {
-    lexer.tokens.push(Token::Semicolon);
+    lexer.emit(Token::Semicolon(lexer.span_here(1)));
+    lexer.clear_lexeme();
+}
+
+fn action_emit_colon(lexer: &mut Lexer, _: Option<char>, _next_ch: Option<char>) {
+    lexer.emit(Token::Colon(lexer.span_here(1)));
+    lexer.clear_lexeme();
+}
+
+fn action_emit_bracket_l(lexer: &mut Lexer, _: Option<char>, _next_ch: Option<char>) {
+    lexer.emit(Token::BracketL(lexer.span_here(1)));
+    lexer.clear_lexeme();
+}
+
+fn action_emit_bracket_r(lexer: &mut Lexer, _: Option<char>, _next_ch: Option<char>) {
+    lexer.emit(Token::BracketR(lexer.span_here(1)));
+    lexer.clear_lexeme();
+}
+
+fn action_emit_at(lexer: &mut Lexer, _: Option<char>, _next_ch: Option<char>) {
+    lexer.emit(Token::At(lexer.span_here(1)));
     lexer.clear_lexeme();
 }
 
 fn action_emit_pipe(lexer: &mut Lexer, _: Option<char>, _next_ch: Option<char>) {
-    lexer.tokens.push(Token::Pipe);
+    lexer.emit(Token::Pipe(lexer.lexeme_span()));
     lexer.clear_lexeme();
 }
 
 fn action_maybe_emit_assign(lexer: &mut Lexer, _: Option<char>, next_ch: Option<char>) {
     if lexer.current_lexeme.as_str() == "<-" && !is_identifier_char(next_ch.unwrap_or(' ')) {
-        lexer.tokens.push(Token::Assign);
+        lexer.emit(Token::Assign(lexer.lexeme_span()));
         lexer.clear_lexeme();
     }
 }
 
 fn action_maybe_emit_arrow(lexer: &mut Lexer, _: Option<char>, next_ch: Option<char>) {
     if lexer.current_lexeme.as_str() == "->" && !is_identifier_char(next_ch.unwrap_or(' ')) {
-        lexer.tokens.push(Token::Arrow);
+        lexer.emit(Token::Arrow(lexer.lexeme_span()));
         lexer.clear_lexeme();
     }
 }
@@ -405,13 +820,13 @@ fn action_append_and_maybe_emit_arrow(lexer: &mut Lexer, ch: Option<char>, next_
 fn action_maybe_emit_paren_l(lexer: &mut Lexer, _: Option<char>, next_ch: Option<char>) {
     // Check if the next character is '*' to start a comment, otherwise emit ParenL
     if next_ch != Some('*') {
-        lexer.tokens.push(Token::ParenL);
+        lexer.emit(Token::ParenL(lexer.span_here(1)));
     }
     lexer.clear_lexeme();
 }
 
 fn action_maybe_emit_paren_r(lexer: &mut Lexer, _: Option<char>, _: Option<char>) {
-    lexer.tokens.push(Token::ParenR);
+    lexer.emit(Token::ParenR(lexer.span_here(1)));
     lexer.clear_lexeme();
 }
 
@@ -448,8 +863,14 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_maybe_emit_paren_r, // )
         action_emit_semicolon,     // ;
         action_noop,               // whitespace
-        action_noop,               // { } [ ] . :
+        action_noop,               // { } .
         action_start_lexeme,       // &
+        action_start_lexeme,       // "
+        action_emit_colon,         // :
+        action_noop,               // \
+        action_emit_bracket_l,     // [
+        action_emit_bracket_r,     // ]
+        action_emit_at,            // @
     ],
     // q1 (Digit)
     [
@@ -474,6 +895,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,          // whitespace
         action_noop,          // punct group
         action_noop,          // &
+        action_noop,          // "
+        action_noop,          // :
+        action_noop,          // \
+        action_noop,          // [
+        action_noop,          // ]
+        action_noop,          // @
     ],
     // q2 (PipeOrIdentifier)
     [
@@ -498,6 +925,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,          // whitespace
         action_noop,          // punct group
         action_append_lexeme, // &
+        action_noop,          // "
+        action_noop,          // :
+        action_noop,          // \
+        action_noop,          // [
+        action_noop,          // ]
+        action_noop,          // @
     ],
     // q3 (AssignOrIdentifier)
     [
@@ -522,6 +955,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,                         // whitespace
         action_noop,                         // punct group
         action_append_lexeme,                // &
+        action_noop,                         // "
+        action_noop,                         // :
+        action_noop,                         // \
+        action_noop,                         // [
+        action_noop,                         // ]
+        action_noop,                         // @
     ],
     // q4 (FinishAssignOrIdentifier)
     [
@@ -546,6 +985,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,          // whitespace
         action_noop,          // punct group
         action_append_lexeme, // &
+        action_noop,          // "
+        action_noop,          // :
+        action_noop,          // \
+        action_noop,          // [
+        action_noop,          // ]
+        action_noop,          // @
     ],
     // q5 (Identifier)
     [
@@ -570,6 +1015,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,          // whitespace
         action_noop,          // punct group
         action_append_lexeme, // &
+        action_noop,          // "
+        action_noop,          // :
+        action_noop,          // \
+        action_noop,          // [
+        action_noop,          // ]
+        action_noop,          // @
     ],
     // q6 (FinishArrowOrIdentifier)
     [
@@ -594,6 +1045,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,          // whitespace
         action_noop,          // punct group
         action_append_lexeme, // &
+        action_noop,          // "
+        action_noop,          // :
+        action_noop,          // \
+        action_noop,          // [
+        action_noop,          // ]
+        action_noop,          // @
     ],
     // q7 (ArrowOrIdentifier)
     [
@@ -618,6 +1075,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,                        // whitespace
         action_noop,                        // punct group
         action_append_lexeme,               // &
+        action_noop,                        // "
+        action_noop,                        // :
+        action_noop,                        // \
+        action_noop,                        // [
+        action_noop,                        // ]
+        action_noop,                        // @
     ],
     // q8 (ParenLOrComment)
     [
@@ -642,6 +1105,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,          // whitespace
         action_noop,          // punct group
         action_noop,          // &
+        action_noop,          // "
+        action_noop,          // :
+        action_noop,          // \
+        action_noop,          // [
+        action_noop,          // ]
+        action_noop,          // @
     ],
     // q9 (Comment)
     [
@@ -666,6 +1135,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop, // whitespace
         action_noop, // punct group
         action_noop, // &
+        action_noop, // "
+        action_noop, // :
+        action_noop, // \
+        action_noop, // [
+        action_noop, // ]
+        action_noop, // @
     ],
     // q10 (MayFinishComment)
     [
@@ -690,6 +1165,12 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop,        // whitespace
         action_noop,        // punct group
         action_noop,        // &
+        action_noop,        // "
+        action_noop,        // :
+        action_noop,        // \
+        action_noop,        // [
+        action_noop,        // ]
+        action_noop,        // @
     ],
     // q11 (ParenR)
     [
@@ -714,6 +1195,102 @@ pub static TRANSITION_ACTIONS: [[TransitionAction; NUM_CLASSES]; NUM_STATES] = [
         action_noop, // whitespace
         action_noop, // punct group
         action_noop, // &
+        action_noop, // "
+        action_noop, // :
+        action_noop, // \
+        action_noop, // [
+        action_noop, // ]
+        action_noop, // @
+    ],
+    // q12 (StringLiteral)
+    [
+        action_append_lexeme, // Digit
+        action_append_lexeme, // LowerAlpha
+        action_append_lexeme, // UpperAlpha
+        action_append_lexeme, // <
+        action_append_lexeme, // >
+        action_append_lexeme, // -
+        action_append_lexeme, // +
+        action_append_lexeme, // *
+        action_append_lexeme, // /
+        action_append_lexeme, // =
+        action_append_lexeme, // !
+        action_append_lexeme, // %
+        action_append_lexeme, // ^
+        action_append_lexeme, // _
+        action_append_lexeme, // |
+        action_append_lexeme, // (
+        action_append_lexeme, // )
+        action_append_lexeme, // ;
+        action_append_lexeme, // whitespace
+        action_append_lexeme, // punct group
+        action_append_lexeme, // &
+        action_append_lexeme, // "
+        action_append_lexeme, // :
+        action_append_lexeme, // \
+        action_append_lexeme, // [
+        action_append_lexeme, // ]
+        action_append_lexeme, // @
+    ],
+    // q13 (FinishString)
+    [
+        action_noop, // Digit
+        action_noop, // LowerAlpha
+        action_noop, // UpperAlpha
+        action_noop, // <
+        action_noop, // >
+        action_noop, // -
+        action_noop, // +
+        action_noop, // *
+        action_noop, // /
+        action_noop, // =
+        action_noop, // !
+        action_noop, // %
+        action_noop, // ^
+        action_noop, // _
+        action_noop, // |
+        action_noop, // (
+        action_noop, // )
+        action_noop, // ;
+        action_noop, // whitespace
+        action_noop, // punct group
+        action_noop, // &
+        action_noop, // "
+        action_noop, // :
+        action_noop, // \
+        action_noop, // [
+        action_noop, // ]
+        action_noop, // @
+    ],
+    // q14 (StringEscape): the character after a backslash, whatever it is
+    [
+        action_append_lexeme, // Digit
+        action_append_lexeme, // LowerAlpha
+        action_append_lexeme, // UpperAlpha
+        action_append_lexeme, // <
+        action_append_lexeme, // >
+        action_append_lexeme, // -
+        action_append_lexeme, // +
+        action_append_lexeme, // *
+        action_append_lexeme, // /
+        action_append_lexeme, // =
+        action_append_lexeme, // !
+        action_append_lexeme, // %
+        action_append_lexeme, // ^
+        action_append_lexeme, // _
+        action_append_lexeme, // |
+        action_append_lexeme, // (
+        action_append_lexeme, // )
+        action_append_lexeme, // ;
+        action_append_lexeme, // whitespace
+        action_append_lexeme, // punct group
+        action_append_lexeme, // &
+        action_append_lexeme, // "
+        action_append_lexeme, // :
+        action_append_lexeme, // \
+        action_append_lexeme, // [
+        action_append_lexeme, // ]
+        action_append_lexeme, // @
     ],
 ];
 
@@ -731,21 +1308,18 @@ mod tests {
         );
         let tokens = tokens.unwrap();
 
-        assert_eq!(
-            tokens[0],
-            Token::IntegerLiteral(123),
+        assert!(
+            matches!(tokens[0], Token::IntegerLiteral((_, 123))),
             "El token 0 no es un entero: {:?}",
             tokens[0]
         );
-        assert_eq!(
-            tokens[1],
-            Token::IntegerLiteral(456_123),
+        assert!(
+            matches!(tokens[1], Token::IntegerLiteral((_, 456_123))),
             "El token 1 no es un entero: {:?}",
             tokens[1]
         );
-        assert_eq!(
-            tokens[2],
-            Token::IntegerLiteral(0),
+        assert!(
+            matches!(tokens[2], Token::IntegerLiteral((_, 0))),
             "El token 2 no es un entero: {:?}",
             tokens[2]
         );
@@ -762,11 +1336,11 @@ mod tests {
         );
         let tokens = tokens.unwrap();
 
-        assert_eq!(tokens[0], Token::Identifier("hola".to_string()));
-        assert_eq!(tokens[1], Token::Identifier("mundo".to_string()));
-        assert_eq!(tokens[2], Token::Identifier("cómo".to_string()));
-        assert_eq!(tokens[3], Token::Identifier("estas".to_string()));
-        assert_eq!(tokens[4], Token::Identifier("_test".to_string()));
+        assert!(matches!(tokens[0], Token::Identifier((_, ref s)) if s == "hola"));
+        assert!(matches!(tokens[1], Token::Identifier((_, ref s)) if s == "mundo"));
+        assert!(matches!(tokens[2], Token::Identifier((_, ref s)) if s == "cómo"));
+        assert!(matches!(tokens[3], Token::Identifier((_, ref s)) if s == "estas"));
+        assert!(matches!(tokens[4], Token::Identifier((_, ref s)) if s == "_test"));
     }
 
     #[test]
@@ -779,9 +1353,8 @@ mod tests {
         );
         let tokens = tokens.unwrap();
 
-        assert_eq!(
-            tokens[0],
-            Token::Decl,
+        assert!(
+            matches!(tokens[0], Token::Decl(_)),
             "El token 0 no es un identificador: {:?}",
             tokens[0]
         );
@@ -797,15 +1370,13 @@ mod tests {
         );
         let tokens = tokens.unwrap();
 
-        assert_eq!(
-            tokens[0],
-            Token::ParenL,
+        assert!(
+            matches!(tokens[0], Token::ParenL(_)),
             "El token 0 no es un paréntesis izquierdo: {:?}",
             tokens[0]
         );
-        assert_eq!(
-            tokens[1],
-            Token::ParenR,
+        assert!(
+            matches!(tokens[1], Token::ParenR(_)),
             "El token 1 no es un paréntesis derecho: {:?}",
             tokens[1]
         );
@@ -822,11 +1393,11 @@ mod tests {
         );
         let tokens = tokens.unwrap();
 
-        assert_eq!(tokens[0], Token::ParenL);
-        assert_eq!(tokens[1], Token::Plus);
-        assert_eq!(tokens[2], Token::IntegerLiteral(1));
-        assert_eq!(tokens[3], Token::IntegerLiteral(2));
-        assert_eq!(tokens[4], Token::ParenR);
+        assert!(matches!(tokens[0], Token::ParenL(_)));
+        assert!(matches!(tokens[1], Token::Plus(_)));
+        assert!(matches!(tokens[2], Token::IntegerLiteral((_, 1))));
+        assert!(matches!(tokens[3], Token::IntegerLiteral((_, 2))));
+        assert!(matches!(tokens[4], Token::ParenR(_)));
     }
 
     #[test]
@@ -839,12 +1410,12 @@ mod tests {
         );
         let tokens = tokens.unwrap();
 
-        assert_eq!(tokens[0], Token::ParenL);
-        assert_eq!(tokens[1], Token::ParenL);
-        assert_eq!(tokens[2], Token::ParenL);
-        assert_eq!(tokens[3], Token::ParenR);
-        assert_eq!(tokens[4], Token::ParenR);
-        assert_eq!(tokens[5], Token::ParenR);
+        assert!(matches!(tokens[0], Token::ParenL(_)));
+        assert!(matches!(tokens[1], Token::ParenL(_)));
+        assert!(matches!(tokens[2], Token::ParenL(_)));
+        assert!(matches!(tokens[3], Token::ParenR(_)));
+        assert!(matches!(tokens[4], Token::ParenR(_)));
+        assert!(matches!(tokens[5], Token::ParenR(_)));
     }
 
     #[test]
@@ -858,8 +1429,8 @@ mod tests {
         let tokens = tokens.unwrap();
 
         // Should only have the outer parentheses, comment should be ignored
-        assert_eq!(tokens[0], Token::ParenL);
-        assert_eq!(tokens[1], Token::ParenR);
+        assert!(matches!(tokens[0], Token::ParenL(_)));
+        assert!(matches!(tokens[1], Token::ParenR(_)));
         assert_eq!(tokens.len(), 2); // ParenL, ParenR (pomelo handles EOF)
     }
 
@@ -888,11 +1459,11 @@ mod tests {
         );
         let tokens = tokens.unwrap();
         // quick invariants
-        assert!(tokens.iter().any(|t| matches!(t, Token::Decl)));
-        assert!(tokens.iter().any(|t| matches!(t, Token::Assign)));
-        assert!(tokens.iter().any(|t| matches!(t, Token::Arrow)));
-        assert!(tokens.iter().any(|t| matches!(t, Token::While)));
-        assert!(tokens.iter().any(|t| matches!(t, Token::Done)));
+        assert!(tokens.iter().any(|t| matches!(t, Token::Decl(_))));
+        assert!(tokens.iter().any(|t| matches!(t, Token::Assign(_))));
+        assert!(tokens.iter().any(|t| matches!(t, Token::Arrow(_))));
+        assert!(tokens.iter().any(|t| matches!(t, Token::While(_))));
+        assert!(tokens.iter().any(|t| matches!(t, Token::Done(_))));
         // Note: pomelo handles end-of-input automatically
     }
 
@@ -901,21 +1472,30 @@ mod tests {
     fn test_while_keyword() {
         let mut lexer = Lexer::new("while".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(tokens[0], Token::While, "Should recognize 'while' keyword");
+        assert!(
+            matches!(tokens[0], Token::While(_)),
+            "Should recognize 'while' keyword"
+        );
     }
 
     #[test]
     fn test_do_keyword() {
         let mut lexer = Lexer::new("do".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(tokens[0], Token::Do, "Should recognize 'do' keyword");
+        assert!(
+            matches!(tokens[0], Token::Do(_)),
+            "Should recognize 'do' keyword"
+        );
     }
 
     #[test]
     fn test_done_keyword() {
         let mut lexer = Lexer::new("done".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(tokens[0], Token::Done, "Should recognize 'done' keyword");
+        assert!(
+            matches!(tokens[0], Token::Done(_)),
+            "Should recognize 'done' keyword"
+        );
     }
 
     #[test]
@@ -923,9 +1503,9 @@ mod tests {
         let mut lexer = Lexer::new("while do done".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
         assert_eq!(tokens.len(), 3, "Should have 3 tokens");
-        assert_eq!(tokens[0], Token::While);
-        assert_eq!(tokens[1], Token::Do);
-        assert_eq!(tokens[2], Token::Done);
+        assert!(matches!(tokens[0], Token::While(_)));
+        assert!(matches!(tokens[1], Token::Do(_)));
+        assert!(matches!(tokens[2], Token::Done(_)));
     }
 
     // T005: Tokenizer tests for match keywords (Match, With, Pipe, Underscore, Arrow)
@@ -933,30 +1513,38 @@ mod tests {
     fn test_match_keyword() {
         let mut lexer = Lexer::new("match".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(tokens[0], Token::Match, "Should recognize 'match' keyword");
+        assert!(
+            matches!(tokens[0], Token::Match(_)),
+            "Should recognize 'match' keyword"
+        );
     }
 
     #[test]
     fn test_with_keyword() {
         let mut lexer = Lexer::new("with".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(tokens[0], Token::With, "Should recognize 'with' keyword");
+        assert!(
+            matches!(tokens[0], Token::With(_)),
+            "Should recognize 'with' keyword"
+        );
     }
 
     #[test]
     fn test_pipe_token() {
         let mut lexer = Lexer::new("|".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(tokens[0], Token::Pipe, "Should recognize '|' token");
+        assert!(
+            matches!(tokens[0], Token::Pipe(_)),
+            "Should recognize '|' token"
+        );
     }
 
     #[test]
     fn test_underscore_token() {
         let mut lexer = Lexer::new("_".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(
-            tokens[0],
-            Token::Underscore,
+        assert!(
+            matches!(tokens[0], Token::Underscore(_)),
             "Should recognize '_' (wildcard) token"
         );
     }
@@ -965,7 +1553,10 @@ mod tests {
     fn test_arrow_token() {
         let mut lexer = Lexer::new("->".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(tokens[0], Token::Arrow, "Should recognize '->' token");
+        assert!(
+            matches!(tokens[0], Token::Arrow(_)),
+            "Should recognize '->' token"
+        );
     }
 
     #[test]
@@ -974,17 +1565,17 @@ mod tests {
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
 
         // Check key tokens are present
-        assert_eq!(tokens[0], Token::Match);
-        assert!(matches!(tokens[1], Token::Identifier(ref s) if s == "x"));
-        assert_eq!(tokens[2], Token::With);
-        assert_eq!(tokens[3], Token::Pipe);
-        assert_eq!(tokens[4], Token::IntegerLiteral(1));
-        assert_eq!(tokens[5], Token::Arrow);
-        assert_eq!(tokens[6], Token::IntegerLiteral(10));
-        assert_eq!(tokens[7], Token::Pipe);
-        assert_eq!(tokens[8], Token::Underscore);
-        assert_eq!(tokens[9], Token::Arrow);
-        assert_eq!(tokens[10], Token::IntegerLiteral(20));
+        assert!(matches!(tokens[0], Token::Match(_)));
+        assert!(matches!(tokens[1], Token::Identifier((_, ref s)) if s == "x"));
+        assert!(matches!(tokens[2], Token::With(_)));
+        assert!(matches!(tokens[3], Token::Pipe(_)));
+        assert!(matches!(tokens[4], Token::IntegerLiteral((_, 1))));
+        assert!(matches!(tokens[5], Token::Arrow(_)));
+        assert!(matches!(tokens[6], Token::IntegerLiteral((_, 10))));
+        assert!(matches!(tokens[7], Token::Pipe(_)));
+        assert!(matches!(tokens[8], Token::Underscore(_)));
+        assert!(matches!(tokens[9], Token::Arrow(_)));
+        assert!(matches!(tokens[10], Token::IntegerLiteral((_, 20))));
     }
 
     #[test]
@@ -992,9 +1583,8 @@ mod tests {
         // Underscore alone should be wildcard token
         let mut lexer = Lexer::new("_".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
-        assert_eq!(
-            tokens[0],
-            Token::Underscore,
+        assert!(
+            matches!(tokens[0], Token::Underscore(_)),
             "Single '_' should be wildcard"
         );
 
@@ -1002,15 +1592,215 @@ mod tests {
         let mut lexer = Lexer::new("_foo".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
         assert!(
-            matches!(tokens[0], Token::Identifier(ref s) if s == "_foo"),
+            matches!(tokens[0], Token::Identifier((_, ref s)) if s == "_foo"),
             "_foo should be identifier"
         );
 
         let mut lexer = Lexer::new("foo_bar".to_string());
         let tokens = lexer.tokenize().expect("Tokenization should succeed");
         assert!(
-            matches!(tokens[0], Token::Identifier(ref s) if s == "foo_bar"),
+            matches!(tokens[0], Token::Identifier((_, ref s)) if s == "foo_bar"),
             "foo_bar should be identifier"
         );
     }
+
+    #[test]
+    fn test_token_spans() {
+        let mut lexer = Lexer::new("decl x <- 1 in\n  print (f x);".to_string());
+        let tokens = lexer.tokenize().expect("Tokenization should succeed");
+
+        let span = |line, column, len| Span { line, column, len };
+        assert_eq!(tokens[0], Token::Decl(span(1, 1, 4)));
+        assert_eq!(tokens[1], Token::Identifier((span(1, 6, 1), "x".into())));
+        assert_eq!(tokens[2], Token::Assign(span(1, 8, 2)));
+        assert_eq!(tokens[3], Token::IntegerLiteral((span(1, 11, 1), 1)));
+        assert_eq!(tokens[4], Token::In(span(1, 13, 2)));
+        assert_eq!(tokens[5], Token::Print(span(2, 3, 5)));
+        assert_eq!(tokens[6], Token::ParenL(span(2, 9, 1)));
+        assert_eq!(tokens[7], Token::Identifier((span(2, 10, 1), "f".into())));
+        assert_eq!(tokens[9], Token::ParenR(span(2, 13, 1)));
+        assert_eq!(tokens[10], Token::Semicolon(span(2, 14, 1)));
+    }
+
+    #[test]
+    fn test_string_literals() {
+        let mut lexer = Lexer::new("import \"lib/math.mlia\"".to_string());
+        let tokens = lexer.tokenize().expect("Tokenization should succeed");
+        let span = |line, column, len| Span { line, column, len };
+        assert_eq!(tokens[0], Token::Import(span(1, 1, 6)));
+        assert_eq!(
+            tokens[1],
+            Token::StringLiteral((span(1, 8, 15), "lib/math.mlia".to_string().into()))
+        );
+
+        let mut lexer = Lexer::new("import \"a (b) # c\"".to_string());
+        let tokens = lexer.tokenize().expect("Tokenization should succeed");
+        assert!(matches!(tokens[1], Token::StringLiteral((_, ref s)) if s.text == "a (b) # c"));
+
+        let mut lexer = Lexer::new("import \"lib".to_string());
+        let error = lexer.tokenize().unwrap_err();
+        assert!(error.message.contains("línea 1, columna 8"), "{}", error);
+        assert_eq!((error.span.line, error.span.column), (1, 8));
+    }
+
+    #[test]
+    fn test_colon_token() {
+        let mut lexer = Lexer::new("(f x: 1)".to_string());
+        let tokens = lexer.tokenize().expect("Tokenization should succeed");
+        let span = |line, column, len| Span { line, column, len };
+        assert_eq!(tokens[2], Token::Identifier((span(1, 4, 1), "x".into())));
+        assert_eq!(tokens[3], Token::Colon(span(1, 5, 1)));
+    }
+
+    #[test]
+    fn test_decode_escapes() {
+        let at = Span {
+            line: 3,
+            column: 5,
+            len: 0,
+        };
+        assert_eq!(
+            decode_escapes(r#"a\nb\tc\\d\"e\'f\0"#, at),
+            Ok("a\nb\tc\\d\"e'f\0".to_string())
+        );
+        assert_eq!(
+            decode_escapes("no escapes", at),
+            Ok("no escapes".to_string())
+        );
+
+        let error = decode_escapes(r"ab\q", at).unwrap_err();
+        assert_eq!(
+            error.message,
+            r"unknown escape sequence '\q' at line 3, column 7"
+        );
+        assert_eq!(
+            error.span,
+            Span {
+                line: 3,
+                column: 7,
+                len: 2
+            }
+        );
+        // Positions follow line breaks
+        let error = decode_escapes("a\n b\\x", at).unwrap_err();
+        assert_eq!(
+            error.message,
+            r"unknown escape sequence '\x' at line 4, column 3"
+        );
+
+        // A lone backslash at the end
+        let error = decode_escapes(r"abc\", at).unwrap_err();
+        assert_eq!(
+            error.message,
+            "unterminated escape sequence at line 3, column 8"
+        );
+    }
+
+    #[test]
+    fn test_escapes_in_string_literals() {
+        let mut lexer = Lexer::new(r#"print "say \"hi\"\n" 1"#.to_string());
+        let tokens = lexer.tokenize().expect("Tokenization should succeed");
+        let span = |line, column, len| Span { line, column, len };
+        assert_eq!(
+            tokens[1],
+            Token::StringLiteral((
+                span(1, 7, 14),
+                Literal {
+                    text: "say \"hi\"\n".to_string(),
+                    raw: r#"say \"hi\"\n"#.to_string(),
+                }
+            ))
+        );
+        assert_eq!(tokens[2], Token::IntegerLiteral((span(1, 22, 1), 1)));
+
+        let mut lexer = Lexer::new(r#"print "tab\q""#.to_string());
+        let error = lexer.tokenize().unwrap_err();
+        assert_eq!(
+            error.message,
+            r"unknown escape sequence '\q' at line 1, column 11"
+        );
+
+        // The backslash escapes the closing quote, so the string never ends
+        let mut lexer = Lexer::new(r#"print "abc\""#.to_string());
+        let error = lexer.tokenize().unwrap_err();
+        assert!(
+            error.message.starts_with("Cadena sin terminar"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_char_that_starts_no_token() {
+        // Used to be processed again from Start forever
+        for (source, column) in [("{", 1), ("x {", 3), ("\"a\"{", 4)] {
+            let error = Lexer::new(source.to_string()).tokenize().unwrap_err();
+            assert!(
+                error.message.starts_with("Caracter inesperado '{'"),
+                "{}",
+                error
+            );
+            assert_eq!((error.span.line, error.span.column), (1, column));
+        }
+    }
+
+    #[test]
+    fn test_staged_keywords_follow_the_version() {
+        let mut lexer = Lexer::new("decl until <- 3 in until".to_string());
+        let tokens = lexer.tokenize().unwrap();
+        assert!(matches!(tokens[1], Token::Identifier(_)), "{:?}", tokens[1]);
+        let found: Vec<(&str, u32, usize)> = lexer
+            .future_keywords()
+            .iter()
+            .map(|keyword| (keyword.word, keyword.version, keyword.span.column))
+            .collect();
+        assert_eq!(found, [("until", 2, 6), ("until", 2, 20)]);
+
+        let mut lexer = Lexer::new("until".to_string()).with_version(2);
+        let tokens = lexer.tokenize().unwrap();
+        assert!(matches!(tokens[0], Token::Until(_)), "{:?}", tokens[0]);
+        assert!(lexer.future_keywords().is_empty());
+
+        // La pragma gana sobre la versión del lexer
+        let tokens = Lexer::new("(* lang-version 2 *)\nuntil".to_string())
+            .tokenize()
+            .unwrap();
+        assert!(matches!(tokens[0], Token::Until(_)), "{:?}", tokens[0]);
+        let tokens = Lexer::new("(* lang-version 1 *)\nuntil".to_string())
+            .with_version(2)
+            .tokenize()
+            .unwrap();
+        assert!(matches!(tokens[0], Token::Identifier(_)), "{:?}", tokens[0]);
+
+        let error = Lexer::new("(* lang-version 7 *)\n1".to_string())
+            .tokenize()
+            .unwrap_err();
+        assert!(
+            error.message.starts_with("unknown language version '7'"),
+            "{}",
+            error
+        );
+        assert_eq!((error.span.line, error.span.column), (1, 1));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_tokens_json() {
+        let tokens = Lexer::new("print \"hi\" 42".to_string())
+            .tokenize()
+            .unwrap();
+        let json = tokens_to_json(&tokens);
+        let back: Vec<Token> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, tokens);
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!([
+                {"type": "Print", "fields": {"line": 1, "column": 1, "len": 5}},
+                {"type": "StringLiteral", "fields": [{"line": 1, "column": 7, "len": 4}, "hi"]},
+                {"type": "IntegerLiteral", "fields": [{"line": 1, "column": 12, "len": 2}, 42]},
+            ])
+        );
+    }
 }
@@ -0,0 +1,1031 @@
+//! Type inference: works out the type of every value in a program without
+//! annotations, Hindley-Milner style. Every expression gets a type, possibly
+//! with type variables in it, and the places where two types have to be the
+//! same (the value and the uses of a variable, the arguments and parameters
+//! of a call, the arms of a match...) unify them. Functions are generic over
+//! what their body doesn't pin down: `decl id x <- x in ...` takes anything.
+//! Variables can be assigned, so they never are generic.
+//!
+//! Codegen still treats every value as an i64; the types only reject programs
+//! that mix them up. Truth values are ints, as in the rest of the compiler.
+
+use crate::parser::{Expr, Name, Pattern, Span, is_reduction};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+/// The type of a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    /// What `read_line` gives and string literals are.
+    Str,
+    Array(Box<Type>),
+    Vector(Box<Type>),
+    /// What `ref` gives: where a variable lives.
+    Ref(Box<Type>),
+    Option(Box<Type>),
+    /// The type of the values of `ok` and of the errors of `err`.
+    Result(Box<Type>, Box<Type>),
+    /// The types of a function's parameters and of its result.
+    Function(Vec<Type>, Box<Type>),
+    /// A type that isn't known, or anything in a generic function.
+    Var(u32),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Type arguments go first, like in ML: `int array option`
+        let argument = |ty: &Type| match ty {
+            Type::Function(..) => format!("({})", ty),
+            _ => ty.to_string(),
+        };
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Str => write!(f, "string"),
+            Type::Array(element) => write!(f, "{} array", argument(element)),
+            Type::Vector(element) => write!(f, "{} vector", argument(element)),
+            Type::Ref(value) => write!(f, "{} ref", argument(value)),
+            Type::Option(value) => write!(f, "{} option", argument(value)),
+            Type::Result(value, error) => write!(f, "({}, {}) result", value, error),
+            Type::Function(params, result) => {
+                for param in params {
+                    write!(f, "{} -> ", argument(param))?;
+                }
+                write!(f, "{}", result)
+            }
+            Type::Var(n) if *n < 26 => write!(f, "'{}", (b'a' + *n as u8) as char),
+            Type::Var(n) => write!(f, "'t{}", n),
+        }
+    }
+}
+
+/// Two types that have to be the same and aren't, with where that is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeError {
+    /// A value of one type where one of another type is needed (a loop
+    /// condition, an interpolated value, what `try` passes on...).
+    Mismatch {
+        expected: Type,
+        found: Type,
+        span: Span,
+    },
+    /// An arm of a match whose value isn't of the type of the arms before it.
+    ArmMismatch {
+        arm: Type,
+        previous: Type,
+        span: Span,
+    },
+    /// An argument of a call that the function (or builtin) doesn't take.
+    Argument {
+        function: String,
+        position: usize,
+        expected: Type,
+        found: Type,
+        span: Span,
+    },
+    /// An assignment of a value that isn't of the variable's type.
+    Assign {
+        name: String,
+        variable: Type,
+        found: Type,
+        span: Span,
+    },
+    /// A pattern that can't match the values matched (`some x` against ints).
+    Pattern {
+        pattern: Type,
+        scrutinee: Type,
+        span: Span,
+    },
+    /// An array element that isn't of the type of the elements before it.
+    Element {
+        element: Type,
+        previous: Type,
+        span: Span,
+    },
+    /// A type that would have to contain itself, like that of a vector
+    /// pushed into itself.
+    Infinite { ty: Type, span: Span },
+}
+
+impl TypeError {
+    pub fn span(&self) -> Span {
+        match self {
+            TypeError::Mismatch { span, .. }
+            | TypeError::ArmMismatch { span, .. }
+            | TypeError::Argument { span, .. }
+            | TypeError::Assign { span, .. }
+            | TypeError::Pattern { span, .. }
+            | TypeError::Element { span, .. }
+            | TypeError::Infinite { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::Mismatch {
+                expected, found, ..
+            } => write!(f, "expected {} but found {}", expected, found),
+            TypeError::ArmMismatch { arm, previous, .. } => write!(
+                f,
+                "this arm returns {} but the previous arms return {}",
+                arm, previous
+            ),
+            TypeError::Argument {
+                function,
+                position,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "argument {} of '{}' is {} but '{}' takes {}",
+                position, function, found, function, expected
+            ),
+            TypeError::Assign {
+                name,
+                variable,
+                found,
+                ..
+            } => write!(f, "'{}' holds {} but is assigned {}", name, variable, found),
+            TypeError::Pattern {
+                pattern, scrutinee, ..
+            } => write!(
+                f,
+                "a pattern for {} can't match the value matched, which is {}",
+                pattern, scrutinee
+            ),
+            TypeError::Element {
+                element, previous, ..
+            } => write!(
+                f,
+                "this element is {} but the elements before it are {}",
+                element, previous
+            ),
+            TypeError::Infinite { ty, .. } => {
+                write!(f, "a value of type {} would have to contain itself", ty)
+            }
+        }?;
+        let span = self.span();
+        write!(f, " at line {}, column {}", span.line, span.column)
+    }
+}
+
+impl Error for TypeError {}
+
+/// The types inferred for a program's declarations, in the order they appear.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TypeTable {
+    pub declarations: Vec<Declaration>,
+}
+
+/// A variable, global or function, and its type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declaration {
+    pub name: String,
+    pub span: Span,
+    pub ty: Type,
+}
+
+impl TypeTable {
+    /// The type of the first declaration of `name`.
+    pub fn get(&self, name: &str) -> Option<&Type> {
+        self.declarations
+            .iter()
+            .find(|declaration| declaration.name == name)
+            .map(|declaration| &declaration.ty)
+    }
+}
+
+impl fmt::Display for TypeTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for declaration in &self.declarations {
+            let span = declaration.span;
+            let position = format!("{}:{}", span.line, span.column);
+            writeln!(
+                f,
+                "{:7}  {} : {}",
+                position, declaration.name, declaration.ty
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Infers the types of `expr`, which has passed `semantic::resolve`. Returns
+/// the type of every declaration, or every conflict found.
+pub fn infer(expr: &Expr) -> Result<TypeTable, Vec<TypeError>> {
+    let mut inference = Inference::default();
+    inference.visit(expr, Span::default());
+    if !inference.errors.is_empty() {
+        return Err(inference.errors);
+    }
+    let declarations = inference
+        .declarations
+        .iter()
+        .map(|(name, span, ty)| Declaration {
+            name: name.to_string(),
+            span: *span,
+            ty: rename(&inference.resolve(ty), &mut HashMap::new()),
+        })
+        .collect();
+    Ok(TypeTable { declarations })
+}
+
+/// A type in scope, generic over some of its type variables.
+#[derive(Debug, Clone)]
+struct Scheme {
+    generic: Vec<u32>,
+    ty: Type,
+}
+
+impl Scheme {
+    fn plain(ty: Type) -> Self {
+        Scheme {
+            generic: Vec::new(),
+            ty,
+        }
+    }
+}
+
+/// Why two types couldn't be unified.
+enum Conflict {
+    Mismatch,
+    Infinite,
+}
+
+#[derive(Default)]
+struct Inference {
+    /// What each type variable stands for, once known.
+    bindings: Vec<Option<Type>>,
+    /// Names in scope, innermost last.
+    scope: Vec<(Name, Scheme)>,
+    /// The results of the functions around the current expression, innermost
+    /// last, for `try` to return an error from.
+    results: Vec<Type>,
+    /// The values of the `loop`s around the current expression in its
+    /// function, innermost last.
+    loops: Vec<Type>,
+    declarations: Vec<(Name, Span, Type)>,
+    errors: Vec<TypeError>,
+}
+
+impl Inference {
+    /// The type of `expr`; `at` is where the nearest expression around it
+    /// with a position is, for the ones without (numbers). Blocks,
+    /// declarations and globals on the chain a long program is (see
+    /// `CodeGen::compile_chain`) are followed in a loop, with the names they
+    /// declare in scope until its end.
+    fn visit(&mut self, expr: &Expr, at: Span) -> Type {
+        let outer_len = self.scope.len();
+        let mut at = at;
+        let mut rest = expr;
+        let ty = loop {
+            at = rest.span().unwrap_or(at);
+            match rest {
+                Expr::Block(statements) => {
+                    let Some((last, statements)) = statements.split_last() else {
+                        break Type::Int;
+                    };
+                    for statement in statements {
+                        self.visit(statement, at);
+                    }
+                    rest = last;
+                }
+                Expr::Decl(name, params, value, body, span) if params.is_empty() => {
+                    let ty = self.visit(value, at);
+                    self.declarations.push((name.clone(), *span, ty.clone()));
+                    self.scope.push((name.clone(), Scheme::plain(ty)));
+                    rest = &**body;
+                }
+                Expr::Global(name, value, body, span) => {
+                    let ty = self.visit(value, at);
+                    self.declarations.push((name.clone(), *span, ty.clone()));
+                    self.scope.push((name.clone(), Scheme::plain(ty)));
+                    rest = &**body;
+                }
+                Expr::Decl(name, params, func_body, continuation, span) => {
+                    self.declare_function(name, params, func_body, *span);
+                    rest = &**continuation;
+                }
+                last => break self.visit_node(last, at),
+            }
+        };
+        self.scope.truncate(outer_len);
+        ty
+    }
+
+    /// The type of an expression that doesn't go on with a chain (see
+    /// `visit`), at `at`.
+    fn visit_node(&mut self, expr: &Expr, at: Span) -> Type {
+        match expr {
+            Expr::Number(_) | Expr::EnvInt(..) => Type::Int,
+            Expr::Str(..) => Type::Str,
+
+            Expr::Ident(name, _) => match self.lookup(name) {
+                Some(scheme) => self.instantiate(&scheme),
+                // Already reported by `semantic::resolve`
+                None => self.fresh(),
+            },
+
+            Expr::NamedArg(_, value, _) => self.visit(value, at),
+
+            Expr::PrintFormat(_, args, _) => {
+                for arg in args {
+                    let found = self.visit(arg, at);
+                    let span = arg.span().unwrap_or(at);
+                    self.expect(&Type::Int, &found, span, |expected, found| {
+                        TypeError::Mismatch {
+                            expected,
+                            found,
+                            span,
+                        }
+                    });
+                }
+                Type::Int
+            }
+
+            Expr::Array(elements, _) => {
+                let element_type = self.fresh();
+                for element in elements {
+                    let found = self.visit(element, at);
+                    let span = element.span().unwrap_or(at);
+                    self.expect(&element_type, &found, span, |previous, element| {
+                        TypeError::Element {
+                            element,
+                            previous,
+                            span,
+                        }
+                    });
+                }
+                Type::Array(Box::new(element_type))
+            }
+
+            Expr::Call(name, args, span) => self.visit_call(name, args, *span),
+
+            Expr::Block(..) | Expr::Decl(..) | Expr::Global(..) => self.visit(expr, at),
+
+            Expr::Assign(name, value, span) => {
+                let found = self.visit(value, at);
+                if let Some(scheme) = self.lookup(name) {
+                    self.expect(&scheme.ty, &found, *span, |variable, found| {
+                        TypeError::Assign {
+                            name: name.to_string(),
+                            variable,
+                            found,
+                            span: *span,
+                        }
+                    });
+                }
+                found
+            }
+
+            Expr::While(cond, body, _) | Expr::WhileYield(cond, body, _) => {
+                let found = self.visit(cond, at);
+                let span = cond.span().unwrap_or(at);
+                self.expect(&Type::Int, &found, span, |expected, found| {
+                    TypeError::Mismatch {
+                        expected,
+                        found,
+                        span,
+                    }
+                });
+                let body = self.visit(body, at);
+                match expr {
+                    Expr::WhileYield(..) => body,
+                    _ => Type::Int,
+                }
+            }
+
+            Expr::Loop(body, _) => {
+                let value = self.fresh();
+                self.loops.push(value);
+                self.visit(body, at);
+                self.loops.pop().unwrap_or(Type::Int)
+            }
+
+            Expr::Break(value, span) => {
+                let found = match value {
+                    Some(value) => self.visit(value, *span),
+                    None => Type::Int,
+                };
+                if let Some(loop_value) = self.loops.last().cloned() {
+                    self.expect(&loop_value, &found, *span, |expected, found| {
+                        TypeError::Mismatch {
+                            expected,
+                            found,
+                            span: *span,
+                        }
+                    });
+                }
+                // Nothing is left to use what a `break` evaluates to
+                self.fresh()
+            }
+
+            Expr::Match(scrutinee, arms, span) => {
+                let scrutinee = self.visit(scrutinee, *span);
+                let mut result: Option<Type> = None;
+                for (pattern, arm) in arms {
+                    let outer_len = self.scope.len();
+                    self.check_pattern(pattern, &scrutinee, *span);
+                    let found = self.visit(arm, *span);
+                    self.scope.truncate(outer_len);
+                    let Some(previous) = &result else {
+                        result = Some(found);
+                        continue;
+                    };
+                    let arm_span = arm.span().unwrap_or(*span);
+                    self.expect(previous, &found, arm_span, |previous, arm| {
+                        TypeError::ArmMismatch {
+                            arm,
+                            previous,
+                            span: arm_span,
+                        }
+                    });
+                }
+                result.unwrap_or_else(|| self.fresh())
+            }
+        }
+    }
+
+    /// The type of a call to a builtin or to a function in scope, with the
+    /// arguments checked against its parameters.
+    fn visit_call(&mut self, name: &str, args: &[Expr], span: Span) -> Type {
+        let found: Vec<Type> = args.iter().map(|arg| self.visit(arg, span)).collect();
+        let (params, result) = if is_reduction(name) {
+            (vec![Type::Int; args.len()], Type::Int)
+        } else if let Some(signature) = self.builtin_signature(name) {
+            signature
+        } else {
+            let Some(scheme) = self.lookup(name) else {
+                // Already reported by `semantic::resolve`
+                return self.fresh();
+            };
+            match self.instantiate(&scheme) {
+                Type::Function(params, result) => (params, *result),
+                // A variable, which codegen won't call either
+                _ => return self.fresh(),
+            }
+        };
+
+        // Parameters left out have their (int) default value
+        let args = args.iter().zip(&found);
+        for (position, (param, (arg, found))) in params.iter().zip(args).enumerate() {
+            let span = arg.span().unwrap_or(span);
+            self.expect(param, found, span, |expected, found| TypeError::Argument {
+                function: name.to_string(),
+                position: position + 1,
+                expected,
+                found,
+                span,
+            });
+        }
+
+        if name == "try" {
+            // The error goes on to the function around, or ends the program
+            // with it as the result
+            let Some(Type::Result(_, error)) = params.first().map(|param| self.resolve(param))
+            else {
+                return result;
+            };
+            let (expected, found) = match self.results.last().cloned() {
+                Some(function_result) => {
+                    let value = self.fresh();
+                    (function_result, Type::Result(Box::new(value), error))
+                }
+                None => (Type::Int, *error),
+            };
+            self.expect(&expected, &found, span, |expected, found| {
+                TypeError::Mismatch {
+                    expected,
+                    found,
+                    span,
+                }
+            });
+        }
+        result
+    }
+
+    /// Brings the function `decl name params <- body in ...` into scope, for
+    /// the continuation that `visit` goes on with: the function is in scope
+    /// in its body too, and generic in the continuation.
+    fn declare_function(
+        &mut self,
+        name: &Name,
+        params: &[(Name, Option<i64>)],
+        body: &Expr,
+        span: Span,
+    ) {
+        let param_types: Vec<Type> = params
+            .iter()
+            .map(|(_, default)| match default {
+                Some(_) => Type::Int,
+                None => self.fresh(),
+            })
+            .collect();
+        let result = self.fresh();
+        let ty = Type::Function(param_types.clone(), Box::new(result.clone()));
+
+        let outer_len = self.scope.len();
+        self.scope.push((name.clone(), Scheme::plain(ty.clone())));
+        for ((param, _), param_type) in params.iter().zip(param_types) {
+            self.scope.push((param.clone(), Scheme::plain(param_type)));
+        }
+        self.results.push(result.clone());
+        // A break in the body can't leave the loops around the declaration
+        let outer_loops = std::mem::take(&mut self.loops);
+        let found = self.visit(body, span);
+        let body_span = body.span().unwrap_or(span);
+        self.expect(&result, &found, body_span, |expected, found| {
+            TypeError::Mismatch {
+                expected,
+                found,
+                span: body_span,
+            }
+        });
+        self.loops = outer_loops;
+        self.results.pop();
+        self.scope.truncate(outer_len);
+
+        let ty = self.resolve(&ty);
+        let in_scope = self.free_in_scope();
+        let mut generic = Vec::new();
+        type_vars(&ty, &mut generic);
+        generic.retain(|var| !in_scope.contains(var));
+        self.declarations.push((name.clone(), span, ty.clone()));
+        self.scope.push((name.clone(), Scheme { generic, ty }));
+    }
+
+    /// Binds the names in `pattern`, after checking that it can match values
+    /// of type `ty`.
+    fn check_pattern(&mut self, pattern: &Pattern, ty: &Type, span: Span) {
+        let (pattern_type, payload) = match pattern {
+            Pattern::Wildcard => return,
+            Pattern::Bind(name, _) => {
+                self.scope.push((name.clone(), Scheme::plain(ty.clone())));
+                return;
+            }
+            Pattern::At(name, inner, _) => {
+                self.scope.push((name.clone(), Scheme::plain(ty.clone())));
+                self.check_pattern(inner, ty, span);
+                return;
+            }
+            Pattern::Literal(_) | Pattern::Cmp(..) => (Type::Int, None),
+            Pattern::None | Pattern::Some(_) => {
+                let value = self.fresh();
+                (Type::Option(Box::new(value.clone())), Some(value))
+            }
+            Pattern::Ok(_) | Pattern::Err(_) => {
+                let (value, error) = (self.fresh(), self.fresh());
+                let payload = match pattern {
+                    Pattern::Ok(_) => value.clone(),
+                    _ => error.clone(),
+                };
+                (
+                    Type::Result(Box::new(value), Box::new(error)),
+                    Some(payload),
+                )
+            }
+        };
+        self.expect(ty, &pattern_type, span, |scrutinee, pattern| {
+            TypeError::Pattern {
+                pattern,
+                scrutinee,
+                span,
+            }
+        });
+        if let (Some(inner), Some(payload)) = (pattern.payload(), payload) {
+            self.check_pattern(inner, &payload, span);
+        }
+    }
+
+    /// The types of the parameters and the result of the builtin `name`.
+    fn builtin_signature(&mut self, name: &str) -> Option<(Vec<Type>, Type)> {
+        use Type::Int;
+        let (a, b) = (self.fresh(), self.fresh());
+        let array = || Type::Array(Box::new(a.clone()));
+        let vector = || Type::Vector(Box::new(a.clone()));
+        let result = || Type::Result(Box::new(a.clone()), Box::new(b.clone()));
+        Some(match name {
+            "+" | "-" | "*" | "/" | "%" | "emod" | "fdiv" | "<" | ">" | "=" | "!=" | "&" | "|" => {
+                (vec![Int, Int], Int)
+            }
+            "print" | "!" | "ord" | "chr" | "print_char" => (vec![Int], Int),
+            "read_line" => (vec![], Type::Str),
+            "strlen" | "print_str" => (vec![Type::Str], Int),
+            "fopen" => (vec![Type::Str, Type::Str], Int),
+            "write_line" => (vec![Int, Int], Int),
+            "fread_int" | "fclose" | "alloc" | "free" => (vec![Int], Int),
+            "load8" => (vec![Int, Int], Int),
+            "store8" => (vec![Int, Int, Int], Int),
+            "len" => (vec![array()], Int),
+            "get" => (vec![array(), Int], a.clone()),
+            "set" => (vec![array(), Int, a.clone()], a.clone()),
+            "slice" => (vec![array(), Int, Int], array()),
+            "vec_new" => (vec![], vector()),
+            "push" => (vec![vector(), a.clone()], a.clone()),
+            "pop" => (vec![vector()], a.clone()),
+            "vlen" => (vec![vector()], Int),
+            "vget" => (vec![vector(), Int], a.clone()),
+            "vset" => (vec![vector(), Int, a.clone()], a.clone()),
+            "ref" => (vec![a.clone()], Type::Ref(Box::new(a.clone()))),
+            "deref" => (vec![Type::Ref(Box::new(a.clone()))], a.clone()),
+            "store" => (vec![Type::Ref(Box::new(a.clone())), a.clone()], a.clone()),
+            "some" => (vec![a.clone()], Type::Option(Box::new(a.clone()))),
+            "none" => (vec![], Type::Option(Box::new(a.clone()))),
+            "ok" => (vec![a.clone()], result()),
+            "err" => (vec![b.clone()], result()),
+            "try" => (vec![result()], a.clone()),
+            _ => return None,
+        })
+    }
+
+    /// Unifies `expected` and `found`, or records the error `error` makes of
+    /// them (or that the type would be infinite).
+    fn expect(
+        &mut self,
+        expected: &Type,
+        found: &Type,
+        span: Span,
+        error: impl FnOnce(Type, Type) -> TypeError,
+    ) {
+        let conflict = match self.unify(expected, found) {
+            Ok(()) => return,
+            Err(conflict) => conflict,
+        };
+        // The two types are shown with the same names for their variables
+        let mut names = HashMap::new();
+        let expected = rename(&self.resolve(expected), &mut names);
+        let found = rename(&self.resolve(found), &mut names);
+        self.errors.push(match conflict {
+            Conflict::Mismatch => error(expected, found),
+            Conflict::Infinite => TypeError::Infinite { ty: found, span },
+        });
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), Conflict> {
+        let (a, b) = (self.shallow(a), self.shallow(b));
+        match (&a, &b) {
+            (Type::Var(x), Type::Var(y)) if x == y => Ok(()),
+            (Type::Var(var), other) | (other, Type::Var(var)) => {
+                if self.occurs(*var, other) {
+                    return Err(Conflict::Infinite);
+                }
+                self.bindings[*var as usize] = Some(other.clone());
+                Ok(())
+            }
+            (Type::Int, Type::Int) | (Type::Str, Type::Str) => Ok(()),
+            (Type::Array(x), Type::Array(y))
+            | (Type::Vector(x), Type::Vector(y))
+            | (Type::Ref(x), Type::Ref(y))
+            | (Type::Option(x), Type::Option(y)) => self.unify(x, y),
+            (Type::Result(x, e), Type::Result(y, f)) => {
+                self.unify(x, y)?;
+                self.unify(e, f)
+            }
+            (Type::Function(xs, x), Type::Function(ys, y)) if xs.len() == ys.len() => {
+                for (x, y) in xs.iter().zip(ys) {
+                    self.unify(x, y)?;
+                }
+                self.unify(x, y)
+            }
+            _ => Err(Conflict::Mismatch),
+        }
+    }
+
+    /// Whether the variable `var` appears in `ty`.
+    fn occurs(&self, var: u32, ty: &Type) -> bool {
+        let mut vars = Vec::new();
+        type_vars(&self.resolve(ty), &mut vars);
+        vars.contains(&var)
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.bindings.push(None);
+        Type::Var(self.bindings.len() as u32 - 1)
+    }
+
+    /// `ty`, or what it stands for if it is a variable that is known.
+    fn shallow(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(var) => match &self.bindings[*var as usize] {
+                Some(bound) => self.shallow(bound),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    /// `ty` with every known variable replaced by what it stands for.
+    fn resolve(&self, ty: &Type) -> Type {
+        let resolve = |ty: &Type| Box::new(self.resolve(ty));
+        match self.shallow(ty) {
+            Type::Array(element) => Type::Array(resolve(&element)),
+            Type::Vector(element) => Type::Vector(resolve(&element)),
+            Type::Ref(value) => Type::Ref(resolve(&value)),
+            Type::Option(value) => Type::Option(resolve(&value)),
+            Type::Result(value, error) => Type::Result(resolve(&value), resolve(&error)),
+            Type::Function(params, result) => Type::Function(
+                params.iter().map(|param| self.resolve(param)).collect(),
+                resolve(&result),
+            ),
+            ty => ty,
+        }
+    }
+
+    /// The type of a use of `scheme`: fresh variables for its generic ones.
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let fresh: HashMap<u32, Type> = scheme
+            .generic
+            .iter()
+            .map(|var| (*var, self.fresh()))
+            .collect();
+        substitute(&self.resolve(&scheme.ty), &fresh)
+    }
+
+    /// The variables in the types of the names in scope that aren't generic.
+    fn free_in_scope(&self) -> HashSet<u32> {
+        let mut free = HashSet::new();
+        for (_, scheme) in &self.scope {
+            let mut vars = Vec::new();
+            type_vars(&self.resolve(&scheme.ty), &mut vars);
+            free.extend(vars.into_iter().filter(|var| !scheme.generic.contains(var)));
+        }
+        free
+    }
+
+    fn lookup(&self, name: &str) -> Option<Scheme> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|(in_scope, _)| in_scope == name)
+            .map(|(_, scheme)| scheme.clone())
+    }
+}
+
+/// Adds the variables in `ty` to `vars`, each once, in order.
+fn type_vars(ty: &Type, vars: &mut Vec<u32>) {
+    match ty {
+        Type::Var(var) => {
+            if !vars.contains(var) {
+                vars.push(*var);
+            }
+        }
+        Type::Int | Type::Str => {}
+        Type::Array(inner) | Type::Vector(inner) | Type::Ref(inner) | Type::Option(inner) => {
+            type_vars(inner, vars)
+        }
+        Type::Result(value, error) => {
+            type_vars(value, vars);
+            type_vars(error, vars);
+        }
+        Type::Function(params, result) => {
+            for param in params {
+                type_vars(param, vars);
+            }
+            type_vars(result, vars);
+        }
+    }
+}
+
+/// `ty` with the variables in `types` replaced.
+fn substitute(ty: &Type, types: &HashMap<u32, Type>) -> Type {
+    let map = |ty: &Type| Box::new(substitute(ty, types));
+    match ty {
+        Type::Var(var) => types.get(var).cloned().unwrap_or(Type::Var(*var)),
+        Type::Int => Type::Int,
+        Type::Str => Type::Str,
+        Type::Array(element) => Type::Array(map(element)),
+        Type::Vector(element) => Type::Vector(map(element)),
+        Type::Ref(value) => Type::Ref(map(value)),
+        Type::Option(value) => Type::Option(map(value)),
+        Type::Result(value, error) => Type::Result(map(value), map(error)),
+        Type::Function(params, result) => Type::Function(
+            params
+                .iter()
+                .map(|param| substitute(param, types))
+                .collect(),
+            map(result),
+        ),
+    }
+}
+
+/// `ty` with its variables numbered from 0 in the order they appear (after
+/// the ones already in `names`), so that they show as `'a`, `'b`...
+fn rename(ty: &Type, names: &mut HashMap<u32, u32>) -> Type {
+    let mut vars = Vec::new();
+    type_vars(ty, &mut vars);
+    for var in vars {
+        let next = names.len() as u32;
+        names.entry(var).or_insert(next);
+    }
+    let renamed = names
+        .iter()
+        .map(|(var, name)| (*var, Type::Var(*name)))
+        .collect();
+    substitute(ty, &renamed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{builtin_arity, parse_program};
+
+    fn infer_source(source: &str) -> Result<TypeTable, Vec<TypeError>> {
+        infer(&parse_program(source.to_string()).unwrap())
+    }
+
+    /// The type of every declaration, as `name : type`.
+    fn types_of(source: &str) -> Vec<String> {
+        infer_source(source)
+            .unwrap()
+            .declarations
+            .iter()
+            .map(|declaration| format!("{} : {}", declaration.name, declaration.ty))
+            .collect()
+    }
+
+    /// The messages of the errors in `source`.
+    fn errors_of(source: &str) -> Vec<String> {
+        infer_source(source)
+            .unwrap_err()
+            .iter()
+            .map(TypeError::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_infers_variables_and_functions() {
+        assert_eq!(
+            types_of(
+                "decl n <- 3 in\n\
+                 decl line <- read_line in\n\
+                 decl rows <- [[1 2] [3]] in\n\
+                 decl add a b <- + a b in\n\
+                 decl first a <- get a 0 in\n\
+                 decl find a x <- match (= (get a 0) x) with | 1 -> some 0 | _ -> none in\n\
+                 (add (first (first rows)) n)"
+            ),
+            vec![
+                "n : int",
+                "line : string",
+                "rows : int array array",
+                "add : int -> int -> int",
+                "first : 'a array -> 'a",
+                "find : int array -> int -> int option",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_functions_are_generic_and_variables_are_not() {
+        // `id` is used with two types; `v` gets its type from its first push
+        assert_eq!(
+            types_of(
+                "decl id x <- x in\n\
+                 decl v <- vec_new in\n\
+                 push v (id read_line); (strlen (id (vget v 0)))"
+            ),
+            vec!["id : 'a -> 'a", "v : string vector"]
+        );
+        // A function that only passes errors on is generic over its values
+        assert_eq!(
+            types_of(
+                "decl unwrap r <- ok (try r) in\n\
+                 match (unwrap (ok 1)) with | ok n -> n | err e -> e"
+            ),
+            vec!["unwrap : ('a, 'b) result -> ('a, 'b) result"]
+        );
+    }
+
+    #[test]
+    fn test_arms_must_agree() {
+        assert_eq!(
+            errors_of("decl x <- 1 in\nmatch x with | 1 -> 2 | _ -> read_line"),
+            vec!["this arm returns string but the previous arms return int at line 2, column 30"]
+        );
+    }
+
+    #[test]
+    fn test_arguments_must_fit_the_parameters() {
+        assert_eq!(
+            errors_of("decl twice n <- * n 2 in (twice read_line)"),
+            vec!["argument 1 of 'twice' is string but 'twice' takes int at line 1, column 33"]
+        );
+        assert_eq!(
+            errors_of("decl a <- [1 2] in + (len a) a"),
+            vec!["argument 2 of '+' is int array but '+' takes int at line 1, column 30"]
+        );
+    }
+
+    #[test]
+    fn test_assignments_keep_the_type() {
+        assert_eq!(
+            errors_of("decl name <- read_line in name <- 3"),
+            vec!["'name' holds string but is assigned int at line 1, column 27"]
+        );
+    }
+
+    #[test]
+    fn test_patterns_must_fit_the_value_matched() {
+        assert_eq!(
+            errors_of("match 3 with | some n -> n | none -> 0"),
+            vec![
+                "a pattern for 'a option can't match the value matched, which is int \
+                 at line 1, column 1",
+                "a pattern for 'a option can't match the value matched, which is int \
+                 at line 1, column 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_array_elements_agree() {
+        assert_eq!(
+            errors_of("[1 read_line]"),
+            vec!["this element is string but the elements before it are int at line 1, column 4"]
+        );
+    }
+
+    #[test]
+    fn test_infinite_types() {
+        assert_eq!(
+            errors_of("decl v <- vec_new in push v v"),
+            vec!["a value of type 'a vector would have to contain itself at line 1, column 29"]
+        );
+    }
+
+    #[test]
+    fn test_try_passes_errors_on() {
+        // The function's result has to be a result
+        assert_eq!(
+            errors_of("decl f r <- + (try r) 1 in (f (err read_line))"),
+            vec!["expected ('a, 'b) result but found int at line 1, column 13"]
+        );
+        // The program's result is the error
+        assert_eq!(
+            errors_of("try (err read_line)"),
+            vec!["expected int but found string at line 1, column 1"]
+        );
+    }
+
+    #[test]
+    fn test_every_builtin_has_a_signature() {
+        let mut inference = Inference::default();
+        let names = [
+            "read_line",
+            "vec_new",
+            "none",
+            "print",
+            "!",
+            "ord",
+            "chr",
+            "print_char",
+            "strlen",
+            "print_str",
+            "fread_int",
+            "fclose",
+            "len",
+            "pop",
+            "vlen",
+            "ref",
+            "deref",
+            "alloc",
+            "free",
+            "some",
+            "ok",
+            "err",
+            "try",
+            "+",
+            "-",
+            "*",
+            "/",
+            "%",
+            "emod",
+            "fdiv",
+            "<",
+            ">",
+            "=",
+            "!=",
+            "&",
+            "|",
+            "fopen",
+            "write_line",
+            "get",
+            "push",
+            "vget",
+            "store",
+            "load8",
+            "set",
+            "vset",
+            "slice",
+            "store8",
+        ];
+        for name in names {
+            let (params, _) = inference.builtin_signature(name).unwrap();
+            assert_eq!(Some(params.len()), builtin_arity(name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Allocations made while compiling, counted by a global allocator. It only
+//! counts on the thread that asks it to, so other tests can't skew the count.
+
+use mlia::{Source, tokenize};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get().map(|n| n + 1)));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// How many allocations (and reallocations) `f` makes.
+fn allocations(f: impl FnOnce()) -> usize {
+    ALLOCATIONS.with(|count| count.set(Some(0)));
+    f();
+    ALLOCATIONS.with(|count| count.take()).unwrap()
+}
+
+#[test]
+fn test_repeated_names_are_allocated_once() {
+    let uses = 10_000;
+    let source = Source {
+        name: "counter.mlia".to_string(),
+        code: format!(
+            "decl total <- 0 in {}total",
+            "total <- + total 1; ".repeat(uses)
+        ),
+    };
+
+    let made = allocations(|| {
+        tokenize(&source).unwrap();
+    });
+    // Each use of `total` used to be a string of its own
+    assert!(
+        made < uses / 100,
+        "{} allocations to tokenize {} assignments to one name",
+        made,
+        uses
+    );
+}
@@ -0,0 +1,1190 @@
+mod common;
+
+use common::scratch_dir;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+/// Runs the mlia binary with `args` in `dir`.
+fn mlia(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .expect("Failed to run the compiler")
+}
+
+/// Runs the mlia binary with `args` in `dir`, with `input` on its stdin.
+fn mlia_with_stdin(dir: &Path, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to run the compiler");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_build_subcommand() {
+    let dir = scratch_dir("cli_build");
+    fs::write(dir.join("six.mlia"), "print 6; 0").unwrap();
+
+    let build = mlia(&dir, &["build", "six.mlia", "-o", "six"]);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let run = Command::new(dir.join("six")).output().unwrap();
+    assert_eq!(stdout(&run), "6\n");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_functions_named_like_c_symbols_link() {
+    let dir = scratch_dir("cli_c_names");
+    fs::write(
+        dir.join("clash.mlia"),
+        "decl printf x <- * x 2 in decl main x <- + x 1 in print (printf 21); (main 6)",
+    )
+    .unwrap();
+
+    let build = mlia(&dir, &["build", "clash.mlia", "-o", "clash"]);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let run = Command::new(dir.join("clash")).output().unwrap();
+    assert_eq!(stdout(&run), "42\n");
+    assert_eq!(run.status.code(), Some(7));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_bare_file_is_build() {
+    let dir = scratch_dir("cli_bare");
+    fs::write(dir.join("seven.mlia"), "7").unwrap();
+
+    let build = mlia(&dir, &["seven.mlia"]);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let run = Command::new(dir.join("seven")).output().unwrap();
+    assert_eq!(run.status.code(), Some(7));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_run_subcommand() {
+    let dir = scratch_dir("cli_run");
+    fs::write(dir.join("sum.mlia"), "decl x <- 40 in + x 2").unwrap();
+
+    let run = mlia(&dir, &["run", "sum.mlia"]);
+    assert_eq!(run.status.code(), Some(42), "{}", stderr(&run));
+    assert!(stderr(&run).contains("Result: 42"), "{}", stderr(&run));
+    assert!(!dir.join("sum").exists(), "run doesn't write an executable");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_test_subcommand() {
+    let dir = scratch_dir("cli_test");
+    fs::write(
+        dir.join("echo.mlia"),
+        "decl line <- read_line in\nprint_str line;\nprint (strlen line);\n0",
+    )
+    .unwrap();
+    fs::write(dir.join("echo.in"), "hello\n").unwrap();
+    fs::write(dir.join("echo.out"), "hello\n5\n").unwrap();
+    fs::write(dir.join("wrong.out"), "hello\n6\n").unwrap();
+
+    let test = mlia(
+        &dir,
+        &[
+            "test",
+            "echo.mlia",
+            "--expect",
+            "echo.out",
+            "--input",
+            "echo.in",
+        ],
+    );
+    assert_eq!(test.status.code(), Some(0), "{}", stderr(&test));
+    assert_eq!(stdout(&test), "");
+    assert!(
+        stderr(&test).contains("Test passed: the output matches echo.out\n"),
+        "{}",
+        stderr(&test)
+    );
+
+    let test = mlia(
+        &dir,
+        &[
+            "test",
+            "echo.mlia",
+            "--expect",
+            "wrong.out",
+            "--input",
+            "echo.in",
+        ],
+    );
+    assert_eq!(test.status.code(), Some(1), "{}", stderr(&test));
+    assert_eq!(
+        stdout(&test),
+        "--- wrong.out\n+++ output\n@@ -1,2 +1,2 @@\n hello\n-6\n+5\n"
+    );
+    assert!(
+        stderr(&test).contains("Test failed: the output differs from wrong.out\n"),
+        "{}",
+        stderr(&test)
+    );
+
+    // Without --input the program reads nothing
+    let test = mlia(&dir, &["test", "-q", "echo.mlia", "--expect", "echo.out"]);
+    assert_eq!(test.status.code(), Some(1));
+    assert!(
+        stdout(&test).contains("-hello\n-5\n+\n+0\n"),
+        "{}",
+        stdout(&test)
+    );
+    assert_eq!(stderr(&test), "");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_test_subcommand_stops_programs_that_never_end() {
+    let dir = scratch_dir("cli_test_loop");
+    // The output is the expected one, but the program doesn't finish
+    fs::write(dir.join("loop.mlia"), "print 1; while 1 do 0 done").unwrap();
+    fs::write(dir.join("loop.out"), "1\n").unwrap();
+
+    let test = mlia(&dir, &["test", "loop.mlia", "--expect", "loop.out"]);
+    assert_eq!(test.status.code(), Some(1), "{}", stderr(&test));
+    assert!(
+        stderr(&test).contains("the program ran for more than 100000000 loop iterations"),
+        "{}",
+        stderr(&test)
+    );
+
+    let test = mlia(
+        &dir,
+        &[
+            "test",
+            "loop.mlia",
+            "--expect",
+            "loop.out",
+            "--max-iterations",
+            "1000",
+        ],
+    );
+    assert_eq!(test.status.code(), Some(1));
+    assert!(
+        stderr(&test).contains("the program ran for more than 1000 loop iterations"),
+        "{}",
+        stderr(&test)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_check_subcommand() {
+    let dir = scratch_dir("cli_check");
+    fs::write(dir.join("good.mlia"), "decl x <- 1 in + x 1").unwrap();
+    fs::write(dir.join("bad.mlia"), "decl x <- 1 in + x y").unwrap();
+
+    let good = mlia(&dir, &["check", "good.mlia"]);
+    assert!(good.status.success(), "{}", stderr(&good));
+    assert!(
+        !dir.join("good").exists(),
+        "check doesn't write an executable"
+    );
+
+    let bad = mlia(&dir, &["check", "bad.mlia"]);
+    assert!(!bad.status.success());
+    assert!(
+        stderr(&bad).contains(
+            "error[E0001]: undefined variable 'y' at line 1, column 20; did you mean 'x'?\n \
+             --> bad.mlia:1:20\n"
+        ),
+        "{}",
+        stderr(&bad)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_check_subcommand_on_a_long_program() {
+    let dir = scratch_dir("cli_check_long");
+    // 100k statements, each declaring a variable in the body of the one before
+    let count = 100_000;
+    let statements: String = (1..count)
+        .map(|i| format!("print v{}; decl v{} <- + v{} 1 in\n", i - 1, i, i - 1))
+        .collect();
+    let code = format!("decl v0 <- 0 in\n{}v{}", statements, count - 1);
+    fs::write(dir.join("long.mlia"), code).unwrap();
+
+    let check = mlia(&dir, &["check", "long.mlia"]);
+    assert!(check.status.success(), "{}", stderr(&check));
+    assert!(stderr(&check).contains("No errors found."));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_types_subcommand() {
+    let dir = scratch_dir("cli_types");
+    fs::write(
+        dir.join("good.mlia"),
+        "decl n <- 2 in\ndecl first a <- get a 0 in\n(first [n])",
+    )
+    .unwrap();
+    fs::write(dir.join("bad.mlia"), "decl n <- 2 in\nn <- read_line").unwrap();
+
+    let good = mlia(&dir, &["types", "good.mlia"]);
+    assert!(good.status.success(), "{}", stderr(&good));
+    assert_eq!(
+        stdout(&good),
+        "1:6      n : int\n2:6      first : 'a array -> 'a\n"
+    );
+    let flag = mlia(&dir, &["--types", "good.mlia"]);
+    assert_eq!(stdout(&flag), stdout(&good));
+
+    let bad = mlia(&dir, &["check", "bad.mlia"]);
+    assert!(!bad.status.success());
+    assert!(
+        stderr(&bad).contains(
+            "error[E0007]: 'n' holds int but is assigned string at line 2, column 1\n \
+             --> bad.mlia:2:1\n"
+        ),
+        "{}",
+        stderr(&bad)
+    );
+    assert!(stderr(&bad).contains("1 type error(s) found, nothing was compiled"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_lang_version_stages_new_keywords() {
+    let dir = scratch_dir("cli_lang_version");
+    fs::write(dir.join("name.mlia"), "decl until <- 3 in + until 1").unwrap();
+    let program = "decl x <- 0 in until = x 5 do x <- + x 1 done; x";
+    fs::write(dir.join("loop.mlia"), program).unwrap();
+    fs::write(
+        dir.join("pragma.mlia"),
+        format!("(* lang-version 2 *)\n{}", program),
+    )
+    .unwrap();
+
+    let check = mlia(&dir, &["check", "name.mlia"]);
+    assert!(check.status.success(), "{}", stderr(&check));
+    assert!(
+        stderr(&check).contains(
+            "warning[W0005]: identifier 'until' at line 1, column 6 will become a keyword \
+             in a future version (it is one with --lang-version 2); try another name, \
+             like 'my_until'\n"
+        ),
+        "{}",
+        stderr(&check)
+    );
+    let check = mlia(&dir, &["check", "--lang-version", "2", "name.mlia"]);
+    assert!(!check.status.success());
+
+    let run = mlia(&dir, &["run", "--lang-version", "2", "loop.mlia"]);
+    assert_eq!(run.status.code(), Some(5), "{}", stderr(&run));
+    let run = mlia(&dir, &["run", "pragma.mlia"]);
+    assert_eq!(run.status.code(), Some(5), "{}", stderr(&run));
+    let run = mlia(&dir, &["run", "loop.mlia"]);
+    assert!(!run.status.success());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_tokens_subcommand() {
+    let dir = scratch_dir("cli_tokens");
+    fs::write(dir.join("one.mlia"), "print 1").unwrap();
+    // Tokenizes fine but doesn't parse
+    fs::write(dir.join("open.mlia"), "decl x <- 1 in\n  print (x").unwrap();
+    fs::write(dir.join("bad.mlia"), "decl x <- 1 in\n  + x $").unwrap();
+
+    let tokens = mlia(&dir, &["tokens", "one.mlia"]);
+    assert!(tokens.status.success(), "{}", stderr(&tokens));
+    assert_eq!(stdout(&tokens), "   1  1:1  print\n   2  1:7  integer 1\n");
+
+    let tokens = mlia(&dir, &["--tokens", "open.mlia"]);
+    assert!(tokens.status.success(), "{}", stderr(&tokens));
+    assert_eq!(
+        stdout(&tokens),
+        "   1  1:1  decl\n   2  1:6  identifier 'x'\n   3  1:8  '<-'\n   \
+         4  1:11  integer 1\n   5  1:13  in\n   6  2:3  print\n   7  2:9  '('\n   \
+         8  2:10  identifier 'x'\n"
+    );
+
+    let tokens = mlia(&dir, &["tokens", "bad.mlia"]);
+    assert!(!tokens.status.success());
+    assert_eq!(stdout(&tokens), "");
+    assert!(
+        stderr(&tokens).contains("Tokenization error"),
+        "{}",
+        stderr(&tokens)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_ast_subcommand() {
+    let dir = scratch_dir("cli_ast");
+    fs::write(dir.join("seq.mlia"), "print 1; 2").unwrap();
+
+    let ast = mlia(&dir, &["ast", "seq.mlia"]);
+    assert!(ast.status.success(), "{}", stderr(&ast));
+    assert!(stdout(&ast).starts_with("Block("), "{}", stdout(&ast));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_bench_reports_the_result_and_the_statistics() {
+    let dir = scratch_dir("cli_bench");
+    fs::write(
+        dir.join("app.mlia"),
+        "decl x <- 0 in while < x 50 do x <- + x 1 done; x",
+    )
+    .unwrap();
+
+    let bench = mlia(&dir, &["bench", "-q", "app.mlia", "--iterations", "3"]);
+    assert!(bench.status.success(), "{}", stderr(&bench));
+    let table = stdout(&bench);
+    let names: Vec<&str> = table
+        .lines()
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect();
+    assert_eq!(names, ["result", "runs", "min", "median", "mean", "max"]);
+    assert!(table.starts_with("result                50\n"), "{}", table);
+    assert!(table.contains("\nruns       3 (+0 warmup)\n"), "{}", table);
+
+    for runner in ["jit", "exe"] {
+        let args = [
+            "bench", "app.mlia", "--json", "--warmup", "1", "--runner", runner,
+        ];
+        let bench = mlia(&dir, &args);
+        assert!(bench.status.success(), "{}", stderr(&bench));
+        let report: serde_json::Value = serde_json::from_str(&stdout(&bench)).unwrap();
+        assert_eq!(report["result"], 50);
+        assert_eq!(report["iterations"], 10);
+        assert_eq!(report["warmup"], 1);
+        assert_eq!(report["samples_ns"].as_array().unwrap().len(), 10);
+        for stat in ["min_ns", "median_ns", "mean_ns", "max_ns"] {
+            assert!(report[stat].is_u64(), "{}", report);
+        }
+    }
+    assert!(
+        !dir.join("app").exists(),
+        "bench doesn't leave an executable"
+    );
+
+    let run = mlia(&dir, &["run", "--iterations", "3", "app.mlia"]);
+    assert_eq!(run.status.code(), Some(2));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_json_output() {
+    let dir = scratch_dir("cli_json");
+    fs::write(dir.join("seq.mlia"), "print 1; 2").unwrap();
+
+    let json = mlia(&dir, &["ast", "--json", "seq.mlia"]);
+    assert!(json.status.success(), "{}", stderr(&json));
+    let value: serde_json::Value = serde_json::from_str(&stdout(&json)).unwrap();
+    assert_eq!(value["type"], "Block");
+    assert_eq!(
+        value["fields"][1],
+        serde_json::json!({"type": "Number", "fields": 2})
+    );
+
+    let json = mlia(&dir, &["tokens", "--json", "seq.mlia"]);
+    assert!(json.status.success(), "{}", stderr(&json));
+    let value: serde_json::Value = serde_json::from_str(&stdout(&json)).unwrap();
+    assert_eq!(value.as_array().unwrap().len(), 4);
+    assert_eq!(value[0]["type"], "Print");
+
+    // The same from a build, to files
+    let build = mlia(
+        &dir,
+        &[
+            "build",
+            "seq.mlia",
+            "--ast-json",
+            "seq.json",
+            "--tokens-json",
+            "seq.tokens.json",
+        ],
+    );
+    assert!(build.status.success(), "{}", stderr(&build));
+    let written: mlia::parser::Expr =
+        serde_json::from_str(&fs::read_to_string(dir.join("seq.json")).unwrap()).unwrap();
+    assert_eq!(written, mlia::parse_file(dir.join("seq.mlia")).unwrap());
+    let written: Vec<mlia::parser::Token> =
+        serde_json::from_str(&fs::read_to_string(dir.join("seq.tokens.json")).unwrap()).unwrap();
+    assert_eq!(written, mlia::tokenize_file(dir.join("seq.mlia")).unwrap());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(not(feature = "serde"))]
+#[test]
+fn test_json_output_needs_the_feature() {
+    let dir = scratch_dir("cli_no_json");
+    fs::write(dir.join("seq.mlia"), "print 1; 2").unwrap();
+
+    for args in [
+        &["ast", "--json", "seq.mlia"][..],
+        &["build", "seq.mlia", "--tokens-json", "-"],
+    ] {
+        let output = mlia(&dir, args);
+        assert!(!output.status.success());
+        assert!(
+            stderr(&output).contains("--features serde"),
+            "{}",
+            stderr(&output)
+        );
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_unknown_subcommand_points_to_help() {
+    let dir = scratch_dir("cli_unknown");
+
+    let output = mlia(&dir, &["biuld", "prog.mlia"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        stderr(&output),
+        "Error: Unknown subcommand: biuld\nTry 'mlia --help' for more information.\n"
+    );
+
+    let output = mlia(&dir, &["run"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains("Try 'mlia --help'"),
+        "{}",
+        stderr(&output)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_program_from_stdin() {
+    let dir = scratch_dir("cli_stdin");
+
+    let run = mlia_with_stdin(&dir, &["-", "--jit"], "decl x <- 20 in print x; * x 2");
+    assert_eq!(run.status.code(), Some(40), "{}", stderr(&run));
+    assert_eq!(stdout(&run), "20\n");
+    assert!(stderr(&run).contains("Parsing source code from <stdin>"));
+    assert!(stderr(&run).contains("Result: 40"), "{}", stderr(&run));
+
+    // Without -o the executable is called `out`
+    let build = mlia_with_stdin(&dir, &["build", "-"], "3");
+    assert!(build.status.success(), "{}", stderr(&build));
+    let exe = Command::new(dir.join("out")).output().unwrap();
+    assert_eq!(exe.status.code(), Some(3));
+
+    let twice = mlia_with_stdin(&dir, &["run", "-", "-"], "3");
+    assert!(!twice.status.success());
+    assert!(stderr(&twice).contains("can only be read once"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_inline_expressions() {
+    let dir = scratch_dir("cli_inline");
+
+    let run = mlia(&dir, &["-e", "print ( * 6 7); 0"]);
+    assert_eq!(run.status.code(), Some(0), "{}", stderr(&run));
+    assert_eq!(stdout(&run), "42\n");
+    assert!(stderr(&run).contains("Parsing source code from <cmdline>"));
+
+    // The result is the exit code, and several -e are one program
+    let run = mlia(&dir, &["-q", "-e", "decl x <- 5 in print x", "-e", "+ x 2"]);
+    assert_eq!(run.status.code(), Some(7), "{}", stderr(&run));
+    assert_eq!(stdout(&run), "5\n");
+
+    let broken = mlia(&dir, &["-q", "--color=never", "-e", "print )"]);
+    assert_eq!(broken.status.code(), Some(1));
+    assert_eq!(
+        stderr(&broken),
+        "error[E0010]: Parse error: unexpected ')' at line 1, column 7\n \
+         --> <cmdline>:1:7\n  \
+         |\n\
+         1 | print )\n  \
+         |       ^\n"
+    );
+
+    // With -o it is built instead
+    let build = mlia(&dir, &["-e", "+ 40 2", "-o", "answer"]);
+    assert!(build.status.success(), "{}", stderr(&build));
+    assert!(stderr(&build).contains("Wrote executable: answer"));
+    let exe = Command::new(dir.join("answer")).output().unwrap();
+    assert_eq!(exe.status.code(), Some(42));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_run_exits_with_the_program_result() {
+    let dir = scratch_dir("cli_exit_code");
+    fs::write(dir.join("seven.mlia"), "print 1; 7").unwrap();
+    fs::write(dir.join("zero.mlia"), "0").unwrap();
+    fs::write(dir.join("two_hundred.mlia"), "200").unwrap();
+    fs::write(dir.join("big.mlia"), "+ 255 2").unwrap();
+    fs::write(dir.join("negative.mlia"), "- 0 1").unwrap();
+    fs::write(dir.join("broken.mlia"), "+ 6 x").unwrap();
+
+    let seven = mlia(&dir, &["run", "seven.mlia"]);
+    assert_eq!(seven.status.code(), Some(7));
+    assert_eq!(
+        stdout(&seven),
+        "1\n",
+        "stdout only has what the program prints"
+    );
+    assert!(stderr(&seven).contains("Result: 7"));
+
+    assert_eq!(mlia(&dir, &["run", "zero.mlia"]).status.code(), Some(0));
+    let status = |file| mlia(&dir, &["run", file]).status.code();
+    assert_eq!(status("two_hundred.mlia"), Some(200));
+    // Clamped to what an exit status can be
+    assert_eq!(status("big.mlia"), Some(255));
+    assert_eq!(status("negative.mlia"), Some(0));
+
+    let broken = mlia(&dir, &["run", "broken.mlia"]);
+    assert_eq!(broken.status.code(), Some(1));
+    assert_eq!(stdout(&broken), "", "nothing ran");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_debug_heap_reports_blocks_never_freed() {
+    let dir = scratch_dir("cli_debug_heap");
+    let list = "decl node v next <- decl p <- alloc 2 in store8 p 0 v; store8 p 1 next; p in\n\
+                decl a <- (node 1 0) in decl b <- (node 2 a) in decl c <- (node 3 b) in\n\
+                decl total <- + (load8 c 0) (+ (load8 (load8 c 1) 0) (load8 a 0)) in\n";
+    fs::write(
+        dir.join("freed.mlia"),
+        format!("{list}free c; free b; free a; total"),
+    )
+    .unwrap();
+    fs::write(dir.join("leaky.mlia"), format!("{list}free c; total")).unwrap();
+
+    let freed = mlia(&dir, &["run", "-q", "--debug-heap", "freed.mlia"]);
+    assert_eq!(freed.status.code(), Some(6));
+    assert_eq!(stderr(&freed), "");
+
+    let leaky = mlia(&dir, &["run", "-q", "--debug-heap", "leaky.mlia"]);
+    assert_eq!(leaky.status.code(), Some(6));
+    assert_eq!(stderr(&leaky), "leak: 2 block(s) from alloc never freed\n");
+
+    // Without the flag nothing is counted
+    let leaky = mlia(&dir, &["run", "-q", "leaky.mlia"]);
+    assert_eq!(stderr(&leaky), "");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_no_verify_changes_nothing_for_valid_programs() {
+    let dir = scratch_dir("cli_no_verify");
+    fs::write(
+        dir.join("app.mlia"),
+        "decl sq n <- * n n in print (sq 4); 3",
+    )
+    .unwrap();
+
+    let build = |name: &str, flags: &[&str]| {
+        let args = [
+            &["build", "-q", "app.mlia", "--emit=ir,exe", "-o", name][..],
+            flags,
+        ]
+        .concat();
+        let build = mlia(&dir, &args);
+        assert!(build.status.success(), "{}", stderr(&build));
+        assert_eq!(stderr(&build), "");
+        let run = Command::new(dir.join(name)).output().unwrap();
+        (
+            fs::read_to_string(dir.join(format!("{}.ll", name))).unwrap(),
+            run,
+        )
+    };
+    let (verified_ir, verified) = build("verified", &[]);
+    let (ir, run) = build("unverified", &["--no-verify"]);
+    assert_eq!(ir, verified_ir);
+    assert_eq!(stdout(&run), stdout(&verified));
+    assert_eq!(run.status.code(), verified.status.code());
+    assert_eq!(stdout(&run), "16\n");
+
+    let run = mlia(&dir, &["run", "-q", "--no-verify", "app.mlia"]);
+    assert_eq!(run.status.code(), Some(3));
+    assert_eq!(stdout(&run), "16\n");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_status_output_goes_to_stderr_and_quiet_silences_it() {
+    let dir = scratch_dir("cli_quiet");
+    fs::write(dir.join("app.mlia"), "print 1; print 2; 3").unwrap();
+
+    let run = mlia(&dir, &["run", "app.mlia"]);
+    assert_eq!(stdout(&run), "1\n2\n");
+    assert_eq!(
+        stderr(&run),
+        "Parsing source code from app.mlia...\nCompiling...\n\
+         Program executed successfully.\nResult: 3\n"
+    );
+
+    let run = mlia(&dir, &["run", "app.mlia", "--quiet"]);
+    assert_eq!(run.status.code(), Some(3));
+    assert_eq!(stdout(&run), "1\n2\n");
+    assert_eq!(stderr(&run), "");
+
+    let build = mlia(&dir, &["build", "app.mlia"]);
+    assert!(build.status.success());
+    assert_eq!(stdout(&build), "");
+    assert_eq!(
+        stderr(&build),
+        "Parsing source code from app.mlia...\nCompiling...\nWrote executable: app\n"
+    );
+
+    let build = mlia(&dir, &["build", "-q", "app.mlia"]);
+    assert!(build.status.success());
+    assert_eq!(stdout(&build), "");
+    assert_eq!(stderr(&build), "");
+
+    // Diagnostics are not status output
+    fs::write(dir.join("unused.mlia"), "decl x <- 1 in 2").unwrap();
+    let build = mlia(&dir, &["build", "-q", "unused.mlia"]);
+    assert_eq!(
+        stderr(&build),
+        "warning[W0001]: unused variable 'x' at line 1, column 6\n \
+         --> unused.mlia:1:6\n  \
+         |\n\
+         1 | decl x <- 1 in 2\n  \
+         |      ^\n"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_help_lists_every_flag() {
+    let dir = scratch_dir("cli_help");
+
+    let help = mlia(&dir, &["--help"]);
+    assert!(help.status.success());
+    let text = stdout(&help);
+    for flag in [
+        "build",
+        "run",
+        "test",
+        "check",
+        "bench",
+        "types",
+        "tokens",
+        "ast",
+        "-e <expression>",
+        "-o, --output",
+        "--emit",
+        "--keep-intermediates",
+        "--target",
+        "-g ",
+        "--no-opt",
+        "--bounds-checks",
+        "--no-bounds-checks",
+        "--debug-heap",
+        "--no-verify",
+        "--max-iterations",
+        "--lang-version",
+        "--allow-shadowing",
+        "-q, --quiet",
+        "--verbose",
+        "--ast-json",
+        "--tokens-json",
+        "--explain",
+        "--error-format",
+        "--jit",
+        "--each",
+        "--expect",
+        "--input",
+        "--tokens",
+        "--types",
+        "--json",
+        "--iterations",
+        "--warmup",
+        "--runner",
+        "-h, --help",
+        "-V, --version",
+    ] {
+        assert!(
+            text.contains(flag),
+            "--help doesn't mention {}:\n{}",
+            flag,
+            text
+        );
+    }
+    assert_eq!(stdout(&mlia(&dir, &["run", "-h"])), text);
+
+    let version = mlia(&dir, &["--version"]);
+    assert!(version.status.success());
+    assert_eq!(
+        stdout(&version),
+        format!("mlia {}\n", env!("CARGO_PKG_VERSION"))
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_argument_errors_exit_with_2() {
+    let dir = scratch_dir("cli_argument_errors");
+    fs::write(dir.join("app.mlia"), "1").unwrap();
+
+    for (args, message) in [
+        (
+            &["app.mlia", "-o"][..],
+            "Error: --output requires a filename",
+        ),
+        (&["-e"][..], "Error: -e requires an expression"),
+        (
+            &["app.mlia", "--frobnicate"][..],
+            "Error: Unknown argument: --frobnicate",
+        ),
+        (&[][..], "Error: Please provide an input file"),
+        (
+            &["app.mlia", "--color=sometimes"][..],
+            "Error: invalid --color value 'sometimes'",
+        ),
+        (
+            &["app.mlia", "--error-format=xml"][..],
+            "Error: invalid --error-format value 'xml'",
+        ),
+        (
+            &["bench", "app.mlia", "--iterations", "0"][..],
+            "Error: --iterations must be at least 1",
+        ),
+        (
+            &["bench", "app.mlia", "--runner=aot"][..],
+            "Error: invalid --runner value 'aot'",
+        ),
+        (
+            &["test", "app.mlia"][..],
+            "Error: mlia test requires --expect <file>",
+        ),
+        (
+            &["run", "app.mlia", "--input", "app.in"][..],
+            "Error: --expect and --input only apply to mlia test",
+        ),
+        (
+            &["app.mlia", "--lang-version", "9"][..],
+            "Error: invalid --lang-version value '9'; valid values are 1, 2",
+        ),
+        (
+            &["--explain", "E9999"][..],
+            "Error: Unknown error code: E9999",
+        ),
+        (
+            &["--explain"][..],
+            "Error: --explain requires an error code",
+        ),
+    ] {
+        let output = mlia(&dir, args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(stderr(&output).starts_with(message), "{}", stderr(&output));
+        assert!(stderr(&output).ends_with("Try 'mlia --help' for more information.\n"));
+        assert_eq!(stderr(&output).lines().count(), 2);
+    }
+
+    let missing = dir.join("nested").join("missing.mlia");
+    let output = mlia(&dir, &["run", missing.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains(&format!("Input file not found: {}", missing.display())),
+        "{}",
+        stderr(&output)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_build_each_builds_the_files_that_compile() {
+    let dir = scratch_dir("cli_build_each");
+    fs::write(dir.join("a.mlia"), "print 1; 0").unwrap();
+    fs::write(dir.join("b.mlia"), "+ nope 1").unwrap();
+    fs::write(dir.join("c.mlia"), "print 3; 0").unwrap();
+
+    let build = mlia(
+        &dir,
+        &[
+            "build",
+            "--each",
+            "-q",
+            "--color=never",
+            "a.mlia",
+            "b.mlia",
+            "c.mlia",
+        ],
+    );
+    assert_eq!(build.status.code(), Some(1));
+    let errors = stderr(&build);
+    assert_eq!(
+        errors.matches("undefined variable 'nope'").count(),
+        1,
+        "{}",
+        errors
+    );
+    assert!(errors.contains("--> b.mlia:1:3"), "{}", errors);
+    assert!(
+        !errors.contains("a.mlia") && !errors.contains("c.mlia"),
+        "{}",
+        errors
+    );
+    assert!(!dir.join("b").exists());
+    for (name, printed) in [("a", "1\n"), ("c", "3\n")] {
+        let run = Command::new(dir.join(name)).output().unwrap();
+        assert_eq!(stdout(&run), printed);
+    }
+
+    let named = mlia(&dir, &["build", "--each", "a.mlia", "c.mlia", "-o", "app"]);
+    assert_eq!(named.status.code(), Some(2));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_out_dir_holds_every_file() {
+    let dir = scratch_dir("cli_out_dir");
+    fs::create_dir_all(dir.join("examples")).unwrap();
+    fs::write(dir.join("examples/foo.mlia"), "print 3; 0").unwrap();
+
+    let mut args = vec![
+        "examples/foo.mlia",
+        "--out-dir",
+        "build/",
+        "--emit=ir,obj,exe",
+        "--verbose",
+    ];
+    let mut expected = vec!["foo", "foo.ll", "foo.o", "foo_verbose.txt"];
+    if cfg!(feature = "serde") {
+        args.extend(["--ast-json", "foo.json"]);
+        expected.push("foo.json");
+    }
+    let build = mlia(&dir, &args);
+    assert!(build.status.success(), "{}", stderr(&build));
+
+    let mut top: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    top.sort();
+    assert_eq!(top, ["build", "examples"]);
+    let examples: Vec<_> = fs::read_dir(dir.join("examples")).unwrap().collect();
+    assert_eq!(examples.len(), 1, "only the source is left in examples/");
+    for file in expected {
+        assert!(
+            dir.join("build").join(file).exists(),
+            "build/{} is missing",
+            file
+        );
+    }
+    let run = Command::new(dir.join("build/foo")).output().unwrap();
+    assert_eq!(stdout(&run), "3\n");
+
+    // -o is relative to the output directory
+    let build = mlia(
+        &dir,
+        &["examples/foo.mlia", "--out-dir", "build", "-o", "app"],
+    );
+    assert!(build.status.success(), "{}", stderr(&build));
+    assert!(dir.join("build/app").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_diagnostics_show_the_source_line() {
+    let dir = scratch_dir("cli_diagnostics");
+    fs::write(dir.join("lex.mlia"), "decl x <- 1 in\n  + x $").unwrap();
+    fs::write(dir.join("parse.mlia"), "decl x <- 1 in\nprint )").unwrap();
+
+    let lex = mlia(&dir, &["check", "-q", "--color=never", "lex.mlia"]);
+    assert_eq!(lex.status.code(), Some(1));
+    assert_eq!(
+        stderr(&lex),
+        "error[E0009]: Tokenization error: Caracter inesperado '$' en la línea 2, columna 7\n \
+         --> lex.mlia:2:7\n  \
+         |\n\
+         2 |   + x $\n  \
+         |       ^\n"
+    );
+
+    let parse = mlia(&dir, &["build", "-q", "--color", "never", "parse.mlia"]);
+    assert_eq!(
+        stderr(&parse),
+        "error[E0010]: Parse error: unexpected ')' at line 2, column 7\n \
+         --> parse.mlia:2:7\n  \
+         |\n\
+         2 | print )\n  \
+         |       ^\n"
+    );
+
+    let colored = mlia(&dir, &["check", "-q", "--color=always", "parse.mlia"]);
+    assert!(
+        stderr(&colored).starts_with("\x1b[1;31merror[E0010]:\x1b[0m"),
+        "{:?}",
+        stderr(&colored)
+    );
+    assert!(stderr(&colored).contains("\x1b[1;31m^\x1b[0m"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_error_format_json() {
+    let dir = scratch_dir("cli_error_format");
+    fs::write(dir.join("good.mlia"), "decl x <- 1 in + x 1").unwrap();
+    fs::write(
+        dir.join("broken.mlia"),
+        "decl x <- 1 in\n+ x y;\nz <- 2;\nx",
+    )
+    .unwrap();
+
+    // Only the summary, without status lines
+    let good = mlia(&dir, &["check", "--error-format=json", "good.mlia"]);
+    assert!(good.status.success(), "{}", stderr(&good));
+    assert_eq!(
+        stderr(&good),
+        "{\"type\":\"summary\",\"errors\":0,\"warnings\":0}\n"
+    );
+
+    // A JSON object per line: one per diagnostic, with every field (null
+    // when there is nothing to say), then the summary
+    let broken = mlia(&dir, &["check", "--error-format", "json", "broken.mlia"]);
+    assert_eq!(broken.status.code(), Some(1));
+    let objects: Vec<serde_json::Value> = stderr(&broken)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let (summary, diagnostics) = objects.split_last().unwrap();
+    for diagnostic in diagnostics {
+        assert_eq!(diagnostic["type"], "diagnostic");
+        for field in ["severity", "code", "message", "file", "span", "notes"] {
+            assert!(
+                diagnostic.get(field).is_some(),
+                "{} has no {}",
+                diagnostic,
+                field
+            );
+        }
+        assert!(diagnostic["notes"].is_array());
+    }
+    let errors: Vec<&serde_json::Value> = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic["severity"] == "error")
+        .collect();
+    assert_eq!(errors.len(), 2, "{}", stderr(&broken));
+    assert_eq!(errors[0]["code"], "E0001");
+    assert_eq!(errors[0]["file"], "broken.mlia");
+    assert_eq!(
+        errors[0]["span"],
+        serde_json::json!({"start": {"line": 2, "column": 5}, "end": {"line": 2, "column": 6}})
+    );
+    assert!(
+        errors[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("undefined variable 'y'")
+    );
+    assert_eq!(errors[1]["code"], "E0002");
+    assert_eq!(
+        errors[1]["span"]["start"],
+        serde_json::json!({"line": 3, "column": 1})
+    );
+    assert_eq!(
+        summary,
+        &serde_json::json!({"type": "summary", "errors": 2, "warnings": diagnostics.len() - 2})
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_explain_prints_what_a_code_means() {
+    let dir = scratch_dir("cli_explain");
+
+    let explain = mlia(&dir, &["--explain", "E0001"]);
+    assert!(explain.status.success(), "{}", stderr(&explain));
+    assert!(
+        stdout(&explain).starts_with("E0001: undefined variable\n\nA variable"),
+        "{}",
+        stdout(&explain)
+    );
+    assert!(stdout(&explain).contains("```mlia\ndecl x <- 1 in\n+ x y\n```"));
+
+    let warning = mlia(&dir, &["--explain=w0002"]);
+    assert!(warning.status.success());
+    assert!(stdout(&warning).starts_with("W0002: shadowed variable\n"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_dump_writes_one_file_per_part() {
+    let dir = scratch_dir("cli_dump");
+    fs::write(dir.join("app.mlia"), "decl x <- 2 in print x; 0").unwrap();
+
+    let build = mlia(&dir, &["app.mlia", "--dump=tokens", "--dump", "ast,ir"]);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let tokens = fs::read_to_string(dir.join("app.tokens.txt")).unwrap();
+    assert!(tokens.contains("\nTOKENS\n"), "{}", tokens);
+    assert!(tokens.contains("   1: Decl("), "{}", tokens);
+    let ast = fs::read_to_string(dir.join("app.ast.txt")).unwrap();
+    assert!(ast.contains("\nABSTRACT SYNTAX TREE\n"), "{}", ast);
+    assert!(ast.contains("Decl("), "{}", ast);
+    let ir = fs::read_to_string(dir.join("app.ll")).unwrap();
+    assert!(ir.starts_with("; ModuleID"), "{}", ir);
+    assert!(ir.contains("define i64 @main()"), "{}", ir);
+    assert!(stderr(&build).contains("Wrote app.tokens.txt"));
+
+    // Only what was asked for, and to stdout when asked
+    fs::remove_file(dir.join("app.tokens.txt")).unwrap();
+    fs::remove_file(dir.join("app.ll")).unwrap();
+    let run = mlia(
+        &dir,
+        &["run", "-q", "app.mlia", "--dump=ir", "--dump-to=stdout"],
+    );
+    assert_eq!(run.status.code(), Some(0), "{}", stderr(&run));
+    assert!(
+        stdout(&run).starts_with("2\n================"),
+        "{}",
+        stdout(&run)
+    );
+    assert!(stdout(&run).contains("\nLLVM IR CODE\n"));
+    assert!(!stdout(&run).contains("TOKENS"));
+    assert!(!dir.join("app.ll").exists() && !dir.join("app.tokens.txt").exists());
+
+    let check = mlia(&dir, &["check", "app.mlia", "--dump=ir"]);
+    assert_eq!(check.status.code(), Some(2));
+    let bad = mlia(&dir, &["app.mlia", "--dump=bc"]);
+    assert!(stderr(&bad).contains("invalid --dump value 'bc'"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_verbose_out() {
+    let dir = scratch_dir("cli_verbose_out");
+    fs::create_dir_all(dir.join("bin")).unwrap();
+    fs::write(dir.join("app.mlia"), "decl x <- 1 in + x 1").unwrap();
+
+    // By default the verbose file goes next to the output
+    let build = mlia(&dir, &["app.mlia", "-o", "bin/app", "--verbose"]);
+    assert!(build.status.success(), "{}", stderr(&build));
+    let verbose = fs::read_to_string(dir.join("bin/app_verbose.txt")).unwrap();
+    assert!(verbose.contains("LLVM IR CODE"), "{}", verbose);
+    assert!(!dir.join("app_verbose.txt").exists());
+
+    let build = mlia(&dir, &["app.mlia", "--verbose-out", "debug.txt"]);
+    assert!(build.status.success(), "{}", stderr(&build));
+    assert!(stderr(&build).contains("Verbose output written to: debug.txt"));
+    assert!(dir.join("debug.txt").exists());
+    assert!(!dir.join("app_verbose.txt").exists());
+
+    // Failing to write it is reported with the path, after building
+    fs::remove_file(dir.join("app")).unwrap();
+    let build = mlia(&dir, &["app.mlia", "--verbose-out", "missing/debug.txt"]);
+    assert_eq!(build.status.code(), Some(1));
+    assert!(
+        stderr(&build).contains("error: missing/debug.txt: No such file or directory"),
+        "{}",
+        stderr(&build)
+    );
+    let run = Command::new(dir.join("app")).output().unwrap();
+    assert_eq!(run.status.code(), Some(2));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_timings_table() {
+    let dir = scratch_dir("cli_timings");
+    fs::write(dir.join("app.mlia"), "+ 1 2").unwrap();
+
+    let check = mlia(&dir, &["check", "-q", "--timings", "app.mlia"]);
+    assert!(check.status.success(), "{}", stderr(&check));
+    let table = stderr(&check);
+    assert!(table.starts_with("phase "), "{}", table);
+    for phase in ["lexing", "parsing", "semantic analysis", "total"] {
+        assert!(table.contains(&format!("\n{} ", phase)), "{}", table);
+    }
+    assert!(!table.contains("linking"), "{}", table);
+
+    let run = mlia(&dir, &["run", "--timings", "app.mlia"]);
+    assert_eq!(run.status.code(), Some(2));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_run_with_arguments_builds_and_runs_an_executable() {
+    let dir = scratch_dir("cli_run_args");
+    fs::write(dir.join("app.mlia"), "decl x <- * 6 7 in print x; - x 40").unwrap();
+
+    let run = mlia(&dir, &["run", "-q", "app.mlia", "--", "one", "-h"]);
+    assert_eq!(stdout(&run), "42\n");
+    assert_eq!(run.status.code(), Some(2), "{}", stderr(&run));
+    assert!(!dir.join("app").exists(), "the executable is removed");
+
+    let run = mlia(&dir, &["run", "-q", "--keep", "app.mlia", "--"]);
+    assert_eq!(run.status.code(), Some(2), "{}", stderr(&run));
+    assert!(dir.join("app").exists(), "--keep keeps the executable");
+    fs::remove_file(dir.join("app")).unwrap();
+
+    let build = mlia(&dir, &["build", "--run", "app.mlia", "-o", "named"]);
+    assert_eq!(build.status.code(), Some(2), "{}", stderr(&build));
+    assert_eq!(stdout(&build), "42\n");
+    assert!(dir.join("named").exists(), "-o names an executable to keep");
+
+    // Nothing runs when the program doesn't compile
+    fs::write(dir.join("bad.mlia"), "print y").unwrap();
+    let run = mlia(&dir, &["run", "bad.mlia", "--", "x"]);
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(stdout(&run), "");
+
+    let check = mlia(&dir, &["check", "app.mlia", "--", "x"]);
+    assert_eq!(check.status.code(), Some(2));
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,10 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// Creates an empty scratch directory for a single test.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mlia_test_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,106 @@
+//! Golden-file tests over the programs in `tests/programs`.
+//!
+//! Every `name.mlia` is run with the JIT, and what it prints followed by
+//! `result: <value>` must match `name.expected`. Every `name.err.mlia` must
+//! fail to compile (or to run), with messages matching `name.stderr`.
+//!
+//! `UPDATE_EXPECT=1 cargo test --test examples` rewrites the expected files
+//! from what the programs do now.
+
+use inkwell::context::Context;
+use mlia::codegen::CodeGen;
+use mlia::{Error, Options, load_files};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[test]
+fn test_example_programs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut programs: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mlia"))
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty(), "no programs in {}", dir.display());
+
+    let update = env::var_os("UPDATE_EXPECT").is_some_and(|value| value == "1");
+    let mut failures = Vec::new();
+    for program in &programs {
+        let name = program.file_name().unwrap().to_string_lossy().to_string();
+        let (expected_path, actual) = match name.strip_suffix(".err.mlia") {
+            Some(stem) => (dir.join(format!("{}.stderr", stem)), failure(program)),
+            None => {
+                let stem = name.strip_suffix(".mlia").unwrap();
+                (dir.join(format!("{}.expected", stem)), output(program))
+            }
+        };
+
+        if update {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected == actual => {}
+            Ok(expected) => failures.push(format!(
+                "{}:\n--- expected ({})\n{}--- actual\n{}",
+                name,
+                expected_path.display(),
+                expected,
+                actual
+            )),
+            Err(error) => failures.push(format!(
+                "{}: cannot read {}: {}",
+                name,
+                expected_path.display(),
+                error
+            )),
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} programs don't match (UPDATE_EXPECT=1 rewrites the expected files):\n\n{}",
+        failures.len(),
+        programs.len(),
+        failures.join("\n")
+    );
+}
+
+/// What `program` prints, then its result.
+fn output(program: &Path) -> String {
+    let loaded = load_files(&[program], &Options::default())
+        .unwrap_or_else(|error| panic!("{}: {}", program.display(), messages(&error)));
+    let context = Context::create();
+    let mut codegen = CodeGen::new(&context).unwrap();
+    let (result, printed) = codegen
+        .execute_program_captured(&loaded.ast)
+        .unwrap_or_else(|error| panic!("{}: {}", program.display(), error));
+    format!("{}result: {}\n", printed, result)
+}
+
+/// The messages `program` fails with.
+fn failure(program: &Path) -> String {
+    let loaded = match load_files(&[program], &Options::default()) {
+        Ok(loaded) => loaded,
+        Err(error) => return messages(&error),
+    };
+    let context = Context::create();
+    let mut codegen = CodeGen::new(&context).unwrap();
+    match codegen.execute_program_captured(&loaded.ast) {
+        Ok((result, _)) => panic!(
+            "{} should fail, but it ran and returned {}",
+            program.display(),
+            result
+        ),
+        Err(error) => format!("{}\n", error),
+    }
+}
+
+/// One line per error in `error`.
+fn messages(error: &Error) -> String {
+    match error {
+        Error::Semantic { errors, .. } => errors.iter().map(|e| format!("{}\n", e)).collect(),
+        error => format!("{}\n", error),
+    }
+}
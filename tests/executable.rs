@@ -0,0 +1,940 @@
+mod common;
+
+use common::scratch_dir;
+use std::fs;
+use std::process::Command;
+
+// The object file is linked with the system's default gcc flags (no -no-pie),
+// which on Ubuntu/Fedora means a PIE executable.
+#[test]
+fn test_executable_links_with_default_flags() {
+    let dir = scratch_dir("pie");
+    let source = dir.join("countdown.mlia");
+    fs::write(
+        &source,
+        "decl x <- 3 in while x do print x; x <- - x 1 done; 7",
+    )
+    .unwrap();
+    let exe = dir.join("countdown");
+
+    build(&source, &exe);
+
+    let run = Command::new(&exe)
+        .output()
+        .expect("Failed to run executable");
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "3\n2\n1\n");
+    assert_eq!(
+        run.status.code(),
+        Some(7),
+        "Exit code is the program result"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Compiles `source` with the mlia binary into `exe`, panicking on failure.
+fn build(source: &std::path::Path, exe: &std::path::Path) {
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(source)
+        .arg("-o")
+        .arg(exe)
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "Compilation of {} should succeed: {}",
+        source.display(),
+        String::from_utf8_lossy(&build.stderr)
+    );
+}
+
+// Every build writes the same output path, which used to mean the same
+// `app.o` next to it: builds deleted or overwrote each other's object file.
+#[test]
+fn test_concurrent_builds_with_same_output_path() {
+    let dir = scratch_dir("concurrent");
+    let exe = dir.join("app");
+    let programs: Vec<(String, i32)> = (1..=6)
+        .map(|n| (format!("print {}; {}", n, n * 11), n * 11))
+        .collect();
+
+    let handles: Vec<_> = programs
+        .iter()
+        .enumerate()
+        .map(|(i, (code, _))| {
+            let source = dir.join(format!("app{}.mlia", i + 1));
+            fs::write(&source, code).unwrap();
+            let exe = exe.clone();
+            std::thread::spawn(move || build(&source, &exe))
+        })
+        .collect();
+    for handle in handles {
+        handle
+            .join()
+            .expect("Every concurrent build should succeed");
+    }
+
+    // Whichever build linked last, the executable is one whole program
+    let run = Command::new(&exe).output().unwrap();
+    let code = run.status.code().unwrap();
+    let n = code / 11;
+    assert!(
+        programs.iter().any(|(_, expected)| *expected == code),
+        "Unexpected exit code {}",
+        code
+    );
+    assert_eq!(String::from_utf8_lossy(&run.stdout), format!("{}\n", n));
+    assert!(!dir.join("app.o").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_existing_object_file_is_not_touched() {
+    let dir = scratch_dir("existing_obj");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "0").unwrap();
+    let user_file = dir.join("app.o");
+    fs::write(&user_file, "not an object file").unwrap();
+
+    build(&source, &dir.join("app"));
+
+    assert_eq!(
+        fs::read_to_string(&user_file).unwrap(),
+        "not an object file",
+        "A pre-existing app.o next to the output must be preserved"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_keep_intermediates_leaves_object_file() {
+    let dir = scratch_dir("keep");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "+ 1 2").unwrap();
+    let exe = dir.join("app");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("-o")
+        .arg(&exe)
+        .arg("--keep-intermediates")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(build.status.success());
+
+    let obj = dir.join("app.o");
+    assert!(obj.exists(), "The object file should be kept");
+    assert!(
+        String::from_utf8_lossy(&build.stderr).contains(&obj.display().to_string()),
+        "The kept file's path should be printed"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_emit_ir_and_obj_without_executable() {
+    let dir = scratch_dir("emit_ir_obj");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "print 5; 0").unwrap();
+    let exe = dir.join("app");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("-o")
+        .arg(&exe)
+        .arg("--emit=ir,obj")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "{}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let ir = fs::read_to_string(dir.join("app.ll")).expect("The IR should be written");
+    assert!(ir.contains("define i64 @main()"), "{}", ir);
+    assert!(
+        dir.join("app.o").exists(),
+        "The object file should be written"
+    );
+    assert!(!exe.exists(), "Nothing is linked without exe");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_emit_exe_is_the_default() {
+    let dir = scratch_dir("emit_exe");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "print 5; 3").unwrap();
+    let exe = dir.join("app");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("-o")
+        .arg(&exe)
+        .arg("--emit")
+        .arg("exe")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(build.status.success());
+
+    let run = Command::new(&exe)
+        .output()
+        .expect("Failed to run executable");
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "5\n");
+    assert_eq!(run.status.code(), Some(3));
+    let files: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(
+        files.len(),
+        2,
+        "Only the source and executable: {:?}",
+        files
+    );
+
+    let invalid = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("--emit=ir,bc")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(!invalid.status.success());
+    assert!(
+        String::from_utf8_lossy(&invalid.stderr)
+            .contains("invalid --emit value 'bc'; valid values are ir, asm, obj, exe"),
+        "{}",
+        String::from_utf8_lossy(&invalid.stderr)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_no_intermediates_left_by_default() {
+    let dir = scratch_dir("no_keep");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "+ 1 2").unwrap();
+
+    build(&source, &dir.join("app"));
+
+    let mut entries: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    entries.sort();
+    assert_eq!(entries, vec!["app", "app.mlia"]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// readelf reads ELF only; on macOS the debug info ends up in a .dSYM bundle,
+// see `test_debug_info_is_collected_into_dsym`.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_debug_info_maps_back_to_source() {
+    let dir = scratch_dir("debug_info");
+    let source = dir.join("lines.mlia");
+    fs::write(
+        &source,
+        "decl double n <- * n 2 in\ndecl x <- (double 3) in\nprint x;\nx",
+    )
+    .unwrap();
+    let exe = dir.join("lines");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("-o")
+        .arg(&exe)
+        .arg("-g")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "Compilation with -g should succeed: {}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let info = Command::new("readelf")
+        .arg("--debug-dump=info")
+        .arg(&exe)
+        .output()
+        .expect("Failed to run readelf");
+    let info = String::from_utf8_lossy(&info.stdout);
+    assert!(
+        info.contains("lines.mlia"),
+        "Compile unit names the source file"
+    );
+    assert!(info.contains("double"), "User functions get a subprogram");
+
+    let lines = Command::new("readelf")
+        .arg("--debug-dump=decodedline")
+        .arg(&exe)
+        .output()
+        .expect("Failed to run readelf");
+    let lines = String::from_utf8_lossy(&lines.stdout);
+    assert!(
+        lines
+            .lines()
+            .any(|l| l.starts_with("lines.mlia") && l.split_whitespace().nth(1) == Some("3")),
+        "Line table should have an entry for the print on line 3:\n{}",
+        lines
+    );
+
+    let run = Command::new(&exe).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "6\n");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn test_debug_info_is_collected_into_dsym() {
+    let dir = scratch_dir("dsym");
+    let source = dir.join("lines.mlia");
+    fs::write(
+        &source,
+        "decl double n <- * n 2 in\ndecl x <- (double 3) in\nprint x;\nx",
+    )
+    .unwrap();
+    let exe = dir.join("lines");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("-o")
+        .arg(&exe)
+        .arg("-g")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "Compilation with -g should succeed: {}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    // The object file is gone by now, so the DWARF must have been copied out of it
+    let dsym = dir.join("lines.dSYM");
+    assert!(dsym.is_dir(), "Expected {} to exist", dsym.display());
+    let info = Command::new("dwarfdump")
+        .arg("--debug-info")
+        .arg(&dsym)
+        .output()
+        .expect("Failed to run dwarfdump");
+    let info = String::from_utf8_lossy(&info.stdout);
+    assert!(
+        info.contains("lines.mlia"),
+        "Compile unit names the source file"
+    );
+    assert!(info.contains("double"), "User functions get a subprogram");
+
+    let run = Command::new(&exe).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "6\n");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_jit_output_comes_before_status_lines() {
+    let dir = scratch_dir("jit_order");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "print 1; print 2; 3").unwrap();
+
+    // Run through the shell to merge stdout and stderr in a single pipe.
+    let run = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "\"{}\" \"{}\" --jit 2>&1",
+            env!("CARGO_BIN_EXE_mlia"),
+            source.display()
+        ))
+        .output()
+        .expect("Failed to run the compiler");
+    assert_eq!(run.status.code(), Some(3));
+    let merged = String::from_utf8_lossy(&run.stdout);
+    let program_output = merged.find("1\n2\n").expect("Program output missing");
+    let status = merged
+        .find("Program executed successfully.")
+        .expect("Status line missing");
+    assert!(
+        program_output < status,
+        "Program output should be flushed before the status line:\n{}",
+        merged
+    );
+
+    let run = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("--jit")
+        .output()
+        .expect("Failed to run the compiler");
+    assert_eq!(
+        String::from_utf8_lossy(&run.stdout),
+        "1\n2\n",
+        "Only the program's own output goes to stdout"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_codegen_errors_name_the_variable() {
+    let dir = scratch_dir("codegen_error");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "decl x <- 1 in\n+ x y").unwrap();
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("--jit")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(!build.status.success());
+    assert!(
+        String::from_utf8_lossy(&build.stderr)
+            .contains("error[E0001]: undefined variable 'y' at line 2, column 5"),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_jit_and_executable_agree() {
+    let dir = scratch_dir("jit_vs_exe");
+    let source = dir.join("app.mlia");
+    fs::write(
+        &source,
+        "decl sq n <- * n n in\n\
+         decl i <- 3 in\n\
+         while i do print (sq i); i <- - i 1 done;\n\
+         match (sq 2) with | 4 -> 40 | _ -> 0",
+    )
+    .unwrap();
+    let exe = dir.join("app");
+
+    build(&source, &exe);
+    let native = Command::new(&exe).output().unwrap();
+
+    let jit = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("--jit")
+        .output()
+        .expect("Failed to run the compiler");
+
+    assert_eq!(String::from_utf8_lossy(&native.stdout), "9\n4\n1\n");
+    assert_eq!(native.stdout, jit.stdout);
+    assert_eq!(native.status.code(), Some(40));
+    assert_eq!(jit.status.code(), Some(40));
+    assert!(String::from_utf8_lossy(&jit.stderr).contains("Result: 40"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_verbose_dump_shows_parsed_ast_and_no_opt_keeps_bindings() {
+    let dir = scratch_dir("no_opt");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "decl x <- 5 in + x x").unwrap();
+
+    let dump = |extra: &[&str]| {
+        let run = Command::new(env!("CARGO_BIN_EXE_mlia"))
+            .arg(&source)
+            .args(["--jit", "--verbose"])
+            .args(extra)
+            .current_dir(&dir)
+            .output()
+            .expect("Failed to run the compiler");
+        assert_eq!(run.status.code(), Some(10));
+        assert!(String::from_utf8_lossy(&run.stderr).contains("Result: 10"));
+        fs::read_to_string(dir.join("app_verbose.txt")).unwrap()
+    };
+
+    // Optimized: the IR is the folded constant, the AST dump is still the parsed Decl
+    let optimized = dump(&[]);
+    let (ast, ir) = optimized.split_once("LLVM IR CODE").unwrap();
+    assert!(ast.contains("Decl("), "{}", ast);
+    assert!(!ir.contains("alloca"), "{}", ir);
+
+    let unoptimized = dump(&["--no-opt"]);
+    let (_, ir) = unoptimized.split_once("LLVM IR CODE").unwrap();
+    assert!(ir.contains("alloca"), "{}", ir);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// Timing sanity check: hoisting the invariant `* n n` and keeping the counters
+// in registers must not make the nested loops slower than the unoptimized build.
+// Timings depend on the machine and on what else runs on it, so it's left out of
+// the default run; `test_optimization_hoists_loop_invariant_code` checks the
+// hoisting itself.
+#[test]
+#[ignore = "timing-dependent; run with `cargo test --test executable -- --ignored`"]
+fn test_optimized_nested_loops_are_not_slower() {
+    let dir = scratch_dir("nested_loops");
+    let source = dir.join("nested.mlia");
+    fs::write(
+        &source,
+        "decl count n <- (\n\
+           decl total <- 0 in decl i <- 0 in\n\
+           while < i n do\n\
+             decl j <- 0 in\n\
+             while < j ( * n n) do total <- + total 1; j <- + j 1 done;\n\
+             i <- + i 1\n\
+           done;\n\
+           % total 256\n\
+         ) in (count 300)",
+    )
+    .unwrap();
+
+    // The fastest of a few runs, so that one slow start doesn't decide it
+    let time = |exe: &std::path::Path| {
+        (0..3)
+            .map(|_| {
+                let start = std::time::Instant::now();
+                let run = Command::new(exe)
+                    .output()
+                    .expect("Failed to run executable");
+                // 300 * 300 * 300 = 27000000, modulo 256
+                assert_eq!(run.status.code(), Some(27000000 % 256));
+                start.elapsed()
+            })
+            .min()
+            .unwrap()
+    };
+
+    let optimized = dir.join("optimized");
+    build(&source, &optimized);
+    let unoptimized = dir.join("unoptimized");
+    let status = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .args(["--no-opt", "-o"])
+        .arg(&unoptimized)
+        .status()
+        .expect("Failed to run the compiler");
+    assert!(status.success());
+
+    let optimized = time(&optimized);
+    let unoptimized = time(&unoptimized);
+    assert!(
+        optimized <= unoptimized * 2,
+        "optimized: {:?}, unoptimized: {:?}",
+        optimized,
+        unoptimized
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_jit_max_iterations_stops_infinite_loop() {
+    let dir = scratch_dir("max_iterations");
+    let source = dir.join("forever.mlia");
+    fs::write(&source, "while 1 do 0 done").unwrap();
+
+    let run = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .args(["--jit", "--max-iterations", "1000"])
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(!run.status.success());
+    assert!(
+        String::from_utf8_lossy(&run.stderr).contains("execution budget exceeded"),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_program_split_across_two_files() {
+    let dir = scratch_dir("two_files");
+    let util = dir.join("util.mlia");
+    fs::write(&util, "decl square n <- * n n in\ndecl offset <- 1 in\n0").unwrap();
+    let main = dir.join("main.mlia");
+    fs::write(&main, "print (square 4);\n+ (square 3) offset").unwrap();
+    let exe = dir.join("app");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&util)
+        .arg(&main)
+        .arg("-o")
+        .arg(&exe)
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "Compilation should succeed: {}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let run = Command::new(&exe).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "16\n");
+    assert_eq!(run.status.code(), Some(10));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// The operands come from the environment so that the optimizer can't fold
+// the divisions away.
+#[test]
+fn test_floor_division_in_executable() {
+    let dir = scratch_dir("fdiv");
+    let source = dir.join("fdiv.mlia");
+    fs::write(
+        &source,
+        "decl a <- env_int \"A\" in\n\
+         decl b <- env_int \"B\" in\n\
+         print (fdiv a b);\n\
+         print (fdiv (- 0 a) b);\n\
+         print (fdiv a (- 0 b));\n\
+         print (fdiv (- 0 a) (- 0 b));\n\
+         fdiv (- 0 (+ a 1)) b",
+    )
+    .unwrap();
+    let exe = dir.join("fdiv");
+
+    build(&source, &exe);
+
+    let run = Command::new(&exe)
+        .env("A", "7")
+        .env("B", "2")
+        .output()
+        .expect("Failed to run executable");
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "3\n-4\n-4\n3\n");
+    // fdiv -8 2 is exact: -4, with no adjustment (the exit code is its low byte)
+    assert_eq!(run.status.code(), Some(252));
+
+    // Dividing by zero stops the program, compiled or with the JIT
+    let jit = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .args(["run", "-q"])
+        .arg(&source)
+        .env("A", "7")
+        .env("B", "0")
+        .output()
+        .expect("Failed to run the compiler");
+    let native = Command::new(&exe)
+        .env("A", "7")
+        .env("B", "0")
+        .output()
+        .unwrap();
+    for run in [native, jit] {
+        let stderr = String::from_utf8_lossy(&run.stderr);
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "");
+        assert_eq!(run.status.code(), Some(1));
+        assert_eq!(stderr, "error: division by zero\n");
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_emod_by_zero_stops_the_program() {
+    let dir = scratch_dir("emod_zero");
+    let source = dir.join("emod.mlia");
+    fs::write(&source, "print 1; emod 7 (env_int \"B\")").unwrap();
+    let exe = dir.join("emod");
+
+    build(&source, &exe);
+
+    let jit = |b: &str| {
+        Command::new(env!("CARGO_BIN_EXE_mlia"))
+            .args(["run", "-q"])
+            .arg(&source)
+            .env("B", b)
+            .output()
+            .expect("Failed to run the compiler")
+    };
+    let native = |b: &str| Command::new(&exe).env("B", b).output().unwrap();
+    for run in [native("0"), jit("0")] {
+        let stderr = String::from_utf8_lossy(&run.stderr);
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "1\n");
+        assert_eq!(run.status.code(), Some(1));
+        assert_eq!(stderr, "error: division by zero\n");
+    }
+    for run in [native("-3"), jit("-3")] {
+        assert_eq!(run.status.code(), Some(1), "emod 7 -3 is 1");
+        assert_eq!(String::from_utf8_lossy(&run.stderr), "");
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_globals_in_executable() {
+    let dir = scratch_dir("globals");
+    let source = dir.join("counter.mlia");
+    fs::write(
+        &source,
+        "global count <- 0\n\
+         global step <- env_int \"STEP\"\n\
+         decl tick n <- count <- + count ( * n step) in\n\
+         decl reset n <- count <- n in\n\
+         (tick 1); (tick 2); print count;\n\
+         (reset 1); (tick 3); print count;\n\
+         count",
+    )
+    .unwrap();
+    let exe = dir.join("counter");
+
+    build(&source, &exe);
+
+    let run = Command::new(&exe)
+        .env("STEP", "10")
+        .output()
+        .expect("Failed to run executable");
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "30\n31\n");
+    assert_eq!(run.status.code(), Some(31));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_interpolated_print_in_executable() {
+    let dir = scratch_dir("interpolation");
+    let source = dir.join("interpolation.mlia");
+    fs::write(
+        &source,
+        "decl n <- env_int \"N\" in\n\
+         decl square <- ( * n n) in\n\
+         print \"{n} squared is {square}, 100%\";\n\
+         square",
+    )
+    .unwrap();
+    let exe = dir.join("interpolation");
+
+    build(&source, &exe);
+
+    let run = Command::new(&exe)
+        .env("N", "7")
+        .output()
+        .expect("Failed to run executable");
+    assert_eq!(
+        String::from_utf8_lossy(&run.stdout),
+        "7 squared is 49, 100%\n"
+    );
+    assert_eq!(run.status.code(), Some(49));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_print_char_in_executable() {
+    let dir = scratch_dir("print_char");
+    let source = dir.join("hi.mlia");
+    fs::write(&source, "print_char (chr 72); print_char (chr 73); 0").unwrap();
+    let exe = dir.join("hi");
+
+    build(&source, &exe);
+
+    let run = Command::new(&exe)
+        .output()
+        .expect("Failed to run executable");
+    assert_eq!(run.stdout, b"HI");
+    assert_eq!(run.status.code(), Some(0));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_read_line_in_executable() {
+    let dir = scratch_dir("read_line");
+    let source = dir.join("echo.mlia");
+    fs::write(
+        &source,
+        "decl a <- read_line in decl b <- read_line in decl c <- read_line in\n\
+         print_str a; print_str b; print_str c; (strlen a)",
+    )
+    .unwrap();
+    let exe = dir.join("echo");
+
+    build(&source, &exe);
+
+    let mut child = Command::new(&exe)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("Failed to run executable");
+    {
+        use std::io::Write;
+        // No newline after the last line; the third read_line is at EOF
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"hello\nworld").unwrap();
+    }
+    let run = child.wait_with_output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "hello\nworld\n\n");
+    assert_eq!(run.status.code(), Some(5));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_numbers_written_to_a_file_are_read_back() {
+    let dir = scratch_dir("file_io");
+    let data = dir.join("numbers.txt");
+    let writer = dir.join("writer.mlia");
+    fs::write(
+        &writer,
+        format!(
+            "decl f <- fopen \"{}\" \"w\" in\n\
+             write_line f 11; write_line f 22; write_line f 33; fclose f",
+            data.display()
+        ),
+    )
+    .unwrap();
+    let reader = dir.join("reader.mlia");
+    fs::write(
+        &reader,
+        format!(
+            "decl f <- fopen \"{}\" \"r\" in\n\
+             decl total <- + (fread_int f) (+ (fread_int f) (fread_int f)) in\n\
+             fclose f; total",
+            data.display()
+        ),
+    )
+    .unwrap();
+
+    build(&writer, &dir.join("writer"));
+    build(&reader, &dir.join("reader"));
+
+    let write = Command::new(dir.join("writer")).output().unwrap();
+    assert_eq!(write.status.code(), Some(0));
+    assert_eq!(fs::read_to_string(&data).unwrap(), "11\n22\n33\n");
+    let read = Command::new(dir.join("reader")).output().unwrap();
+    assert_eq!(read.status.code(), Some(66));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_closed_handle_is_a_runtime_error() {
+    let dir = scratch_dir("bad_handle");
+    let source = dir.join("missing.mlia");
+    fs::write(
+        &source,
+        "decl f <- fopen \"/nonexistent/dir/file\" \"r\" in fread_int f",
+    )
+    .unwrap();
+    let exe = dir.join("missing");
+
+    build(&source, &exe);
+
+    let run = Command::new(&exe).output().unwrap();
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&run.stderr),
+        "error: fread_int needs an open file, got handle 0\n"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_index_out_of_bounds_stops_the_executable() {
+    let dir = scratch_dir("bounds");
+    let source = dir.join("index.mlia");
+    fs::write(
+        &source,
+        "decl a <- [10 20 30] in get a (env_int \"MLIA_INDEX\")",
+    )
+    .unwrap();
+    let exe = dir.join("index");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("-o")
+        .arg(&exe)
+        .arg("--bounds-checks")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "{}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let run = Command::new(&exe).env("MLIA_INDEX", "2").output().unwrap();
+    assert_eq!(run.status.code(), Some(30));
+    let run = Command::new(&exe).env("MLIA_INDEX", "7").output().unwrap();
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&run.stderr),
+        "error: index 7 out of bounds (len 3)\n"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_popping_an_empty_vector_stops_the_executable() {
+    let dir = scratch_dir("pop_empty");
+    let source = dir.join("pop.mlia");
+    fs::write(
+        &source,
+        "decl v <- vec_new in push v 7; print (pop v); pop v",
+    )
+    .unwrap();
+    let exe = dir.join("pop");
+
+    build(&source, &exe);
+
+    let run = Command::new(&exe).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "7\n");
+    assert_eq!(run.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&run.stderr),
+        "error: pop from an empty vector\n"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_slice_out_of_range_stops_the_executable() {
+    let dir = scratch_dir("slice");
+    let source = dir.join("slice.mlia");
+    fs::write(
+        &source,
+        "decl a <- [1 2 3 4] in len (slice a 1 (env_int \"MLIA_COUNT\"))",
+    )
+    .unwrap();
+    let exe = dir.join("slice");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .arg("-o")
+        .arg(&exe)
+        .arg("--bounds-checks")
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "{}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let run = Command::new(&exe).env("MLIA_COUNT", "3").output().unwrap();
+    assert_eq!(run.status.code(), Some(3));
+    for (count, message) in [
+        (
+            "4",
+            "error: slice of 4 elements from 1 out of bounds (len 4)\n",
+        ),
+        (
+            "-1",
+            "error: slice of -1 elements from 1 out of bounds (len 4)\n",
+        ),
+    ] {
+        let run = Command::new(&exe)
+            .env("MLIA_COUNT", count)
+            .output()
+            .unwrap();
+        assert_eq!(run.status.code(), Some(1));
+        assert_eq!(String::from_utf8_lossy(&run.stderr), message);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
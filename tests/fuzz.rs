@@ -0,0 +1,35 @@
+//! Runs the fuzz targets (see `fuzz/`) over their corpus, without a fuzzer:
+//! the inputs in `fuzz/corpus`, the programs in `tests/programs`, and every
+//! prefix of each, since a program cut short anywhere is a good input too.
+
+use mlia::fuzz::{fuzz_check, fuzz_lex, fuzz_parse};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn test_fuzz_targets_over_corpus() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut inputs = Vec::new();
+    for dir in ["fuzz/corpus", "tests/programs"] {
+        for entry in fs::read_dir(root.join(dir)).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "mlia") {
+                inputs.push(fs::read(&path).unwrap());
+            }
+        }
+    }
+    assert!(
+        inputs.len() > 20,
+        "the corpus has only {} inputs",
+        inputs.len()
+    );
+
+    for input in &inputs {
+        for end in 0..=input.len() {
+            let data = &input[..end];
+            fuzz_lex(data);
+            fuzz_parse(data);
+            fuzz_check(data);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+@fmt_str = private unnamed_addr constant [6 x i8] c"%lld\0A\00", align 1
+
+define internal void @mlia_print_i64(i64 %N) {
+entry:
+  %printf_call = call i32 (ptr, ...) @printf(ptr @fmt_str, i64 %N)
+  ret void
+}
+
+define i64 @main() {
+entry:
+  %i = alloca i64, align 8
+  store i64 0, ptr %i, align 8
+  br label %loop_header_l1
+
+loop_header_l1:
+  %i1 = load i64, ptr %i, align 8
+  %cmp = icmp slt i64 %i1, 10
+  %i2 = load i64, ptr %i, align 8
+  %cmp3 = icmp eq i64 %i2, 5
+  %not = xor i1 %cmp3, true
+  %and_tmp = and i1 %cmp, %not
+  br i1 %and_tmp, label %loop_body_l1, label %loop_exit_l1
+
+loop_body_l1:
+  %i4 = load i64, ptr %i, align 8
+  %add = add i64 %i4, 1
+  store i64 %add, ptr %i, align 8
+  br label %loop_header_l1
+
+loop_exit_l1:
+  %i5 = load i64, ptr %i, align 8
+  ret i64 %i5
+}
+
+declare i32 @printf(ptr, ...)
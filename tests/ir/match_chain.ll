@@ -0,0 +1,42 @@
+@fmt_str = private unnamed_addr constant [6 x i8] c"%lld\0A\00", align 1
+
+define internal void @mlia_print_i64(i64 %N) {
+entry:
+  %printf_call = call i32 (ptr, ...) @printf(ptr @fmt_str, i64 %N)
+  ret void
+}
+
+define i64 @main() {
+entry:
+  %match_result = alloca i64, align 8
+  %x = alloca i64, align 8
+  store i64 2, ptr %x, align 8
+  %x1 = load i64, ptr %x, align 8
+  br label %match_check_l1_0
+
+match_merge_l1:
+  %match_result2 = load i64, ptr %match_result, align 8
+  ret i64 %match_result2
+
+match_check_l1_0:
+  %match_cmp_0 = icmp eq i64 %x1, 1
+  br i1 %match_cmp_0, label %match_arm_l1_0, label %match_check_l1_1
+
+match_arm_l1_0:
+  store i64 10, ptr %match_result, align 8
+  br label %match_merge_l1
+
+match_check_l1_1:
+  %match_cmp_1 = icmp eq i64 %x1, 2
+  br i1 %match_cmp_1, label %match_arm_l1_1, label %match_check_l1_2
+
+match_arm_l1_1:
+  store i64 20, ptr %match_result, align 8
+  br label %match_merge_l1
+
+match_check_l1_2:
+  store i64 0, ptr %match_result, align 8
+  br label %match_merge_l1
+}
+
+declare i32 @printf(ptr, ...)
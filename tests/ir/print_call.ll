@@ -0,0 +1,15 @@
+@fmt_str = private unnamed_addr constant [6 x i8] c"%lld\0A\00", align 1
+
+define internal void @mlia_print_i64(i64 %N) {
+entry:
+  %printf_call = call i32 (ptr, ...) @printf(ptr @fmt_str, i64 %N)
+  ret void
+}
+
+define i64 @main() {
+entry:
+  call void @mlia_print_i64(i64 42)
+  ret i64 42
+}
+
+declare i32 @printf(ptr, ...)
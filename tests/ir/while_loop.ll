@@ -0,0 +1,31 @@
+@fmt_str = private unnamed_addr constant [6 x i8] c"%lld\0A\00", align 1
+
+define internal void @mlia_print_i64(i64 %N) {
+entry:
+  %printf_call = call i32 (ptr, ...) @printf(ptr @fmt_str, i64 %N)
+  ret void
+}
+
+define i64 @main() {
+entry:
+  %i = alloca i64, align 8
+  store i64 3, ptr %i, align 8
+  br label %loop_header_l1
+
+loop_header_l1:
+  %i1 = load i64, ptr %i, align 8
+  %cond = icmp ne i64 %i1, 0
+  br i1 %cond, label %loop_body_l1, label %loop_exit_l1
+
+loop_body_l1:
+  %i2 = load i64, ptr %i, align 8
+  %sub = sub i64 %i2, 1
+  store i64 %sub, ptr %i, align 8
+  br label %loop_header_l1
+
+loop_exit_l1:
+  %i3 = load i64, ptr %i, align 8
+  ret i64 %i3
+}
+
+declare i32 @printf(ptr, ...)
@@ -0,0 +1,326 @@
+//! Snapshot tests of the IR generated for small programs, against the files
+//! in `tests/ir`.
+//!
+//! The IR is compared after `normalize_ir`, so that the snapshots don't
+//! change when LLVM numbers temporaries differently, adds attributes or
+//! metadata, or prints pointers with their pointee type (before LLVM 15)
+//! instead of `ptr`. `UPDATE_EXPECT=1 cargo test --test ir_snapshots`
+//! rewrites the snapshots from the IR generated now.
+
+use inkwell::context::Context;
+use mlia::codegen::CodeGen;
+use mlia::parser::parse_program;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// `ir` without what changes between LLVM versions and runs:
+///
+/// - the module header (`ModuleID`, `source_filename`, datalayout, triple),
+/// - attribute groups (`#0`) and metadata (`!dbg !12`, `!0 = ...`),
+/// - `; preds = ...` comments,
+/// - the numbers of unnamed values (`%0`, `%12` become `%N`),
+/// - pointee types (`i8*` becomes `ptr`, and a constant `getelementptr` to
+///   the start of a global becomes the global, as with opaque pointers).
+///
+/// Function declarations are sorted and go last, and blank lines are
+/// collapsed.
+fn normalize_ir(ir: &str) -> String {
+    let mut lines = Vec::new();
+    let mut declarations = Vec::new();
+    for line in ir.lines() {
+        let line = line.trim_end();
+        if line.starts_with("; ModuleID")
+            || line.starts_with("source_filename")
+            || line.starts_with("target ")
+            || line.starts_with("attributes #")
+            || line.starts_with('!')
+        {
+            continue;
+        }
+        let line = strip_comment(line);
+        let line = without_metadata(&without_attribute_refs(&line));
+        let line = opaque_pointers(&unnamed_values(&line));
+        let line = line.trim_end().to_string();
+        if line.starts_with("declare ") {
+            declarations.push(line);
+        } else if !(line.is_empty() && lines.last().is_none_or(|last: &String| last.is_empty())) {
+            lines.push(line);
+        }
+    }
+    declarations.sort();
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    if !declarations.is_empty() {
+        lines.push(String::new());
+        lines.extend(declarations);
+    }
+    lines.join("\n") + "\n"
+}
+
+/// `line` without a `;` comment (outside string constants).
+fn strip_comment(line: &str) -> String {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return line[..i].trim_end().to_string(),
+            _ => {}
+        }
+    }
+    line.to_string()
+}
+
+/// `line` without references to attribute groups, like the `#0` in
+/// `define i64 @main() #0 {`.
+fn without_attribute_refs(line: &str) -> String {
+    let is_group = |word: &str| {
+        word.strip_prefix('#')
+            .is_some_and(|group| !group.is_empty() && group.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let words: Vec<&str> = line.split(' ').filter(|word| !is_group(word)).collect();
+    words.join(" ")
+}
+
+/// `line` without metadata attachments, like `, !dbg !12`.
+fn without_metadata(line: &str) -> String {
+    match line.find(", !") {
+        Some(start) if !line[start..].contains('"') => line[..start].to_string(),
+        _ => line.to_string(),
+    }
+}
+
+/// `line` with every unnamed value (`%0`, `%12`) written `%N`.
+fn unnamed_values(line: &str) -> String {
+    replace_words(line, |word| {
+        let number = word.strip_prefix('%')?;
+        let digits = number.trim_end_matches([',', ')', ':']);
+        (!digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| format!("%N{}", &number[digits.len()..]))
+    })
+}
+
+/// Applies `replace` to every space-separated word of `line`, keeping the
+/// words it returns `None` for.
+fn replace_words(line: &str, replace: impl Fn(&str) -> Option<String>) -> String {
+    let words: Vec<String> = line
+        .split(' ')
+        .map(|word| replace(word).unwrap_or_else(|| word.to_string()))
+        .collect();
+    words.join(" ")
+}
+
+/// `line` with typed pointers (`i8*`, `[6 x i8]*`, `i32 (i8*, ...)*`) written
+/// `ptr`, and `getelementptr inbounds ([N x T], ptr @g, i32 0, i32 0)` as `@g`.
+fn opaque_pointers(line: &str) -> String {
+    let mut out = String::new();
+    let mut in_string = false;
+    for c in line.chars() {
+        if c == '"' {
+            in_string = !in_string;
+        }
+        if c != '*' || in_string {
+            out.push(c);
+            continue;
+        }
+        // Take the pointee type back off the end of `out`
+        let end = pointee_start(&out);
+        out.truncate(end);
+        out.push_str("ptr");
+    }
+
+    const GEP: &str = "getelementptr inbounds (";
+    while let Some(start) = out.find(GEP) {
+        let args = &out[start + GEP.len()..];
+        let Some(close) = args.find(')') else {
+            break;
+        };
+        let parts: Vec<&str> = args[..close].split(", ").collect();
+        let global = match parts.as_slice() {
+            [_, pointer, "i32 0", "i32 0"] => pointer.strip_prefix("ptr "),
+            _ => None,
+        };
+        let Some(global) = global else {
+            break;
+        };
+        out = format!("{}{}{}", &out[..start], global, &args[close + 1..]);
+    }
+    out
+}
+
+/// Where the type that ends `out` starts.
+fn pointee_start(out: &str) -> usize {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '%';
+    let Some(last) = out.chars().next_back() else {
+        return 0;
+    };
+    let open = match last {
+        ']' => '[',
+        ')' => '(',
+        '}' => '{',
+        c if is_name(c) => return out.trim_end_matches(is_name).len(),
+        _ => return out.len(),
+    };
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in out.char_indices().rev() {
+        if c == last {
+            depth += 1;
+        } else if c == open {
+            depth -= 1;
+            if depth == 0 {
+                start = i;
+                break;
+            }
+        }
+    }
+    // A function type also has its return type before the `(`
+    if last == ')' && out[..start].ends_with(' ') {
+        pointee_start(&out[..start - 1])
+    } else {
+        start
+    }
+}
+
+/// Compiles `source` and compares its normalized IR with `tests/ir/<name>.ll`
+/// (or writes it there with `UPDATE_EXPECT=1`).
+fn assert_ir_snapshot(source: &str, name: &str) {
+    let ast = parse_program(source.to_string()).unwrap();
+    let context = Context::create();
+    let mut codegen = CodeGen::new(&context).unwrap();
+    codegen.compile_program(&ast).unwrap();
+    let actual = normalize_ir(&codegen.get_ir_string());
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/ir")
+        .join(format!("{}.ll", name));
+    if env::var_os("UPDATE_EXPECT").is_some_and(|value| value == "1") {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|error| panic!("cannot read {}: {}", path.display(), error));
+    assert!(
+        expected == actual,
+        "the IR of `{}` doesn't match {} (UPDATE_EXPECT=1 rewrites it)\n\
+         --- expected\n{}--- actual\n{}",
+        source,
+        path.display(),
+        expected,
+        actual
+    );
+}
+
+#[test]
+fn test_normalize_ir() {
+    let llvm14 = r#"; ModuleID = 'mlia_module'
+source_filename = "mlia_module"
+target datalayout = "e-m:e-i64:64"
+
+@fmt_str = private unnamed_addr constant [6 x i8] c"%lld\0A\00", align 1
+
+define internal void @print(i64 %0) #0 {
+entry:
+  %1 = call i32 (i8*, ...) @printf(i8* getelementptr inbounds ([6 x i8], [6 x i8]* @fmt_str, i32 0, i32 0), i64 %0), !dbg !7
+  ret void
+}
+
+declare i32 @printf(i8*, ...)
+
+define i64 @main() {
+entry:
+  %x = alloca i64, align 8
+  br label %next
+
+next:                                             ; preds = %entry
+  %x1 = load i64, i64* %x, align 8
+  ret i64 %x1
+}
+
+declare i64 @atoll(i8*)
+
+attributes #0 = { nounwind }
+!7 = !DILocation(line: 1, column: 1, scope: !3)
+"#;
+    let llvm18 = r#"; ModuleID = 'mlia_module'
+source_filename = "mlia_module"
+target datalayout = "e-m:o-i64:64"
+target triple = "arm64-apple-macosx14.0.0"
+
+@fmt_str = private unnamed_addr constant [6 x i8] c"%lld\0A\00", align 1
+
+define internal void @print(i64 %0) {
+entry:
+  %2 = call i32 (ptr, ...) @printf(ptr @fmt_str, i64 %0)
+  ret void
+}
+
+declare i32 @printf(ptr, ...)
+
+define i64 @main() {
+entry:
+  %x = alloca i64, align 8
+  br label %next
+
+next:                                             ; preds = %entry
+  %x1 = load i64, ptr %x, align 8
+  ret i64 %x1
+}
+
+declare i64 @atoll(ptr)
+"#;
+    let expected = r#"@fmt_str = private unnamed_addr constant [6 x i8] c"%lld\0A\00", align 1
+
+define internal void @print(i64 %N) {
+entry:
+  %N = call i32 (ptr, ...) @printf(ptr @fmt_str, i64 %N)
+  ret void
+}
+
+define i64 @main() {
+entry:
+  %x = alloca i64, align 8
+  br label %next
+
+next:
+  %x1 = load i64, ptr %x, align 8
+  ret i64 %x1
+}
+
+declare i32 @printf(ptr, ...)
+declare i64 @atoll(ptr)
+"#;
+    assert_eq!(normalize_ir(llvm14), expected);
+    assert_eq!(normalize_ir(llvm18), expected);
+    assert_eq!(
+        opaque_pointers("store i32 (i8*, ...)* @printf, i32 (i8*, ...)** %f"),
+        "store ptr @printf, ptr %f"
+    );
+}
+
+#[test]
+fn test_print_call_snapshot() {
+    assert_ir_snapshot("print 42", "print_call");
+}
+
+#[test]
+fn test_while_loop_snapshot() {
+    assert_ir_snapshot("decl i <- 3 in while i do i <- - i 1 done; i", "while_loop");
+}
+
+#[test]
+fn test_match_chain_snapshot() {
+    assert_ir_snapshot(
+        "decl x <- 2 in match x with | 1 -> 10 | 2 -> 20 | _ -> 0",
+        "match_chain",
+    );
+}
+
+#[test]
+fn test_logical_condition_snapshot() {
+    assert_ir_snapshot(
+        "decl i <- 0 in while & (< i 10) (! (= i 5)) do i <- + i 1 done; i",
+        "logical_condition",
+    );
+}
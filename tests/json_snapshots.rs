@@ -0,0 +1,41 @@
+//! The JSON of a reference program, against `tests/json`, so that the format
+//! external tools read (`ast --json`, `--ast-json`) only changes on purpose.
+//!
+//! The trees are compared as JSON values, so whitespace doesn't matter.
+//! `UPDATE_EXPECT=1 cargo test --features serde --test json_snapshots`
+//! rewrites the snapshot from the JSON written now.
+#![cfg(feature = "serde")]
+
+use mlia::parser::{Expr, parse_program};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn test_reference_program() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/json");
+    let source = fs::read_to_string(dir.join("reference.mlia")).unwrap();
+    let ast = parse_program(source).unwrap();
+    let actual = ast.to_json() + "\n";
+
+    let path = dir.join("reference.json");
+    if env::var_os("UPDATE_EXPECT").is_some_and(|value| value == "1") {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|error| panic!("cannot read {}: {}", path.display(), error));
+    let value = |json: &str| serde_json::from_str::<serde_json::Value>(json).unwrap();
+    assert!(
+        value(&expected) == value(&actual),
+        "the JSON of reference.mlia doesn't match {} (UPDATE_EXPECT=1 rewrites it)\n\
+         --- expected\n{}--- actual\n{}",
+        path.display(),
+        expected,
+        actual
+    );
+
+    // Files written before still read back
+    let read: Expr = serde_json::from_str(&expected).unwrap();
+    assert_eq!(read, ast);
+}
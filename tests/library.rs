@@ -0,0 +1,426 @@
+mod common;
+
+use common::scratch_dir;
+use mlia::bench::{Runner, Settings};
+use mlia::codegen::{CodeGen, CodegenError};
+use mlia::parser::parse_program;
+use mlia::timings;
+use mlia::{
+    Error, Options, Severity, Source, build_each, check_str, compile_file, load_files,
+    load_sources, run_str,
+};
+use std::fs;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_run_str_returns_the_result() {
+    let result = run_str("decl x <- 5 in decl y <- 7 in + x y").unwrap();
+    assert_eq!(result, 12);
+}
+
+#[test]
+fn test_run_str_reports_undefined_names() {
+    match run_str("+ x 1") {
+        Err(Error::Semantic { errors, .. }) => assert_eq!(errors.len(), 1),
+        other => panic!("Expected a semantic error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_run_str_reports_runtime_errors() {
+    // The program runs inside this process, so a failed runtime check has to
+    // come back as an error instead of exiting
+    match run_str("/ 1 0") {
+        Err(Error::Codegen(CodegenError::RuntimeError(message))) => {
+            assert_eq!(message, "division by zero")
+        }
+        other => panic!("Expected a runtime error, got {:?}", other),
+    }
+    // From inside a function called in a loop, which would never end
+    let looping = "decl f n <- / 10 n in decl i <- 3 in while 1 do i <- - i 1; (f i) done";
+    assert!(matches!(
+        run_str(looping),
+        Err(Error::Codegen(CodegenError::RuntimeError(_)))
+    ));
+    assert_eq!(run_str("+ 1 2").unwrap(), 3);
+}
+
+#[test]
+fn test_check_str() {
+    assert_eq!(check_str("decl x <- 1 in + x 1"), []);
+
+    // Warnings come before the errors
+    let diagnostics = check_str("decl unused <- 1 in\n+ y 1");
+    let severities: Vec<Severity> = diagnostics.iter().map(|d| d.severity).collect();
+    assert_eq!(severities, [Severity::Warning, Severity::Error]);
+    assert_eq!(
+        diagnostics[1].span.map(|s| (s.line, s.column)),
+        Some((2, 3))
+    );
+
+    // Warnings alone don't stop the program from compiling
+    let diagnostics = check_str("decl unused <- 1 in 2");
+    let severities: Vec<Severity> = diagnostics.iter().map(|d| d.severity).collect();
+    assert_eq!(severities, [Severity::Warning]);
+
+    let diagnostics = check_str("decl x <-");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].severity, Severity::Error);
+}
+
+/// A program of `count` statements, each declaring a variable in the body
+/// of the one before it, that ends in `last`.
+fn long_program(count: usize, last: &str) -> String {
+    let statements: String = (1..count)
+        .map(|i| format!("print v{}; decl v{} <- + v{} 1 in\n", i - 1, i, i - 1))
+        .collect();
+    format!("decl v0 <- 0 in\n{}{}", statements, last)
+}
+
+#[test]
+fn test_long_programs_are_checked() {
+    // A chain of declarations 100k deep, which no pass can go down recursively
+    let count = 100_000;
+    let last = format!("v{}", count - 1);
+    assert_eq!(check_str(&long_program(count, &last)), []);
+
+    let diagnostics = check_str(&long_program(count, &format!("+ {} w", last)));
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.contains("undefined variable 'w'"));
+    assert_eq!(diagnostics[0].span.map(|s| s.line), Some(count + 1));
+}
+
+// Benchmark of the whole pipeline, parse to JIT run, on a program 100k
+// statements long and as deep. Its tree is cloned, compared and dropped with
+// a work list (see `Expr`), so handling it has to stay a small part of
+// compiling it. Timings depend on the machine, so it's left out of the
+// default run.
+#[test]
+#[ignore = "timing-dependent; run with `cargo test --release --test library -- --ignored`"]
+fn test_long_programs_are_cheap_to_handle() {
+    let count = 100_000;
+    let source = Source {
+        name: "long.mlia".to_string(),
+        code: long_program(count, &format!("v{}", count - 1)),
+    };
+    let options = Options::default();
+
+    let start = Instant::now();
+    let program = load_sources(&[source], &options).unwrap();
+    let loading = start.elapsed();
+    let start = Instant::now();
+    let (result, _) = program.run_captured(&options, "").unwrap();
+    let running = start.elapsed();
+    assert_eq!(result, count as i64 - 1);
+
+    let start = Instant::now();
+    let copy = program.ast.clone();
+    assert!(copy == program.ast);
+    drop((copy, program));
+    let handling = start.elapsed();
+
+    eprintln!(
+        "{} statements: {:?} to load, {:?} to run, {:?} to clone, compare and drop",
+        count, loading, running, handling
+    );
+    assert!(
+        handling * 10 < loading + running,
+        "loading: {:?}, running: {:?}, handling the tree: {:?}",
+        loading,
+        running,
+        handling
+    );
+}
+
+// Checking is for feedback on every save, so it has to stay well ahead of a
+// build, which also generates, optimizes and links the code. An order of
+// magnitude is expected; the bound leaves room for a busy machine.
+#[test]
+fn test_checking_is_much_faster_than_building() {
+    let dir = scratch_dir("library_check_speed");
+    let source = dir.join("functions.mlia");
+    let functions: String = (0..500)
+        .map(|i| {
+            format!(
+                "decl f{i} n <- (decl t <- 0 in decl k <- 0 in \
+                 while < k n do t <- + t ( * k {i}); k <- + k 1 done; t) in\n"
+            )
+        })
+        .collect();
+    let code = format!("{}(f1 3)", functions);
+    fs::write(&source, &code).unwrap();
+
+    let checking = (0..3)
+        .map(|_| {
+            let start = Instant::now();
+            let diagnostics = check_str(&code);
+            assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+            start.elapsed()
+        })
+        .min()
+        .unwrap();
+    let options = Options {
+        output: Some(dir.join("functions")),
+        ..Options::default()
+    };
+    let start = Instant::now();
+    compile_file(&source, options).unwrap();
+    let building = start.elapsed();
+
+    assert!(
+        checking * 5 < building,
+        "checking: {:?}, building: {:?}",
+        checking,
+        building
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_compile_file_builds_an_executable() {
+    let dir = scratch_dir("library_compile");
+    let source = dir.join("sum.mlia");
+    fs::write(&source, "decl x <- 4 in print x; + x 1").unwrap();
+    let options = Options {
+        output: Some(dir.join("sum")),
+        ..Options::default()
+    };
+
+    let artifacts = compile_file(&source, options).unwrap();
+    assert_eq!(artifacts.executable, Some(dir.join("sum")));
+    assert_eq!(artifacts.intermediates, Vec::<std::path::PathBuf>::new());
+    assert_eq!(artifacts.verbose_file, None);
+
+    let run = Command::new(dir.join("sum")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "4\n");
+    assert_eq!(run.status.code(), Some(5));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_compile_file_reports_the_missing_path() {
+    let dir = scratch_dir("library_missing");
+    let source = dir.join("missing.mlia");
+    match compile_file(&source, Options::default()) {
+        Err(Error::Io { path, .. }) => assert_eq!(path, source),
+        other => panic!("Expected an I/O error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_build_each_keeps_going_after_a_failure() {
+    let dir = scratch_dir("library_build_each");
+    let source = |name: &str, code: &str| Source {
+        name: dir.join(name).display().to_string(),
+        code: code.to_string(),
+    };
+    let sources = [
+        source("one.mlia", "1"),
+        source("two.mlia", "+ missing 1"),
+        source("three.mlia", "3"),
+    ];
+    let options = Options {
+        out_dir: Some(dir.clone()),
+        ..Options::default()
+    };
+
+    let builds = build_each(&sources, &options);
+    let files: Vec<&str> = builds.iter().map(|build| build.file.as_str()).collect();
+    assert_eq!(
+        files,
+        [&sources[0].name, &sources[1].name, &sources[2].name]
+    );
+    assert_eq!(
+        builds[0].result.as_ref().unwrap().executable,
+        Some(dir.join("one"))
+    );
+    assert!(matches!(builds[1].result, Err(Error::Semantic { .. })));
+    assert_eq!(
+        builds[2].result.as_ref().unwrap().executable,
+        Some(dir.join("three"))
+    );
+    let run = Command::new(dir.join("three")).status().unwrap();
+    assert_eq!(run.code(), Some(3));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_codegen_captures_printed_output() {
+    let ast = parse_program("print 1; print 2; 3".to_string()).unwrap();
+    let context = inkwell::context::Context::create();
+    let mut codegen = CodeGen::new(&context).unwrap();
+    let (result, output) = codegen.execute_program_captured(&ast).unwrap();
+    assert_eq!(result, 3);
+    assert_eq!(output, "1\n2\n");
+    assert!(codegen.get_ir_string().contains("define i64 @main()"));
+}
+
+#[test]
+fn test_imports_two_levels() {
+    let dir = scratch_dir("library_imports");
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("lib/square.mlia"), "decl square n <- * n n in 0").unwrap();
+    fs::write(
+        dir.join("lib/math.mlia"),
+        "import \"square.mlia\"\ndecl cube n <- * n (square n) in 0",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("main.mlia"),
+        "import \"lib/math.mlia\"\n+ (square 2) (cube 2)",
+    )
+    .unwrap();
+
+    let options = Options::default();
+    let program = load_files(&[dir.join("main.mlia")], &options).unwrap();
+    assert_eq!(program.files.len(), 3);
+    assert!(program.files[0].ends_with("square.mlia"));
+    assert_eq!(program.run(&options).unwrap(), 12);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_imports_diamond_parses_each_file_once() {
+    let dir = scratch_dir("library_imports_diamond");
+    fs::write(dir.join("base.mlia"), "decl one <- 1 in 0").unwrap();
+    fs::write(
+        dir.join("left.mlia"),
+        "import \"base.mlia\"\ndecl two <- + one one in 0",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("right.mlia"),
+        "import \"base.mlia\"\ndecl three <- + one 2 in 0",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("main.mlia"),
+        "import \"left.mlia\"\nimport \"right.mlia\"\n+ two three",
+    )
+    .unwrap();
+
+    let options = Options::default();
+    let program = load_files(&[dir.join("main.mlia")], &options).unwrap();
+    assert_eq!(program.files.len(), 4);
+    assert_eq!(program.run(&options).unwrap(), 5);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_import_cycle_shows_the_chain() {
+    let dir = scratch_dir("library_imports_cycle");
+    fs::write(dir.join("a.mlia"), "import \"b.mlia\"\ndecl a <- 1 in 0").unwrap();
+    fs::write(dir.join("b.mlia"), "import \"a.mlia\"\ndecl b <- 2 in 0").unwrap();
+    fs::write(dir.join("main.mlia"), "import \"a.mlia\"\n+ a b").unwrap();
+
+    match load_files(&[dir.join("main.mlia")], &Options::default()) {
+        Err(Error::Import(message)) => {
+            let a = dir.join("a.mlia").display().to_string();
+            let b = dir.join("b.mlia").display().to_string();
+            assert_eq!(message, format!("Import cycle: {} -> {} -> {}", a, b, a));
+        }
+        other => panic!("Expected an import cycle, got {:?}", other),
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_missing_import_reports_its_position() {
+    let dir = scratch_dir("library_imports_missing");
+    let main = dir.join("main.mlia");
+    fs::write(&main, "import \"nowhere.mlia\"\n1").unwrap();
+
+    match load_files(&[&main], &Options::default()) {
+        Err(Error::Import(message)) => assert!(
+            message.starts_with(&format!(
+                "{}:1:8: cannot import 'nowhere.mlia'",
+                main.display()
+            )),
+            "{}",
+            message
+        ),
+        other => panic!("Expected an import error, got {:?}", other),
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_timings_cover_every_phase() {
+    let dir = scratch_dir("library_timings");
+    let source = dir.join("timed.mlia");
+    fs::write(&source, "decl x <- 4 in while x do x <- - x 1 done; x").unwrap();
+    let options = Options {
+        output: Some(dir.join("timed")),
+        timings: true,
+        ..Options::default()
+    };
+
+    let artifacts = compile_file(&source, options).unwrap();
+    let timings = artifacts.timings.unwrap();
+    for phase in [
+        timings::LEXING,
+        timings::PARSING,
+        timings::SEMANTIC,
+        timings::AST_OPTIMIZATION,
+        timings::CODEGEN,
+        timings::LLVM_OPTIMIZATION,
+        timings::EMISSION,
+        timings::LINKING,
+    ] {
+        let duration = timings.get(phase);
+        assert!(
+            duration.is_some_and(|d| d >= Duration::ZERO),
+            "{} is missing",
+            phase
+        );
+    }
+    assert_eq!(
+        timings.total(),
+        timings.phases().iter().map(|(_, d)| *d).sum()
+    );
+
+    // Nothing is timed unless asked
+    let artifacts = compile_file(
+        &source,
+        Options {
+            output: Some(dir.join("timed")),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(artifacts.timings, None);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_bench_runs_the_program_compiled_once() {
+    let source = Source {
+        name: "bench.mlia".to_string(),
+        code: "decl x <- 0 in while < x 300 do x <- + x 1 done; x".to_string(),
+    };
+    let program = load_sources(&[source], &Options::default()).unwrap();
+
+    for (runner, result) in [(Runner::Jit, 300), (Runner::Executable, 300 % 256)] {
+        let settings = Settings {
+            iterations: 4,
+            warmup: 2,
+            runner,
+        };
+        let report = program.bench(&Options::default(), &settings).unwrap();
+        assert_eq!(report.result, result, "{:?}", runner);
+        assert_eq!(report.warmup, 2);
+        assert_eq!(report.samples.len(), 4);
+        let stats = report.stats;
+        assert!(stats.min <= stats.median && stats.median <= stats.max);
+        assert!(stats.min <= stats.mean && stats.mean <= stats.max);
+    }
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use common::scratch_dir;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+#[test]
+#[ignore = "needs node on the PATH; run with `cargo test --test wasm -- --ignored`"]
+fn test_wasm32_module_runs_in_js_host() {
+    let dir = scratch_dir("wasm32");
+    let source = dir.join("countdown.mlia");
+    fs::write(
+        &source,
+        "decl twice n <- * n 2 in\n\
+         decl x <- 3 in\n\
+         while x do print (twice x); x <- - x 1 done;\n\
+         42",
+    )
+    .unwrap();
+    let module = dir.join("countdown.wasm");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .args(["--target", "wasm32-unknown-unknown", "-o"])
+        .arg(&module)
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "Compilation should succeed: {}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let harness = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/wasm_harness.js");
+    let run = Command::new("node")
+        .arg(&harness)
+        .arg(&module)
+        .output()
+        .expect("Failed to run node");
+    assert!(
+        run.status.success(),
+        "The module should run: {}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&run.stdout),
+        "6\n4\n2\nresult: 42\n"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_wasm32_rejects_jit() {
+    let dir = scratch_dir("wasm32_jit");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "1").unwrap();
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .args(["--target", "wasm32-unknown-unknown", "--jit"])
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(!build.status.success());
+    assert!(
+        String::from_utf8_lossy(&build.stderr).contains("only available for the native target")
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_wasm32_keeps_explicit_output_name() {
+    let dir = scratch_dir("wasm32_output");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "1").unwrap();
+    let module = dir.join("app");
+
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .args(["--target", "wasm32-unknown-unknown", "-o"])
+        .arg(&module)
+        .output()
+        .expect("Failed to run the compiler");
+    assert!(
+        build.status.success(),
+        "Compilation should succeed: {}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+    assert!(module.is_file(), "-o names the output exactly");
+    assert!(!dir.join("app.wasm").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_wasm32_without_wasm_ld_warns_and_writes_the_object() {
+    let dir = scratch_dir("wasm32_unlinked");
+    let source = dir.join("app.mlia");
+    fs::write(&source, "1").unwrap();
+    let module = dir.join("app.wasm");
+
+    // Nothing to run on a PATH that is only this directory
+    let build = Command::new(env!("CARGO_BIN_EXE_mlia"))
+        .arg(&source)
+        .args(["--target", "wasm32-unknown-unknown", "--timings", "-o"])
+        .arg(&module)
+        .env("PATH", &dir)
+        .output()
+        .expect("Failed to run the compiler");
+    let stderr = String::from_utf8_lossy(&build.stderr);
+    assert!(build.status.success(), "{}", stderr);
+    assert!(stderr.contains("warning: wasm-ld not found"), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("Wrote {}", module.display())),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("Wrote executable"), "{}", stderr);
+    assert!(stderr.contains("linking"), "{}", stderr);
+    assert!(fs::read(&module).unwrap().starts_with(b"\0asm"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,20 @@
+// Runs an MLIA program compiled with `--target wasm32-unknown-unknown`.
+// Prints every number the program printed, one per line, then "result: N".
+const fs = require('fs');
+
+const bytes = fs.readFileSync(process.argv[2]);
+const printed = [];
+const env = {
+  print_i64: (value) => printed.push(value),
+  // Only imported by unlinked object files (when wasm-ld wasn't available)
+  __linear_memory: new WebAssembly.Memory({ initial: 2 }),
+  __stack_pointer: new WebAssembly.Global({ value: 'i32', mutable: true }, 65536),
+};
+
+WebAssembly.instantiate(bytes, { env }).then(({ instance }) => {
+  const result = instance.exports.main();
+  for (const value of printed) {
+    console.log(value.toString());
+  }
+  console.log(`result: ${result}`);
+});